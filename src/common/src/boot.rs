@@ -127,6 +127,14 @@ impl BootInformation {
         &self.memory_map
     }
 
+    pub fn kernel_start(&self) -> u64 {
+        self.kernel_start
+    }
+
+    pub fn kernel_size(&self) -> usize {
+        self.kernel_size
+    }
+
     pub fn elf_section(&self) -> &Elf<'static> {
         &self.elf_section
     }
@@ -1,5 +1,8 @@
 #![feature(os_str_display)]
 use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
 
 fn main() {
     nasm_rs::compile_library_args("bootlib", &["src/boot/boot.asm"], &["-felf64"]).unwrap();
@@ -7,4 +10,34 @@ fn main() {
     println!("cargo:rustc-link-arg={}/boot.o", outdir.display());
     println!("cargo:rustc-link-arg=-T");
     println!("cargo:rustc-link-arg=linker.ld");
+
+    for program in [
+        "exit",
+        "hello",
+        "bad_write",
+        "args",
+        "pie",
+        "parent",
+        "spin",
+        "close",
+        "fork",
+        "segv",
+        "mmap",
+        "protect",
+        "kill_self",
+    ] {
+        assemble_program(&outdir, program);
+    }
+}
+
+/// Embedded user programs are flat nasm binaries carrying their own elf headers
+fn assemble_program(outdir: &OsStr, name: &str) {
+    let output = Path::new(outdir).join(format!("{}.elf", name));
+    let status = Command::new("nasm")
+        .args(["-f", "bin", "-i", "src/userland/programs/", "-o"])
+        .arg(&output)
+        .arg(format!("src/userland/programs/{}.asm", name))
+        .status()
+        .expect("Failed to run nasm");
+    assert!(status.success(), "Failed to assemble {}", name);
 }
@@ -0,0 +1,395 @@
+use core::error::Error;
+use core::ffi::c_void;
+use core::fmt::Display;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+
+use alloc::vec::Vec;
+use common::boot::BootInformation;
+use conquer_once::spin::OnceCell;
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+use uefi::table::{Runtime, SystemTable};
+
+use crate::log;
+use crate::memory::memory_controller;
+use crate::memory::paging::EntryFlags;
+
+use self::fadt::Fadt;
+use self::hpet::Hpet;
+use self::madt::Madt;
+use self::mcfg::Mcfg;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+static ACPI: OnceCell<Acpi> = OnceCell::uninit();
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    InvalidRsdp,
+    InvalidChecksum([u8; 4]),
+    InvalidSignature([u8; 4]),
+    InvalidLength([u8; 4]),
+    TableNotFound([u8; 4]),
+}
+
+impl Display for AcpiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoRsdp => write!(f, "Cannot find RSDP in the uefi configuration table"),
+            Self::InvalidRsdp => write!(f, "RSDP signature or checksum is invalid"),
+            Self::InvalidChecksum(sig) => {
+                write!(f, "Invalid checksum for table {}", signature_str(sig))
+            }
+            Self::InvalidSignature(sig) => {
+                write!(f, "Unexpected table signature {}", signature_str(sig))
+            }
+            Self::InvalidLength(sig) => {
+                write!(f, "Table {} is too short", signature_str(sig))
+            }
+            Self::TableNotFound(sig) => write!(f, "Cannot find table {}", signature_str(sig)),
+        }
+    }
+}
+
+impl Error for AcpiError {}
+
+fn signature_str(signature: &[u8; 4]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Fields below only exist on revision >= 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// Header shared by every system description table
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// ACPI Generic Address Structure
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    pub fn address_space(&self) -> AddressSpace {
+        match self.address_space {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        let address = self.address;
+        address == 0
+    }
+}
+
+/// A validated system description table that is identity mapped in memory
+#[derive(Clone, Copy)]
+pub struct Sdt {
+    address: u64,
+    header: SdtHeader,
+    data: &'static [u8],
+}
+
+impl Sdt {
+    /// Map and validate the table located at the physical `address`
+    ///
+    /// # Safety
+    /// The address must point to an ACPI system description table
+    unsafe fn from_address(address: u64) -> Result<Self, AcpiError> {
+        map_physical(address, size_of::<SdtHeader>() as u64);
+        let header = ptr::read_unaligned(address as *const SdtHeader);
+        let length = header.length as usize;
+        if length < size_of::<SdtHeader>() {
+            return Err(AcpiError::InvalidLength(header.signature));
+        }
+        map_physical(address, length as u64);
+        let data = slice::from_raw_parts(address as *const u8, length);
+        if !checksum(data) {
+            return Err(AcpiError::InvalidChecksum(header.signature));
+        }
+        Ok(Self {
+            address,
+            header,
+            data,
+        })
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.header.signature
+    }
+
+    /// Table content after the header
+    pub fn body(&self) -> &'static [u8] {
+        &self.data[size_of::<SdtHeader>()..]
+    }
+
+    fn expect_signature(&self, signature: &[u8; 4]) -> Result<(), AcpiError> {
+        if &self.header.signature != signature {
+            return Err(AcpiError::InvalidSignature(self.header.signature));
+        }
+        Ok(())
+    }
+}
+
+/// Read a plain old data structure from `data` at `offset`
+///
+/// # Safety
+/// Every bit pattern must be valid for `T`
+unsafe fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    if offset.checked_add(size_of::<T>())? > data.len() {
+        return None;
+    }
+    Some(ptr::read_unaligned(data.as_ptr().add(offset) as *const T))
+}
+
+/// Read a structure that may be truncated on older table revisions, missing fields are zeroed
+///
+/// # Safety
+/// Every bit pattern must be valid for `T`
+unsafe fn read_truncated<T: Copy>(data: &[u8]) -> T {
+    let mut value = core::mem::zeroed::<T>();
+    let len = data.len().min(size_of::<T>());
+    ptr::copy_nonoverlapping(data.as_ptr(), &mut value as *mut T as *mut u8, len);
+    value
+}
+
+fn checksum(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn map_physical(address: u64, size: u64) {
+    memory_controller().lock().ident_map_unmapped(
+        size,
+        address,
+        EntryFlags::PRESENT | EntryFlags::NO_EXECUTE,
+    );
+}
+
+pub struct Acpi {
+    revision: u8,
+    oem_id: [u8; 6],
+    tables: Vec<Sdt>,
+    madt: Option<Madt>,
+    fadt: Option<Fadt>,
+    hpet: Option<Hpet>,
+    mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    /// Locate the RSDP through the uefi configuration table and parse every table it points to
+    ///
+    /// # Safety
+    /// `runtime_system_table` must be the address of the uefi runtime system table
+    unsafe fn new(runtime_system_table: u64) -> Result<Self, AcpiError> {
+        let rsdp_address = Self::find_rsdp(runtime_system_table)?;
+        map_physical(rsdp_address, size_of::<Rsdp>() as u64);
+        let rsdp = ptr::read_unaligned(rsdp_address as *const Rsdp);
+        if &rsdp.signature != RSDP_SIGNATURE
+            || !checksum(slice::from_raw_parts(
+                rsdp_address as *const u8,
+                RSDP_V1_SIZE,
+            ))
+        {
+            return Err(AcpiError::InvalidRsdp);
+        }
+
+        // The extended checksum covers `length` bytes, which may go past the structure
+        let use_xsdt = rsdp.revision >= 2 && {
+            let length = rsdp.length as usize;
+            length >= size_of::<Rsdp>()
+                && {
+                    map_physical(rsdp_address, length as u64);
+                    checksum(slice::from_raw_parts(rsdp_address as *const u8, length))
+                }
+                && rsdp.xsdt_address != 0
+        };
+
+        let root = if use_xsdt {
+            let xsdt = Sdt::from_address(rsdp.xsdt_address)?;
+            xsdt.expect_signature(b"XSDT")?;
+            xsdt
+        } else {
+            let rsdt = Sdt::from_address(rsdp.rsdt_address as u64)?;
+            rsdt.expect_signature(b"RSDT")?;
+            rsdt
+        };
+
+        let entry_size = if use_xsdt {
+            size_of::<u64>()
+        } else {
+            size_of::<u32>()
+        };
+        let mut tables = Vec::new();
+        for entry in root.body().chunks_exact(entry_size) {
+            let address = match entry_size {
+                8 => u64::from_le_bytes(entry.try_into().unwrap()),
+                _ => u32::from_le_bytes(entry.try_into().unwrap()) as u64,
+            };
+            match Sdt::from_address(address) {
+                Ok(table) => tables.push(table),
+                Err(err) => log!(Warning, "Skipping acpi table at {:#x}: {}", address, err),
+            }
+        }
+
+        let mut acpi = Self {
+            revision: rsdp.revision,
+            oem_id: rsdp.oem_id,
+            tables,
+            madt: None,
+            fadt: None,
+            hpet: None,
+            mcfg: None,
+        };
+        acpi.madt = acpi.parse(b"APIC", Madt::parse);
+        acpi.fadt = acpi.parse(b"FACP", Fadt::parse);
+        acpi.hpet = acpi.parse(b"HPET", Hpet::parse);
+        acpi.mcfg = acpi.parse(b"MCFG", Mcfg::parse);
+        Ok(acpi)
+    }
+
+    unsafe fn find_rsdp(runtime_system_table: u64) -> Result<u64, AcpiError> {
+        map_physical(runtime_system_table, 0x1000);
+        let system_table = SystemTable::<Runtime>::from_ptr(runtime_system_table as *mut c_void)
+            .ok_or(AcpiError::NoRsdp)?;
+        let config_table = system_table.config_table();
+        if config_table.is_empty() {
+            return Err(AcpiError::NoRsdp);
+        }
+        map_physical(
+            config_table.as_ptr() as u64,
+            core::mem::size_of_val(config_table) as u64,
+        );
+
+        config_table
+            .iter()
+            .find(|entry| entry.guid == ACPI2_GUID)
+            .or_else(|| config_table.iter().find(|entry| entry.guid == ACPI_GUID))
+            .map(|entry| entry.address as u64)
+            .ok_or(AcpiError::NoRsdp)
+    }
+
+    fn parse<T>(&self, signature: &[u8; 4], parser: fn(&Sdt) -> Result<T, AcpiError>) -> Option<T> {
+        let table = self.find_table(signature)?;
+        match parser(table) {
+            Ok(table) => Some(table),
+            Err(err) => {
+                log!(Error, "Failed to parse acpi table: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<&Sdt> {
+        self.tables.iter().find(|e| &e.signature() == signature)
+    }
+
+    pub fn tables(&self) -> &[Sdt] {
+        &self.tables
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    pub fn oem_id(&self) -> &[u8; 6] {
+        &self.oem_id
+    }
+
+    pub fn madt(&self) -> Option<&Madt> {
+        self.madt.as_ref()
+    }
+
+    pub fn fadt(&self) -> Option<&Fadt> {
+        self.fadt.as_ref()
+    }
+
+    pub fn hpet(&self) -> Option<&Hpet> {
+        self.hpet.as_ref()
+    }
+
+    pub fn mcfg(&self) -> Option<&Mcfg> {
+        self.mcfg.as_ref()
+    }
+}
+
+pub fn acpi() -> &'static Acpi {
+    ACPI.get().expect("ACPI not initialized")
+}
+
+pub fn init(boot_info: &BootInformation) {
+    let acpi = match unsafe { Acpi::new(boot_info.runtime_system_table()) } {
+        Ok(acpi) => acpi,
+        Err(err) => panic!("Failed to initialize acpi: {}", err),
+    };
+    log!(
+        Info,
+        "ACPI revision {}, OEM: {}",
+        acpi.revision(),
+        core::str::from_utf8(acpi.oem_id()).unwrap_or("unknown")
+    );
+    for table in acpi.tables() {
+        log!(
+            Trace,
+            "Found acpi table {} at {:#x}",
+            signature_str(&table.signature()),
+            table.address()
+        );
+    }
+    ACPI.init_once(|| acpi);
+}
@@ -0,0 +1,166 @@
+use super::{read_truncated, AcpiError, GenericAddress, Sdt};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct BootArchFlags: u16 {
+        const LEGACY_DEVICES = 1 << 0;
+        const I8042 = 1 << 1; // 8042 ps/2 controller
+        const VGA_NOT_PRESENT = 1 << 2;
+        const MSI_NOT_SUPPORTED = 1 << 3;
+        const PCIE_ASPM_CONTROLS = 1 << 4;
+        const CMOS_RTC_NOT_PRESENT = 1 << 5;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct FadtFlags: u32 {
+        const WBINVD = 1 << 0;
+        const PROC_C1 = 1 << 2;
+        const PWR_BUTTON = 1 << 4;
+        const SLP_BUTTON = 1 << 5;
+        const RTC_S4 = 1 << 7;
+        const TMR_VAL_EXT = 1 << 8; // PM timer is 32 bits wide instead of 24
+        const RESET_REG_SUP = 1 << 10;
+        const HW_REDUCED_ACPI = 1 << 20;
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawFadt {
+    firmware_ctrl: u32,
+    dsdt: u32,
+    _reserved: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command_port: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_length: u8,
+    gpe1_length: u8,
+    gpe1_base: u8,
+    c_state_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    boot_arch_flags: u16,
+    _reserved2: u8,
+    flags: u32,
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    fadt_minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    x_pm1a_event_block: GenericAddress,
+    x_pm1b_event_block: GenericAddress,
+    x_pm1a_control_block: GenericAddress,
+    x_pm1b_control_block: GenericAddress,
+    x_pm2_control_block: GenericAddress,
+    x_pm_timer_block: GenericAddress,
+    x_gpe0_block: GenericAddress,
+    x_gpe1_block: GenericAddress,
+}
+
+/// Fixed ACPI Description Table
+pub struct Fadt {
+    raw: RawFadt,
+}
+
+impl Fadt {
+    pub fn parse(sdt: &Sdt) -> Result<Self, AcpiError> {
+        sdt.expect_signature(b"FACP")?;
+        // Older revisions are shorter, everything past the end of the table reads as zero
+        Ok(Self {
+            raw: unsafe { read_truncated::<RawFadt>(sdt.body()) },
+        })
+    }
+
+    pub fn sci_interrupt(&self) -> u16 {
+        self.raw.sci_interrupt
+    }
+
+    pub fn smi_command_port(&self) -> u32 {
+        self.raw.smi_command_port
+    }
+
+    pub fn acpi_enable_value(&self) -> u8 {
+        self.raw.acpi_enable
+    }
+
+    pub fn acpi_disable_value(&self) -> u8 {
+        self.raw.acpi_disable
+    }
+
+    pub fn century_register(&self) -> Option<u8> {
+        match self.raw.century {
+            0 => None,
+            century => Some(century),
+        }
+    }
+
+    pub fn flags(&self) -> FadtFlags {
+        FadtFlags::from_bits_truncate(self.raw.flags)
+    }
+
+    pub fn boot_arch_flags(&self) -> BootArchFlags {
+        BootArchFlags::from_bits_truncate(self.raw.boot_arch_flags)
+    }
+
+    pub fn dsdt_address(&self) -> u64 {
+        match self.raw.x_dsdt {
+            0 => self.raw.dsdt as u64,
+            x_dsdt => x_dsdt,
+        }
+    }
+
+    /// The ACPI power management timer, runs at 3.579545 MHz
+    pub fn pm_timer(&self) -> Option<GenericAddress> {
+        let x_pm_timer = self.raw.x_pm_timer_block;
+        if !x_pm_timer.is_null() {
+            return Some(x_pm_timer);
+        }
+        match self.raw.pm_timer_block {
+            0 => None,
+            port => Some(GenericAddress {
+                address_space: 1,
+                bit_width: 32,
+                bit_offset: 0,
+                access_size: 3,
+                address: port as u64,
+            }),
+        }
+    }
+
+    pub fn pm_timer_is_32bit(&self) -> bool {
+        self.flags().contains(FadtFlags::TMR_VAL_EXT)
+    }
+
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if !self.flags().contains(FadtFlags::RESET_REG_SUP) {
+            return None;
+        }
+        Some((self.raw.reset_reg, self.raw.reset_value))
+    }
+}
@@ -0,0 +1,72 @@
+use bit_field::BitField;
+
+use super::{read, AcpiError, GenericAddress, Sdt};
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawHpet {
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+/// High Precision Event Timer description table
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+impl Hpet {
+    pub fn parse(sdt: &Sdt) -> Result<Self, AcpiError> {
+        sdt.expect_signature(b"HPET")?;
+        let raw = unsafe { read::<RawHpet>(sdt.body(), 0) }
+            .ok_or(AcpiError::InvalidLength(sdt.signature()))?;
+        Ok(Self {
+            event_timer_block_id: raw.event_timer_block_id,
+            base_address: raw.base_address,
+            hpet_number: raw.hpet_number,
+            minimum_tick: raw.minimum_tick,
+            page_protection: raw.page_protection,
+        })
+    }
+
+    /// Physical address of the hpet registers
+    pub fn base_address(&self) -> u64 {
+        self.base_address.address
+    }
+
+    pub fn hpet_number(&self) -> u8 {
+        self.hpet_number
+    }
+
+    /// Minimum clock ticks that can be set without losing interrupts in periodic mode
+    pub fn minimum_tick(&self) -> u16 {
+        self.minimum_tick
+    }
+
+    pub fn page_protection(&self) -> u8 {
+        self.page_protection.get_bits(0..4)
+    }
+
+    pub fn comparator_count(&self) -> u8 {
+        self.event_timer_block_id.get_bits(8..13) as u8 + 1
+    }
+
+    pub fn counter_is_64bit(&self) -> bool {
+        self.event_timer_block_id.get_bit(13)
+    }
+
+    pub fn legacy_replacement_capable(&self) -> bool {
+        self.event_timer_block_id.get_bit(15)
+    }
+
+    pub fn pci_vendor_id(&self) -> u16 {
+        self.event_timer_block_id.get_bits(16..32) as u16
+    }
+}
@@ -0,0 +1,308 @@
+use alloc::vec::Vec;
+
+use super::{read, AcpiError, Sdt};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct MadtFlags: u32 {
+        const PCAT_COMPAT = 1 << 0; // A dual 8259 setup is also installed
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct LocalApicFlags: u32 {
+        const ENABLED = 1 << 0;
+        const ONLINE_CAPABLE = 1 << 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ConformsToBus,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    ConformsToBus,
+    Edge,
+    Level,
+}
+
+/// MPS INTI flags used by interrupt source overrides and NMI entries
+#[derive(Debug, Clone, Copy)]
+pub struct MpsIntiFlags(u16);
+
+impl MpsIntiFlags {
+    pub fn polarity(&self) -> Polarity {
+        match self.0 & 0b11 {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ConformsToBus,
+        }
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        match (self.0 >> 2) & 0b11 {
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::ConformsToBus,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorLocalApic {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub flags: LocalApicFlags,
+}
+
+impl ProcessorLocalApic {
+    /// Whether the processor is enabled or can be brought online
+    pub fn is_usable(&self) -> bool {
+        self.flags
+            .intersects(LocalApicFlags::ENABLED | LocalApicFlags::ONLINE_CAPABLE)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: MpsIntiFlags,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// 0xFFFFFFFF means every processor
+    pub processor_id: u32,
+    pub flags: MpsIntiFlags,
+    pub lint: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtHeader {
+    local_apic_address: u32,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct EntryHeader {
+    typ: u8,
+    length: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawLocalApic {
+    processor_id: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawIoApic {
+    id: u8,
+    _reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawInterruptSourceOverride {
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawLocalApicNmi {
+    processor_id: u8,
+    flags: u16,
+    lint: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawLocalApicAddressOverride {
+    _reserved: u16,
+    address: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawLocalX2Apic {
+    _reserved: u16,
+    x2apic_id: u32,
+    flags: u32,
+    processor_uid: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawLocalX2ApicNmi {
+    flags: u16,
+    processor_uid: u32,
+    lint: u8,
+    _reserved: [u8; 3],
+}
+
+/// Multiple APIC Description Table
+#[derive(Debug)]
+pub struct Madt {
+    local_apic_address: u64,
+    flags: MadtFlags,
+    processors: Vec<ProcessorLocalApic>,
+    io_apics: Vec<IoApicInfo>,
+    overrides: Vec<InterruptSourceOverride>,
+    local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    pub fn parse(sdt: &Sdt) -> Result<Self, AcpiError> {
+        sdt.expect_signature(b"APIC")?;
+        let body = sdt.body();
+        let header = unsafe { read::<MadtHeader>(body, 0) }
+            .ok_or(AcpiError::InvalidLength(sdt.signature()))?;
+
+        let mut madt = Self {
+            local_apic_address: header.local_apic_address as u64,
+            flags: MadtFlags::from_bits_truncate(header.flags),
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let mut offset = size_of::<MadtHeader>();
+        while let Some(entry) = unsafe { read::<EntryHeader>(body, offset) } {
+            if entry.length < 2 {
+                return Err(AcpiError::InvalidLength(sdt.signature()));
+            }
+            let data = body
+                .get(offset + 2..offset + entry.length as usize)
+                .ok_or(AcpiError::InvalidLength(sdt.signature()))?;
+            madt.parse_entry(entry.typ, data);
+            offset += entry.length as usize;
+        }
+
+        Ok(madt)
+    }
+
+    fn parse_entry(&mut self, typ: u8, data: &[u8]) {
+        match typ {
+            0 => {
+                if let Some(e) = unsafe { read::<RawLocalApic>(data, 0) } {
+                    self.processors.push(ProcessorLocalApic {
+                        processor_id: e.processor_id as u32,
+                        apic_id: e.apic_id as u32,
+                        flags: LocalApicFlags::from_bits_truncate(e.flags),
+                    });
+                }
+            }
+            1 => {
+                if let Some(e) = unsafe { read::<RawIoApic>(data, 0) } {
+                    self.io_apics.push(IoApicInfo {
+                        id: e.id,
+                        address: e.address,
+                        gsi_base: e.gsi_base,
+                    });
+                }
+            }
+            2 => {
+                if let Some(e) = unsafe { read::<RawInterruptSourceOverride>(data, 0) } {
+                    self.overrides.push(InterruptSourceOverride {
+                        bus: e.bus,
+                        source: e.source,
+                        gsi: e.gsi,
+                        flags: MpsIntiFlags(e.flags),
+                    });
+                }
+            }
+            4 => {
+                if let Some(e) = unsafe { read::<RawLocalApicNmi>(data, 0) } {
+                    self.local_apic_nmis.push(LocalApicNmi {
+                        processor_id: match e.processor_id {
+                            0xFF => u32::MAX,
+                            id => id as u32,
+                        },
+                        flags: MpsIntiFlags(e.flags),
+                        lint: e.lint,
+                    });
+                }
+            }
+            5 => {
+                if let Some(e) = unsafe { read::<RawLocalApicAddressOverride>(data, 0) } {
+                    self.local_apic_address = e.address;
+                }
+            }
+            9 => {
+                if let Some(e) = unsafe { read::<RawLocalX2Apic>(data, 0) } {
+                    self.processors.push(ProcessorLocalApic {
+                        processor_id: e.processor_uid,
+                        apic_id: e.x2apic_id,
+                        flags: LocalApicFlags::from_bits_truncate(e.flags),
+                    });
+                }
+            }
+            0xA => {
+                if let Some(e) = unsafe { read::<RawLocalX2ApicNmi>(data, 0) } {
+                    self.local_apic_nmis.push(LocalApicNmi {
+                        processor_id: e.processor_uid,
+                        flags: MpsIntiFlags(e.flags),
+                        lint: e.lint,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn local_apic_address(&self) -> u64 {
+        self.local_apic_address
+    }
+
+    pub fn flags(&self) -> MadtFlags {
+        self.flags
+    }
+
+    pub fn processors(&self) -> &[ProcessorLocalApic] {
+        &self.processors
+    }
+
+    pub fn io_apics(&self) -> &[IoApicInfo] {
+        &self.io_apics
+    }
+
+    pub fn interrupt_overrides(&self) -> &[InterruptSourceOverride] {
+        &self.overrides
+    }
+
+    pub fn local_apic_nmis(&self) -> &[LocalApicNmi] {
+        &self.local_apic_nmis
+    }
+
+    /// Find the override for a legacy isa irq
+    pub fn find_override(&self, irq: u8) -> Option<&InterruptSourceOverride> {
+        self.overrides
+            .iter()
+            .find(|e| e.bus == 0 && e.source == irq)
+    }
+}
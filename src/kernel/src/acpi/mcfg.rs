@@ -0,0 +1,66 @@
+use alloc::vec::Vec;
+
+use super::{read, AcpiError, Sdt};
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RawMcfgEntry {
+    base_address: u64,
+    segment_group: u16,
+    start_bus: u8,
+    end_bus: u8,
+    _reserved: u32,
+}
+
+/// An enhanced configuration space (ECAM) region
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// PCI Express memory mapped configuration table
+#[derive(Debug)]
+pub struct Mcfg {
+    entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    pub fn parse(sdt: &Sdt) -> Result<Self, AcpiError> {
+        sdt.expect_signature(b"MCFG")?;
+        // 8 reserved bytes precede the configuration space entries
+        let entries = sdt
+            .body()
+            .get(8..)
+            .ok_or(AcpiError::InvalidLength(sdt.signature()))?
+            .chunks_exact(size_of::<RawMcfgEntry>())
+            .filter_map(|e| unsafe { read::<RawMcfgEntry>(e, 0) })
+            .map(|e| McfgEntry {
+                base_address: e.base_address,
+                segment_group: e.segment_group,
+                start_bus: e.start_bus,
+                end_bus: e.end_bus,
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    pub fn entries(&self) -> &[McfgEntry] {
+        &self.entries
+    }
+
+    /// Physical address of the configuration space of a function
+    pub fn config_address(&self, segment: u16, bus: u8, device: u8, function: u8) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|e| e.segment_group == segment && (e.start_bus..=e.end_bus).contains(&bus))
+            .map(|e| {
+                e.base_address
+                    + (((bus - e.start_bus) as u64) << 20)
+                    + ((device as u64) << 15)
+                    + ((function as u64) << 12)
+            })
+    }
+}
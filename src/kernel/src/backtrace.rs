@@ -0,0 +1,118 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::memory::paging::ActivePageTable;
+use crate::serial::report_port;
+
+use self::demangle::Demangle;
+
+pub mod demangle;
+pub mod symbols;
+
+pub use self::symbols::init;
+
+const MAX_FRAMES: usize = 64;
+
+static FAULT_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Walks the frame pointer chain and yields the return address of every frame
+pub struct Backtrace {
+    interrupted: Option<u64>,
+    rbp: u64,
+    depth: usize,
+}
+
+impl Backtrace {
+    /// Backtrace of the calling function
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self {
+            interrupted: None,
+            rbp: current_rbp(),
+            depth: 0,
+        }
+    }
+
+    /// Backtrace starting at the interrupted instruction with the frame pointer it had
+    pub fn from_interrupt(instruction_pointer: u64, rbp: u64) -> Self {
+        Self {
+            interrupted: Some(instruction_pointer),
+            rbp,
+            depth: 0,
+        }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(address) = self.interrupted.take() {
+            return Some(address);
+        }
+        if self.depth >= MAX_FRAMES || !is_readable(self.rbp) || !is_readable(self.rbp + 8) {
+            return None;
+        }
+        let frame = self.rbp as *const u64;
+        let (rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            return None;
+        }
+        self.rbp = rbp;
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+#[inline(always)]
+fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// Checked without the memory controller lock, the code that faulted may be holding it
+fn is_readable(address: u64) -> bool {
+    address != 0
+        && address % 8 == 0
+        && VirtAddr::try_new(address)
+            .is_ok_and(|e| unsafe { ActivePageTable::new() }.translate(e).is_some())
+}
+
+/// Write every frame as `#N address function+offset` straight to the serial port, the heap may be what broke
+pub fn log_backtrace(backtrace: Backtrace) {
+    interrupts::without_interrupts(|| {
+        let mut serial = report_port();
+        let _ = serial.write_str("Backtrace:");
+        write_frames(&mut *serial, backtrace);
+        let _ = serial.write_str("\n");
+    });
+}
+
+/// Append every return address in `frames` as a `\n#N address function+offset` line
+pub fn write_frames(trace: &mut impl Write, frames: impl Iterator<Item = u64>) {
+    for (i, address) in frames.enumerate() {
+        let _ = write!(trace, "\n#{} {:#018x} ", i, address);
+        let _ = match symbols::resolve(address) {
+            Some((name, offset)) => write!(trace, "{}+{:#x}", Demangle(name), offset),
+            None => write!(trace, "<unknown>"),
+        };
+    }
+}
+
+/// Log the backtrace of a fault, the panic that follows won't log its own less precise one
+pub fn log_fault_backtrace(instruction_pointer: u64, rbp: u64) {
+    FAULT_LOGGED.store(true, Ordering::Relaxed);
+    log_backtrace(Backtrace::from_interrupt(instruction_pointer, rbp));
+}
+
+/// Whether a fault handler already logged a backtrace
+pub fn fault_logged() -> bool {
+    FAULT_LOGGED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,102 @@
+use core::fmt::{Display, Formatter, Result};
+
+use crate::inline_if;
+
+/// Displays a legacy mangled rust symbol without its hash, any other name is shown as is
+pub struct Demangle<'a>(pub &'a str);
+
+impl Display for Demangle<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let inner = match self.0.strip_prefix("_ZN") {
+            Some(inner) if Components(inner).all(|e| e.is_some()) => inner,
+            _ => return f.write_str(self.0),
+        };
+        let count = Components(inner).count();
+        for (i, component) in Components(inner).map_while(|e| e).enumerate() {
+            if i + 1 == count && is_hash(component) {
+                break;
+            }
+            if i > 0 {
+                f.write_str("::")?;
+            }
+            write_identifier(f, component)?;
+        }
+        Ok(())
+    }
+}
+
+/// Length prefixed path components, yields `None` once for a malformed name
+struct Components<'a>(&'a str);
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Option<&'a str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() || self.0.starts_with('E') {
+            return None;
+        }
+        let digits = self.0.bytes().take_while(u8::is_ascii_digit).count();
+        let component = self.0[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|len| self.0.get(digits..digits + len));
+        match component {
+            Some(component) => {
+                self.0 = &self.0[digits + component.len()..];
+                Some(Some(component))
+            }
+            None => {
+                self.0 = "";
+                Some(None)
+            }
+        }
+    }
+}
+
+fn is_hash(component: &str) -> bool {
+    component.len() == 17
+        && component.starts_with('h')
+        && component[1..].bytes().all(|e| e.is_ascii_hexdigit())
+}
+
+fn write_identifier(f: &mut Formatter<'_>, identifier: &str) -> Result {
+    // A leading underscore escapes identifiers starting with a `$`
+    let mut rest = inline_if!(identifier.starts_with("_$"), &identifier[1..], identifier);
+    while !rest.is_empty() {
+        if let Some(escaped) = rest.strip_prefix('$') {
+            if let Some(end) = escaped.find('$') {
+                if let Some(c) = unescape(&escaped[..end]) {
+                    write!(f, "{}", c)?;
+                    rest = &escaped[end + 1..];
+                    continue;
+                }
+            }
+        }
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+            continue;
+        }
+        let c = rest.chars().next().unwrap();
+        write!(f, "{}", c)?;
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(())
+}
+
+fn unescape(escape: &str) -> Option<char> {
+    match escape {
+        "SP" => Some('@'),
+        "BP" => Some('*'),
+        "RF" => Some('&'),
+        "LT" => Some('<'),
+        "GT" => Some('>'),
+        "LP" => Some('('),
+        "RP" => Some(')'),
+        "C" => Some(','),
+        _ => escape
+            .strip_prefix('u')
+            .and_then(|e| u32::from_str_radix(e, 16).ok())
+            .and_then(char::from_u32),
+    }
+}
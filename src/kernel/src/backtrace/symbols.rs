@@ -0,0 +1,81 @@
+use alloc::vec::Vec;
+use common::boot::BootInformation;
+use conquer_once::spin::OnceCell;
+use elf_rs::{ElfFile, SectionType};
+
+use crate::memory::memory_controller;
+use crate::memory::paging::EntryFlags;
+use crate::{inline_if, log};
+
+const SYMBOL_SIZE: usize = 24;
+const SYMBOL_TYPE_FUNCTION: u8 = 2;
+
+static SYMBOLS: OnceCell<Vec<Symbol>> = OnceCell::uninit();
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: &'static str,
+}
+
+/// Load the function symbols of the kernel elf handed over by the bootloader
+pub fn init(boot_info: &'static BootInformation) {
+    let elf = boot_info.elf_section();
+    let content = elf.content();
+    memory_controller().lock().ident_map_unmapped(
+        content.len() as u64,
+        content.as_ptr() as u64,
+        EntryFlags::NO_EXECUTE,
+    );
+    let symbols = elf
+        .section_header_iter()
+        .find(|e| matches!(e.sh_type(), SectionType::SHT_SYMTAB))
+        .and_then(|symtab| {
+            let strtab = elf.section_header_nth(symtab.link() as usize)?.content()?;
+            Some(parse(symtab.content()?, strtab))
+        });
+    match symbols {
+        Some(symbols) => {
+            log!(Info, "Loaded {} kernel symbols", symbols.len());
+            SYMBOLS.init_once(|| symbols);
+        }
+        None => log!(
+            Warning,
+            "Kernel has no symbol table, backtraces won't be symbolized"
+        ),
+    }
+}
+
+fn parse(symtab: &'static [u8], strtab: &'static [u8]) -> Vec<Symbol> {
+    let mut symbols: Vec<Symbol> = symtab
+        .chunks_exact(SYMBOL_SIZE)
+        .filter(|e| e[4] & 0xF == SYMBOL_TYPE_FUNCTION)
+        .filter_map(|e| {
+            let name_offset = u32::from_le_bytes(e[0..4].try_into().unwrap()) as usize;
+            let address = u64::from_le_bytes(e[8..16].try_into().unwrap());
+            let size = u64::from_le_bytes(e[16..24].try_into().unwrap());
+            let name = strtab.get(name_offset..)?.split(|e| *e == 0).next()?;
+            Some(Symbol {
+                address,
+                size,
+                name: core::str::from_utf8(name).ok()?,
+            })
+        })
+        .filter(|e| e.address != 0)
+        .collect();
+    symbols.sort_unstable_by_key(|e| e.address);
+    symbols
+}
+
+/// Function containing `address` and the offset into it
+///
+/// The end of a function counts as part of it since a return address can point right after a call
+pub fn resolve(address: u64) -> Option<(&'static str, u64)> {
+    let symbols = SYMBOLS.get()?;
+    let index = symbols
+        .partition_point(|e| e.address < address)
+        .checked_sub(1)?;
+    let symbol = &symbols[index];
+    let offset = address - symbol.address;
+    inline_if!(offset <= symbol.size, Some((symbol.name, offset)), None)
+}
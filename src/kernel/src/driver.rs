@@ -1,6 +1,8 @@
 pub mod display;
+pub mod mmio;
 pub mod pci;
 pub mod storage;
+pub mod timer;
 
 pub fn init() {
     storage::init();
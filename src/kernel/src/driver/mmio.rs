@@ -0,0 +1,167 @@
+use core::cell::SyncUnsafeCell;
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+use bitflags::Flags;
+
+use crate::memory::address_space::AddressSpaceError;
+use crate::memory::virtual_alloc::{map_physical, Mapping};
+
+/// Access a register allows, see [`access`]
+pub trait Access {
+    const READABLE: bool;
+}
+pub trait Readable: Access {}
+pub trait Writable: Access {}
+
+pub mod access {
+    use super::{Access, Readable, Writable};
+
+    pub enum R {}
+    pub enum W {}
+    pub enum RW {}
+
+    impl Access for R {
+        const READABLE: bool = true;
+    }
+    impl Access for W {
+        const READABLE: bool = false;
+    }
+    impl Access for RW {
+        const READABLE: bool = true;
+    }
+    impl Readable for R {}
+    impl Readable for RW {}
+    impl Writable for W {}
+    impl Writable for RW {}
+}
+
+pub type ReadOnly<T> = Register<T, access::R>;
+pub type WriteOnly<T> = Register<T, access::W>;
+pub type ReadWrite<T> = Register<T, access::RW>;
+
+/// Device register of type `T`, only ever accessed with volatile reads and writes
+#[repr(transparent)]
+pub struct Register<T, A: Access> {
+    value: SyncUnsafeCell<T>,
+    access: PhantomData<A>,
+}
+
+impl<T: Copy, A: Readable> Register<T, A> {
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.value.get()) }
+    }
+}
+
+impl<T: Copy, A: Writable> Register<T, A> {
+    #[inline]
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.value.get(), value) }
+    }
+}
+
+impl<T: Copy, A: Readable + Writable> Register<T, A> {
+    /// Write back what `f` makes of the current value
+    #[inline]
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+impl<T: Flags + Copy, A: Readable> Register<T, A> {
+    pub fn contains(&self, flags: T) -> bool {
+        self.read().contains(flags)
+    }
+
+    pub fn intersects(&self, flags: T) -> bool {
+        self.read().intersects(flags)
+    }
+}
+
+impl<T: Flags + Copy, A: Readable + Writable> Register<T, A> {
+    /// Set `flags`, the other bits are written back as they were read
+    pub fn insert(&self, flags: T) {
+        self.modify(|e| e.union(flags));
+    }
+
+    /// Clear `flags`, the other bits are written back as they were read
+    pub fn remove(&self, flags: T) {
+        self.modify(|e| e.difference(flags));
+    }
+}
+
+impl<T: Copy + Debug, A: Access> Debug for Register<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match A::READABLE {
+            true => write!(f, "{:?}", unsafe { ptr::read_volatile(self.value.get()) }),
+            false => write!(f, "(write only)"),
+        }
+    }
+}
+
+/// Layout of device registers, [`MmioRegion`] hands out references to it
+///
+/// # Safety
+/// Every byte of it must be inside a [`Register`], plain fields would be assumed never to change.
+pub unsafe trait RegisterBlock {}
+
+unsafe impl<T, A: Access> RegisterBlock for Register<T, A> {}
+unsafe impl<B: RegisterBlock, const N: usize> RegisterBlock for [B; N] {}
+
+/// Device memory mapped for as long as it lives, its registers are borrowed from it
+#[derive(Debug)]
+pub struct MmioRegion {
+    mapping: Mapping,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Map `size` bytes of device memory from `phy_start`, uncached
+    pub fn map(phy_start: u64, size: usize) -> Result<MmioRegion, AddressSpaceError> {
+        Ok(MmioRegion {
+            mapping: map_physical(size as u64, phy_start)?,
+            size,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// `count` blocks laid out like `B` one after the other from `offset`, if they fit in the region
+    pub fn try_blocks<B: RegisterBlock>(&self, offset: usize, count: usize) -> Option<&[B]> {
+        let address = self.mapping.address() as usize + offset;
+        let fits = size_of::<B>()
+            .checked_mul(count)
+            .and_then(|e| e.checked_add(offset))
+            .is_some_and(|e| e <= self.size);
+        if !fits || address % align_of::<B>() != 0 {
+            return None;
+        }
+        Some(unsafe { core::slice::from_raw_parts(address as *const B, count) })
+    }
+
+    /// Registers laid out like `B` at `offset`, panics when they don't fit in the region or are misaligned
+    pub fn block<B: RegisterBlock>(&self, offset: usize) -> &B {
+        &self.blocks(offset, 1)[0]
+    }
+
+    pub fn blocks<B: RegisterBlock>(&self, offset: usize, count: usize) -> &[B] {
+        self.try_blocks(offset, count).unwrap_or_else(|| {
+            panic!(
+                "{} registers of {} bytes at {:#x} are outside of the {:#x} byte region",
+                count,
+                size_of::<B>(),
+                offset,
+                self.size
+            )
+        })
+    }
+
+    pub fn register<T, A: Access>(&self, offset: usize) -> &Register<T, A> {
+        self.block(offset)
+    }
+}
@@ -4,6 +4,12 @@ use spin::Mutex;
 
 use crate::{inline_if, log, utils::port::Port32Bit};
 
+use self::capability::{Capability, CapabilityId, CapabilityIter};
+use self::msi::{Msi, MsiError, MsiX};
+
+pub mod capability;
+pub mod msi;
+
 pub static DRIVER: Mutex<PCIControler> = Mutex::new(PCIControler::new());
 
 const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
@@ -388,8 +394,8 @@ impl PciHeader {
         self.command_port.write(address);
         match core::mem::size_of::<T>() {
             1 => {
-                let mask = !(0xffu32 << offset);
-                let value = (current & mask) | ((value & 0xff) << offset);
+                let mask = !(0xffu32 << noffset);
+                let value = (current & mask) | ((value & 0xff) << noffset);
                 self.data_port.write(value);
             }
             2 => {
@@ -418,6 +424,45 @@ impl PciHeader {
         unsafe { self.write::<u16>(0x04, command | (1 << 10)) };
     }
 
+    pub fn enable_legacy_irq(&self) {
+        let command = unsafe { self.read::<u16>(0x04) };
+        unsafe { self.write::<u16>(0x04, command & !(1 << 10)) };
+    }
+
+    /// Legacy irq line assigned by the firmware
+    pub fn interrupt_line(&self) -> u8 {
+        unsafe { self.read::<u8>(0x3C) as u8 }
+    }
+
+    /// Interrupt pin used by the function, 0 means none
+    pub fn interrupt_pin(&self) -> u8 {
+        unsafe { self.read::<u8>(0x3D) as u8 }
+    }
+
+    pub fn status(&self) -> u16 {
+        unsafe { self.read::<u16>(0x06) as u16 }
+    }
+
+    pub fn capabilities(&self) -> CapabilityIter {
+        CapabilityIter::new(self)
+    }
+
+    pub fn find_capability(&self, id: CapabilityId) -> Option<Capability> {
+        self.capabilities().find(|e| e.id() == id)
+    }
+
+    pub fn msi(&self) -> Result<Msi, MsiError> {
+        self.find_capability(CapabilityId::Msi)
+            .map(|e| Msi::new(self, e))
+            .ok_or(MsiError::Unsupported)
+    }
+
+    pub fn msix(&self) -> Result<MsiX, MsiError> {
+        self.find_capability(CapabilityId::MsiX)
+            .ok_or(MsiError::Unsupported)
+            .and_then(|e| MsiX::new(self, e))
+    }
+
     pub fn get_device(&self) -> DeviceType {
         let id = unsafe { self.read::<u32>(0x08) };
 
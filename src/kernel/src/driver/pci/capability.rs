@@ -0,0 +1,85 @@
+use super::PciHeader;
+
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const CAPABILITIES_POINTER: u32 = 0x34;
+// A malformed list could point back to itself
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityId {
+    PowerManagement,
+    Msi,
+    VendorSpecific,
+    PciExpress,
+    MsiX,
+    Unknown(u8),
+}
+
+impl CapabilityId {
+    pub fn new(id: u8) -> Self {
+        match id {
+            0x01 => Self::PowerManagement,
+            0x05 => Self::Msi,
+            0x09 => Self::VendorSpecific,
+            0x10 => Self::PciExpress,
+            0x11 => Self::MsiX,
+            id => Self::Unknown(id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    id: CapabilityId,
+    offset: u32,
+}
+
+impl Capability {
+    pub fn id(&self) -> CapabilityId {
+        self.id
+    }
+
+    /// Offset of the capability in the configuration space
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
+pub struct CapabilityIter<'a> {
+    header: &'a PciHeader,
+    next: u32,
+    visited: usize,
+}
+
+impl<'a> CapabilityIter<'a> {
+    pub(super) fn new(header: &'a PciHeader) -> Self {
+        let next = if header.status() & STATUS_CAPABILITIES_LIST != 0 {
+            unsafe { header.read::<u8>(CAPABILITIES_POINTER) & 0xFC }
+        } else {
+            0
+        };
+        Self {
+            header,
+            next,
+            visited: 0,
+        }
+    }
+}
+
+impl Iterator for CapabilityIter<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == 0 || self.visited >= MAX_CAPABILITIES {
+            return None;
+        }
+        let offset = self.next;
+        let id = unsafe { self.header.read::<u8>(offset) } as u8;
+        self.next = unsafe { self.header.read::<u8>(offset + 1) } & 0xFC;
+        self.visited += 1;
+        Some(Capability {
+            id: CapabilityId::new(id),
+            offset,
+        })
+    }
+}
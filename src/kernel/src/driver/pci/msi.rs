@@ -0,0 +1,230 @@
+use core::error::Error;
+use core::fmt::Display;
+use core::slice;
+
+use bit_field::BitField;
+
+use crate::memory::memory_controller;
+use crate::memory::paging::EntryFlags;
+use crate::utils::VolatileCell;
+
+use super::capability::Capability;
+use super::{Bar, PciHeader};
+
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug)]
+pub enum MsiError {
+    Unsupported,
+    InvalidBar(u8),
+    EntryOutOfRange(u16),
+}
+
+impl Display for MsiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "Device doesn't support message signalled interrupts"),
+            Self::InvalidBar(bar) => write!(f, "MSI-X table is in an invalid bar {}", bar),
+            Self::EntryOutOfRange(index) => {
+                write!(f, "MSI-X table entry {} is out of range", index)
+            }
+        }
+    }
+}
+
+impl Error for MsiError {}
+
+/// Address and data pair the device writes to raise an interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct MsiMessage {
+    address: u64,
+    data: u32,
+}
+
+impl MsiMessage {
+    /// Edge triggered fixed interrupt delivered to `vector` on the local apic `apic_id`
+    pub fn new(apic_id: u32, vector: u8) -> Self {
+        Self {
+            address: MSI_ADDRESS_BASE | ((apic_id as u64 & 0xFF) << 12),
+            data: vector as u32,
+        }
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn data(&self) -> u32 {
+        self.data
+    }
+}
+
+pub struct Msi<'a> {
+    header: &'a PciHeader,
+    offset: u32,
+}
+
+impl<'a> Msi<'a> {
+    pub(super) fn new(header: &'a PciHeader, capability: Capability) -> Self {
+        Self {
+            header,
+            offset: capability.offset(),
+        }
+    }
+
+    fn control(&self) -> u16 {
+        unsafe { self.header.read::<u16>(self.offset + 2) as u16 }
+    }
+
+    fn set_control(&self, control: u16) {
+        unsafe { self.header.write::<u16>(self.offset + 2, control as u32) };
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.control().get_bit(7)
+    }
+
+    pub fn supports_masking(&self) -> bool {
+        self.control().get_bit(8)
+    }
+
+    /// Number of vectors the function is able to use
+    pub fn vector_count(&self) -> u8 {
+        1 << self.control().get_bits(1..4)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control().get_bit(0)
+    }
+
+    /// Program the message of the function, only a single vector is used
+    pub fn set_message(&self, message: MsiMessage) {
+        unsafe {
+            self.header
+                .write::<u32>(self.offset + 4, message.address() as u32);
+            if self.is_64bit() {
+                self.header
+                    .write::<u32>(self.offset + 8, (message.address() >> 32) as u32);
+                self.header.write::<u16>(self.offset + 0xC, message.data());
+            } else {
+                self.header.write::<u16>(self.offset + 8, message.data());
+            }
+        }
+        let mut control = self.control();
+        control.set_bits(4..7, 0);
+        self.set_control(control);
+    }
+
+    pub fn enable(&self) {
+        let mut control = self.control();
+        control.set_bit(0, true);
+        self.set_control(control);
+    }
+
+    pub fn disable(&self) {
+        let mut control = self.control();
+        control.set_bit(0, false);
+        self.set_control(control);
+    }
+}
+
+#[repr(C)]
+struct MsiXEntry {
+    address_low: VolatileCell<u32>,
+    address_high: VolatileCell<u32>,
+    data: VolatileCell<u32>,
+    control: VolatileCell<u32>,
+}
+
+pub struct MsiX<'a> {
+    header: &'a PciHeader,
+    offset: u32,
+    table: &'static [MsiXEntry],
+}
+
+impl<'a> MsiX<'a> {
+    /// Parse the capability and identity map the vector table
+    pub(super) fn new(header: &'a PciHeader, capability: Capability) -> Result<Self, MsiError> {
+        let offset = capability.offset();
+        let table_size = unsafe { header.read::<u16>(offset + 2) }.get_bits(0..11) as usize + 1;
+        let table = unsafe { header.read::<u32>(offset + 4) };
+        let bir = table.get_bits(0..3) as u8;
+        let table_address = match header.get_bar(bir) {
+            Some(Bar::Memory32 { address, .. }) => address as u64,
+            Some(Bar::Memory64 { address, .. }) => address,
+            _ => return Err(MsiError::InvalidBar(bir)),
+        } + (table & !0b111) as u64;
+
+        memory_controller().lock().ident_map_unmapped(
+            (table_size * size_of::<MsiXEntry>()) as u64,
+            table_address,
+            EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+        );
+        let table = unsafe { slice::from_raw_parts(table_address as *const MsiXEntry, table_size) };
+
+        Ok(Self {
+            header,
+            offset,
+            table,
+        })
+    }
+
+    fn control(&self) -> u16 {
+        unsafe { self.header.read::<u16>(self.offset + 2) as u16 }
+    }
+
+    fn set_control(&self, control: u16) {
+        unsafe { self.header.write::<u16>(self.offset + 2, control as u32) };
+    }
+
+    fn entry(&self, index: u16) -> Result<&MsiXEntry, MsiError> {
+        self.table
+            .get(index as usize)
+            .ok_or(MsiError::EntryOutOfRange(index))
+    }
+
+    pub fn table_size(&self) -> u16 {
+        self.table.len() as u16
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control().get_bit(15)
+    }
+
+    /// Program and unmask the entry `index`
+    pub fn set_message(&self, index: u16, message: MsiMessage) -> Result<(), MsiError> {
+        let entry = self.entry(index)?;
+        entry.control.set(entry.control.get() | MSIX_ENTRY_MASKED);
+        entry.address_low.set(message.address() as u32);
+        entry.address_high.set((message.address() >> 32) as u32);
+        entry.data.set(message.data());
+        entry.control.set(entry.control.get() & !MSIX_ENTRY_MASKED);
+        Ok(())
+    }
+
+    pub fn mask(&self, index: u16) -> Result<(), MsiError> {
+        let entry = self.entry(index)?;
+        entry.control.set(entry.control.get() | MSIX_ENTRY_MASKED);
+        Ok(())
+    }
+
+    pub fn unmask(&self, index: u16) -> Result<(), MsiError> {
+        let entry = self.entry(index)?;
+        entry.control.set(entry.control.get() & !MSIX_ENTRY_MASKED);
+        Ok(())
+    }
+
+    pub fn enable(&self) {
+        let mut control = self.control();
+        control.set_bit(15, true);
+        control.set_bit(14, false); // Function mask
+        self.set_control(control);
+    }
+
+    pub fn disable(&self) {
+        let mut control = self.control();
+        control.set_bit(15, false);
+        self.set_control(control);
+    }
+}
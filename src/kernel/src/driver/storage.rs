@@ -2,19 +2,25 @@ pub mod ahci_driver;
 pub mod ata_driver;
 
 use core::error::Error;
+use core::f64;
 use core::fmt::Display;
 use core::future::Future;
-use core::{f64, slice};
+use core::mem;
 
+use alloc::collections::VecDeque;
 use alloc::vec::Vec;
-use x86_64::PhysAddr;
+use x86_64::{PhysAddr, VirtAddr};
 
-use crate::inline_if;
-use crate::memory::memory_controller;
+use crate::memory::dma::{DmaBuffer, DmaConstraints, DmaSegment, ScatterList};
+use crate::memory::paging::EntryFlags;
+use crate::memory::{memory_controller, PAGE_SIZE};
 use crate::utils::floorf64;
 
 pub const MAX_SECTOR: f64 = 63.0;
 pub const MAX_HEAD: f64 = 255.0;
+pub const SECTOR_SIZE: usize = 512;
+/// Biggest bounce buffer a request allocates, longer runs get several
+const BOUNCE_SIZE: usize = 0x10000;
 
 #[derive(Debug, Clone, Copy)]
 pub struct CHS {
@@ -31,16 +37,26 @@ enum DriveCommand {
     Identify,
 }
 
+/// Part of the caller's buffer the device can't reach, copied through `buffer`
 #[derive(Debug)]
-struct DmaBuffer {
-    start: PhysAddr,
-    size: usize, // Size in bytes
-    allocated_size: usize,
+struct Bounce {
+    offset: usize,
+    size: usize,
+    buffer: DmaBuffer,
 }
 
+/// Caller's buffer as physical segments, the device reads or writes it in place
 struct DmaRequest {
     command: DriveCommand,
-    buffer: Vec<DmaBuffer>,
+    segments: ScatterList,
+    bounces: Vec<Bounce>,
+}
+
+/// Piece of a request one command of the device transfers
+struct DmaCommand {
+    command: DriveCommand,
+    count: usize,
+    segments: Vec<DmaSegment>,
 }
 
 #[derive(Debug)]
@@ -72,68 +88,6 @@ impl Display for CHSError {
 
 impl Error for CHSError {}
 
-impl DmaBuffer {
-    /// Allocate a buffer for a count and return a left over
-    fn new(mut count: usize) -> Option<(Self, usize)> {
-        let size = [0x1000, 0x2000, 0x4000]
-            .iter()
-            .find_map(|e| inline_if!(count <= e >> 9, Some(*e), None))
-            .unwrap_or(0x4000);
-        let old_count = count;
-        count = count.saturating_sub(size >> 9);
-        Some((
-            Self {
-                start: memory_controller().lock().physical_alloc(size)?,
-                size: (old_count - count) * 512,
-                allocated_size: size,
-            },
-            count,
-        ))
-    }
-
-    fn count(&self) -> usize {
-        self.size >> 9
-    }
-
-    fn copy_into(&self, target: &mut [u8]) {
-        assert!(target.len() == self.size);
-        assert!(self.size <= self.allocated_size);
-        memory_controller()
-            .lock()
-            .ident_map(self.allocated_size as u64, self.start.as_u64());
-
-        let buffer = unsafe { slice::from_raw_parts(self.start.as_u64() as *const u8, self.size) };
-        target.copy_from_slice(buffer);
-
-        memory_controller()
-            .lock()
-            .unmap_addr(self.start.as_u64(), self.allocated_size as u64);
-    }
-
-    fn copy_into_self(&self, source: &[u8]) {
-        assert!(source.len() == self.size);
-        memory_controller()
-            .lock()
-            .ident_map(self.allocated_size as u64, self.start.as_u64());
-
-        let buffer =
-            unsafe { slice::from_raw_parts_mut(self.start.as_u64() as *mut u8, self.size) };
-        buffer.copy_from_slice(source);
-
-        memory_controller()
-            .lock()
-            .unmap_addr(self.start.as_u64(), self.allocated_size as u64);
-    }
-}
-
-impl Drop for DmaBuffer {
-    fn drop(&mut self) {
-        memory_controller()
-            .lock()
-            .physical_dealloc(self.start, self.allocated_size);
-    }
-}
-
 impl DriveCommand {
     fn to_ata(&self) -> u8 {
         match self {
@@ -164,43 +118,161 @@ impl DriveCommand {
 }
 
 impl DmaRequest {
-    fn new(mut count: usize, command: DriveCommand) -> Option<Self> {
-        let mut buffers = Vec::new();
-
-        while count > 0 {
-            let (buffer, left_count) = DmaBuffer::new(count)?;
-
-            count = left_count;
-
-            buffers.push(buffer);
+    /// Translate the pages of `size` bytes from `data`, segments start `align` aligned and hold at most `max_segment` bytes
+    ///
+    /// Pages that aren't mapped go through bounce buffers, so do read only pages of a read, and all of it if `data`
+    /// is misaligned.
+    /// A pointer since the device writes it behind the compiler's back, it must stay alive until the request is done.
+    /// Drivers abort an issued command before giving the memory back, even when the future running it is dropped.
+    fn new(
+        command: DriveCommand,
+        data: *const u8,
+        size: usize,
+        align: u64,
+        max_segment: usize,
+    ) -> Option<Self> {
+        let start = data as u64;
+        // Every piece but the page aligned ones would be misaligned, and the device can't split a word
+        let misaligned = start % align != 0;
+        let writes_memory = !command.is_write();
+        // Pieces end at page and bounce window boundaries, reserved up front so nothing allocates under the lock
+        let mut pieces: Vec<(usize, usize, Option<PhysAddr>)> =
+            Vec::with_capacity(size / PAGE_SIZE as usize + size / BOUNCE_SIZE + 2);
+        {
+            let mut controller = memory_controller().lock();
+            let mut offset = 0;
+            while offset < size {
+                let address = VirtAddr::new(start + offset as u64);
+                let piece = (size - offset)
+                    .min((PAGE_SIZE - address.as_u64() % PAGE_SIZE) as usize)
+                    .min(BOUNCE_SIZE - offset % BOUNCE_SIZE);
+                let reachable = !misaligned
+                    && (!writes_memory
+                        || controller
+                            .page_flags(address)
+                            .is_some_and(|e| e.contains(EntryFlags::WRITABLE)));
+                let phys = controller.get_physical(address).filter(|_| reachable);
+                pieces.push((offset, piece, phys));
+                offset += piece;
+            }
         }
 
-        return Some(Self {
-            buffer: buffers,
+        let mut request = Self {
             command,
+            segments: ScatterList::new(max_segment, 0),
+            bounces: Vec::new(),
+        };
+        // Bytes of `data` waiting for a bounce buffer
+        let mut run: Option<(usize, usize)> = None;
+        for (offset, piece, phys) in pieces {
+            match (phys, run) {
+                (Some(phys), _) => {
+                    if let Some((run_offset, run_size)) = run.take() {
+                        request.bounce(run_offset, run_size)?;
+                    }
+                    request.segments.push(phys, piece);
+                }
+                // Runs stay inside one bounce sized window of `data`, so they start and end aligned
+                (None, Some((run_offset, run_size))) if offset % BOUNCE_SIZE != 0 => {
+                    run = Some((run_offset, run_size + piece));
+                }
+                (None, _) => {
+                    if let Some((run_offset, run_size)) = run.replace((offset, piece)) {
+                        request.bounce(run_offset, run_size)?;
+                    }
+                }
+            }
+        }
+        if let Some((run_offset, run_size)) = run {
+            request.bounce(run_offset, run_size)?;
+        }
+        Some(request)
+    }
+
+    fn bounce(&mut self, offset: usize, size: usize) -> Option<()> {
+        let buffer = DmaBuffer::new(size, DmaConstraints::default()).ok()?;
+        self.segments.push_buffer(&buffer, size);
+        self.bounces.push(Bounce {
+            offset,
+            size,
+            buffer,
         });
+        Some(())
     }
 
+    /// Copy what the device read into bounce buffers to `target`
     fn copy_into(&self, target: &mut [u8]) {
-        assert!(target.len() >= self.count() * 512);
-        let mut offset = 0;
-        for buffer in self.buffer.iter() {
-            buffer.copy_into(&mut target[offset..(offset + buffer.size)]);
-            offset += buffer.size;
+        assert!(target.len() >= self.segments.len());
+        for bounce in self.bounces.iter() {
+            target[bounce.offset..bounce.offset + bounce.size]
+                .copy_from_slice(&bounce.buffer.as_slice()[..bounce.size]);
         }
     }
 
+    /// Fill the bounce buffers from `source` before the device writes them
     fn copy_into_self(&mut self, source: &[u8]) {
-        assert!(source.len() == self.count() * 512);
-        let mut offset = 0;
-        for buffer in self.buffer.iter() {
-            buffer.copy_into_self(&source[offset..(offset + buffer.size)]);
-            offset += buffer.size;
+        assert!(source.len() == self.segments.len());
+        for bounce in self.bounces.iter_mut() {
+            bounce.buffer.as_mut_slice()[..bounce.size]
+                .copy_from_slice(&source[bounce.offset..bounce.offset + bounce.size]);
         }
     }
 
-    fn count(&self) -> usize {
-        self.buffer.iter().map(|buffer| buffer.count()).sum()
+    /// Cut the request into commands of whole sectors, each with at most `max_segments` segments
+    fn commands(&self, max_segments: usize, max_count: usize) -> Vec<DmaCommand> {
+        let mut commands = Vec::new();
+        let mut remaining: VecDeque<DmaSegment> =
+            self.segments.segments().iter().copied().collect();
+        let mut segments = Vec::new();
+        let mut size = 0;
+        let mut sector = self.command.sector();
+        while let Some(segment) = remaining.pop_front() {
+            let taken = segment.len.min(max_count * SECTOR_SIZE - size);
+            segments.push(DmaSegment {
+                phys: segment.phys,
+                len: taken,
+            });
+            size += taken;
+            if taken < segment.len {
+                remaining.push_front(DmaSegment {
+                    phys: segment.phys + taken as u64,
+                    len: segment.len - taken,
+                });
+            }
+            if size < max_count * SECTOR_SIZE
+                && segments.len() < max_segments
+                && !remaining.is_empty()
+            {
+                continue;
+            }
+            // The part of a sector past the last whole one goes to the next command
+            let mut tail = size % SECTOR_SIZE;
+            size -= tail;
+            while tail > 0 {
+                let last = segments.pop().expect("Segments too short to hold a sector");
+                let kept = last.len.saturating_sub(tail);
+                remaining.push_front(DmaSegment {
+                    phys: last.phys + kept as u64,
+                    len: last.len - kept,
+                });
+                if kept > 0 {
+                    segments.push(DmaSegment {
+                        phys: last.phys,
+                        len: kept,
+                    });
+                }
+                tail -= last.len - kept;
+            }
+            assert!(size > 0, "Segments too short to hold a sector");
+            commands.push(DmaCommand {
+                command: self.command.replace_sector(sector),
+                count: size / SECTOR_SIZE,
+                segments: mem::take(&mut segments),
+            });
+            sector += (size / SECTOR_SIZE) as u64;
+            size = 0;
+        }
+        commands
     }
 }
 
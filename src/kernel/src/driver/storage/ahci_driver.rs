@@ -1,33 +1,56 @@
-use core::alloc::Layout;
 use core::error::Error;
 use core::fmt::Display;
 use core::future::Future;
 use core::intrinsics::size_of;
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
-use core::ptr::{self, write_bytes};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::Poll;
 use core::{u32, usize};
 
-use alloc::alloc::alloc;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use bit_field::BitField;
-use proc::comptime_alloc;
 use spin::mutex::Mutex;
 use spin::Once;
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::PhysAddr;
 
+use crate::acpi::acpi;
+use crate::acpi::madt::{Polarity, TriggerMode};
+use crate::driver::mmio::{MmioRegion, ReadOnly, ReadWrite, RegisterBlock};
+use crate::driver::pci::msi::MsiMessage;
 use crate::driver::pci::{self, register_driver, Bar, DeviceType, PciDeviceHandle, Vendor};
-use crate::log;
-use crate::memory::memory_controller;
+use crate::interrupt::handler::{
+    free_vector, request_interrupt, EoiPolicy, HandlerFlags, IrqReturn,
+};
+use crate::interrupt::{local_apic_id, route_gsi};
+use crate::memory::dma::{DmaBuffer, DmaConstraints, DmaSegment};
+use crate::task::waker::InterruptWaker;
+use crate::time::timer::timeout;
+use crate::time::{busy_wait, wait_until, Duration};
 use crate::utils::VolatileCell;
+use crate::{inline_if, log};
 
-use super::{DmaBuffer, DmaRequest, Drive, DriveCommand};
+use super::{DmaRequest, Drive, DriveCommand, SECTOR_SIZE};
 
 pub static DRIVER: Once<Arc<AhciDriver>> = Once::new();
 
-pub const ABAR_START: u64 = comptime_alloc!(0xFF);
-pub const ABAR_SIZE: u64 = size_of::<HbaMem>() as u64;
+/// Registers of the controller, mapped once the pci bus finds it
+static ABAR: Once<MmioRegion> = Once::new();
+
+const HBA_GHC_IE: u32 = 1 << 1; // Global interrupt enable
+const ATA_DEV_BUSY: u32 = 0x80;
+const ATA_DEV_DRQ: u32 = 0x08;
+const PORT_TIMEOUT: Duration = Duration::from_millis(500);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Prdt entries that fit a command table in one page
+const PRDT_ENTRIES: usize = 248;
+/// Byte count of a prdt entry is 22 bits
+const PRDT_MAX_SIZE: usize = 0x400000;
+/// Sector count of a command is 16 bits
+const COMMAND_MAX_COUNT: usize = 0x8000;
+
+static PORT_STATES: [PortState; 32] = [const { PortState::new() }; 32];
 
 #[derive(Debug)]
 pub enum SataDriveError {
@@ -35,6 +58,7 @@ pub enum SataDriveError {
     DmaRequest,
     TaskFileError(HbaPortSerr),
     DriveNotFound(usize),
+    Timeout,
 }
 
 impl Display for SataDriveError {
@@ -46,6 +70,7 @@ impl Display for SataDriveError {
             }
             Self::DriveNotFound(id) => write!(f, "Trying to get drive with id: {}", id),
             Self::DmaRequest => write!(f, "Failed to request dma memory"),
+            Self::Timeout => write!(f, "Drive didn't respond in time"),
         }
     }
 }
@@ -113,25 +138,27 @@ enum FisType {
 #[repr(C)]
 pub struct HbaMem {
     // 0x00 - 0x2B, Generic Host Control
-    cap: VolatileCell<HbaCapabilities>, // 0x00, Host capability
-    ghc: VolatileCell<u32>,             // 0x04, Global host control
-    is: VolatileCell<u32>,              // 0x08, Interrupt status
-    pi: VolatileCell<u32>,              // 0x0C, Port implemented
-    vs: VolatileCell<u32>,              // 0x10, Version
-    ccc_ctl: VolatileCell<u32>,         // 0x14, Command completion coalescing control
-    ccc_pts: VolatileCell<u32>,         // 0x18, Command completion coalescing ports
-    em_loc: VolatileCell<u32>,          // 0x1C, Enclosure management location
-    em_ctl: VolatileCell<u32>,          // 0x20, Enclosure management control
-    cap2: VolatileCell<u32>,            // 0x24, Host capabilities extended
-    bohc: VolatileCell<u32>,            // 0x28, BIOS/OS handoff control and status
+    cap: ReadOnly<HbaCapabilities>, // 0x00, Host capability
+    ghc: ReadWrite<u32>,            // 0x04, Global host control
+    is: ReadWrite<u32>,             // 0x08, Interrupt status
+    pi: ReadOnly<u32>,              // 0x0C, Port implemented
+    vs: ReadOnly<u32>,              // 0x10, Version
+    ccc_ctl: ReadWrite<u32>,        // 0x14, Command completion coalescing control
+    ccc_pts: ReadWrite<u32>,        // 0x18, Command completion coalescing ports
+    em_loc: ReadOnly<u32>,          // 0x1C, Enclosure management location
+    em_ctl: ReadWrite<u32>,         // 0x20, Enclosure management control
+    cap2: ReadOnly<u32>,            // 0x24, Host capabilities extended
+    bohc: ReadWrite<u32>,           // 0x28, BIOS/OS handoff control and status
 
     // 0x2C - 0x9F, Reserved
-    rsv: [VolatileCell<u8>; 0xA0 - 0x2C],
+    rsv: [ReadOnly<u8>; 0xA0 - 0x2C],
 
     // 0xA0 - 0xFF, Vendor specific registers
-    vendor: [VolatileCell<u8>; 0x100 - 0xA0],
+    vendor: [ReadWrite<u8>; 0x100 - 0xA0],
 }
 
+unsafe impl RegisterBlock for HbaMem {}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct HbaPRDTEntry {
@@ -151,7 +178,7 @@ impl HbaPRDTEntry {
 
     pub fn set_dbc(&mut self, value: u32) {
         let mut old = self.dw3.get();
-        old.set_bits(0..21, value);
+        old.set_bits(0..22, value);
 
         self.dw3.set(old);
     }
@@ -170,7 +197,7 @@ struct HbaCmdTbl {
     _reserved: [VolatileCell<u8>; 48], // Reserved
 
     // 0x80
-    prdt_entry: [HbaPRDTEntry; 8], // Physical region descriptor table entries, 0 ~ 65535
+    prdt_entry: [HbaPRDTEntry; PRDT_ENTRIES], // Physical region descriptor table entries, 0 ~ 65535
 }
 
 #[derive(Debug)]
@@ -488,145 +515,152 @@ impl Display for AhciDriveType {
 #[derive(Debug)]
 #[repr(C)]
 pub struct HbaPort {
-    clb: VolatileCell<PhysAddr>,
-    fb: VolatileCell<PhysAddr>,
-    is: VolatileCell<HbaPortIS>,         // 0x10, interrupt status
-    ie: VolatileCell<HbaPortIE>,         // 0x14, interrupt enable
-    cmd: VolatileCell<HbaPortCmd>,       // 0x18, command and status
-    _reserved: VolatileCell<u32>,        // 0x1C, Reserved
-    tfd: VolatileCell<u32>,              // 0x20, task file data
-    sig: VolatileCell<u32>,              // 0x24, signature
-    ssts: VolatileCell<HbaSataStatus>,   // 0x28, SATA status (SCR0:SStatus)
-    sctl: VolatileCell<u32>,             // 0x2C, SATA control (SCR2:SControl)
-    serr: VolatileCell<HbaPortSerr>,     // 0x30, SATA error (SCR1:SError)
-    sact: VolatileCell<u32>,             // 0x34, SATA active (SCR3:SActive)
-    ci: VolatileCell<u32>,               // 0x38, command issue
-    sntf: VolatileCell<u32>,             // 0x3C, SATA notification (SCR4:SNotification)
-    fbs: VolatileCell<u32>,              // 0x40, FIS-based switch control
-    _reserved1: [VolatileCell<u32>; 11], // 0x44 ~ 0x6F, Reserved
-    vendor: [VolatileCell<u32>; 4],      // 0x70 ~ 0x7F, vendor specific
+    clb: ReadWrite<PhysAddr>,
+    fb: ReadWrite<PhysAddr>,
+    is: ReadWrite<HbaPortIS>,        // 0x10, interrupt status
+    ie: ReadWrite<HbaPortIE>,        // 0x14, interrupt enable
+    cmd: ReadWrite<HbaPortCmd>,      // 0x18, command and status
+    _reserved: ReadOnly<u32>,        // 0x1C, Reserved
+    tfd: ReadOnly<u32>,              // 0x20, task file data
+    sig: ReadOnly<u32>,              // 0x24, signature
+    ssts: ReadOnly<HbaSataStatus>,   // 0x28, SATA status (SCR0:SStatus)
+    sctl: ReadWrite<u32>,            // 0x2C, SATA control (SCR2:SControl)
+    serr: ReadWrite<HbaPortSerr>,    // 0x30, SATA error (SCR1:SError)
+    sact: ReadWrite<u32>,            // 0x34, SATA active (SCR3:SActive)
+    ci: ReadWrite<u32>,              // 0x38, command issue
+    sntf: ReadWrite<u32>,            // 0x3C, SATA notification (SCR4:SNotification)
+    fbs: ReadWrite<u32>,             // 0x40, FIS-based switch control
+    _reserved1: [ReadOnly<u32>; 11], // 0x44 ~ 0x6F, Reserved
+    vendor: [ReadWrite<u32>; 4],     // 0x70 ~ 0x7F, vendor specific
+}
+
+unsafe impl RegisterBlock for HbaPort {}
+
+/// Command completion state shared between a port and the ahci interrupt handler
+struct PortState {
+    slots: [InterruptWaker; 32],
+    task_file_error: AtomicBool,
+}
+
+impl PortState {
+    const fn new() -> Self {
+        Self {
+            slots: [const { InterruptWaker::new() }; 32],
+            task_file_error: AtomicBool::new(false),
+        }
+    }
 }
 
 pub struct SataPort {
-    hba_port: &'static mut HbaPort,
-    clb: VirtAddr,
-    fb: VirtAddr,
-    ctba: [VirtAddr; 32],
+    hba_port: &'static HbaPort,
+    index: usize,
+    /// Command list, received fis and command tables, allocated by rebase
+    clb: Option<DmaBuffer>,
+    fb: Option<DmaBuffer>,
+    ctba: Vec<DmaBuffer>,
     cap: HbaCapabilities,
 }
 
 impl SataPort {
     fn cmd_header(&mut self, slot: usize) -> &mut HbaCmdHeader {
-        unsafe { &mut *(self.clb.as_mut_ptr::<HbaCmdHeader>().add(slot)) }
+        let clb = self.clb.as_ref().expect("Port wasn't rebased");
+        unsafe { &mut *(clb.as_ptr::<HbaCmdHeader>().add(slot)) }
     }
 
     fn cmd_tbl(&mut self, slot: usize) -> &mut HbaCmdTbl {
-        unsafe { &mut *(self.ctba[slot].as_mut_ptr::<HbaCmdTbl>()) }
+        unsafe { &mut *(self.ctba[slot].as_ptr::<HbaCmdTbl>()) }
     }
 
-    fn start_cmd(&mut self) {
-        while self.hba_port.cmd.get().contains(HbaPortCmd::CR) {
-            core::hint::spin_loop();
-        }
-        let value = self.hba_port.cmd.get() | HbaPortCmd::FRE | HbaPortCmd::ST;
-        self.hba_port.cmd.set(value);
+    fn start_cmd(&mut self) -> Result<(), SataDriveError> {
+        wait_until(PORT_TIMEOUT, || !self.hba_port.cmd.contains(HbaPortCmd::CR))
+            .map_err(|_| SataDriveError::Timeout)?;
+        self.hba_port.cmd.insert(HbaPortCmd::FRE | HbaPortCmd::ST);
+        Ok(())
     }
 
-    fn stop_cmd(&mut self) {
-        let mut cmd = self.hba_port.cmd.get();
-        cmd.remove(HbaPortCmd::FRE | HbaPortCmd::ST);
+    fn stop_cmd(&mut self) -> Result<(), SataDriveError> {
+        self.hba_port.cmd.remove(HbaPortCmd::FRE | HbaPortCmd::ST);
 
-        self.hba_port.cmd.set(cmd);
-
-        while self
-            .hba_port
-            .cmd
-            .get()
-            .intersects(HbaPortCmd::FR | HbaPortCmd::CR)
-        {
-            core::hint::spin_loop();
-        }
+        wait_until(PORT_TIMEOUT, || {
+            !self
+                .hba_port
+                .cmd
+                .intersects(HbaPortCmd::FR | HbaPortCmd::CR)
+        })
+        .map_err(|_| SataDriveError::Timeout)
     }
 
     fn check_type(&mut self) -> Option<AhciDriveType> {
-        let status = self.hba_port.ssts.get();
+        let status = self.hba_port.ssts.read();
 
         let ipm = status.interface_power_management();
         let dd = status.device_detection();
 
         if let (HbaPortDd::PresentAndE, HbaPortIpm::Active) = (dd, ipm) {
-            return Some(AhciDriveType::from_signature(self.hba_port.sig.get()));
+            return Some(AhciDriveType::from_signature(self.hba_port.sig.read()));
         } else {
             return None;
         }
     }
 
-    fn rebase(&mut self) {
-        self.stop_cmd();
-        let virt_clb: VirtAddr = unsafe {
-            VirtAddr::new(alloc(
-                Layout::from_size_align(size_of::<HbaCmdHeader>() * 32, 1024).unwrap(),
-            ) as u64)
-        };
-
-        unsafe {
-            write_bytes(
-                virt_clb.as_mut_ptr::<u8>(),
-                0,
-                size_of::<HbaCmdHeader>() * 32,
-            );
-        }
-        self.hba_port
-            .clb
-            .set(memory_controller().lock().get_physical(virt_clb).unwrap());
-        let virt_fb: VirtAddr =
-            unsafe { VirtAddr::new(alloc(Layout::from_size_align(0xFF, 256).unwrap()) as u64) };
-        unsafe {
-            write_bytes(virt_fb.as_mut_ptr::<u8>(), 0, 0xFF);
-        }
-        self.hba_port
-            .fb
-            .set(memory_controller().lock().get_physical(virt_fb).unwrap());
-        self.fb = virt_fb;
-        self.clb = virt_clb;
-
-        let cmdheader = unsafe { &mut *(virt_clb.as_mut_ptr() as *mut [HbaCmdHeader; 32]) };
-        for i in 0..32 {
-            cmdheader[i].prdtl.set(8);
-            let virt_ctba: VirtAddr =
-                unsafe { VirtAddr::new(alloc(Layout::from_size_align(4096, 128).unwrap()) as u64) };
-            unsafe {
-                ptr::write_bytes(virt_ctba.as_mut_ptr::<u8>(), 0, 4096);
-            }
-
-            self.ctba[i] = virt_ctba;
-            cmdheader[i]
-                .ctba
-                .set(memory_controller().lock().get_physical(virt_ctba).unwrap());
+    fn rebase(&mut self) -> Result<(), SataDriveError> {
+        self.stop_cmd()?;
+        let clb = dma_alloc(size_of::<HbaCmdHeader>() * 32, 1024)?;
+        self.hba_port.clb.write(clb.phys());
+        let fb = dma_alloc(0x100, 256)?;
+        self.hba_port.fb.write(fb.phys());
+
+        let cmdheader = unsafe { &mut *clb.as_ptr::<[HbaCmdHeader; 32]>() };
+        self.ctba.clear();
+        for header in cmdheader.iter_mut() {
+            header.prdtl.set(0);
+            let ctba = dma_alloc(size_of::<HbaCmdTbl>(), 128)?;
+            header.ctba.set(ctba.phys());
+            self.ctba.push(ctba);
         }
+        self.clb = Some(clb);
+        self.fb = Some(fb);
         // Reset port for good mesure
-        self.hba_port
-            .sctl
-            .set(*self.hba_port.sctl.get().set_bits(0..3, 1));
-        // TODO: use a proper timer for this
-        for _ in 0..1000000 {}
-        self.hba_port
-            .sctl
-            .set(*self.hba_port.sctl.get().set_bits(0..3, 0));
+        self.comreset()?;
+        self.hba_port.ie.write(HbaPortIE::all());
+        self.hba_port.cmd.remove(HbaPortCmd::ALPE);
+        self.start_cmd()
+    }
+
+    fn comreset(&mut self) -> Result<(), SataDriveError> {
+        self.hba_port.sctl.modify(|mut e| *e.set_bits(0..3, 1));
+        // COMRESET has to be held for at least 1ms
+        busy_wait(Duration::from_millis(1));
+        self.hba_port.sctl.modify(|mut e| *e.set_bits(0..3, 0));
         // Wait for reestablished
-        while self.hba_port.ssts.get().device_detection() != HbaPortDd::PresentAndE {
-            core::hint::spin_loop();
+        wait_until(PORT_TIMEOUT, || {
+            self.hba_port.ssts.read().device_detection() == HbaPortDd::PresentAndE
+        })
+        .map_err(|_| SataDriveError::Timeout)?;
+        self.hba_port.serr.write(HbaPortSerr::all());
+        Ok(())
+    }
+
+    /// Abort the command in `slot` and bring the port back up
+    ///
+    /// Clearing ST clears PxCI, once CR is clear the device no longer reads or writes the command's buffers.
+    fn recover(&mut self, slot: usize) -> Result<(), SataDriveError> {
+        if self.stop_cmd().is_err() {
+            self.comreset()?;
+            self.stop_cmd()
+                .expect("ahci port kept running a command after a COMRESET");
+        } else {
+            self.comreset()?;
         }
-        self.hba_port.serr.set(HbaPortSerr::all());
-        self.hba_port.ie.set(HbaPortIE::all());
-        let mut cmd = self.hba_port.cmd.get();
-        cmd.remove(HbaPortCmd::ALPE);
-        self.hba_port.cmd.set(cmd);
-        self.start_cmd();
+        self.hba_port.is.write(HbaPortIS::all());
+        let state = &PORT_STATES[self.index];
+        state.task_file_error.store(false, Ordering::Release);
+        state.slots[slot].clear();
+        self.start_cmd()
     }
 
     fn find_cmdslot(&self) -> Result<usize, SataDriveError> {
-        let mut slots = self.hba_port.sact.get() | self.hba_port.ci.get();
+        let mut slots = self.hba_port.sact.read() | self.hba_port.ci.read();
         let num_of_slots = self.cap.number_of_slots().into();
         for i in 0..num_of_slots {
             if (slots & 1) == 0 {
@@ -641,7 +675,7 @@ impl SataPort {
     async fn run_command(
         &mut self,
         count: usize,
-        buffer: &[DmaBuffer],
+        segments: &[DmaSegment],
         command: DriveCommand,
     ) -> Result<(), SataDriveError> {
         let slot = self.find_cmdslot()?;
@@ -649,7 +683,7 @@ impl SataPort {
 
         let mut flags = cmd_header.flags.get();
         if command.is_write() {
-            flags.intersects(HbaCmdHeaderFlags::W);
+            flags.insert(HbaCmdHeaderFlags::W);
         } else {
             flags.remove(HbaCmdHeaderFlags::W);
         }
@@ -658,14 +692,13 @@ impl SataPort {
         flags.set_cfl(size_of::<FisRegInner<FisRegH2D>>() / size_of::<u32>());
         cmd_header.flags.set(flags);
 
-        let length = ((count - 1) >> 5) + 1;
-        cmd_header.prdtl.set(length as _);
+        cmd_header.prdtl.set(segments.len() as _);
         let cmdtbl = self.cmd_tbl(slot);
-        for (buffer, i) in buffer.iter().zip(0..length) {
-            assert!(buffer.start.as_u64() % 2 == 0);
-            cmdtbl.get_prdt_entry(i).dba.set(buffer.start);
-            cmdtbl.get_prdt_entry(i).set_dbc((buffer.size - 1) as u32);
-            cmdtbl.get_prdt_entry(i).set_i(i == length - 1);
+        for (i, segment) in segments.iter().enumerate() {
+            let entry = cmdtbl.get_prdt_entry(i);
+            entry.dba.set(segment.phys);
+            entry.set_dbc((segment.len - 1) as u32);
+            entry.set_i(i == segments.len() - 1);
         }
 
         let mut cmdfis = cmdtbl.command_fis::<FisRegH2D>();
@@ -679,23 +712,78 @@ impl SataPort {
         cmdfis.device.set(1 << 6);
         cmdfis.count.set(count as u16);
 
-        self.hba_port.ci.set(1 << slot);
-        while self.hba_port.tfd.get() & 0x80 | 0x08 == 1 {
-            core::hint::spin_loop();
-        }
+        // Wait for the device to be able to accept a command
+        wait_until(PORT_TIMEOUT, || {
+            self.hba_port.tfd.read() & (ATA_DEV_BUSY | ATA_DEV_DRQ) == 0
+        })
+        .map_err(|_| SataDriveError::Timeout)?;
+        self.hba_port.ci.write(1 << slot);
+
+        let (hba_port, state) = (self.hba_port, &PORT_STATES[self.index]);
+        let issued = IssuedCommand {
+            port: self,
+            slot,
+            finished: false,
+        };
+        let completion = timeout(COMMAND_TIMEOUT, DriveAsync::new(hba_port, slot, state))
+            .await
+            .unwrap_or(Err(SataDriveError::Timeout));
+        issued.finish(completion)
+    }
+}
 
-        DriveAsync::new(self.hba_port, slot).await?;
+/// Command the device may still be running, aborted if it's dropped before it finished
+///
+/// The caller gets its pages back once the future is gone, the device must be done with them by then.
+struct IssuedCommand<'a> {
+    port: &'a mut SataPort,
+    slot: usize,
+    finished: bool,
+}
 
-        return Ok(());
+impl IssuedCommand<'_> {
+    fn finish(mut self, completion: Result<(), SataDriveError>) -> Result<(), SataDriveError> {
+        self.finished = true;
+        if completion.is_err() {
+            self.port.recover(self.slot)?;
+        }
+        completion
     }
 }
 
-impl HbaMem {
-    pub fn get_port(&mut self, port: usize) -> &'static mut HbaPort {
-        unsafe { &mut *((self as *mut HbaMem).offset(1) as *mut HbaPort).add(port) }
+impl Drop for IssuedCommand<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.port.hba_port.ci.read().get_bit(self.slot) {
+            if let Err(err) = self.port.recover(self.slot) {
+                log!(
+                    Error,
+                    "Ahci port {} didn't recover: {}",
+                    self.port.index,
+                    err
+                );
+            }
+        }
     }
 }
 
+/// Zeroed memory for the controller's own structures
+fn dma_alloc(size: usize, align: u64) -> Result<DmaBuffer, SataDriveError> {
+    let constraints = DmaConstraints {
+        align,
+        ..Default::default()
+    };
+    DmaBuffer::new(size, constraints).map_err(|_| SataDriveError::DmaRequest)
+}
+
+/// The device reads and writes `data` in place, prdt entries only need to be word aligned
+fn request(
+    command: DriveCommand,
+    data: *const u8,
+    size: usize,
+) -> Result<DmaRequest, SataDriveError> {
+    DmaRequest::new(command, data, size, 2, PRDT_MAX_SIZE).ok_or(SataDriveError::DmaRequest)
+}
+
 pub struct AhciDrive {
     port: Arc<Mutex<SataPort>>,
     identifier: Option<[u8; 512]>,
@@ -704,11 +792,21 @@ pub struct AhciDrive {
 struct DriveAsync<'a> {
     port: &'a HbaPort,
     slot: usize,
+    state: &'static PortState,
 }
 
 impl<'a> DriveAsync<'a> {
-    pub fn new(port: &'a HbaPort, slot: usize) -> Self {
-        Self { port, slot }
+    fn new(port: &'a HbaPort, slot: usize, state: &'static PortState) -> Self {
+        Self { port, slot, state }
+    }
+
+    fn completion(&self) -> Option<Result<(), SataDriveError>> {
+        if self.state.task_file_error.swap(false, Ordering::AcqRel)
+            || self.port.is.contains(HbaPortIS::TFES)
+        {
+            return Some(Err(SataDriveError::TaskFileError(self.port.serr.read())));
+        }
+        inline_if!(self.port.ci.read().get_bit(self.slot), None, Some(Ok(())))
     }
 }
 
@@ -717,26 +815,28 @@ impl Future for DriveAsync<'_> {
 
     fn poll(
         self: core::pin::Pin<&mut Self>,
-        _cx: &mut core::task::Context<'_>,
+        cx: &mut core::task::Context<'_>,
     ) -> core::task::Poll<Self::Output> {
-        if self.port.is.get().contains(HbaPortIS::TFES) {
-            return Poll::Ready(Err(SataDriveError::TaskFileError(self.port.serr.get())));
+        if let Some(result) = self.completion() {
+            return Poll::Ready(result);
         }
-        if self.port.ci.get() & (1 << self.slot) == 1 {
-            return Poll::Pending;
-        } else {
-            return Poll::Ready(Ok(()));
+        self.state.slots[self.slot].register(cx.waker());
+        // The command may have completed before the waker got registered
+        match self.completion() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
         }
     }
 }
 
 impl AhciDrive {
-    fn new(hba_port: &'static mut HbaPort, cap: HbaCapabilities) -> Self {
+    fn new(hba_port: &'static HbaPort, index: usize, cap: HbaCapabilities) -> Self {
         let port = Mutex::new(SataPort {
             hba_port,
-            clb: VirtAddr::new(0),
-            fb: VirtAddr::new(0),
-            ctba: [VirtAddr::new(0); 32],
+            index,
+            clb: None,
+            fb: None,
+            ctba: Vec::new(),
             cap,
         });
         Self {
@@ -746,31 +846,18 @@ impl AhciDrive {
     }
 
     async fn run_request(&self, request: &DmaRequest) -> Result<(), SataDriveError> {
-        let mut count = request.count();
-        let mut offset = 0;
-        let mut current_sector = request.command.sector();
-
-        while count > 0 {
-            let this_count = count.min(128);
+        for command in request.commands(PRDT_ENTRIES, COMMAND_MAX_COUNT) {
             self.port
                 .lock()
-                .run_command(
-                    this_count,
-                    &request.buffer[offset..],
-                    request.command.replace_sector(current_sector),
-                )
+                .run_command(command.count, &command.segments, command.command)
                 .await?;
-            count -= this_count; // 128 sector (65536 byte)
-            offset += this_count >> 5; // 65536 byte per buffer
-            current_sector += this_count as u64;
         }
         return Ok(());
     }
 
     pub async fn identify(&mut self) -> Result<(), SataDriveError> {
         let mut buf = [0u8; 512];
-        let request =
-            DmaRequest::new(1, DriveCommand::Identify).ok_or(SataDriveError::DmaRequest)?;
+        let request = request(DriveCommand::Identify, buf.as_mut_ptr(), buf.len())?;
         self.run_request(&request).await?;
         request.copy_into(&mut buf);
         self.identifier = Some(buf);
@@ -799,8 +886,12 @@ impl Drive for AhciDrive {
         buffer: &mut [u8],
         count: usize,
     ) -> Result<(), Self::Error> {
-        let request = DmaRequest::new(count, DriveCommand::Read(from_sector))
-            .ok_or(SataDriveError::DmaRequest)?;
+        let buffer = &mut buffer[..count * SECTOR_SIZE];
+        let request = request(
+            DriveCommand::Read(from_sector),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+        )?;
         self.run_request(&request).await?;
         request.copy_into(buffer);
         Ok(())
@@ -812,8 +903,12 @@ impl Drive for AhciDrive {
         buffer: &[u8],
         count: usize,
     ) -> Result<(), Self::Error> {
-        let mut request = DmaRequest::new(count, DriveCommand::Write(from_sector))
-            .ok_or(SataDriveError::DmaRequest)?;
+        let buffer = &buffer[..count * SECTOR_SIZE];
+        let mut request = request(
+            DriveCommand::Write(from_sector),
+            buffer.as_ptr(),
+            buffer.len(),
+        )?;
         request.copy_into_self(buffer);
         self.run_request(&request).await?;
         Ok(())
@@ -822,7 +917,6 @@ impl Drive for AhciDrive {
 
 pub struct AhciController {
     drives: [Option<AhciDrive>; 32],
-    hba: &'static mut HbaMem,
 }
 
 pub struct AhciDriver {
@@ -847,17 +941,21 @@ impl PciDeviceHandle for AhciDriver {
         log!(Info, "Starting ahci driver");
         let abar = header.get_bar(5).expect("Failed to get ABAR for ahci");
 
-        let (abar_address, _) = match abar {
+        let (abar_address, abar_size) = match abar {
             Bar::Memory32 { address, size, .. } => (address as u64, size as u64),
             Bar::Memory64 { address, size, .. } => (address, size),
             Bar::IO { .. } => panic!("ABAR is in port space somehow"),
         };
 
-        memory_controller()
-            .lock()
-            .phy_map(ABAR_SIZE, abar_address, ABAR_START);
+        ABAR.call_once(|| {
+            MmioRegion::map(abar_address, abar_size as usize).expect("Failed to map ABAR")
+        });
+        // Needed for both dma transfers and msi writes
+        header.enable_bus_mastering();
 
-        self.inner.lock().probe_port();
+        let mut controller = self.inner.lock();
+        controller.probe_port();
+        controller.enable_interrupt(header);
     }
 }
 
@@ -865,33 +963,36 @@ impl AhciController {
     pub fn new() -> Self {
         Self {
             drives: [const { None }; 32],
-            hba: unsafe { &mut *((ABAR_START as *mut u8) as *mut HbaMem) },
         }
     }
 
     pub fn probe_port(&mut self) {
-        let pi = self.hba.pi.get();
+        let hba = hba();
+        let pi = hba.pi.read();
 
-        if self.hba.bohc.get() & 2 == 0 {
-            self.hba.bohc.set(self.hba.bohc.get() | 0b10);
+        if hba.bohc.read() & 2 == 0 {
+            hba.bohc.write(hba.bohc.read() | 0b10);
             let mut spin = 0;
-            while self.hba.bohc.get() & 1 != 0 && spin < 50000 {
+            while hba.bohc.read() & 1 != 0 && spin < 50000 {
                 spin += 1;
             }
-            if self.hba.bohc.get() & 1 != 0 {
-                self.hba.bohc.set(2);
-                self.hba.bohc.set(self.hba.bohc.get() | 8);
+            if hba.bohc.read() & 1 != 0 {
+                hba.bohc.write(2);
+                hba.bohc.write(hba.bohc.read() | 8);
             }
         }
 
         for i in 0..32 {
             if pi.get_bit(i) {
-                let drive = AhciDrive::new(self.hba.get_port(i), self.hba.cap.get());
+                let drive = AhciDrive::new(hba_port(i), i, hba.cap.read());
                 let dt = drive.port.lock().check_type();
                 if let Some(dt) = dt {
                     match dt {
                         AhciDriveType::Sata => {
-                            drive.port.lock().rebase();
+                            if let Err(err) = drive.port.lock().rebase() {
+                                log!(Error, "Failed to start sata drive on port {}: {}", i, err);
+                                continue;
+                            }
                             log!(Info, "Found sata drive on port {}", i);
                             self.drives[i] = Some(drive);
                         }
@@ -907,6 +1008,70 @@ impl AhciController {
         }
     }
 
+    /// Let the hba raise its interrupt through msi, or through the io apic without msi support
+    fn enable_interrupt(&mut self, header: &pci::PciHeader) {
+        let msi = header.msi().ok();
+        // A message signalled interrupt is never shared with other devices
+        let flags = inline_if!(msi.is_some(), HandlerFlags::empty(), HandlerFlags::SHARED);
+        let vector = match request_interrupt("ahci", EoiPolicy::Auto, flags, |_| handle_interrupt())
+        {
+            Ok(vector) => vector,
+            Err(err) => {
+                log!(Error, "Failed to request ahci interrupt: {}", err);
+                return;
+            }
+        };
+        match msi {
+            Some(msi) => {
+                msi.set_message(MsiMessage::new(local_apic_id(), vector));
+                msi.enable();
+                header.disable_legacy_irq();
+                log!(Info, "AHCI interrupt uses msi on vector {:#x}", vector);
+            }
+            None => {
+                if !Self::route_legacy_interrupt(header, vector) {
+                    let _ = free_vector(vector);
+                    return;
+                }
+            }
+        }
+
+        let hba = hba();
+        hba.is.write(hba.is.read());
+        hba.ghc.write(hba.ghc.read() | HBA_GHC_IE);
+    }
+
+    /// Route the interrupt pin of the controller through the io apic
+    fn route_legacy_interrupt(header: &pci::PciHeader, vector: u8) -> bool {
+        if header.interrupt_pin() == 0 {
+            log!(Warning, "AHCI controller has no interrupt pin");
+            return false;
+        }
+        let irq = header.interrupt_line();
+        // The firmware routes the pin to the isa irq in the interrupt line register,
+        // unlike isa devices the line is shared, level triggered and active low
+        let (gsi, polarity) = match acpi().madt().and_then(|e| e.find_override(irq)) {
+            Some(source_override) => (
+                source_override.gsi,
+                inline_if!(
+                    source_override.flags.polarity() == Polarity::ActiveHigh,
+                    Polarity::ActiveHigh,
+                    Polarity::ActiveLow
+                ),
+            ),
+            None => (irq as u32, Polarity::ActiveLow),
+        };
+        header.enable_legacy_irq();
+        route_gsi(gsi, vector, polarity, TriggerMode::Level);
+        log!(
+            Info,
+            "AHCI interrupt routed from gsi {} to vector {:#x}",
+            gsi,
+            vector
+        );
+        true
+    }
+
     pub fn get_drive(&mut self, id: usize) -> Result<&mut AhciDrive, SataDriveError> {
         if let Some(drive) = self.drives.get_mut(id) {
             if let Some(value) = drive {
@@ -920,6 +1085,44 @@ impl AhciController {
     }
 }
 
+fn hba() -> &'static HbaMem {
+    ABAR.get().expect("ABAR isn't mapped").block(0)
+}
+
+/// Registers of port `index`, they follow the generic host control ones
+fn hba_port(index: usize) -> &'static HbaPort {
+    ABAR.get()
+        .expect("ABAR isn't mapped")
+        .block(size_of::<HbaMem>() + index * size_of::<HbaPort>())
+}
+
+/// Acknowledge the pending port interrupts and wake the tasks waiting on their command slots
+pub fn handle_interrupt() -> IrqReturn {
+    let hba = hba();
+    let pending = hba.is.read();
+    if pending == 0 {
+        return IrqReturn::NotHandled;
+    }
+    for i in (0..32).filter(|i| pending.get_bit(*i)) {
+        let port = hba_port(i);
+        let status = port.is.read();
+        port.is.write(status); // Write 1 to clear
+        let state = &PORT_STATES[i];
+        let failed = status.contains(HbaPortIS::TFES);
+        if failed {
+            state.task_file_error.store(true, Ordering::Release);
+        }
+        let issued = port.ci.read();
+        for (slot, waker) in state.slots.iter().enumerate() {
+            if failed || !issued.get_bit(slot) {
+                waker.wake();
+            }
+        }
+    }
+    hba.is.write(pending);
+    IrqReturn::Handled
+}
+
 pub fn get_ahci() -> &'static Arc<AhciDriver> {
     return DRIVER.get().expect("AHCI driver not initialized");
 }
@@ -0,0 +1,276 @@
+use core::error::Error;
+use core::fmt::Display;
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+use crate::acpi::acpi;
+use crate::acpi::madt::{Polarity, TriggerMode};
+use crate::interrupt::{handles_gsi, route_gsi};
+use crate::log;
+use crate::memory::virtual_alloc::{map_physical, Mapping};
+use crate::time::clocksource::{ClockSource, CounterExtender};
+use crate::time::Duration;
+use crate::utils::VolatileCell;
+
+const HPET_SIZE: u64 = 0x1000;
+const FEMTOS_PER_SEC: u128 = 1_000_000_000_000_000;
+// The specification caps the counter period at 100ns
+const MAX_PERIOD_FEMTOS: u64 = 100_000_000;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CONF_ENABLE: u64 = 1 << 0;
+const CONF_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum HpetError {
+    InvalidComparator(u8),
+    PeriodicUnsupported(u8),
+    NotRouted(u8),
+    NoRoute(u8),
+}
+
+impl Display for HpetError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidComparator(index) => write!(f, "HPET has no comparator {}", index),
+            Self::PeriodicUnsupported(index) => {
+                write!(f, "HPET comparator {} cannot run periodically", index)
+            }
+            Self::NotRouted(index) => {
+                write!(f, "HPET comparator {} isn't routed to a vector", index)
+            }
+            Self::NoRoute(index) => write!(
+                f,
+                "HPET comparator {} cannot be routed to any io apic input",
+                index
+            ),
+        }
+    }
+}
+
+impl Error for HpetError {}
+
+#[repr(C)]
+struct HpetRegisters {
+    capabilities: VolatileCell<u64>,
+    _reserved0: VolatileCell<u64>,
+    configuration: VolatileCell<u64>,
+    _reserved1: VolatileCell<u64>,
+    interrupt_status: VolatileCell<u64>,
+    _reserved2: [VolatileCell<u64>; 25],
+    main_counter: VolatileCell<u64>,
+    _reserved3: VolatileCell<u64>,
+    timers: [TimerRegisters; 32],
+}
+
+#[repr(C)]
+struct TimerRegisters {
+    configuration: VolatileCell<u64>,
+    comparator: VolatileCell<u64>,
+    fsb_route: VolatileCell<u64>,
+    _reserved: VolatileCell<u64>,
+}
+
+/// The high precision event timer, a main counter with comparators raising interrupts
+pub struct Hpet {
+    registers: &'static HpetRegisters,
+    /// Keeps the registers mapped
+    _mapping: Mapping,
+    period: u64,
+    comparator_count: u8,
+    counter_64bit: bool,
+    counter: CounterExtender,
+    // Io apic input each comparator is routed to
+    routes: Mutex<[Option<u32>; 32]>,
+}
+
+impl Hpet {
+    fn new(base_address: u64) -> Self {
+        let mapping = map_physical(HPET_SIZE, base_address).expect("Failed to map the HPET");
+        let registers = unsafe { &*mapping.as_ptr::<HpetRegisters>() };
+        let capabilities = registers.capabilities.get();
+        let period = capabilities >> 32;
+        assert!(
+            period != 0 && period <= MAX_PERIOD_FEMTOS,
+            "Invalid HPET counter period {}fs",
+            period
+        );
+
+        let hpet = Self {
+            registers,
+            _mapping: mapping,
+            period,
+            comparator_count: ((capabilities >> 8) & 0x1F) as u8 + 1,
+            counter_64bit: capabilities & CAP_COUNTER_64BIT != 0,
+            counter: CounterExtender::new(32),
+            routes: Mutex::new([None; 32]),
+        };
+        for timer in hpet.timers() {
+            let conf = timer.configuration.get();
+            timer
+                .configuration
+                .set(conf & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE));
+        }
+        // Comparators are routed through the io apic, not in place of the pit and rtc
+        let conf = registers.configuration.get() & !CONF_LEGACY_REPLACEMENT;
+        registers.configuration.set(conf | CONF_ENABLE);
+        hpet
+    }
+
+    fn timers(&self) -> &[TimerRegisters] {
+        &self.registers.timers[..self.comparator_count as usize]
+    }
+
+    fn timer(&self, index: u8) -> Result<&TimerRegisters, HpetError> {
+        self.timers()
+            .get(index as usize)
+            .ok_or(HpetError::InvalidComparator(index))
+    }
+
+    /// Raw value of the main counter, it wraps after 32 bits on some chipsets
+    pub fn counter(&self) -> u64 {
+        self.registers.main_counter.get()
+    }
+
+    /// Length of a counter tick in femtoseconds
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    pub fn comparator_count(&self) -> u8 {
+        self.comparator_count
+    }
+
+    pub fn counter_is_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+
+    pub fn supports_periodic(&self, index: u8) -> Result<bool, HpetError> {
+        Ok(self.timer(index)?.configuration.get() & TIMER_PERIODIC_CAPABLE != 0)
+    }
+
+    /// Io apic inputs the comparator can be routed to, one bit per gsi
+    pub fn route_capabilities(&self, index: u8) -> Result<u32, HpetError> {
+        Ok((self.timer(index)?.configuration.get() >> 32) as u32)
+    }
+
+    /// Route comparator `index` to `vector`, returns the gsi it got routed through
+    ///
+    /// The highest capable gsi is picked since the low ones are usually taken by isa devices
+    pub fn route(&self, index: u8, vector: u8) -> Result<u32, HpetError> {
+        let capabilities = self.route_capabilities(index)?;
+        let gsi = (0..32)
+            .rev()
+            .find(|gsi| capabilities & (1 << gsi) != 0 && handles_gsi(*gsi))
+            .ok_or(HpetError::NoRoute(index))?;
+        let timer = self.timer(index)?;
+        let conf = timer.configuration.get() & !(TIMER_ROUTE_MASK | TIMER_LEVEL_TRIGGERED);
+        timer
+            .configuration
+            .set(conf | ((gsi as u64) << TIMER_ROUTE_SHIFT));
+        route_gsi(gsi, vector, Polarity::ActiveHigh, TriggerMode::Edge);
+        self.routes.lock()[index as usize] = Some(gsi);
+        Ok(gsi)
+    }
+
+    fn ticks(&self, duration: Duration) -> u64 {
+        ((duration.as_nanos() * 1_000_000 / self.period as u128) as u64).max(1)
+    }
+
+    fn routed_timer(&self, index: u8) -> Result<&TimerRegisters, HpetError> {
+        let timer = self.timer(index)?;
+        match self.routes.lock()[index as usize] {
+            Some(_) => Ok(timer),
+            None => Err(HpetError::NotRouted(index)),
+        }
+    }
+
+    /// Raise the routed interrupt of comparator `index` once, after `delay`
+    pub fn start_oneshot(&self, index: u8, delay: Duration) -> Result<(), HpetError> {
+        let timer = self.routed_timer(index)?;
+        let conf = timer.configuration.get() & !(TIMER_PERIODIC | TIMER_32BIT_MODE);
+        timer.configuration.set(conf & !TIMER_INTERRUPT_ENABLE);
+        timer
+            .comparator
+            .set(self.counter().wrapping_add(self.ticks(delay)));
+        timer.configuration.set(conf | TIMER_INTERRUPT_ENABLE);
+        Ok(())
+    }
+
+    /// Raise the routed interrupt of comparator `index` every `period`
+    pub fn start_periodic(&self, index: u8, period: Duration) -> Result<(), HpetError> {
+        if !self.supports_periodic(index)? {
+            return Err(HpetError::PeriodicUnsupported(index));
+        }
+        let timer = self.routed_timer(index)?;
+        let ticks = self.ticks(period);
+        let conf = timer.configuration.get() & !TIMER_32BIT_MODE;
+        timer.configuration.set(conf & !TIMER_INTERRUPT_ENABLE);
+        // With value set, the first write sets the comparator and the second one the period
+        timer
+            .configuration
+            .set(conf | TIMER_PERIODIC | TIMER_VALUE_SET | TIMER_INTERRUPT_ENABLE);
+        timer.comparator.set(self.counter().wrapping_add(ticks));
+        timer.comparator.set(ticks);
+        Ok(())
+    }
+
+    /// Stop comparator `index` from raising interrupts
+    pub fn stop(&self, index: u8) -> Result<(), HpetError> {
+        let timer = self.timer(index)?;
+        let conf = timer.configuration.get();
+        timer
+            .configuration
+            .set(conf & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+        Ok(())
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        if self.counter_64bit {
+            self.counter()
+        } else {
+            self.counter.extend(self.counter())
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        (FEMTOS_PER_SEC / self.period as u128) as u64
+    }
+}
+
+/// Map the hpet described by acpi, `None` if the firmware doesn't provide one
+pub fn init() -> Option<&'static Hpet> {
+    let table = acpi().hpet()?;
+    let hpet = HPET.get_or_init(|| Hpet::new(table.base_address()));
+    log!(
+        Info,
+        "HPET at {:#x} runs at {} MHz with {} comparators",
+        table.base_address(),
+        hpet.frequency() / 1_000_000,
+        hpet.comparator_count
+    );
+    Some(hpet)
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
@@ -1,18 +1,19 @@
+use core::cell::SyncUnsafeCell;
+
 use spin::Once;
-use x86_64::registers::segmentation::{Segment, CS};
+use x86_64::registers::segmentation::{Segment, CS, SS};
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::{PrivilegeLevel, VirtAddr};
 
 use crate::memory::memory_controller;
+use crate::smp::percpu;
 
 pub struct Gdt {
     table: [u64; 8],
     next_free: usize,
 }
 
-static TSS: Once<TaskStateSegment> = Once::new();
-static GDT: Once<Gdt> = Once::new();
 impl Gdt {
     pub fn new() -> Gdt {
         Gdt {
@@ -30,7 +31,7 @@ impl Gdt {
                 index
             }
         };
-        SegmentSelector::new(index as u16, PrivilegeLevel::Ring0)
+        SegmentSelector::new(index as u16, entry.privilege_level())
     }
 
     pub fn load(&'static self) {
@@ -57,30 +58,55 @@ impl Gdt {
     }
 }
 
+/// Segment selectors, every processor builds its gdt with the same layout
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+static SELECTORS: Once<Selectors> = Once::new();
+
+pub fn selectors() -> &'static Selectors {
+    SELECTORS.get().expect("Gdt not loaded")
+}
+
+/// Load the gdt and tss of the executing processor, each one gets its own double fault stack
 pub fn init_gdt() {
     use x86_64::instructions::tables::load_tss;
+    let cpu = percpu::current();
     let double_fault = memory_controller()
         .lock()
-        .alloc_stack(1)
+        .alloc_stack(DOUBLE_FAULT_STACK_PAGES, "double fault")
         .expect("Could not allocate stack for double fault handle");
-    let tss = TSS.call_once(|| {
+    let tss = cpu.tss.call_once(|| {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
             VirtAddr::new(double_fault.top() as u64);
-        tss
+        SyncUnsafeCell::new(tss)
     });
-    let mut code_selector = SegmentSelector(0);
-    let mut tss_selector = SegmentSelector(0);
-    let gdt = GDT.call_once(|| {
+    let mut selectors = None;
+    let gdt = cpu.gdt.call_once(|| {
         let mut gdt = Gdt::new();
-        code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        tss_selector = gdt.add_entry(Descriptor::tss_segment(&tss));
+        // Sysret expects user data right before user code, both after kernel data
+        selectors = Some(Selectors {
+            kernel_code: gdt.add_entry(Descriptor::kernel_code_segment()),
+            kernel_data: gdt.add_entry(Descriptor::kernel_data_segment()),
+            user_data: gdt.add_entry(Descriptor::user_data_segment()),
+            user_code: gdt.add_entry(Descriptor::user_code_segment()),
+            tss: gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss.get() })),
+        });
         gdt
     });
+    let selectors = SELECTORS.call_once(|| selectors.unwrap());
     gdt.load();
     unsafe {
-        CS::set_reg(code_selector);
-        load_tss(tss_selector);
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
     }
 }
 
@@ -89,6 +115,8 @@ pub enum Descriptor {
     SystemSegment(u64, u64),
 }
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Big enough to log an oops and a backtrace after a kernel stack overflow
+pub const DOUBLE_FAULT_STACK_PAGES: usize = 4;
 
 impl Descriptor {
     pub fn kernel_code_segment() -> Descriptor {
@@ -99,6 +127,39 @@ impl Descriptor {
         Descriptor::UserSegment(flags.bits())
     }
 
+    pub fn kernel_data_segment() -> Descriptor {
+        let flags =
+            DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT
+            | DescriptorFlags::PRESENT
+            | DescriptorFlags::EXECUTABLE
+            | DescriptorFlags::LONG_MODE
+            | DescriptorFlags::DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT
+            | DescriptorFlags::PRESENT
+            | DescriptorFlags::WRITABLE
+            | DescriptorFlags::DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    /// Ring the segment is meant for, system segments are kernel only
+    fn privilege_level(&self) -> PrivilegeLevel {
+        match self {
+            Descriptor::UserSegment(value) => PrivilegeLevel::from_u16(
+                ((value & DescriptorFlags::DPL_RING_3.bits()) >> 45) as u16,
+            ),
+            Descriptor::SystemSegment(_, _) => PrivilegeLevel::Ring0,
+        }
+    }
+
     pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
         use bit_field::BitField;
         use core::mem::size_of;
@@ -123,9 +184,11 @@ impl Descriptor {
 
 bitflags! {
     pub struct DescriptorFlags: u64 {
+        const WRITABLE          = 1 << 41;
         const CONFORMING        = 1 << 42;
         const EXECUTABLE        = 1 << 43;
         const USER_SEGMENT      = 1 << 44;
+        const DPL_RING_3        = 3 << 45;
         const PRESENT           = 1 << 47;
         const LONG_MODE         = 1 << 53;
     }
@@ -1,62 +1,119 @@
-use core::arch::asm;
-
+use crate::acpi::acpi;
+use crate::acpi::madt::{MadtFlags, Polarity, TriggerMode};
 use crate::defer;
-use crate::gdt;
-use crate::hlt_loop;
-use crate::memory::memory_controller;
-use crate::print;
-use crate::println;
-use alloc::ffi::CString;
+use crate::inline_if;
+use crate::log;
+use crate::memory::virtual_alloc::{map_physical, Mapping};
+use crate::smp::percpu;
+use crate::userland::scheduler;
+use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
 use lazy_static::lazy_static;
-use proc::comptime_alloc;
 use spin::Mutex;
-use x2apic::ioapic::IoApic;
-use x2apic::lapic::xapic_base;
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
 use x2apic::lapic::LocalApic;
 use x2apic::lapic::LocalApicBuilder;
-use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use self::exception::set_exception_handlers;
+use self::handler::{
+    set_dispatch_handlers, spurious_interrupt_handler, SYSCALL_VECTOR, YIELD_VECTOR,
+};
+
+/// Naked entry stub saving the whole register state as a [`FullInterruptStackFrame`] before calling `$handler`
+///
+/// Vectors without an error code push a zero one so every frame has the same layout.
+/// With `switch` the handler returns the frame to resume, which may live on another stack
+macro_rules! interrupt_entry {
+    ($name:ident, $vector:expr, $handler:path) => {
+        interrupt_entry!(@entry $name, $vector, $handler, "push 0", "");
+    };
+    ($name:ident, $vector:expr, $handler:path, error_code) => {
+        interrupt_entry!(@entry $name, $vector, $handler, "", "");
+    };
+    ($name:ident, $vector:expr, $handler:path, switch) => {
+        interrupt_entry!(@entry $name, $vector, $handler, "push 0", "mov rsp, rax");
+    };
+    (@entry $name:ident, $vector:expr, $handler:path, $error_code:literal, $resume:literal) => {
+        #[naked]
+        fn $name() {
+            unsafe {
+                core::arch::asm!(
+                    $error_code,
+                    // Coming from ring 3 the gs base is the user one, see `percpu::init`
+                    "test byte ptr [rsp + 16], 3",
+                    "jz 2f",
+                    "swapgs",
+                    "2:",
+                    "push {vector}",
+                    "push rax",
+                    "push rcx",
+                    "push rdx",
+                    "push rsi",
+                    "push rdi",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push rbx",
+                    "push rbp",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+                    "mov rax, cr2",
+                    "push rax",
+                    "mov rax, cr3",
+                    "push rax",
+                    "mov rdi, rsp",
+                    "cld",
+                    "call {handler}",
+                    $resume,
+                    // The handler may have enabled interrupts, none may come in once gs is swapped back
+                    "cli",
+                    // Control registers aren't restored
+                    "add rsp, 16",
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop rbp",
+                    "pop rbx",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rdi",
+                    "pop rsi",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rax",
+                    // Vector and error code
+                    "add rsp, 16",
+                    "test byte ptr [rsp + 8], 3",
+                    "jz 3f",
+                    "swapgs",
+                    "3:",
+                    "iretq",
+                    vector = const $vector,
+                    handler = sym $handler,
+                    options(noreturn)
+                );
+            }
+        }
+    };
+}
+
+pub mod exception;
+pub mod handler;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handle);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.overflow.set_handler_fn(overflow_handler);
-        idt.divide_error.set_handler_fn(divide_handler);
-        idt.debug.set_handler_fn(debug_handler);
-        idt.invalid_tss.set_handler_fn(tss_handler);
-        idt.machine_check.set_handler_fn(machine_check_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt.hv_injection_exception
-            .set_handler_fn(hv_injection_handler);
-        idt.device_not_available
-            .set_handler_fn(device_not_available_handler);
-        idt.vmm_communication_exception
-            .set_handler_fn(vmm_communication_exception_handler);
-        idt.virtualization.set_handler_fn(virtualization_handler);
-        idt.security_exception
-            .set_handler_fn(security_exception_handler);
-        idt.alignment_check.set_handler_fn(alignment_check_handler);
-        idt.x87_floating_point
-            .set_handler_fn(x87_floating_point_handler);
-        idt.segment_not_present
-            .set_handler_fn(segment_not_present_handler);
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault_handler);
-        idt.cp_protection_exception
-            .set_handler_fn(cp_protection_exception_handler);
-        idt.stack_segment_fault
-            .set_handler_fn(stack_segment_fault_handler);
-        idt.simd_floating_point
-            .set_handler_fn(simd_floating_point_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        set_dispatch_handlers(&mut idt);
+        set_exception_handlers(&mut idt);
         unsafe {
             idt[InterruptIndex::Timer.as_usize()].set_handler_addr(VirtAddr::new(timer as u64));
         }
@@ -64,8 +121,12 @@ lazy_static! {
         idt[InterruptIndex::PrimaryATA.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
         idt[InterruptIndex::SecondaryATA.as_usize()]
             .set_handler_fn(secondary_ata_interrupt_handler);
+        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
         unsafe {
-            idt[0x80].set_handler_addr(VirtAddr::new(syscall as u64));
+            idt[SYSCALL_VECTOR as usize]
+                .set_handler_addr(VirtAddr::new(syscall as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+            idt[YIELD_VECTOR as usize].set_handler_addr(VirtAddr::new(yield_entry as usize as u64));
         }
         idt
     };
@@ -74,12 +135,40 @@ lazy_static! {
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub const LAPIC_VADDR: u64 = comptime_alloc!(0xFFF);
-pub const IO_APIC_MMIO_VADDR: u64 = comptime_alloc!(0x1000);
 pub const LAPIC_SIZE: u64 = 0xFFF;
 pub const IO_APIC_MMIO_SIZE: u64 = 0x1000;
-pub static LAPICS: OnceCell<Mutex<LocalApic>> = OnceCell::uninit();
-pub static IOAPICS: OnceCell<Mutex<IoApic>> = OnceCell::uninit();
+pub const MAX_IO_APICS: usize = 8;
+pub static LAPIC_IDS: OnceCell<Vec<u32>> = OnceCell::uninit();
+pub static IOAPICS: OnceCell<Vec<Mutex<IoApicHandle>>> = OnceCell::uninit();
+/// Registers of the local apic, every processor sees its own at the same address
+static LAPIC_MAPPING: OnceCell<Mapping> = OnceCell::uninit();
+
+pub struct IoApicHandle {
+    id: u8,
+    gsi_base: u32,
+    entries: u32,
+    ioapic: IoApic,
+    /// Keeps the registers mapped
+    _mapping: Mapping,
+}
+
+impl IoApicHandle {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    pub fn ioapic(&mut self) -> &mut IoApic {
+        &mut self.ioapic
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -88,10 +177,12 @@ pub enum InterruptIndex {
     Keyboard,
     PrimaryATA = PIC_1_OFFSET + 14,
     SecondaryATA = PIC_1_OFFSET + 15,
+    LapicError = 0xFE,
+    Spurious = 0xFF,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -101,19 +192,86 @@ impl InterruptIndex {
 }
 
 pub fn init() {
-    let apic_physical_address: u64 = unsafe { xapic_base() };
-    memory_controller()
-        .lock()
-        .phy_map(LAPIC_SIZE, apic_physical_address, LAPIC_VADDR);
-    //memory_controller()
-    //    .lock()
-    //    .phy_map(IO_APIC_MMIO_SIZE, 0xFEC00000, IO_APIC_MMIO_VADDR);
-    LAPICS.init_once(|| {
+    let madt = acpi().madt().expect("MADT is required to setup the apic");
+    LAPIC_MAPPING.init_once(|| {
+        map_physical(LAPIC_SIZE, madt.local_apic_address()).expect("Failed to map the local apic")
+    });
+    init_local_apic();
+    LAPIC_IDS.init_once(|| {
+        madt.processors()
+            .iter()
+            .filter(|e| e.is_usable())
+            .map(|e| e.apic_id)
+            .collect()
+    });
+    log!(
+        Info,
+        "Found {} usable processors",
+        LAPIC_IDS.get().unwrap().len()
+    );
+
+    if madt.flags().contains(MadtFlags::PCAT_COMPAT) {
+        disable_pic();
+    }
+
+    IOAPICS.init_once(|| {
+        assert!(
+            madt.io_apics().len() <= MAX_IO_APICS,
+            "Too many io apics {}",
+            madt.io_apics().len()
+        );
+        madt.io_apics()
+            .iter()
+            .map(|info| {
+                let mapping = map_physical(IO_APIC_MMIO_SIZE, info.address as u64)
+                    .expect("Failed to map an io apic");
+                let mut ioapic = unsafe { IoApic::new(mapping.address()) };
+                let entries = unsafe { ioapic.max_table_entry() } as u32 + 1;
+                for irq in 0..entries {
+                    let mut entry = RedirectionTableEntry::default();
+                    entry.set_flags(IrqFlags::MASKED);
+                    unsafe { ioapic.set_table_entry(irq as u8, entry) };
+                }
+                log!(
+                    Info,
+                    "IO APIC {} handles gsi {}..{}",
+                    info.id,
+                    info.gsi_base,
+                    info.gsi_base + entries
+                );
+                Mutex::new(IoApicHandle {
+                    id: info.id,
+                    gsi_base: info.gsi_base,
+                    entries,
+                    ioapic,
+                    _mapping: mapping,
+                })
+            })
+            .collect()
+    });
+
+    route_legacy_irq(1, InterruptIndex::Keyboard.as_u8());
+    route_legacy_irq(14, InterruptIndex::PrimaryATA.as_u8());
+    route_legacy_irq(15, InterruptIndex::SecondaryATA.as_u8());
+    IDT.load();
+}
+
+/// Set up the local apic of an application processor, its timer stays off until something schedules on it
+pub fn init_ap() {
+    init_local_apic();
+    unsafe {
+        local_apic().lock().disable_timer();
+    }
+    IDT.load();
+}
+
+fn init_local_apic() {
+    percpu::current().lapic.init_once(|| {
         let mut lapic = LocalApicBuilder::new()
-            .timer_vector(32)
-            .error_vector(34)
-            .spurious_vector(33)
-            .set_xapic_base(LAPIC_VADDR)
+            .timer_vector(InterruptIndex::Timer.as_usize())
+            .error_vector(InterruptIndex::LapicError.as_usize())
+            .spurious_vector(InterruptIndex::Spurious.as_usize())
+            .set_xapic_base(LAPIC_MAPPING.get().unwrap().address())
             .build()
             .expect("Could not create lapic");
         unsafe {
@@ -121,166 +279,149 @@ pub fn init() {
         }
         Mutex::new(lapic)
     });
-    //IOAPICS.init_once(|| unsafe {
-    //    let mut ioapic = IoApic::new(IO_APIC_MMIO_VADDR);
-    //    let mut entry = RedirectionTableEntry::default();
-    //    entry.set_mode(IrqMode::Fixed);
-    //    entry.set_flags(IrqFlags::LEVEL_TRIGGERED);
-    //    entry.set_dest(LAPICS.get().unwrap().lock().id() as u8);
-    //    entry.set_vector(PIC_1_OFFSET + 14);
-    //    ioapic.set_table_entry(10, entry);
-    //    ioapic.enable_irq(10);
-    //    Mutex::new(ioapic)
-    //});
-    IDT.load();
 }
 
-extern "x86-interrupt" fn simd_floating_point_handler(_stack_frame: InterruptStackFrame) {}
-
-extern "x86-interrupt" fn x87_floating_point_handler(_stack_frame: InterruptStackFrame) {}
-
-extern "x86-interrupt" fn virtualization_handler(_stack_frame: InterruptStackFrame) {}
-
-extern "x86-interrupt" fn device_not_available_handler(_stack_frame: InterruptStackFrame) {}
-
-extern "x86-interrupt" fn hv_injection_handler(_stack_frame: InterruptStackFrame) {}
-
-extern "x86-interrupt" fn invalid_opcode_handler(_stack_frame: InterruptStackFrame) {}
-
-extern "x86-interrupt" fn machine_check_handler(_stack_frame: InterruptStackFrame) -> ! {
-    hlt_loop();
-}
-
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
+/// Local apic of the executing processor
+pub fn local_apic() -> &'static Mutex<LocalApic> {
+    percpu::current().lapic()
 }
 
-extern "x86-interrupt" fn cp_protection_exception_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
+/// Mask every line of the legacy 8259 pics, the io apic takes over their job
+fn disable_pic() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xFF);
+        Port::<u8>::new(0xA1).write(0xFF);
+    }
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!(
-        "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}, ERROR_CODE: {}",
-        stack_frame, error_code
+/// Route a legacy isa irq to `vector` on the bootstrap processor applying any interrupt source override
+pub fn route_legacy_irq(irq: u8, vector: u8) {
+    let (gsi, polarity, trigger_mode) = match acpi().madt().and_then(|e| e.find_override(irq)) {
+        Some(source_override) => (
+            source_override.gsi,
+            source_override.flags.polarity(),
+            source_override.flags.trigger_mode(),
+        ),
+        // Isa interrupts are edge triggered and active high unless overridden
+        None => (irq as u32, Polarity::ActiveHigh, TriggerMode::Edge),
+    };
+    let polarity = inline_if!(
+        polarity == Polarity::ConformsToBus,
+        Polarity::ActiveHigh,
+        polarity
     );
+    let trigger_mode = inline_if!(
+        trigger_mode == TriggerMode::ConformsToBus,
+        TriggerMode::Edge,
+        trigger_mode
+    );
+    route_gsi(gsi, vector, polarity, trigger_mode);
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-}
-
-extern "x86-interrupt" fn alignment_check_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
+/// Id of the local apic of the executing processor
+pub fn local_apic_id() -> u32 {
+    percpu::current().apic_id()
 }
 
-extern "x86-interrupt" fn security_exception_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
+/// Whether any io apic has a redirection entry for `gsi`
+pub fn handles_gsi(gsi: u32) -> bool {
+    IOAPICS
+        .get()
+        .is_some_and(|e| e.iter().any(|ioapic| ioapic.lock().handles(gsi)))
 }
 
-extern "x86-interrupt" fn vmm_communication_exception_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
+/// Route a global system interrupt to `vector` on the bootstrap processor
+pub fn route_gsi(gsi: u32, vector: u8, polarity: Polarity, trigger_mode: TriggerMode) {
+    let ioapic = match IOAPICS
+        .get()
+        .and_then(|e| e.iter().find(|ioapic| ioapic.lock().handles(gsi)))
+    {
+        Some(ioapic) => ioapic,
+        None => {
+            log!(Warning, "No io apic handles gsi {}", gsi);
+            return;
+        }
+    };
+    let mut flags = IrqFlags::empty();
+    if polarity == Polarity::ActiveLow {
+        flags.insert(IrqFlags::LOW_ACTIVE);
+    }
+    if trigger_mode == TriggerMode::Level {
+        flags.insert(IrqFlags::LEVEL_TRIGGERED);
+    }
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(IrqMode::Fixed);
+    entry.set_flags(flags);
+    entry.set_dest(percpu::bsp().apic_id() as u8);
+    entry.set_vector(vector);
+
+    let mut ioapic = ioapic.lock();
+    let irq = (gsi - ioapic.gsi_base) as u8;
+    unsafe {
+        ioapic.ioapic.set_table_entry(irq, entry);
+        ioapic.ioapic.enable_irq(irq);
+    }
 }
 
-extern "x86-interrupt" fn tss_handler(_stack_frame: InterruptStackFrame, _error_code: u64) {}
-
-extern "x86-interrupt" fn debug_handler(_stack_frame: InterruptStackFrame) {}
-
-extern "x86-interrupt" fn divide_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: DIVISION\n{:#?}", stack_frame);
+/// Register state saved by the interrupt entry stubs, changes to it are applied on return
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct FullInterruptStackFrame {
+    pub cr3: u64,
+    /// Faulting address of the last page fault
+    pub cr2: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for vectors that don't push one
+    pub error_code: u64,
+    pub instruction_pointer: VirtAddr,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: VirtAddr,
+    pub stack_segment: u64,
 }
 
-extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-}
+interrupt_entry!(timer, InterruptIndex::Timer as u8, inner_timer, switch);
 
-extern "x86-interrupt" fn breakpoint_handle(_stack_frame: InterruptStackFrame) {
-    println!("BreakPoint");
+extern "C" fn inner_timer(
+    stack_frame: *mut FullInterruptStackFrame,
+) -> *mut FullInterruptStackFrame {
+    defer!(unsafe {
+        local_apic().lock().end_of_interrupt();
+    });
+    crate::time::timer::handle_tick();
+    scheduler::preempt(stack_frame)
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
+interrupt_entry!(yield_entry, YIELD_VECTOR, inner_yield, switch);
 
-#[naked]
-#[no_mangle]
-fn timer() {
-    unsafe {
-        asm!(
-            r#"
-        push r11
-        push r10
-        push r9
-        push r8
-        push rdi
-        push rsi
-        push rdx
-        push rcx
-        push rax
-        
-        mov rdi, rsp
-        call inner_timer
-        pop rax
-        pop rcx
-        pop rdx
-        pop rsi
-        pop rdi
-        pop r8
-        pop r9
-        pop r10
-        pop r11
-        iretq
-        "#,
-            options(noreturn)
-        );
-    }
+extern "C" fn inner_yield(
+    stack_frame: *mut FullInterruptStackFrame,
+) -> *mut FullInterruptStackFrame {
+    scheduler::reschedule(stack_frame)
 }
 
-#[no_mangle]
-extern "C" fn inner_timer(_stack_frame: &mut FullInterruptStackFrame) {
-    defer!(unsafe {
-        LAPICS.get().unwrap().lock().end_of_interrupt();
-    });
-    //let mut process = match SCHEDULER.get() {
-    //    Some(scheduler) => scheduler.lock(),
-    //    None => {
-    //        return;
-    //    }
-    //};
-    //match process.schedule_next() {
-    //    Some(_process) => {
-    //        //println!("Running: {}", process.get_name());
-    //    }
-    //    None => {
-    //        //println!("No process ran");
-    //    }
-    //}
-}
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     //let mut port = Port::new(0x60);
     //let scancode: u8 = unsafe { port.read() };
 
     /*crate::driver::keyboard::keyboard_scancode(scancode);*/
     unsafe {
-        LAPICS.get().unwrap().lock().end_of_interrupt();
+        local_apic().lock().end_of_interrupt();
     }
     /*unsafe {
         PICS.lock()
@@ -288,69 +429,15 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }*/
 }
 
-#[naked]
-#[no_mangle]
-fn syscall() {
-    unsafe {
-        asm!(
-            r#"
-        push r11
-        push r10
-        push r9
-        push r8
-        push rdi
-        push rsi
-        push rdx
-        push rcx
-        push rax
-        
-        mov rdi, rsp
-        call inner_syscall
-        pop rax
-        pop rcx
-        pop rdx
-        pop rsi
-        pop rdi
-        pop r8
-        pop r9
-        pop r10
-        pop r11
-        iretq
-        "#,
-            options(noreturn)
-        );
-    }
-}
-
-#[derive(Debug)]
-#[repr(C)]
-struct FullInterruptStackFrame {
-    pub rax: u64,
-    pub rcx: u64,
-    pub rdx: u64,
-    pub rsi: u64,
-    pub rdi: u64,
-    pub r8: u64,
-    pub r9: u64,
-    pub r10: u64,
-    pub r11: u64,
-    pub instruction_pointer: VirtAddr,
-    pub code_segment: u64,
-    pub cpu_flags: u64,
-    pub stack_pointer: VirtAddr,
-    pub stack_segment: u64,
-}
-#[no_mangle]
-extern "C" fn inner_syscall(stack_frame: &mut FullInterruptStackFrame) {
-    if stack_frame.rax == 1 {
-        let data = unsafe { CString::from_raw(stack_frame.rcx as *mut i8) };
-        print!("{}", data.to_str().unwrap());
-    }
-}
+interrupt_entry!(
+    syscall,
+    SYSCALL_VECTOR,
+    crate::userland::syscall::interrupt_handler
+);
 
 extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
     unsafe {
-        LAPICS.get().unwrap().lock().end_of_interrupt();
+        local_apic().lock().end_of_interrupt();
     }
     /*unsafe {
         PICS.lock()
@@ -362,7 +449,7 @@ extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: Interrup
     //println!("aa");
 
     unsafe {
-        LAPICS.get().unwrap().lock().end_of_interrupt();
+        local_apic().lock().end_of_interrupt();
     }
 
     /*unsafe {
@@ -370,16 +457,3 @@ extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: Interrup
             .notify_end_of_interrupt(InterruptIndex::SecondaryATA.as_u8());
     }*/
 }
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    panic!("PAGE FAULT");
-}
@@ -0,0 +1,355 @@
+use core::fmt::{Arguments, Display, Write};
+
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode};
+use x86_64::VirtAddr;
+
+use crate::backtrace;
+use crate::inline_if;
+use crate::log;
+use crate::logger::LOGGER;
+use crate::memory::address_space;
+use crate::memory::stack_allocator::guard_owner;
+use crate::serial::report_port;
+use crate::smp::percpu;
+use crate::userland::usermode;
+
+use super::handler::{InterruptError, IrqReturn};
+use super::FullInterruptStackFrame;
+
+/// Called with the saved register state, returning [`IrqReturn::Handled`] resumes at the (possibly changed) frame
+pub type ExceptionHook = fn(&mut FullInterruptStackFrame) -> IrqReturn;
+
+static HOOKS: [RwLock<Option<ExceptionHook>>; 32] = [const { RwLock::new(None) }; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    CpProtection = 21,
+    HvInjection = 28,
+    VmmCommunication = 29,
+    Security = 30,
+}
+
+impl Exception {
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        Some(match vector {
+            0 => Self::DivideError,
+            1 => Self::Debug,
+            2 => Self::NonMaskableInterrupt,
+            3 => Self::Breakpoint,
+            4 => Self::Overflow,
+            5 => Self::BoundRangeExceeded,
+            6 => Self::InvalidOpcode,
+            7 => Self::DeviceNotAvailable,
+            8 => Self::DoubleFault,
+            10 => Self::InvalidTss,
+            11 => Self::SegmentNotPresent,
+            12 => Self::StackSegmentFault,
+            13 => Self::GeneralProtectionFault,
+            14 => Self::PageFault,
+            16 => Self::X87FloatingPoint,
+            17 => Self::AlignmentCheck,
+            18 => Self::MachineCheck,
+            19 => Self::SimdFloatingPoint,
+            20 => Self::Virtualization,
+            21 => Self::CpProtection,
+            28 => Self::HvInjection,
+            29 => Self::VmmCommunication,
+            30 => Self::Security,
+            _ => return None,
+        })
+    }
+
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DivideError => "divide error",
+            Self::Debug => "debug",
+            Self::NonMaskableInterrupt => "non maskable interrupt",
+            Self::Breakpoint => "breakpoint",
+            Self::Overflow => "overflow",
+            Self::BoundRangeExceeded => "bound range exceeded",
+            Self::InvalidOpcode => "invalid opcode",
+            Self::DeviceNotAvailable => "device not available",
+            Self::DoubleFault => "double fault",
+            Self::InvalidTss => "invalid tss",
+            Self::SegmentNotPresent => "segment not present",
+            Self::StackSegmentFault => "stack segment fault",
+            Self::GeneralProtectionFault => "general protection fault",
+            Self::PageFault => "page fault",
+            Self::X87FloatingPoint => "x87 floating point",
+            Self::AlignmentCheck => "alignment check",
+            Self::MachineCheck => "machine check",
+            Self::SimdFloatingPoint => "simd floating point",
+            Self::Virtualization => "virtualization",
+            Self::CpProtection => "control protection",
+            Self::HvInjection => "hypervisor injection",
+            Self::VmmCommunication => "vmm communication",
+            Self::Security => "security",
+        }
+    }
+
+    pub fn has_error_code(self) -> bool {
+        matches!(
+            self,
+            Self::DoubleFault
+                | Self::InvalidTss
+                | Self::SegmentNotPresent
+                | Self::StackSegmentFault
+                | Self::GeneralProtectionFault
+                | Self::PageFault
+                | Self::AlignmentCheck
+                | Self::CpProtection
+                | Self::VmmCommunication
+                | Self::Security
+        )
+    }
+}
+
+/// Human readable error code of an exception
+pub struct ErrorCode(pub Exception, pub u64);
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let Self(exception, code) = *self;
+        if !exception.has_error_code() {
+            return write!(f, "none");
+        }
+        match exception {
+            Exception::PageFault => {
+                write!(f, "{:?}", PageFaultErrorCode::from_bits_truncate(code))
+            }
+            Exception::InvalidTss
+            | Exception::SegmentNotPresent
+            | Exception::StackSegmentFault
+            | Exception::GeneralProtectionFault
+                if code != 0 =>
+            {
+                let selector = SelectorErrorCode::new_truncate(code);
+                write!(
+                    f,
+                    "{:?} selector {:#x}{}",
+                    selector.descriptor_table(),
+                    selector.index(),
+                    inline_if!(selector.external(), " (external)", "")
+                )
+            }
+            Exception::CpProtection => match code & 0x7FFF {
+                1 => write!(f, "near ret"),
+                2 => write!(f, "far ret or iret"),
+                3 => write!(f, "missing endbranch"),
+                4 => write!(f, "rstorssp"),
+                5 => write!(f, "setssbsy"),
+                other => write!(f, "{:#x}", other),
+            },
+            _ => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// Let `hook` try to recover from `exception` before it escalates to an oops
+pub fn set_exception_hook(exception: Exception, hook: ExceptionHook) -> Result<(), InterruptError> {
+    interrupts::without_interrupts(|| {
+        let mut entry = HOOKS[exception.vector() as usize].write();
+        if entry.is_some() {
+            return Err(InterruptError::VectorBusy(exception.vector()));
+        }
+        *entry = Some(hook);
+        Ok(())
+    })
+}
+
+pub fn clear_exception_hook(exception: Exception) {
+    interrupts::without_interrupts(|| *HOOKS[exception.vector() as usize].write() = None);
+}
+
+/// Point every exception to its entry stub
+pub(super) fn set_exception_handlers(idt: &mut InterruptDescriptorTable) {
+    macro_rules! set_entries {
+        ($($entry:ident => $stub:ident),* $(,)?) => {
+            $(unsafe { idt.$entry.set_handler_addr(VirtAddr::new($stub as usize as u64)); })*
+        };
+    }
+    set_entries!(
+        divide_error => divide_error_entry,
+        debug => debug_entry,
+        non_maskable_interrupt => non_maskable_interrupt_entry,
+        breakpoint => breakpoint_entry,
+        overflow => overflow_entry,
+        bound_range_exceeded => bound_range_exceeded_entry,
+        invalid_opcode => invalid_opcode_entry,
+        device_not_available => device_not_available_entry,
+        invalid_tss => invalid_tss_entry,
+        segment_not_present => segment_not_present_entry,
+        stack_segment_fault => stack_segment_fault_entry,
+        general_protection_fault => general_protection_fault_entry,
+        page_fault => page_fault_entry,
+        x87_floating_point => x87_floating_point_entry,
+        alignment_check => alignment_check_entry,
+        machine_check => machine_check_entry,
+        simd_floating_point => simd_floating_point_entry,
+        virtualization => virtualization_entry,
+        cp_protection_exception => cp_protection_entry,
+        hv_injection_exception => hv_injection_entry,
+        vmm_communication_exception => vmm_communication_entry,
+        security_exception => security_entry,
+    );
+    unsafe {
+        idt.double_fault
+            .set_handler_addr(VirtAddr::new(double_fault_entry as usize as u64))
+            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+}
+
+interrupt_entry!(divide_error_entry, 0, exception_handler);
+interrupt_entry!(debug_entry, 1, exception_handler);
+interrupt_entry!(non_maskable_interrupt_entry, 2, exception_handler);
+interrupt_entry!(breakpoint_entry, 3, exception_handler);
+interrupt_entry!(overflow_entry, 4, exception_handler);
+interrupt_entry!(bound_range_exceeded_entry, 5, exception_handler);
+interrupt_entry!(invalid_opcode_entry, 6, exception_handler);
+interrupt_entry!(device_not_available_entry, 7, exception_handler);
+interrupt_entry!(double_fault_entry, 8, exception_handler, error_code);
+interrupt_entry!(invalid_tss_entry, 10, exception_handler, error_code);
+interrupt_entry!(segment_not_present_entry, 11, exception_handler, error_code);
+interrupt_entry!(stack_segment_fault_entry, 12, exception_handler, error_code);
+interrupt_entry!(
+    general_protection_fault_entry,
+    13,
+    exception_handler,
+    error_code
+);
+interrupt_entry!(page_fault_entry, 14, exception_handler, error_code);
+interrupt_entry!(x87_floating_point_entry, 16, exception_handler);
+interrupt_entry!(alignment_check_entry, 17, exception_handler, error_code);
+interrupt_entry!(machine_check_entry, 18, exception_handler);
+interrupt_entry!(simd_floating_point_entry, 19, exception_handler);
+interrupt_entry!(virtualization_entry, 20, exception_handler);
+interrupt_entry!(cp_protection_entry, 21, exception_handler, error_code);
+interrupt_entry!(hv_injection_entry, 28, exception_handler);
+interrupt_entry!(vmm_communication_entry, 29, exception_handler, error_code);
+interrupt_entry!(security_entry, 30, exception_handler, error_code);
+
+extern "C" fn exception_handler(frame: &mut FullInterruptStackFrame) {
+    let exception = Exception::from_vector(frame.vector as u8).unwrap();
+    // Pushing the page fault frame on an overflowed stack faults again, so this mostly shows up as a double fault
+    if matches!(exception, Exception::PageFault | Exception::DoubleFault) {
+        if let Some(name) = guard_owner(frame.cr2) {
+            oops(format_args!("stack overflow in {}", name), exception, frame);
+        }
+    }
+    if exception == Exception::PageFault {
+        // Resolving it may block on the memory controller, which a preempted thread can hold
+        if RFlags::from_bits_truncate(frame.cpu_flags).contains(RFlags::INTERRUPT_FLAG) {
+            interrupts::enable();
+        }
+        let error = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        if address_space::handle_page_fault(frame.cr2, error) {
+            return;
+        }
+    }
+    let hook = *HOOKS[exception.vector() as usize].read();
+    if hook.is_some_and(|hook| hook(frame) == IrqReturn::Handled) {
+        return;
+    }
+    if usermode::is_user_frame(frame) {
+        log!(
+            Error,
+            "{} in user mode at {:#x}, error code: {}",
+            exception.name(),
+            frame.instruction_pointer,
+            ErrorCode(exception, frame.error_code)
+        );
+        usermode::exit_to_kernel(frame, usermode::FAULT_STATUS);
+        return;
+    }
+    match exception {
+        // Traps, the instruction already completed
+        Exception::Breakpoint => {
+            log!(Debug, "Breakpoint at {:#x}", frame.instruction_pointer);
+        }
+        Exception::Debug => {
+            log!(Debug, "Debug exception at {:#x}", frame.instruction_pointer);
+            // Stop single stepping and don't hit an instruction breakpoint again
+            let flags = RFlags::from_bits_truncate(frame.cpu_flags) - RFlags::TRAP_FLAG;
+            frame.cpu_flags = (flags | RFlags::RESUME_FLAG).bits();
+        }
+        _ => oops(
+            format_args!("{} ({:#x})", exception.name(), exception.vector()),
+            exception,
+            frame,
+        ),
+    }
+}
+
+/// Write everything known about an unrecoverable exception to the serial port and panic
+///
+/// The report doesn't touch the heap, logs still buffered go out first to keep the order.
+fn oops(reason: Arguments, exception: Exception, frame: &FullInterruptStackFrame) -> ! {
+    LOGGER.flush_all();
+    let _ = writeln!(
+        report_port(),
+        "Kernel oops: {} on cpu {}\n\
+         error code: {}\n\
+         rip: {:#018x} cs: {:#x} rflags: {:#x}\n\
+         rsp: {:#018x} ss: {:#x}\n\
+         rax: {:#018x} rbx: {:#018x} rcx: {:#018x}\n\
+         rdx: {:#018x} rsi: {:#018x} rdi: {:#018x}\n\
+         rbp: {:#018x} r8:  {:#018x} r9:  {:#018x}\n\
+         r10: {:#018x} r11: {:#018x} r12: {:#018x}\n\
+         r13: {:#018x} r14: {:#018x} r15: {:#018x}\n\
+         cr2: {:#018x} cr3: {:#018x}",
+        reason,
+        percpu::current().index(),
+        ErrorCode(exception, frame.error_code),
+        frame.instruction_pointer,
+        frame.code_segment,
+        frame.cpu_flags,
+        frame.stack_pointer,
+        frame.stack_segment,
+        frame.rax,
+        frame.rbx,
+        frame.rcx,
+        frame.rdx,
+        frame.rsi,
+        frame.rdi,
+        frame.rbp,
+        frame.r8,
+        frame.r9,
+        frame.r10,
+        frame.r11,
+        frame.r12,
+        frame.r13,
+        frame.r14,
+        frame.r15,
+        frame.cr2,
+        frame.cr3,
+    );
+    backtrace::log_fault_backtrace(frame.instruction_pointer.as_u64(), frame.rbp);
+    panic!("Kernel oops: {}", reason);
+}
@@ -0,0 +1,295 @@
+use core::error::Error;
+use core::fmt::Display;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::{inline_if, log};
+
+use super::local_apic;
+
+/// First vector handed out by the allocator, the ones below are exceptions and legacy isa irqs
+pub const DYNAMIC_VECTOR_START: u8 = 0x30;
+/// Last vector handed out by the allocator, the ones above are reserved for the local apic
+pub const DYNAMIC_VECTOR_END: u8 = 0xEF;
+pub const SYSCALL_VECTOR: u8 = 0x80;
+/// Raised by a thread giving up the processor
+pub const YIELD_VECTOR: u8 = 0x81;
+
+static VECTORS: [RwLock<VectorEntry>; 256] = [const { RwLock::new(VectorEntry::new()) }; 256];
+static UNHANDLED: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum InterruptError {
+    NoFreeVector,
+    ReservedVector(u8),
+    VectorBusy(u8),
+    EoiMismatch(u8),
+    HandlerNotFound(u8),
+}
+
+impl Display for InterruptError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoFreeVector => write!(f, "No free interrupt vector left"),
+            Self::ReservedVector(vector) => {
+                write!(f, "Vector {:#x} is reserved by the kernel", vector)
+            }
+            Self::VectorBusy(vector) => {
+                write!(
+                    f,
+                    "Vector {:#x} is already used by a non shared handler",
+                    vector
+                )
+            }
+            Self::EoiMismatch(vector) => write!(
+                f,
+                "Handlers on vector {:#x} must use the same eoi policy",
+                vector
+            ),
+            Self::HandlerNotFound(vector) => {
+                write!(f, "Cannot find the handler on vector {:#x}", vector)
+            }
+        }
+    }
+}
+
+impl Error for InterruptError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,
+}
+
+/// Who signals the end of interrupt to the local apic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EoiPolicy {
+    /// The dispatcher sends it after every handler on the vector ran
+    Auto,
+    /// The handler sends it itself with [`end_of_interrupt`]
+    Manual,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct HandlerFlags: u8 {
+        const SHARED = 1 << 0; // Other handlers can be chained on the same vector
+    }
+}
+
+pub trait InterruptHandler: Send + Sync {
+    fn handle(&self, vector: u8) -> IrqReturn;
+}
+
+impl<F: Fn(u8) -> IrqReturn + Send + Sync> InterruptHandler for F {
+    fn handle(&self, vector: u8) -> IrqReturn {
+        self(vector)
+    }
+}
+
+#[derive(Clone)]
+struct Handler {
+    name: &'static str,
+    handler: Arc<dyn InterruptHandler>,
+}
+
+struct VectorEntry {
+    allocated: bool,
+    shared: bool,
+    eoi: EoiPolicy,
+    /// Replaced as a whole, the dispatcher runs its own reference without holding the lock
+    handlers: Option<Arc<[Handler]>>,
+}
+
+impl VectorEntry {
+    const fn new() -> Self {
+        Self {
+            allocated: false,
+            shared: false,
+            eoi: EoiPolicy::Auto,
+            handlers: None,
+        }
+    }
+
+    fn handlers(&self) -> &[Handler] {
+        self.handlers.as_deref().unwrap_or(&[])
+    }
+}
+
+fn is_dynamic(vector: u8) -> bool {
+    (DYNAMIC_VECTOR_START..=DYNAMIC_VECTOR_END).contains(&vector)
+        && vector != SYSCALL_VECTOR
+        && vector != YIELD_VECTOR
+}
+
+/// Reserve a free vector
+pub fn allocate_vector() -> Result<u8, InterruptError> {
+    interrupts::without_interrupts(|| {
+        (DYNAMIC_VECTOR_START..=DYNAMIC_VECTOR_END)
+            .filter(|vector| is_dynamic(*vector))
+            .find(|vector| {
+                let mut entry = VECTORS[*vector as usize].write();
+                !core::mem::replace(&mut entry.allocated, true)
+            })
+            .ok_or(InterruptError::NoFreeVector)
+    })
+}
+
+/// Release a vector and drop every handler attached to it
+pub fn free_vector(vector: u8) -> Result<(), InterruptError> {
+    if !is_dynamic(vector) {
+        return Err(InterruptError::ReservedVector(vector));
+    }
+    let handlers = interrupts::without_interrupts(|| {
+        core::mem::replace(&mut *VECTORS[vector as usize].write(), VectorEntry::new()).handlers
+    });
+    drop(handlers);
+    Ok(())
+}
+
+/// Attach a handler to `vector`, the vector is reserved if it wasn't already
+pub fn register_handler(
+    vector: u8,
+    name: &'static str,
+    eoi: EoiPolicy,
+    flags: HandlerFlags,
+    handler: impl InterruptHandler + 'static,
+) -> Result<(), InterruptError> {
+    if !is_dynamic(vector) {
+        return Err(InterruptError::ReservedVector(vector));
+    }
+    let handler = Handler {
+        name,
+        handler: Arc::new(handler),
+    };
+    interrupts::without_interrupts(|| {
+        let mut entry = VECTORS[vector as usize].write();
+        if !entry.handlers().is_empty() {
+            if !(entry.shared && flags.contains(HandlerFlags::SHARED)) {
+                return Err(InterruptError::VectorBusy(vector));
+            }
+            if entry.eoi != eoi {
+                return Err(InterruptError::EoiMismatch(vector));
+            }
+        }
+        entry.allocated = true;
+        entry.shared = flags.contains(HandlerFlags::SHARED);
+        entry.eoi = eoi;
+        let mut handlers = entry.handlers().to_vec();
+        handlers.push(handler);
+        entry.handlers = Some(handlers.into());
+        Ok(())
+    })
+}
+
+/// Allocate a free vector and attach a handler to it
+pub fn request_interrupt(
+    name: &'static str,
+    eoi: EoiPolicy,
+    flags: HandlerFlags,
+    handler: impl InterruptHandler + 'static,
+) -> Result<u8, InterruptError> {
+    let vector = allocate_vector()?;
+    if let Err(err) = register_handler(vector, name, eoi, flags, handler) {
+        free_vector(vector)?;
+        return Err(err);
+    }
+    Ok(vector)
+}
+
+/// Detach the handler called `name`, the vector stays reserved
+pub fn unregister_handler(vector: u8, name: &'static str) -> Result<(), InterruptError> {
+    let handlers = interrupts::without_interrupts(|| {
+        let mut entry = VECTORS[vector as usize].write();
+        let index = entry
+            .handlers()
+            .iter()
+            .position(|e| e.name == name)
+            .ok_or(InterruptError::HandlerNotFound(vector))?;
+        let mut handlers = entry.handlers().to_vec();
+        handlers.remove(index);
+        let handlers = inline_if!(handlers.is_empty(), None, Some(handlers.into()));
+        Ok(core::mem::replace(&mut entry.handlers, handlers))
+    })?;
+    drop(handlers);
+    Ok(())
+}
+
+/// Number of times `vector` fired without any handler claiming it
+pub fn unhandled_count(vector: u8) -> u64 {
+    UNHANDLED[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Number of spurious interrupts raised by the local apic
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+pub fn end_of_interrupt() {
+    unsafe {
+        local_apic().lock().end_of_interrupt();
+    }
+}
+
+/// Point every vector from 0x30 up to the dispatcher, fixed handlers are set on top of it
+pub(super) fn set_dispatch_handlers(idt: &mut InterruptDescriptorTable) {
+    macro_rules! dispatch_rows {
+        ($($high:literal),*) => {
+            $(dispatch_rows!(@row $high; 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);)*
+        };
+        (@row $high:literal; $($low:literal),*) => {
+            $(idt[$high * 16 + $low].set_handler_fn(dispatch::<{ $high * 16 + $low }>);)*
+        };
+    }
+    dispatch_rows!(3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+}
+
+extern "x86-interrupt" fn dispatch<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch_vector(VECTOR);
+}
+
+fn dispatch_vector(vector: u8) {
+    // Handlers run without the lock, they may register and unregister handlers themselves
+    let (snapshot, eoi) = {
+        let entry = VECTORS[vector as usize].read();
+        (entry.handlers.clone(), entry.eoi)
+    };
+    let handlers = snapshot.as_deref().unwrap_or(&[]);
+    // Every handler runs since several devices can assert a shared line at once
+    let handled = handlers.iter().fold(false, |handled, e| {
+        e.handler.handle(vector) == IrqReturn::Handled || handled
+    });
+    if !handled {
+        report_unhandled(vector, !handlers.is_empty());
+    }
+    if inline_if!(handlers.is_empty(), EoiPolicy::Auto, eoi) == EoiPolicy::Auto {
+        end_of_interrupt();
+    }
+}
+
+fn report_unhandled(vector: u8, registered: bool) {
+    let count = UNHANDLED[vector as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    // Only log on powers of two so an interrupt storm doesn't flood the log
+    if count.is_power_of_two() {
+        log!(
+            Warning,
+            "{} interrupt on vector {:#x}, seen {} times",
+            inline_if!(registered, "Unhandled", "Unregistered"),
+            vector,
+            count
+        );
+    }
+}
+
+pub(super) extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // The local apic doesn't expect an end of interrupt for spurious interrupts
+    let count = SPURIOUS.fetch_add(1, Ordering::Relaxed) + 1;
+    if count.is_power_of_two() {
+        log!(Warning, "Spurious interrupt, seen {} times", count);
+    }
+}
@@ -22,6 +22,8 @@ extern crate core;
 extern crate lazy_static;
 extern crate spin;
 
+pub mod acpi;
+pub mod backtrace;
 pub mod driver;
 pub mod filesystem;
 pub mod gdt;
@@ -31,7 +33,9 @@ pub mod logger;
 pub mod memory;
 pub mod print;
 pub mod serial;
+pub mod smp;
 pub mod task;
+pub mod time;
 pub mod userland;
 pub mod utils;
 
@@ -50,10 +54,17 @@ pub fn init(information_address: *const BootInformation) {
     });
     graphics::init(boot_info);
     print::init(boot_info, Color::new(209, 213, 219), BACKGROUND_COLOR);
+    backtrace::init(boot_info);
+    acpi::init(boot_info);
+    smp::percpu::init(0);
     gdt::init_gdt();
+    userland::syscall::init();
     interrupt::init();
+    time::init();
+    smp::init();
     driver::init();
     userland::init();
+    memory::reclaim_boot_memory();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -78,7 +89,11 @@ pub trait Testable {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     log!(Critical, "{}", info);
+    // The backtrace goes straight to the serial port, after the message
     LOGGER.flush_all();
+    if !backtrace::fault_logged() {
+        backtrace::log_backtrace(backtrace::Backtrace::capture());
+    }
     test_panic_handler(info);
     hlt_loop();
 }
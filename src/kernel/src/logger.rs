@@ -9,6 +9,7 @@ use core::{
 use alloc::{format, string::String};
 use spin::RwLock;
 
+use crate::task::waker::InterruptWaker;
 use crate::utils::circular_ring_buffer::CircularRingBuffer;
 
 pub static LOGGER: Logger = Logger::new();
@@ -47,6 +48,7 @@ pub struct Logger {
     main_buffer: CircularRingBuffer<Log, LOG_BUFFER_SIZE>,
     subscribers: RwLock<[Option<LoggerSubscriber>; LOG_SUBSCRIBERS_SIZE]>,
     subscribers_index: AtomicUsize,
+    waker: InterruptWaker,
 }
 
 impl Log {
@@ -75,19 +77,24 @@ impl Display for LogLevel {
 }
 
 struct LoggerAsync<'a> {
-    buffer: &'a CircularRingBuffer<Log, LOG_BUFFER_SIZE>,
+    logger: &'a Logger,
 }
 
 impl<'a> LoggerAsync<'a> {
-    fn new(buffer: &'a CircularRingBuffer<Log, LOG_BUFFER_SIZE>) -> Self {
-        Self { buffer }
+    fn new(logger: &'a Logger) -> Self {
+        Self { logger }
     }
 }
 
 impl<'a> Future for LoggerAsync<'a> {
     type Output = Log;
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.buffer.read() {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(log) = self.logger.main_buffer.read() {
+            return Poll::Ready(log);
+        }
+        self.logger.waker.register(cx.waker());
+        // A log may have been written before the waker got registered
+        match self.logger.main_buffer.read() {
             Some(log) => Poll::Ready(log),
             None => Poll::Pending,
         }
@@ -100,11 +107,13 @@ impl Logger {
             main_buffer: CircularRingBuffer::new(),
             subscribers: RwLock::new([const { None }; LOG_SUBSCRIBERS_SIZE]),
             subscribers_index: AtomicUsize::new(0),
+            waker: InterruptWaker::new(),
         }
     }
 
     pub fn write(&self, log: Log) {
         self.main_buffer.write(log);
+        self.waker.wake();
     }
 
     pub fn add_target(&self, display: fn(&str)) {
@@ -126,7 +135,7 @@ impl Logger {
 
     pub async fn log_async(&self) {
         loop {
-            let msg = LoggerAsync::new(&self.main_buffer).await;
+            let msg = LoggerAsync::new(self).await;
             self.log_msg(msg);
         }
     }
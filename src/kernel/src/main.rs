@@ -19,8 +19,6 @@ use nothingos::task::executor::Executor;
 use nothingos::task::{AwaitType, Task};
 use nothingos::{log, println};
 
-// TODO: Implements acpi to get io apic
-// TODO: Use ahci interrupt (needs io apic) with waker
 // TODO: Implements waker based async mutex
 // TODO: Impelemnts kernel services executor
 
@@ -57,13 +55,13 @@ pub extern "C" fn start(information_address: *const BootInformation) -> ! {
             let partition1 = gpt.read_partition(1).await.expect("Error");
             log!(Debug, "{}", partition1.get_partition_name());
         },
-        AwaitType::Poll,
+        AwaitType::Waker,
     ));
     executor.spawn(Task::new(
         async {
             LOGGER.log_async().await;
         },
-        AwaitType::Poll,
+        AwaitType::Waker,
     ));
 
     #[cfg(test)]
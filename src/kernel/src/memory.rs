@@ -1,45 +1,88 @@
-use allocator::buddy_allocator::BuddyAllocator;
+use address_space::{AddressSpace, AddressSpaceError};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use allocator::buddy_allocator::{BuddyAllocator, LockedBuddy};
+use allocator::HEAP_MAX_SIZE;
 use common::boot::BootInformation;
 use conquer_once::spin::OnceCell;
-use paging::{ActivePageTable, EntryFlags, Page};
-use proc::comptime_alloc;
+use core::ops::Range;
+use paging::{ActivePageTable, EntryFlags, InactivePageTable, Page, PageSize};
+use physical_map::{Owner, PhysicalMap};
+use region::{page_range, populate, protect_pages, unmap_areas, Area, Backing, Regions};
 use spin::Mutex;
 use stack_allocator::{Stack, StackAllocator};
+use uefi::table::boot::MemoryType;
+use virtual_alloc::{Mapping, VirtualAllocator};
+use x86_64::instructions::interrupts;
 use x86_64::{
     registers::control::{Cr0Flags, EferFlags},
     PhysAddr, VirtAddr,
 };
 
 use crate::log;
+use crate::smp::tlb::shootdown;
 
 pub use self::paging::remap_the_kernel;
 
+pub mod address_space;
 pub mod allocator;
+pub mod dma;
 pub mod paging;
+pub mod physical_map;
+pub mod region;
 pub mod stack_allocator;
+pub mod virtual_alloc;
 
 pub const PAGE_SIZE: u64 = 4096;
 pub const MAX_ALIGN: usize = 8192;
 
+/// P4 entry of the kernel's own mappings, created at boot so every address space shares it
+const KERNEL_WINDOW_P4_INDEX: usize = 510;
+/// Start of that entry, the heap comes first as its start must be aligned to the biggest heap block
+pub const KERNEL_WINDOW: u64 = 0xFFFF_0000_0000_0000 | (KERNEL_WINDOW_P4_INDEX as u64) << 39;
+/// Window of kernel addresses [`MemoryController::map_region`] hands out
+const KERNEL_REGIONS_START: u64 = KERNEL_WINDOW + HEAP_MAX_SIZE;
+const KERNEL_REGIONS_SIZE: u64 = 0x10000000;
+const STACKS_START: u64 = KERNEL_REGIONS_START + KERNEL_REGIONS_SIZE;
+const STACKS_SIZE: u64 = 0x400000;
+const SCRATCH_PAGE: u64 = STACKS_START + STACKS_SIZE;
+/// Where [`paging::Mapper::split`] fills in the table replacing a huge page
+const SPLIT_PAGE: u64 = SCRATCH_PAGE + PAGE_SIZE;
+/// Window of kernel addresses [`MemoryController::alloc_virt`] hands out, device registers get mapped there
+const VIRTUAL_WINDOW: Range<u64> = KERNEL_WINDOW + 0x80000000..KERNEL_WINDOW + 0x100000000;
+
 pub fn init(boot_info: &'static BootInformation) {
-    let mut allocator = unsafe { BuddyAllocator::new(boot_info.memory_map()) };
+    let physical_map = PhysicalMap::new(boot_info);
+    let mut allocator = unsafe { BuddyAllocator::new(&physical_map) };
     enable_nxe_bit();
     enable_write_protect_bit();
-    let active_table = remap_the_kernel(&mut allocator, &boot_info);
+    set_page_attributes();
+    let mut active_table = remap_the_kernel(&mut allocator, &boot_info);
+    active_table.create_p4_entry(KERNEL_WINDOW_P4_INDEX, &mut allocator);
     let stack_allocator = {
-        let stack_alloc_start = Page::containing_address(comptime_alloc!(409600));
-        let stack_alloc_end = stack_alloc_start + 100;
+        let stack_alloc_start = Page::containing_address(STACKS_START);
+        let stack_alloc_end = stack_alloc_start + (STACKS_SIZE / PAGE_SIZE - 1);
         let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
         StackAllocator::new(stack_alloc_range)
     };
+    let scratch_page = Page::containing_address(SCRATCH_PAGE);
+    FRAMES.init_once(|| allocator.into());
     MEMORY_CONTROLLER.init_once(|| {
         MemoryController {
             active_table,
-            allocator,
+            allocator: frames(),
             stack_allocator,
+            scratch_page,
+            shared_frames: BTreeMap::new(),
+            regions: BTreeMap::new(),
+            kernel_regions: Regions::new(
+                KERNEL_REGIONS_START..KERNEL_REGIONS_START + KERNEL_REGIONS_SIZE,
+            ),
+            virtual_ranges: VirtualAllocator::new(VIRTUAL_WINDOW),
         }
         .into()
     });
+    PHYSICAL_MAP.init_once(|| physical_map.into());
     allocator::init();
     log!(
         Info,
@@ -48,6 +91,42 @@ pub fn init(boot_info: &'static BootInformation) {
     );
 }
 
+/// Give the memory the bootloader and boot services used to the allocator, the kernel copied out what it needs
+pub fn reclaim_boot_memory() {
+    let mut physical_map = physical_map().lock();
+    if physical_map.reclaimed() {
+        return;
+    }
+    let mut controller = memory_controller().lock();
+    // The renderer parsed the font already, only its identity mapping is left
+    if let Some(font) = physical_map.release(Owner::Font) {
+        let pages = Page::range_inclusive(
+            Page::containing_address(font.start),
+            Page::containing_address(font.end - 1),
+        );
+        for page in pages.filter(|e| !physical_map.is_reserved(e.start_address())) {
+            if controller.active_table.translate_page(page).is_some() {
+                controller.active_table.unmap_addr(page);
+            }
+        }
+    }
+    let mut reclaimed = 0;
+    physical_map.for_each_free(
+        &[
+            MemoryType::LOADER_CODE,
+            MemoryType::LOADER_DATA,
+            MemoryType::BOOT_SERVICES_CODE,
+            MemoryType::BOOT_SERVICES_DATA,
+        ],
+        |range| {
+            reclaimed += range.end - range.start;
+            unsafe { controller.allocator.add_range(range) };
+        },
+    );
+    physical_map.set_reclaimed();
+    log!(Info, "Reclaimed {} KiB of boot memory", reclaimed / 1024);
+}
+
 fn enable_write_protect_bit() {
     use x86_64::registers::control::Cr0;
 
@@ -58,6 +137,24 @@ fn enable_write_protect_bit() {
     }
 }
 
+/// Per processor setup, application processors share the page tables the bootstrap one made
+pub fn init_ap() {
+    set_page_attributes();
+}
+
+/// Make pat entry 4, which [`EntryFlags::PAT`] selects, write combining, the others keep their reset value
+fn set_page_attributes() {
+    use x86_64::registers::model_specific::Msr;
+
+    const IA32_PAT: u32 = 0x277;
+    const WRITE_COMBINING: u64 = 0x01;
+    unsafe {
+        let mut pat = Msr::new(IA32_PAT);
+        let value = pat.read() & !(0xFF << 32);
+        pat.write(value | WRITE_COMBINING << 32);
+    }
+}
+
 fn enable_nxe_bit() {
     use x86_64::registers::model_specific::Efer;
 
@@ -138,24 +235,78 @@ macro_rules! direct_mapping {
     };
 }
 
+static FRAMES: OnceCell<Mutex<BuddyAllocator<64>>> = OnceCell::uninit();
 static MEMORY_CONTROLLER: OnceCell<Mutex<MemoryController<64>>> = OnceCell::uninit();
 
+/// Physical frame allocator, locking it doesn't need the memory controller's lock
+pub fn frames() -> LockedBuddy<64> {
+    LockedBuddy::new(FRAMES.get().expect("Memory not initialized"))
+}
+
 pub fn memory_controller() -> &'static Mutex<MemoryController<64>> {
     return MEMORY_CONTROLLER
         .get()
         .expect("Memory controller not initialized");
 }
 
+static PHYSICAL_MAP: OnceCell<Mutex<PhysicalMap>> = OnceCell::uninit();
+
+/// Physical memory map with the reservations the allocator stays clear of
+pub fn physical_map() -> &'static Mutex<PhysicalMap> {
+    PHYSICAL_MAP.get().expect("Memory not initialized")
+}
+
 pub struct MemoryController<const ORDER: usize> {
     active_table: ActivePageTable,
-    allocator: BuddyAllocator<'static, ORDER>,
+    allocator: LockedBuddy<ORDER>,
     stack_allocator: StackAllocator,
+    /// Kernel page for short lived mappings of frames that aren't mapped anywhere else
+    scratch_page: Page,
+    /// Owners beyond the first of frames mapped by several address spaces, by frame number
+    shared_frames: BTreeMap<u64, usize>,
+    /// Regions of every address space by its p4 table, where page faults look them up
+    regions: BTreeMap<PhysAddr, Regions>,
+    /// Kernel mappings made through [`Self::map_region`], shared by every address space
+    kernel_regions: Regions,
+    virtual_ranges: VirtualAllocator,
+}
+
+/// Allocator handing out frames of the buddy allocator, frames only go back once their last owner frees them
+struct SharedFrames<'a, const ORDER: usize> {
+    allocator: &'a mut LockedBuddy<ORDER>,
+    shared_frames: &'a mut BTreeMap<u64, usize>,
+}
+
+impl<const ORDER: usize> FrameAllocator for SharedFrames<'_, ORDER> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocator.allocate_frame()
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        match self.shared_frames.get_mut(&frame.number) {
+            Some(1) => {
+                self.shared_frames.remove(&frame.number);
+            }
+            Some(owners) => *owners -= 1,
+            None => self.allocator.deallocate_frame(frame),
+        }
+    }
 }
 
 impl<const ORDER: usize> MemoryController<ORDER> {
-    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+    /// Allocate a stack, `name` is reported if it ever overflows into its guard page
+    pub fn alloc_stack(&mut self, size_in_pages: usize, name: &'static str) -> Option<Stack> {
+        self.stack_allocator.alloc_stack(
+            &mut self.active_table,
+            &mut self.allocator,
+            size_in_pages,
+            name,
+        )
+    }
+
+    pub fn dealloc_stack(&mut self, stack: Stack) {
         self.stack_allocator
-            .alloc_stack(&mut self.active_table, &mut self.allocator, size_in_pages)
+            .dealloc_stack(&mut self.active_table, &mut self.allocator, stack)
     }
 
     fn map(&mut self, page: Page, flags: EntryFlags) {
@@ -177,22 +328,23 @@ impl<const ORDER: usize> MemoryController<ORDER> {
         }
     }
 
+    /// Map `size` bytes of physical memory, with the biggest pages the alignment of both addresses allows
     pub fn phy_map(&mut self, size: u64, phy_start: u64, virt_start: u64) {
-        let start_page = Page::containing_address(virt_start);
-        let start_frame = Frame::containing_address(phy_start);
-        let end_page = Page::containing_address(virt_start + size - 1);
-        let end_frame = Frame::containing_address(phy_start + size - 1);
-        for (page, frame) in Page::range_inclusive(start_page, end_page)
-            .zip(Frame::range_inclusive(start_frame, end_frame))
-        {
-            self.map_to(
-                page,
-                frame,
-                EntryFlags::PRESENT
-                    | EntryFlags::NO_CACHE
-                    | EntryFlags::WRITABLE
-                    | EntryFlags::WRITE_THROUGH,
-            );
+        let flags = EntryFlags::PRESENT
+            | EntryFlags::NO_CACHE
+            | EntryFlags::WRITABLE
+            | EntryFlags::WRITE_THROUGH;
+        let end = (virt_start + size).next_multiple_of(PAGE_SIZE);
+        let mut page = Page::containing_address(virt_start);
+        let mut frame = Frame::containing_address(phy_start);
+        while page.start_address() < end {
+            let size = self
+                .active_table
+                .largest_page_size(page, frame, end - page.start_address());
+            self.active_table
+                .map_to_size(page, frame, size, flags, &mut self.allocator);
+            page = page + size.pages();
+            frame = Frame::containing_address(frame.start_address().as_u64() + size.bytes());
         }
     }
 
@@ -208,12 +360,132 @@ impl<const ORDER: usize> MemoryController<ORDER> {
         });
     }
 
+    /// Identity map a physical range, pages that are already mapped are left untouched
+    pub fn ident_map_unmapped(&mut self, size: u64, phy_start: u64, flags: EntryFlags) {
+        let start = Frame::containing_address(phy_start);
+        let end = Frame::containing_address(phy_start + size - 1);
+        for frame in Frame::range_inclusive(start, end) {
+            if self
+                .active_table
+                .translate(VirtAddr::new(frame.start_address().as_u64()))
+                .is_some()
+            {
+                continue;
+            }
+            self.active_table
+                .identity_map(frame, flags | EntryFlags::PRESENT, &mut self.allocator);
+        }
+    }
+
     pub fn unmap_addr(&mut self, mapped_start: u64, size: u64) {
         let start = Page::containing_address(mapped_start);
         let end = Page::containing_address(mapped_start + size - 1);
-        Page::range_inclusive(start, end).for_each(|page| {
-            self.active_table.unmap_addr(page);
-        });
+        self.active_table
+            .unmap_range(start, end, &mut self.allocator);
+    }
+
+    /// Reserve `size` bytes of the virtual window aligned to `align`, nothing gets mapped there
+    pub fn alloc_virt(&mut self, size: u64, align: u64) -> Result<u64, AddressSpaceError> {
+        self.virtual_ranges.allocate(size, align)
+    }
+
+    /// Give back a range of [`Self::alloc_virt`], whatever was mapped there must be unmapped already
+    pub fn free_virt(&mut self, start: u64) -> Result<u64, AddressSpaceError> {
+        self.virtual_ranges.free(start)
+    }
+
+    /// Map `size` bytes of device memory from `phy_start` into the virtual window, uncached
+    pub fn map_physical(
+        &mut self,
+        size: u64,
+        phy_start: u64,
+    ) -> Result<Mapping, AddressSpaceError> {
+        let offset = phy_start % PAGE_SIZE;
+        let frames = phy_start - offset;
+        let size = (size + offset).next_multiple_of(PAGE_SIZE);
+        // Aligned like the frames so phy_map can use huge pages
+        let align = [PageSize::Size1GiB, PageSize::Size2MiB]
+            .into_iter()
+            .map(|e| e.bytes())
+            .find(|e| size >= *e && frames % e == 0)
+            .unwrap_or(PAGE_SIZE);
+        let start = self.alloc_virt(size, align)?;
+        self.phy_map(size, frames, start);
+        Ok(Mapping::new(start, size, offset))
+    }
+
+    /// Map `size` bytes filled from `backing` where the kernel region window has room, returns the start
+    pub fn map_region(
+        &mut self,
+        size: u64,
+        flags: EntryFlags,
+        backing: Backing,
+    ) -> Result<u64, AddressSpaceError> {
+        let size = page_range(0, size)?.end;
+        let start = self
+            .kernel_regions
+            .free_range(0, size, PAGE_SIZE)
+            .ok_or(AddressSpaceError::NoFreeRange(size))?;
+        self.map_region_at(start, size, flags, backing)?;
+        Ok(start)
+    }
+
+    /// Map `start..start + size` of the kernel region window, rejected if any of it is in use
+    pub fn map_region_at(
+        &mut self,
+        start: u64,
+        size: u64,
+        flags: EntryFlags,
+        backing: Backing,
+    ) -> Result<(), AddressSpaceError> {
+        let range = page_range(start, size)?;
+        let area = Area {
+            start: range.start,
+            end: range.end,
+            flags: flags | EntryFlags::PRESENT,
+            backing,
+        };
+        self.kernel_regions.insert(area)?;
+        // Kernel pages don't fault in, they're all backed right away
+        for page in area.pages() {
+            if let Err(err) = populate(self, page, &area, false) {
+                self.kernel_regions.remove(area.start, area.end);
+                unmap_areas(self, &[area]);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap the kernel regions inside `start..start + size`, regions crossing its ends get cut
+    pub fn unmap_region(&mut self, start: u64, size: u64) -> Result<(), AddressSpaceError> {
+        let range = page_range(start, size)?;
+        let removed = self.kernel_regions.remove(range.start, range.end);
+        unmap_areas(self, &removed);
+        Ok(())
+    }
+
+    /// Change the flags of `start..start + size`, all of it must be in kernel regions
+    pub fn protect_region(
+        &mut self,
+        start: u64,
+        size: u64,
+        flags: EntryFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let range = page_range(start, size)?;
+        let changed =
+            self.kernel_regions
+                .protect(range.start, range.end, flags | EntryFlags::PRESENT)?;
+        for area in &changed {
+            protect_pages(self, area);
+        }
+        shootdown(Some(range));
+        Ok(())
+    }
+
+    /// Kernel regions in address order
+    pub fn regions(&self) -> Vec<Area> {
+        self.kernel_regions.iter().copied().collect()
     }
 
     fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) {
@@ -236,6 +508,72 @@ impl<const ORDER: usize> MemoryController<ORDER> {
         return self.active_table.translate(addr);
     }
 
+    /// Flags of the page mapping `addr`, the intermediate tables may restrict it further
+    pub fn page_flags(&self, addr: VirtAddr) -> Option<EntryFlags> {
+        self.active_table
+            .page_flags(Page::containing_address(addr.as_u64()))
+    }
+
+    /// Size of the page mapping `addr`
+    pub fn page_size(&self, addr: VirtAddr) -> Option<PageSize> {
+        self.active_table
+            .page_size(Page::containing_address(addr.as_u64()))
+    }
+
+    /// Run `f` with `space` loaded in cr3, the active table then edits its user half
+    pub fn with_address_space<F, R>(&mut self, space: &AddressSpace, f: F) -> R
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        self.with_table(space.p4_address(), f)
+    }
+
+    /// Like [`Self::with_address_space`] for the table at `p4`, which may be the active one
+    fn with_table<F, R>(&mut self, p4: PhysAddr, f: F) -> R
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        interrupts::without_interrupts(|| {
+            let table = unsafe {
+                InactivePageTable::from_raw_frame(Frame::containing_address(p4.as_u64()))
+            };
+            let previous = self.active_table.switch(table);
+            let result = f(self);
+            self.active_table.switch(previous);
+            result
+        })
+    }
+
+    /// Run `f` on the content of a frame through the scratch page
+    fn with_frame<R>(&mut self, frame: Frame, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        self.active_table.map_to(
+            self.scratch_page,
+            frame,
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            &mut self.allocator,
+        );
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                self.scratch_page.start_address() as *mut u8,
+                PAGE_SIZE as usize,
+            )
+        };
+        let result = f(bytes);
+        self.active_table.unmap_addr(self.scratch_page);
+        result
+    }
+
+    /// Count another owner of `frame`, it stays allocated until each of them frees it
+    fn share_frame(&mut self, frame: &Frame) {
+        *self.shared_frames.entry(frame.number).or_insert(0) += 1;
+    }
+
+    /// Number of address spaces mapping the frame at `address`
+    pub fn frame_owners(&self, address: PhysAddr) -> usize {
+        let frame = Frame::containing_address(address.as_u64());
+        1 + self.shared_frames.get(&frame.number).copied().unwrap_or(0)
+    }
+
     pub fn max_mem(&self) -> usize {
         self.allocator.max_mem()
     }
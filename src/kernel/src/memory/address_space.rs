@@ -0,0 +1,502 @@
+use core::error::Error;
+use core::fmt::Display;
+use core::ops::Range;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::PhysAddr;
+
+use super::paging::{EntryFlags, InactivePageTable, Page, PageIter, USER_P4_ENTRIES};
+use super::region::{page_range, populate, protect_pages, unmap_areas};
+use super::region::{Area, Backing, Regions};
+use super::{memory_controller, FrameAllocator, SharedFrames, PAGE_SIZE};
+
+/// First user address, the p4 entries below map the kernel
+pub const USER_START: u64 = (USER_P4_ENTRIES.start as u64) << 39;
+/// End of the lower canonical half
+pub const USER_END: u64 = (USER_P4_ENTRIES.end as u64) << 39;
+/// Where [`AddressSpace::mmap`] starts looking for room without an address
+pub const MMAP_BASE: u64 = USER_START + 0x10_0000_0000;
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    OutOfMemory,
+    OutsideUserSpace(u64),
+    AlreadyMapped(u64),
+    NotMapped(u64),
+    /// Not page aligned or empty
+    InvalidRange(u64),
+    /// Outside of the addresses the regions may use
+    OutsideWindow(u64),
+    /// No free range of that size
+    NoFreeRange(u64),
+}
+
+impl Display for AddressSpaceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "Out of physical memory"),
+            Self::OutsideUserSpace(address) => {
+                write!(f, "Address {:#x} is outside of user space", address)
+            }
+            Self::AlreadyMapped(address) => write!(f, "Page at {:#x} is already mapped", address),
+            Self::NotMapped(address) => write!(f, "Page at {:#x} is not mapped", address),
+            Self::InvalidRange(address) => write!(f, "Invalid range at {:#x}", address),
+            Self::OutsideWindow(address) => {
+                write!(f, "Address {:#x} is outside of the region window", address)
+            }
+            Self::NoFreeRange(size) => write!(f, "No free range of {:#x} bytes", size),
+        }
+    }
+}
+
+impl Error for AddressSpaceError {}
+
+/// Page table of a process, the kernel half is shared with every other address space
+///
+/// Only the kernel p4 entries present at creation are shared, new kernel mappings must stay below them
+pub struct AddressSpace {
+    table: InactivePageTable,
+}
+
+impl AddressSpace {
+    /// Empty user half on top of the kernel mappings of the active table
+    pub fn new() -> Result<AddressSpace, AddressSpaceError> {
+        let mut controller = memory_controller().lock();
+        let controller = &mut *controller;
+        let frame = controller
+            .allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let table = InactivePageTable::new_sharing_kernel(
+            frame,
+            &mut controller.active_table,
+            controller.scratch_page,
+            &mut controller.allocator,
+        );
+        let space = AddressSpace { table };
+        controller
+            .regions
+            .insert(space.p4_address(), Regions::new(USER_START..USER_END));
+        Ok(space)
+    }
+
+    /// Physical address of the p4 table, the value loaded in cr3
+    pub fn p4_address(&self) -> PhysAddr {
+        self.table.p4_frame().start_address()
+    }
+
+    /// Map zeroed frames over `start..start + size` for ring 3, pages mapped before running out of memory stay mapped
+    pub fn map(
+        &mut self,
+        start: u64,
+        size: u64,
+        flags: EntryFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let Some(pages) = user_pages(start, size)? else {
+            return Ok(());
+        };
+        let area = self.add_area(start, size, flags)?;
+        memory_controller()
+            .lock()
+            .with_address_space(self, |controller| {
+                for page in pages {
+                    populate(controller, page, &area, false)?;
+                }
+                Ok(())
+            })
+    }
+
+    /// Make `start..start + size` valid for ring 3 without backing it yet, frames come on the first access
+    pub fn reserve(
+        &mut self,
+        start: u64,
+        size: u64,
+        flags: EntryFlags,
+    ) -> Result<(), AddressSpaceError> {
+        if user_pages(start, size)?.is_some() {
+            self.add_area(start, size, flags)?;
+        }
+        Ok(())
+    }
+
+    fn add_area(
+        &mut self,
+        start: u64,
+        size: u64,
+        flags: EntryFlags,
+    ) -> Result<Area, AddressSpaceError> {
+        let area = Area {
+            start: start - start % PAGE_SIZE,
+            end: (start + size).next_multiple_of(PAGE_SIZE),
+            flags: flags | EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE,
+            backing: Backing::Anonymous,
+        };
+        let mut controller = memory_controller().lock();
+        controller
+            .regions
+            .get_mut(&self.p4_address())
+            .unwrap()
+            .insert(area)?;
+        Ok(area)
+    }
+
+    /// Add an area of `size` bytes filled from `backing` at `address`, or where there's room from [`MMAP_BASE`] on
+    ///
+    /// The pages get `flags` on first use, ring 3 can only touch them with [`EntryFlags::USER_ACCESSIBLE`]
+    pub fn mmap(
+        &mut self,
+        address: Option<u64>,
+        size: u64,
+        flags: EntryFlags,
+        backing: Backing,
+    ) -> Result<u64, AddressSpaceError> {
+        mmap(self.p4_address(), address, size, flags, backing)
+    }
+
+    /// Remove the areas inside `start..start + size` and free their frames, areas crossing its ends get cut
+    pub fn munmap(&mut self, start: u64, size: u64) -> Result<(), AddressSpaceError> {
+        munmap(self.p4_address(), start, size)
+    }
+
+    /// Change the flags of `start..start + size`, every page of it must be in an area
+    pub fn mprotect(
+        &mut self,
+        start: u64,
+        size: u64,
+        flags: EntryFlags,
+    ) -> Result<(), AddressSpaceError> {
+        mprotect(self.p4_address(), start, size, flags)
+    }
+
+    /// Areas of the address space in address order
+    pub fn areas(&self) -> Vec<Area> {
+        memory_controller().lock().regions[&self.p4_address()]
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Copy of the address space sharing every frame until either side writes to it
+    pub fn fork(&self) -> Result<AddressSpace, AddressSpaceError> {
+        fork(self.p4_address())
+    }
+
+    /// Copy `data` to `address`, write protection of the user pages doesn't apply
+    pub fn write(&mut self, address: u64, data: &[u8]) -> Result<(), AddressSpaceError> {
+        self.with_bytes(address, data.len(), true, |offset, bytes| {
+            bytes.copy_from_slice(&data[offset..offset + bytes.len()])
+        })
+    }
+
+    /// Fill `buffer` with the bytes at `address`
+    pub fn read(&self, address: u64, buffer: &mut [u8]) -> Result<(), AddressSpaceError> {
+        self.with_bytes(address, buffer.len(), false, |offset, bytes| {
+            let count = bytes.len();
+            buffer[offset..offset + count].copy_from_slice(bytes)
+        })
+    }
+
+    /// Run `f` on the memory of `address..address + len` a page at a time, with the offset into the range
+    ///
+    /// Pages get their frames like on a fault, `write` gives shared ones a private copy first
+    fn with_bytes(
+        &self,
+        address: u64,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(usize, &mut [u8]),
+    ) -> Result<(), AddressSpaceError> {
+        if user_pages(address, len as u64)?.is_none() {
+            return Ok(());
+        }
+        let p4 = self.p4_address();
+        memory_controller()
+            .lock()
+            .with_address_space(self, |controller| {
+                let mut offset = 0;
+                while offset < len {
+                    let current = address + offset as u64;
+                    let area = controller.regions[&p4]
+                        .find(current)
+                        .copied()
+                        .ok_or(AddressSpaceError::NotMapped(current))?;
+                    let frame =
+                        populate(controller, Page::containing_address(current), &area, write)?;
+                    let start = (current % PAGE_SIZE) as usize;
+                    let count = (len - offset).min(PAGE_SIZE as usize - start);
+                    controller
+                        .with_frame(frame, |bytes| f(offset, &mut bytes[start..start + count]));
+                    offset += count;
+                }
+                Ok(())
+            })
+    }
+
+    /// Physical address `address` maps to in this address space, none until the page got used
+    pub fn translate(&self, address: u64) -> Option<PhysAddr> {
+        memory_controller()
+            .lock()
+            .with_address_space(self, |controller| {
+                controller.get_physical(x86_64::VirtAddr::new(address))
+            })
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0.start_address(),
+            self.p4_address(),
+            "Dropping the active address space"
+        );
+        let mut controller = memory_controller().lock();
+        let regions = controller.regions.remove(&self.p4_address()).unwrap();
+        // Physical frames don't belong to the address space, they must not be freed with it
+        let physical: Vec<Area> = regions
+            .iter()
+            .filter(|e| matches!(e.backing, Backing::Physical(_)))
+            .copied()
+            .collect();
+        controller.with_address_space(self, |controller| {
+            unmap_areas(controller, &physical);
+            controller.active_table.free_p4_entries(
+                USER_P4_ENTRIES,
+                &mut SharedFrames {
+                    allocator: &mut controller.allocator,
+                    shared_frames: &mut controller.shared_frames,
+                },
+            )
+        });
+        controller.allocator.deallocate_frame(self.table.p4_frame());
+    }
+}
+
+/// Fork the address space running on this processor, see [`AddressSpace::fork`]
+pub fn fork_current() -> Result<AddressSpace, AddressSpaceError> {
+    fork(Cr3::read().0.start_address())
+}
+
+fn fork(p4: PhysAddr) -> Result<AddressSpace, AddressSpaceError> {
+    let child = AddressSpace::new()?;
+    let mut controller = memory_controller().lock();
+    let regions = controller.regions[&p4].clone();
+    let physical = |page: &Page| {
+        regions
+            .find(page.start_address())
+            .is_some_and(|e| matches!(e.backing, Backing::Physical(_)))
+    };
+    // Both sides lose write access, the first write gets a private copy, physical frames stay shared
+    let pages = controller.with_table(p4, |controller| {
+        let pages = controller.active_table.mapped_pages(USER_P4_ENTRIES);
+        for (page, _, flags) in pages.iter().filter(|e| !physical(&e.0)) {
+            if flags.contains(EntryFlags::WRITABLE) {
+                controller
+                    .active_table
+                    .set_flags(*page, *flags - EntryFlags::WRITABLE);
+            }
+        }
+        pages
+    });
+    controller.with_address_space(&child, |controller| {
+        for (page, frame, flags) in pages {
+            let flags = if physical(&page) {
+                flags
+            } else {
+                controller.share_frame(&frame);
+                flags - EntryFlags::WRITABLE
+            };
+            controller
+                .active_table
+                .map_to(page, frame, flags, &mut controller.allocator);
+        }
+    });
+    controller.regions.insert(child.p4_address(), regions);
+    drop(controller);
+    Ok(child)
+}
+
+/// Resolve a page fault at `address` in the active address space, false if the access isn't valid
+///
+/// Not yet used pages of an area get a zeroed frame and writes to shared ones a private copy
+pub fn handle_page_fault(address: u64, error: PageFaultErrorCode) -> bool {
+    if !(USER_START..USER_END).contains(&address) {
+        return false;
+    }
+    let write = error.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    // Present pages only fault again when written to
+    if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && !write {
+        return false;
+    }
+    let mut controller = memory_controller().lock();
+    let p4 = Cr3::read().0.start_address();
+    let Some(area) = controller
+        .regions
+        .get(&p4)
+        .and_then(|e| e.find(address))
+        .copied()
+    else {
+        return false;
+    };
+    if !area.flags.contains(EntryFlags::USER_ACCESSIBLE) {
+        return false;
+    }
+    if write && !area.flags.contains(EntryFlags::WRITABLE) {
+        return false;
+    }
+    if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && area.flags.contains(EntryFlags::NO_EXECUTE)
+    {
+        return false;
+    }
+    let page = Page::containing_address(address);
+    if controller
+        .active_table
+        .page_flags(page)
+        .is_some_and(|e| e.contains(EntryFlags::WRITABLE))
+    {
+        return false;
+    }
+    populate(&mut controller, page, &area, write).is_ok()
+}
+
+/// Whether ring 3 may access all of `address..address + len` in the active address space
+pub fn user_range_accessible(address: u64, len: usize, write: bool) -> bool {
+    let Some(end) = address
+        .checked_add(len as u64)
+        .filter(|e| address >= USER_START && *e <= USER_END)
+    else {
+        return false;
+    };
+    let controller = memory_controller().lock();
+    let Some(regions) = controller.regions.get(&Cr3::read().0.start_address()) else {
+        return false;
+    };
+    let mut current = address;
+    while current < end {
+        let Some(area) = regions.find(current) else {
+            return false;
+        };
+        if !area.flags.contains(EntryFlags::USER_ACCESSIBLE)
+            || (write && !area.flags.contains(EntryFlags::WRITABLE))
+        {
+            return false;
+        }
+        current = area.end;
+    }
+    true
+}
+
+/// [`AddressSpace::mmap`] in the active address space
+pub fn mmap_current(
+    address: Option<u64>,
+    size: u64,
+    flags: EntryFlags,
+    backing: Backing,
+) -> Result<u64, AddressSpaceError> {
+    mmap(Cr3::read().0.start_address(), address, size, flags, backing)
+}
+
+/// [`AddressSpace::munmap`] in the active address space
+pub fn munmap_current(start: u64, size: u64) -> Result<(), AddressSpaceError> {
+    munmap(Cr3::read().0.start_address(), start, size)
+}
+
+/// [`AddressSpace::mprotect`] in the active address space
+pub fn mprotect_current(start: u64, size: u64, flags: EntryFlags) -> Result<(), AddressSpaceError> {
+    mprotect(Cr3::read().0.start_address(), start, size, flags)
+}
+
+fn mmap(
+    p4: PhysAddr,
+    address: Option<u64>,
+    size: u64,
+    flags: EntryFlags,
+    backing: Backing,
+) -> Result<u64, AddressSpaceError> {
+    let mut controller = memory_controller().lock();
+    let regions = regions_mut(&mut controller.regions, p4, address.unwrap_or(MMAP_BASE))?;
+    let range = match address {
+        Some(start) => user_range(start, size)?,
+        None => {
+            let size = page_range(0, size)?.end;
+            let start = regions
+                .free_range(MMAP_BASE, size, PAGE_SIZE)
+                .ok_or(AddressSpaceError::NoFreeRange(size))?;
+            start..start + size
+        }
+    };
+    regions.insert(Area {
+        start: range.start,
+        end: range.end,
+        flags: flags | EntryFlags::PRESENT,
+        backing,
+    })?;
+    Ok(range.start)
+}
+
+fn munmap(p4: PhysAddr, start: u64, size: u64) -> Result<(), AddressSpaceError> {
+    let range = user_range(start, size)?;
+    let mut controller = memory_controller().lock();
+    let removed = regions_mut(&mut controller.regions, p4, start)?.remove(range.start, range.end);
+    controller.with_table(p4, |controller| unmap_areas(controller, &removed));
+    Ok(())
+}
+
+fn mprotect(
+    p4: PhysAddr,
+    start: u64,
+    size: u64,
+    flags: EntryFlags,
+) -> Result<(), AddressSpaceError> {
+    let range = user_range(start, size)?;
+    let mut controller = memory_controller().lock();
+    let changed = regions_mut(&mut controller.regions, p4, start)?.protect(
+        range.start,
+        range.end,
+        flags | EntryFlags::PRESENT,
+    )?;
+    controller.with_table(p4, |controller| {
+        for area in &changed {
+            protect_pages(controller, area);
+        }
+    });
+    Ok(())
+}
+
+/// Regions of the table at `p4`, kernel threads have none and nothing is mapped at `address` for them
+fn regions_mut(
+    regions: &mut BTreeMap<PhysAddr, Regions>,
+    p4: PhysAddr,
+    address: u64,
+) -> Result<&mut Regions, AddressSpaceError> {
+    regions
+        .get_mut(&p4)
+        .ok_or(AddressSpaceError::NotMapped(address))
+}
+
+/// Page aligned range inside user space
+fn user_range(start: u64, size: u64) -> Result<Range<u64>, AddressSpaceError> {
+    let range = page_range(start, size)?;
+    if range.start < USER_START || range.end > USER_END {
+        return Err(AddressSpaceError::OutsideUserSpace(start));
+    }
+    Ok(range)
+}
+
+/// Pages covering `start..start + size`, none if the range is empty
+fn user_pages(start: u64, size: u64) -> Result<Option<PageIter>, AddressSpaceError> {
+    let end = start
+        .checked_add(size)
+        .filter(|e| start >= USER_START && *e <= USER_END)
+        .ok_or(AddressSpaceError::OutsideUserSpace(start))?;
+    if size == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Page::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(end - 1),
+    )))
+}
@@ -1,16 +1,22 @@
 use core::ptr;
 
 use alloc::alloc::*;
-use proc::comptime_alloc;
 pub mod buddy_allocator;
-pub mod linked_list;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod heap;
+pub mod slab;
 
-use self::linked_list::LinkedListAllocator;
+use self::heap::{Heap, HeapStats, MAX_ORDER};
 
-use super::memory_controller;
+use super::paging::{ActivePageTable, EntryFlags, Page};
+use super::{frames, FrameAllocator, KERNEL_WINDOW, PAGE_SIZE};
 
-pub const HEAP_START: u64 = comptime_alloc!(0x2000000);
-pub const HEAP_SIZE: u64 = 0x2000000; // 32 Mib
+/// Aligned to the biggest block of the heap, so all of its blocks are aligned to their size
+pub const HEAP_START: u64 = KERNEL_WINDOW;
+const _: () = assert!(HEAP_START % (PAGE_SIZE << MAX_ORDER) == 0);
+pub const HEAP_MAX_SIZE: u64 = 0x40000000; // 1 GiB
+const HEAP_INITIAL_SIZE: u64 = 0x100000; // 1 Mib
 
 pub fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
@@ -32,38 +38,92 @@ impl<A> Locked<A> {
     }
 }
 
-unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+impl Locked<Heap> {
+    /// Allocate from the heap, growing it when it's out of room
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let allocation = self.lock().allocate(layout);
+            match allocation {
+                Some(ptr) => return ptr,
+                None if !grow(Heap::block_size(layout)) => return ptr::null_mut(),
+                None => {}
             }
-            alloc_start as *mut u8
-        } else {
-            ptr::null_mut()
         }
     }
+}
+
+unsafe impl GlobalAlloc for Locked<Heap> {
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout)
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout);
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        debug::alloc(layout, |e| self.allocate(e))
+    }
 
+    #[cfg(feature = "heap-debug")]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (size, _) = LinkedListAllocator::size_align(layout);
-        self.lock().add_free_region(ptr as usize, size);
+        debug::dealloc(ptr, layout, |ptr, layout| {
+            self.lock().deallocate(ptr, layout)
+        });
     }
 }
 
 #[global_allocator]
-static GLOBAL_ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static GLOBAL_ALLOCATOR: Locked<Heap> = Locked::new(Heap::new(
+    HEAP_START as usize,
+    (HEAP_START + HEAP_MAX_SIZE) as usize,
+));
 
-pub fn init() {
-    memory_controller().lock().alloc_map(HEAP_SIZE, HEAP_START);
-
-    unsafe {
-        GLOBAL_ALLOCATOR
-            .lock()
-            .init(HEAP_START as usize, HEAP_SIZE as usize);
+/// Map more of the heap window so a block of `size` bytes fits, returns whether it did
+///
+/// It takes frames from [`frames`] and maps them with its own handle on the active table, never
+/// locking the memory controller, so allocations made with the controller locked can grow the heap too.
+/// Nothing else maps below the heap's p3 entry of the kernel window.
+fn grow(size: usize) -> bool {
+    let mut heap = GLOBAL_ALLOCATOR.lock();
+    let Some(range) = heap.grow_range(size) else {
+        return false;
+    };
+    let mut frames = frames();
+    let mut table = unsafe { ActivePageTable::new() };
+    let pages = Page::range_inclusive(
+        Page::containing_address(range.start as u64),
+        Page::containing_address(range.end as u64 - 1),
+    );
+    for page in pages.clone() {
+        let Some(frame) = frames.allocate_frame() else {
+            pages
+                .take_while(|e| *e < page)
+                .for_each(|e| table.unmap(e, &mut frames));
+            return false;
+        };
+        table.map_to(
+            page,
+            frame,
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            &mut frames,
+        );
     }
+    unsafe { heap.extend(range.end) };
+    true
+}
+
+/// Statistics of the heap and its slab caches
+pub fn stats() -> HeapStats {
+    GLOBAL_ALLOCATOR.lock().stats()
+}
+
+pub fn init() {
+    assert!(
+        grow(HEAP_INITIAL_SIZE as usize),
+        "Failed to map the initial heap"
+    );
 }
@@ -1,21 +1,21 @@
-use core::{marker::PhantomData, ptr};
+use core::{marker::PhantomData, ops::Range, ptr};
 
-use uefi::table::boot::{MemoryMap, MemoryType};
+use spin::Mutex;
+use uefi::table::boot::MemoryType;
 
 use crate::{
     direct_mapping,
-    memory::{Frame, FrameAllocator, MAX_ALIGN, PAGE_SIZE},
+    memory::{physical_map::PhysicalMap, Frame, FrameAllocator, MAX_ALIGN, PAGE_SIZE},
     utils::NumberUtils,
 };
 
-pub struct BuddyAllocator<'a, const ORDER: usize> {
+pub struct BuddyAllocator<const ORDER: usize> {
     free_lists: [FreeList; ORDER],
     max_mem: usize,
     allocated: usize,
-    areas: Option<&'a MemoryMap<'a>>,
 }
 
-impl<'a, const ORDER: usize> FrameAllocator for BuddyAllocator<'a, ORDER> {
+impl<const ORDER: usize> FrameAllocator for BuddyAllocator<ORDER> {
     fn allocate_frame(&mut self) -> Option<Frame> {
         return Some(Frame::containing_address(
             self.allocate(PAGE_SIZE as usize)? as u64,
@@ -30,41 +30,29 @@ impl<'a, const ORDER: usize> FrameAllocator for BuddyAllocator<'a, ORDER> {
     }
 }
 
-impl<'a, const ORDER: usize> BuddyAllocator<'a, ORDER> {
-    pub unsafe fn new(areas: &'a MemoryMap<'a>) -> Self {
+impl<const ORDER: usize> BuddyAllocator<ORDER> {
+    /// Allocator over the memory of `physical_map` that's free from the start
+    ///
+    /// Physical memory must still be identity mapped
+    pub unsafe fn new(physical_map: &PhysicalMap) -> Self {
         let mut init = Self {
             free_lists: [const { FreeList::new() }; ORDER],
             max_mem: 0,
             allocated: 0,
-            areas: Some(areas),
         };
 
-        init.add_memory_map_to_area();
+        physical_map.for_each_free(&[MemoryType::CONVENTIONAL], |range| {
+            init.add_area(range.start as usize, (range.end - range.start) as usize)
+        });
 
         return init;
     }
 
-    unsafe fn add_memory_map_to_area(&mut self) {
-        let areas = match self.areas {
-            Some(areas) => areas,
-            None => return,
-        };
-        for area in areas.entries().filter(|e| {
-            matches!(
-                e.ty,
-                MemoryType::CONVENTIONAL
-                    | MemoryType::BOOT_SERVICES_DATA
-                    | MemoryType::BOOT_SERVICES_CODE
-            )
-        }) {
-            if area.phys_start == 0 {
-                continue;
-            }
-            self.add_area(
-                area.phys_start as usize,
-                area.page_count as usize * PAGE_SIZE as usize,
-            );
-        }
+    /// Hand out the physical `range` from now on, nothing else may be using it
+    pub unsafe fn add_range(&mut self, range: Range<u64>) {
+        direct_mapping!({
+            self.add_area(range.start as usize, (range.end - range.start) as usize);
+        });
     }
 
     pub fn allocated(&self) -> usize {
@@ -187,6 +175,51 @@ impl<'a, const ORDER: usize> BuddyAllocator<'a, ORDER> {
     }
 }
 
+/// Buddy allocator behind its own lock, each call holds it only for itself
+///
+/// The heap takes its frames through one, so it grows even while the memory controller is locked.
+#[derive(Clone, Copy)]
+pub struct LockedBuddy<const ORDER: usize> {
+    inner: &'static Mutex<BuddyAllocator<ORDER>>,
+}
+
+impl<const ORDER: usize> LockedBuddy<ORDER> {
+    pub const fn new(inner: &'static Mutex<BuddyAllocator<ORDER>>) -> Self {
+        Self { inner }
+    }
+
+    pub fn allocate(&self, size: usize) -> Option<*mut u8> {
+        self.inner.lock().allocate(size)
+    }
+
+    pub fn dealloc(&self, ptr: *mut u8, size: usize) {
+        self.inner.lock().dealloc(ptr, size)
+    }
+
+    /// See [`BuddyAllocator::add_range`]
+    pub unsafe fn add_range(&self, range: Range<u64>) {
+        self.inner.lock().add_range(range)
+    }
+
+    pub fn max_mem(&self) -> usize {
+        self.inner.lock().max_mem()
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.inner.lock().allocated()
+    }
+}
+
+impl<const ORDER: usize> FrameAllocator for LockedBuddy<ORDER> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.inner.lock().allocate_frame()
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.inner.lock().deallocate_frame(frame)
+    }
+}
+
 /// Code below is taken from https://github.com/rcore-os/buddy_system_allocator/blob/master/src/linked_list.rs
 #[derive(Debug)]
 struct FreeNode {
@@ -270,4 +303,4 @@ impl<'a> Iterator for FreeListIterMut<'a> {
     }
 }
 
-unsafe impl<const ORDER: usize> Send for BuddyAllocator<'_, ORDER> {}
+unsafe impl<const ORDER: usize> Send for BuddyAllocator<ORDER> {}
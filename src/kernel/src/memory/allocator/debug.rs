@@ -0,0 +1,214 @@
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::backtrace::{write_frames, Backtrace};
+use crate::log;
+
+/// Bytes guarding each side of an allocation
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xFD;
+/// Freed memory gets filled with it, reading it back points at a use after free
+const POISON_BYTE: u8 = 0xDD;
+/// Return addresses kept of where an allocation was made
+const SITE_FRAMES: usize = 8;
+const ALIVE: u64 = 0xA110_CA7E_DA11_7E00;
+const FREED: u64 = 0xF4EE_DF4E_EDF4_EE00;
+
+/// Put in front of the redzone before every allocation
+///
+/// The magic comes last, right before the redzone, the heap keeps its own pointers at the start of freed blocks
+#[repr(C)]
+struct Header {
+    prev: *mut Header,
+    next: *mut Header,
+    id: u64,
+    size: usize,
+    site: [u64; SITE_FRAMES],
+    magic: u64,
+}
+
+/// Allocations that weren't freed yet, linked through their headers
+struct LiveAllocations {
+    head: *mut Header,
+    next_id: u64,
+    count: usize,
+}
+
+unsafe impl Send for LiveAllocations {}
+
+static LIVE: Mutex<LiveAllocations> = Mutex::new(LiveAllocations {
+    head: ptr::null_mut(),
+    next_id: 0,
+    count: 0,
+});
+
+/// Allocation that is still live, with the return addresses of where it was made
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+    /// Allocations are numbered in the order they're made
+    pub id: u64,
+    pub site: [u64; SITE_FRAMES],
+}
+
+/// Offset of the data in the block, after the header and the front redzone
+fn prefix(layout: Layout) -> usize {
+    (size_of::<Header>() + REDZONE).next_multiple_of(layout.align())
+}
+
+/// Layout of the whole block around an allocation of `layout`
+fn block_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(
+        prefix(layout) + layout.size() + REDZONE,
+        layout.align().max(align_of::<Header>()),
+    )
+    .expect("Allocation too big for its redzones")
+}
+
+unsafe fn header(data: *mut u8) -> *mut Header {
+    data.sub(REDZONE + size_of::<Header>()) as *mut Header
+}
+
+/// Allocate `layout` through `allocate` with a redzone on each side, remembering where it came from
+///
+/// # Safety
+///
+/// `allocate` must return null or a block fitting the layout it's given, the block stays in use until [`dealloc`].
+pub unsafe fn alloc(layout: Layout, allocate: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let block = allocate(block_layout(layout));
+    if block.is_null() {
+        return block;
+    }
+    let data = block.add(prefix(layout));
+    ptr::write_bytes(data.sub(REDZONE), REDZONE_BYTE, REDZONE);
+    ptr::write_bytes(data.add(layout.size()), REDZONE_BYTE, REDZONE);
+    let mut site = [0; SITE_FRAMES];
+    site.iter_mut()
+        .zip(Backtrace::capture())
+        .for_each(|(slot, address)| *slot = address);
+
+    let header = header(data);
+    let mut live = LIVE.lock();
+    header.write(Header {
+        prev: ptr::null_mut(),
+        next: live.head,
+        id: live.next_id,
+        size: layout.size(),
+        site,
+        magic: ALIVE,
+    });
+    if let Some(next) = live.head.as_mut() {
+        next.prev = header;
+    }
+    live.head = header;
+    live.next_id += 1;
+    live.count += 1;
+    data
+}
+
+/// Check the redzones of `data`, poison it and give its block back through `deallocate`
+///
+/// # Safety
+///
+/// `data` must come from [`alloc`] with the same `layout`.
+pub unsafe fn dealloc(data: *mut u8, layout: Layout, deallocate: impl FnOnce(*mut u8, Layout)) {
+    let header = &mut *header(data);
+    match header.magic {
+        ALIVE => {}
+        FREED => panic!("Double free of {:p}", data),
+        _ => panic!("Freeing {:p} which the heap never handed out", data),
+    }
+    let front = core::slice::from_raw_parts(data.sub(REDZONE), REDZONE);
+    let back = core::slice::from_raw_parts(data.add(layout.size()), REDZONE);
+    if !front.iter().chain(back).all(|e| *e == REDZONE_BYTE) {
+        log_allocation("Redzone overwritten", &allocation(header));
+        panic!(
+            "Heap corruption around {:p} ({} bytes)",
+            data,
+            layout.size()
+        );
+    }
+
+    let mut live = LIVE.lock();
+    match header.prev.as_mut() {
+        Some(prev) => prev.next = header.next,
+        None => live.head = header.next,
+    }
+    if let Some(next) = header.next.as_mut() {
+        next.prev = header.prev;
+    }
+    live.count -= 1;
+    drop(live);
+
+    header.magic = FREED;
+    ptr::write_bytes(data, POISON_BYTE, layout.size());
+    deallocate(data.sub(prefix(layout)), block_layout(layout));
+}
+
+fn allocation(header: &Header) -> Allocation {
+    let data = header as *const Header as usize + size_of::<Header>() + REDZONE;
+    Allocation {
+        address: data,
+        size: header.size,
+        id: header.id,
+        site: header.site,
+    }
+}
+
+/// Number the next allocation gets, allocations made from then on can be checked with [`outstanding`]
+pub fn mark() -> u64 {
+    LIVE.lock().next_id
+}
+
+/// Allocations made since `mark` that are still live, oldest first
+pub fn outstanding(mark: u64) -> Vec<Allocation> {
+    let (count, until) = {
+        let live = LIVE.lock();
+        (live.count, live.next_id)
+    };
+    // Allocating with the lock held would deadlock, the list is only read into room made before
+    let mut allocations = Vec::with_capacity(count);
+    let live = LIVE.lock();
+    let mut current = live.head;
+    while let Some(header) = unsafe { current.as_ref() } {
+        if (mark..until).contains(&header.id) && allocations.len() < allocations.capacity() {
+            allocations.push(allocation(header));
+        }
+        current = header.next;
+    }
+    drop(live);
+    allocations.reverse();
+    allocations
+}
+
+/// Log every allocation made since `mark` that is still live, returns how many there are
+pub fn dump(mark: u64) -> usize {
+    let allocations = outstanding(mark);
+    for allocation in &allocations {
+        log_allocation("Outstanding allocation", allocation);
+    }
+    allocations.len()
+}
+
+fn log_allocation(reason: &str, allocation: &Allocation) {
+    let mut trace = String::new();
+    write_frames(
+        &mut trace,
+        allocation.site.into_iter().take_while(|e| *e != 0),
+    );
+    log!(
+        Warning,
+        "{} #{} of {} bytes at {:#x}, allocated from:{}",
+        reason,
+        allocation.id,
+        allocation.size,
+        allocation.address,
+        trace
+    );
+}
@@ -0,0 +1,211 @@
+use core::alloc::Layout;
+use core::ops::Range;
+use core::ptr;
+
+use crate::memory::PAGE_SIZE;
+
+use super::slab::{CacheStats, SlabCache};
+
+/// Blocks of the page allocator go up to `PAGE_SIZE << MAX_ORDER`, 16MiB
+pub const MAX_ORDER: usize = 12;
+/// Object sizes of the slab caches, bigger allocations get blocks of pages
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Least the heap grows by at once
+const GROW_SIZE: usize = 0x100000;
+
+/// Buddy allocator of the mapped heap pages, each free block holds the address of the next one in its list
+///
+/// The heap starts aligned to the biggest block, so every block is aligned to its size
+pub struct PageAllocator {
+    free_lists: [*mut usize; MAX_ORDER + 1],
+    free: usize,
+}
+
+impl PageAllocator {
+    const fn new() -> PageAllocator {
+        PageAllocator {
+            free_lists: [ptr::null_mut(); MAX_ORDER + 1],
+            free: 0,
+        }
+    }
+
+    /// Order of the blocks holding `size` bytes, none past the biggest
+    fn order(size: usize) -> Option<usize> {
+        let pages = size.div_ceil(PAGE_SIZE as usize).next_power_of_two();
+        Some(pages.trailing_zeros() as usize).filter(|e| *e <= MAX_ORDER)
+    }
+
+    fn block_size(order: usize) -> usize {
+        (PAGE_SIZE as usize) << order
+    }
+
+    /// Block of at least `size` bytes aligned to its size, bigger blocks get split in halves to make one
+    pub unsafe fn allocate(&mut self, size: usize) -> Option<*mut u8> {
+        let order = Self::order(size)?;
+        let mut current = (order..=MAX_ORDER).find(|e| !self.free_lists[*e].is_null())?;
+        let block = self.pop(current);
+        while current > order {
+            current -= 1;
+            self.push(block + Self::block_size(current), current);
+        }
+        self.free -= Self::block_size(order);
+        Some(block as *mut u8)
+    }
+
+    /// Give back a block of `size` bytes, it's merged with its buddy for as long as that's free
+    pub unsafe fn deallocate(&mut self, block: *mut u8, size: usize) {
+        let mut order = Self::order(size).expect("Block bigger than any the heap hands out");
+        self.free += Self::block_size(order);
+        let mut block = block as usize;
+        while order < MAX_ORDER {
+            let buddy = block ^ Self::block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    /// Free bytes in blocks
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    /// Hand out the newly mapped `range`, in the biggest aligned blocks it can be cut in
+    unsafe fn add(&mut self, range: Range<usize>) {
+        let mut start = range.start;
+        while start < range.end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|e| {
+                    start % Self::block_size(*e) == 0 && start + Self::block_size(*e) <= range.end
+                })
+                .expect("Heap range isn't page aligned");
+            self.deallocate(start as *mut u8, Self::block_size(order));
+            start += Self::block_size(order);
+        }
+    }
+
+    unsafe fn push(&mut self, block: usize, order: usize) {
+        *(block as *mut usize) = self.free_lists[order] as usize;
+        self.free_lists[order] = block as *mut usize;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> usize {
+        let block = self.free_lists[order];
+        self.free_lists[order] = *block as *mut usize;
+        block as usize
+    }
+
+    /// Take `block` out of the free list of `order`, returns whether it was there
+    unsafe fn remove(&mut self, block: usize, order: usize) -> bool {
+        let mut previous = &mut self.free_lists[order] as *mut *mut usize as *mut usize;
+        let mut current = self.free_lists[order];
+        while !current.is_null() {
+            if current as usize == block {
+                *previous = *current;
+                return true;
+            }
+            previous = current;
+            current = *current as *mut usize;
+        }
+        false
+    }
+}
+
+/// Size of the heap and how much of it is free, with the statistics of every slab cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes mapped for the heap
+    pub size: usize,
+    /// Bytes in free page blocks, free slab objects aren't counted
+    pub free: usize,
+    pub caches: [CacheStats; SIZE_CLASSES.len()],
+}
+
+/// Slab caches for small objects on top of a page allocator, over a window of addresses that gets mapped as it grows
+pub struct Heap {
+    start: usize,
+    /// End of the mapped part of the window
+    end: usize,
+    limit: usize,
+    pages: PageAllocator,
+    caches: [SlabCache; SIZE_CLASSES.len()],
+}
+
+impl Heap {
+    /// Heap over `start..limit` with nothing mapped yet, `start` must be aligned to the biggest block
+    pub const fn new(start: usize, limit: usize) -> Heap {
+        let mut caches = [const { SlabCache::new(8) }; SIZE_CLASSES.len()];
+        let mut index = 0;
+        while index < SIZE_CLASSES.len() {
+            caches[index] = SlabCache::new(SIZE_CLASSES[index]);
+            index += 1;
+        }
+        Heap {
+            start,
+            end: start,
+            limit,
+            pages: PageAllocator::new(),
+            caches,
+        }
+    }
+
+    /// Cache the allocation goes to, none for allocations needing whole pages
+    fn cache(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|e| *e >= size)
+    }
+
+    /// Bytes to allocate from the page allocator for `layout`, a whole slab for the small ones
+    pub fn block_size(layout: Layout) -> usize {
+        match Self::cache(layout) {
+            Some(index) => SlabCache::new(SIZE_CLASSES[index]).slab_size(),
+            None => layout.size().max(layout.align()),
+        }
+    }
+
+    pub unsafe fn allocate(&mut self, layout: Layout) -> Option<*mut u8> {
+        match Self::cache(layout) {
+            Some(index) => self.caches[index].allocate(&mut self.pages),
+            None => self.pages.allocate(Self::block_size(layout)),
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match Self::cache(layout) {
+            Some(index) => self.caches[index].deallocate(ptr, &mut self.pages),
+            None => self.pages.deallocate(ptr, Self::block_size(layout)),
+        }
+    }
+
+    /// Range to map so a free block of `size` bytes exists, none once the window is full
+    pub fn grow_range(&self, size: usize) -> Option<Range<usize>> {
+        let size = PageAllocator::block_size(PageAllocator::order(size)?);
+        let end = (self.end.next_multiple_of(size) + size).max(self.end + GROW_SIZE);
+        Some(self.end..end).filter(|e| e.end <= self.limit)
+    }
+
+    /// Add the mapped `start..end` to the heap, it must start where the heap ends
+    pub unsafe fn extend(&mut self, end: usize) {
+        self.pages.add(self.end..end);
+        self.end = end;
+    }
+
+    /// Free bytes in page blocks
+    pub fn free(&self) -> usize {
+        self.pages.free()
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.end - self.start,
+            free: self.pages.free(),
+            caches: core::array::from_fn(|e| self.caches[e].stats()),
+        }
+    }
+}
+
+unsafe impl Send for Heap {}
@@ -0,0 +1,148 @@
+use core::mem::size_of;
+use core::ptr;
+
+use crate::memory::PAGE_SIZE;
+
+use super::heap::PageAllocator;
+
+/// Start of every slab, its objects follow
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    /// First free object, each one holds the address of the next
+    free: *mut usize,
+    in_use: usize,
+}
+
+/// Objects in use and free across the slabs of a cache, and the pages holding them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub in_use: usize,
+    pub free: usize,
+    pub pages: usize,
+}
+
+/// Objects of one size carved out of slabs, blocks of the page allocator aligned to their size
+pub struct SlabCache {
+    object_size: usize,
+    slab_size: usize,
+    /// Slabs with at least one free object, full ones aren't linked anywhere
+    partial: *mut SlabHeader,
+    slabs: usize,
+    in_use: usize,
+}
+
+impl SlabCache {
+    pub const fn new(object_size: usize) -> SlabCache {
+        let slab_size = object_size * 16;
+        SlabCache {
+            object_size,
+            slab_size: if slab_size < PAGE_SIZE as usize {
+                PAGE_SIZE as usize
+            } else {
+                slab_size
+            },
+            partial: ptr::null_mut(),
+            slabs: 0,
+            in_use: 0,
+        }
+    }
+
+    pub fn slab_size(&self) -> usize {
+        self.slab_size
+    }
+
+    /// Offset of the first object in a slab, objects are aligned to their size
+    fn first_object(&self) -> usize {
+        size_of::<SlabHeader>().next_multiple_of(self.object_size)
+    }
+
+    fn capacity(&self) -> usize {
+        (self.slab_size - self.first_object()) / self.object_size
+    }
+
+    /// Take a free object, a new slab comes from `pages` once every slab is full
+    pub unsafe fn allocate(&mut self, pages: &mut PageAllocator) -> Option<*mut u8> {
+        if self.partial.is_null() {
+            self.add_slab(pages)?;
+        }
+        let slab = &mut *self.partial;
+        let object = slab.free;
+        slab.free = *object as *mut usize;
+        slab.in_use += 1;
+        self.in_use += 1;
+        if slab.free.is_null() {
+            self.unlink(slab);
+        }
+        Some(object as *mut u8)
+    }
+
+    /// Give back `object`, its slab goes back to `pages` once empty unless it's the last one with room
+    pub unsafe fn deallocate(&mut self, object: *mut u8, pages: &mut PageAllocator) {
+        let slab = &mut *((object as usize & !(self.slab_size - 1)) as *mut SlabHeader);
+        if slab.free.is_null() {
+            self.link(slab);
+        }
+        let object = object as *mut usize;
+        *object = slab.free as usize;
+        slab.free = object;
+        slab.in_use -= 1;
+        self.in_use -= 1;
+        if slab.in_use == 0 && !(slab.prev.is_null() && slab.next.is_null()) {
+            self.unlink(slab);
+            self.slabs -= 1;
+            pages.deallocate(slab as *mut SlabHeader as *mut u8, self.slab_size);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            object_size: self.object_size,
+            in_use: self.in_use,
+            free: self.slabs * self.capacity() - self.in_use,
+            pages: self.slabs * self.slab_size / PAGE_SIZE as usize,
+        }
+    }
+
+    unsafe fn add_slab(&mut self, pages: &mut PageAllocator) -> Option<()> {
+        let start = pages.allocate(self.slab_size)? as usize;
+        let mut free = ptr::null_mut();
+        for index in (0..self.capacity()).rev() {
+            let object = (start + self.first_object() + index * self.object_size) as *mut usize;
+            *object = free as usize;
+            free = object;
+        }
+        let slab = start as *mut SlabHeader;
+        slab.write(SlabHeader {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        self.link(&mut *slab);
+        self.slabs += 1;
+        Some(())
+    }
+
+    unsafe fn link(&mut self, slab: &mut SlabHeader) {
+        slab.prev = ptr::null_mut();
+        slab.next = self.partial;
+        if let Some(next) = slab.next.as_mut() {
+            next.prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: &mut SlabHeader) {
+        match slab.prev.as_mut() {
+            Some(prev) => prev.next = slab.next,
+            None => self.partial = slab.next,
+        }
+        if let Some(next) = slab.next.as_mut() {
+            next.prev = slab.prev;
+        }
+        slab.prev = ptr::null_mut();
+        slab.next = ptr::null_mut();
+    }
+}
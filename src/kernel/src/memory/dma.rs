@@ -0,0 +1,265 @@
+use alloc::vec::Vec;
+use core::ptr::write_bytes;
+use core::slice;
+
+use x86_64::PhysAddr;
+
+use super::address_space::AddressSpaceError;
+use super::paging::{EntryFlags, Page};
+use super::{memory_controller, Frame, MemoryController, PAGE_SIZE};
+
+/// How the cpu caches a dma buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// Pci devices snoop the cache on x86, so this is fine for most of them
+    #[default]
+    WriteBack,
+    Uncached,
+    /// Writes get merged before reaching memory, reads are uncached
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn flags(self) -> EntryFlags {
+        match self {
+            Self::WriteBack => EntryFlags::empty(),
+            Self::Uncached => EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH,
+            Self::WriteCombining => EntryFlags::PAT,
+        }
+    }
+}
+
+/// What a device requires of the physical memory of a buffer
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+    /// Power of two the physical start is aligned to
+    pub align: u64,
+    /// Power of two the buffer must not cross, 0 for none
+    pub boundary: u64,
+    pub cache: CacheMode,
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self {
+            align: PAGE_SIZE,
+            boundary: 0,
+            cache: CacheMode::WriteBack,
+        }
+    }
+}
+
+/// Physically contiguous memory mapped in the virtual window, zeroed when allocated
+///
+/// Dropping it locks the memory controller.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    virt: u64,
+    phys: PhysAddr,
+    size: usize,
+    /// Buddy block the buffer was cut from
+    block: PhysAddr,
+    block_size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocate at least `size` bytes, rounded up to whole pages
+    pub fn new(size: usize, constraints: DmaConstraints) -> Result<DmaBuffer, AddressSpaceError> {
+        memory_controller().lock().alloc_dma(size, constraints)
+    }
+
+    /// Uncached buffer whose physical start is `align` aligned, device style mappings of it agree on the cache mode
+    pub fn uncached(size: usize, align: u64) -> Result<DmaBuffer, AddressSpaceError> {
+        let constraints = DmaConstraints {
+            align,
+            cache: CacheMode::Uncached,
+            ..Default::default()
+        };
+        Self::new(size, constraints)
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt(&self) -> u64 {
+        self.virt
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt as *mut T
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut controller = memory_controller().lock();
+        controller.unmap_addr(self.virt, self.size as u64);
+        controller
+            .free_virt(self.virt)
+            .expect("DmaBuffer outlived its virtual range");
+        controller.physical_dealloc(self.block, self.block_size);
+    }
+}
+
+/// Physically contiguous piece of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaSegment {
+    pub phys: PhysAddr,
+    pub len: usize,
+}
+
+impl DmaSegment {
+    pub fn end(&self) -> PhysAddr {
+        self.phys + self.len as u64
+    }
+}
+
+/// Physical pieces of a transfer in order, cut to what one descriptor of the device can hold
+#[derive(Debug, Clone)]
+pub struct ScatterList {
+    segments: Vec<DmaSegment>,
+    max_segment: usize,
+    /// Power of two no segment crosses, 0 for none
+    boundary: u64,
+}
+
+impl ScatterList {
+    pub fn new(max_segment: usize, boundary: u64) -> ScatterList {
+        assert!(max_segment > 0 && (boundary == 0 || boundary.is_power_of_two()));
+        ScatterList {
+            segments: Vec::new(),
+            max_segment,
+            boundary,
+        }
+    }
+
+    /// Append `len` bytes from `phys`, merged into the last segment when they follow it
+    pub fn push(&mut self, mut phys: PhysAddr, mut len: usize) {
+        while len > 0 {
+            let at_boundary = self.boundary != 0 && phys.as_u64() % self.boundary == 0;
+            let last = self
+                .segments
+                .last_mut()
+                .filter(|e| e.end() == phys && e.len < self.max_segment && !at_boundary);
+            let mut room = self.max_segment - last.as_ref().map_or(0, |e| e.len);
+            if self.boundary != 0 {
+                room = room.min((self.boundary - phys.as_u64() % self.boundary) as usize);
+            }
+            let piece = len.min(room);
+            match last {
+                Some(last) => last.len += piece,
+                None => self.segments.push(DmaSegment { phys, len: piece }),
+            }
+            phys += piece as u64;
+            len -= piece;
+        }
+    }
+
+    pub fn push_buffer(&mut self, buffer: &DmaBuffer, len: usize) {
+        assert!(len <= buffer.size());
+        self.push(buffer.phys(), len);
+    }
+
+    pub fn segments(&self) -> &[DmaSegment] {
+        &self.segments
+    }
+
+    /// Bytes in all segments
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|e| e.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+impl<const ORDER: usize> MemoryController<ORDER> {
+    /// Physical memory meeting `constraints` mapped in the virtual window, see [`DmaBuffer::new`]
+    pub fn alloc_dma(
+        &mut self,
+        size: usize,
+        constraints: DmaConstraints,
+    ) -> Result<DmaBuffer, AddressSpaceError> {
+        let DmaConstraints {
+            align,
+            boundary,
+            cache,
+        } = constraints;
+        let size = size.next_multiple_of(PAGE_SIZE as usize);
+        if size == 0
+            || !align.is_power_of_two()
+            || (boundary != 0 && (!boundary.is_power_of_two() || size as u64 > boundary))
+        {
+            return Err(AddressSpaceError::InvalidRange(size as u64));
+        }
+        // A block aligned to its own size can't cross a bigger power of two
+        let align = match boundary {
+            0 => align,
+            _ => align.max(size.next_power_of_two() as u64),
+        }
+        .max(PAGE_SIZE);
+        let (block, block_size) = self.alloc_aligned(size, align)?;
+        let phys = PhysAddr::new(block.as_u64().next_multiple_of(align));
+        let virt = match self.alloc_virt(size as u64, PAGE_SIZE) {
+            Ok(virt) => virt,
+            Err(err) => {
+                self.physical_dealloc(block, block_size);
+                return Err(err);
+            }
+        };
+        // 4KiB pages only, the pat bit means something else in huge ones
+        let flags =
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE | cache.flags();
+        for offset in (0..size as u64).step_by(PAGE_SIZE as usize) {
+            self.map_to(
+                Page::containing_address(virt + offset),
+                Frame::containing_address(phys.as_u64() + offset),
+                flags,
+            );
+        }
+        unsafe { write_bytes(virt as *mut u8, 0, size) };
+        Ok(DmaBuffer {
+            virt,
+            phys,
+            size,
+            block,
+            block_size,
+        })
+    }
+
+    /// Buddy block holding `size` bytes from an `align` aligned address
+    ///
+    /// Blocks are only aligned to [`super::MAX_ALIGN`], a block twice as big always has room once aligned.
+    fn alloc_aligned(
+        &mut self,
+        size: usize,
+        align: u64,
+    ) -> Result<(PhysAddr, usize), AddressSpaceError> {
+        let block_size = size.next_power_of_two().max(align as usize);
+        let block = self
+            .physical_alloc(block_size)
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        if block.is_aligned(align) {
+            return Ok((block, block_size));
+        }
+        self.physical_dealloc(block, block_size);
+        let block = self
+            .physical_alloc(block_size * 2)
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        Ok((block, block_size * 2))
+    }
+}
@@ -1,13 +1,14 @@
 pub use self::entry::*;
 use self::mapper::Mapper;
 use self::table::{Level4, Table};
-use self::temporary_page::TemporaryPage;
+pub use self::temporary_page::TemporaryPage;
 use crate::memory::{Frame, FrameAllocator, PAGE_SIZE};
 use crate::BootInformation;
+use core::arch::x86_64::__cpuid;
 use core::fmt::Display;
-use core::ops::{Add, Deref, DerefMut};
+use core::ops::{Add, Deref, DerefMut, Range};
 use core::ptr::Unique;
-use elf_rs::{ElfFile, SectionHeaderEntry, SectionHeaderFlags};
+use elf_rs::{ElfFile, ProgramHeaderFlags, SectionHeaderEntry, SectionHeaderFlags};
 use uefi::table::boot::MemoryDescriptor;
 use x86_64::registers::control::{self, Cr3, Cr3Flags};
 use x86_64::structures::paging::PhysFrame;
@@ -19,9 +20,11 @@ mod table;
 mod temporary_page;
 
 const ENTRY_COUNT: u64 = 512;
+/// P4 entries private to an address space, the others map the kernel and are shared
+pub const USER_P4_ENTRIES: Range<usize> = 1..256;
 
 bitflags! {
-    #[derive(Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct EntryFlags: u64 {
         const PRESENT =         1 << 0;
         const WRITABLE =        1 << 1;
@@ -31,6 +34,8 @@ bitflags! {
         const ACCESSED =        1 << 5;
         const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
+        /// Same bit as HUGE_PAGE, in a 4KiB page it picks the upper half of the pat
+        const PAT =             1 << 7;
         const GLOBAL =          1 << 8;
         const NO_EXECUTE =      1 << 63;
     }
@@ -52,6 +57,19 @@ impl EntryFlags {
 
         flags
     }
+
+    pub fn from_elf_program_flags(program_flags: ProgramHeaderFlags) -> EntryFlags {
+        let mut flags = EntryFlags::PRESENT;
+
+        if program_flags.contains(ProgramHeaderFlags::WRITE) {
+            flags |= EntryFlags::WRITABLE;
+        }
+        if !program_flags.contains(ProgramHeaderFlags::EXECUTE) {
+            flags |= EntryFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
 }
 
 impl Display for EntryFlags {
@@ -93,6 +111,37 @@ impl Page {
         PageIter { start, end }
     }
 }
+/// Memory covered by a single mapping, huge pages are mapped straight from a p2 or p3 entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            Self::Size4KiB => PAGE_SIZE,
+            Self::Size2MiB => PAGE_SIZE * ENTRY_COUNT,
+            Self::Size1GiB => PAGE_SIZE * ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+
+    /// Number of 4KiB pages it covers
+    pub const fn pages(self) -> u64 {
+        self.bytes() / PAGE_SIZE
+    }
+
+    /// Whether the cpu can map it, 1GiB pages need the pdpe1gb feature
+    pub fn supported(self) -> bool {
+        match self {
+            Self::Size1GiB => unsafe { __cpuid(0x80000001).edx & (1 << 26) != 0 },
+            _ => true,
+        }
+    }
+}
+
 impl Add<u64> for Page {
     type Output = Page;
 
@@ -288,9 +337,42 @@ impl InactivePageTable {
         InactivePageTable { p4_frame: frame }
     }
 
+    /// Table sharing the kernel p4 entries of the active table, its user entries start empty
+    ///
+    /// `scratch` is an unused kernel page the new table is mapped at while it's filled in
+    pub fn new_sharing_kernel<A>(
+        frame: Frame,
+        active_table: &mut ActivePageTable,
+        scratch: Page,
+        allocator: &mut A,
+    ) -> InactivePageTable
+    where
+        A: FrameAllocator,
+    {
+        active_table.map_to(scratch, frame.clone(), EntryFlags::WRITABLE, allocator);
+        {
+            let table = unsafe { &mut *(scratch.start_address() as *mut Table<Level4>) };
+            table.zero();
+            for index in (0..ENTRY_COUNT as usize).filter(|e| !USER_P4_ENTRIES.contains(e)) {
+                let entry = &active_table.p4()[index];
+                if let Some(frame) = entry.pointed_frame() {
+                    table[index].set(frame, entry.flags());
+                }
+            }
+            table[511].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        }
+        active_table.unmap_addr(scratch);
+
+        InactivePageTable { p4_frame: frame }
+    }
+
     pub unsafe fn from_raw_frame(frame: Frame) -> InactivePageTable {
         return InactivePageTable { p4_frame: frame };
     }
+
+    pub fn p4_frame(&self) -> Frame {
+        self.p4_frame.clone()
+    }
 }
 
 pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
@@ -1,10 +1,18 @@
 use x86_64::{PhysAddr, VirtAddr};
 
-use super::table::{self, Level4, Table};
-use super::{EntryFlags, Page, ENTRY_COUNT};
-use crate::memory::{Frame, FrameAllocator, PAGE_SIZE};
+use super::table::{self, Level1, Level4, Table};
+use super::{Entry, EntryFlags, Page, PageSize, ENTRY_COUNT};
+use crate::inline_if;
+use crate::memory::{Frame, FrameAllocator, PAGE_SIZE, SPLIT_PAGE};
+use crate::smp::tlb::shootdown;
+use alloc::vec::Vec;
+use core::ops::Range;
 use core::ptr::Unique;
 
+/// Page tables reached through the recursive entry of the active p4
+///
+/// Unmapping ranges and splitting pages shoot the stale entries down on every online cpu, they all run on the
+/// same kernel tables.
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
 }
@@ -24,6 +32,15 @@ impl Mapper {
         unsafe { self.p4.as_mut() }
     }
 
+    /// Make p4 entry `index` point to a table, address spaces created from then on share it
+    pub fn create_p4_entry<A>(&mut self, index: usize, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        self.p4_mut()
+            .next_table_create(index as u64, false, allocator);
+    }
+
     pub fn translate(&self, virtual_address: VirtAddr) -> Option<PhysAddr> {
         let offset = virtual_address.as_u64() % PAGE_SIZE;
         return self
@@ -32,56 +49,185 @@ impl Mapper {
     }
 
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
-
-        let huge_page = || {
-            p3.and_then(|p3| {
-                let p3_entry = &p3[page.p3_index() as usize];
-                // 1GiB page?
-                if let Some(start_frame) = p3_entry.pointed_frame() {
-                    if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
-                        // address must be 1GiB aligned
-                        assert!(start_frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0);
-                        return Some(Frame {
-                            number: start_frame.number
-                                + page.p2_index() * ENTRY_COUNT
-                                + page.p1_index(),
-                        });
-                    }
-                }
-                if let Some(p2) = p3.next_table(page.p3_index()) {
-                    let p2_entry = &p2[page.p2_index() as usize];
-                    if let Some(start_frame) = p2_entry.pointed_frame() {
-                        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
-                            assert!(start_frame.number % ENTRY_COUNT == 0);
-                            return Some(Frame {
-                                number: start_frame.number + page.p1_index(),
-                            });
+        let (entry, size) = self.leaf(page)?;
+        entry.pointed_frame().map(|start| Frame {
+            number: start.number + page.number % size.pages(),
+        })
+    }
+
+    /// Entry mapping `page` and the size of the page it maps, huge pages stop the walk early
+    fn leaf(&self, page: Page) -> Option<(&Entry, PageSize)> {
+        let huge = |entry: &Entry| {
+            entry
+                .flags()
+                .contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE)
+        };
+        let p3 = self.p4().next_table(page.p4_index())?;
+        let entry = &p3[page.p3_index() as usize];
+        if huge(entry) {
+            return Some((entry, PageSize::Size1GiB));
+        }
+        let p2 = p3.next_table(page.p3_index())?;
+        let entry = &p2[page.p2_index() as usize];
+        if huge(entry) {
+            return Some((entry, PageSize::Size2MiB));
+        }
+        let p1 = p2.next_table(page.p2_index())?;
+        Some((&p1[page.p1_index() as usize], PageSize::Size4KiB))
+            .filter(|(entry, _)| entry.flags().contains(EntryFlags::PRESENT))
+    }
+
+    fn leaf_mut(&mut self, page: Page) -> Option<(&mut Entry, PageSize)> {
+        let size = self.page_size(page)?;
+        let p3 = self.p4_mut().next_table_mut(page.p4_index())?;
+        if size == PageSize::Size1GiB {
+            return Some((&mut p3[page.p3_index() as usize], size));
+        }
+        let p2 = p3.next_table_mut(page.p3_index())?;
+        if size == PageSize::Size2MiB {
+            return Some((&mut p2[page.p2_index() as usize], size));
+        }
+        let p1 = p2.next_table_mut(page.p2_index())?;
+        Some((&mut p1[page.p1_index() as usize], size))
+    }
+
+    /// Size of the page mapping `page`
+    pub fn page_size(&self, page: Page) -> Option<PageSize> {
+        self.leaf(page).map(|(_, size)| size)
+    }
+
+    /// Flags of the entry mapping `page`, huge pages keep [`EntryFlags::HUGE_PAGE`]
+    pub fn page_flags(&self, page: Page) -> Option<EntryFlags> {
+        self.leaf(page).map(|(entry, _)| entry.flags())
+    }
+
+    /// Replace the flags of the entry mapping `page`, returns whether it was mapped
+    ///
+    /// A huge page changes as a whole, split it first to change part of it.
+    pub fn set_flags(&mut self, page: Page, flags: EntryFlags) -> bool {
+        use x86_64::instructions::tlb;
+
+        let Some((entry, size)) = self.leaf_mut(page) else {
+            return false;
+        };
+        let huge = inline_if!(
+            size == PageSize::Size4KiB,
+            EntryFlags::empty(),
+            EntryFlags::HUGE_PAGE
+        );
+        let frame = entry.pointed_frame().unwrap();
+        entry.set(frame, flags | huge | EntryFlags::PRESENT);
+        tlb::flush(VirtAddr::new(page.start_address()));
+        true
+    }
+
+    /// Every 4KiB page mapped below the p4 entries in `range`, with its frame and flags
+    pub fn mapped_pages(&self, range: Range<usize>) -> Vec<(Page, Frame, EntryFlags)> {
+        let mut pages = Vec::new();
+        for p4_index in range {
+            let Some(p3) = self.p4().next_table(p4_index as u64) else {
+                continue;
+            };
+            for p3_index in 0..ENTRY_COUNT {
+                let Some(p2) = p3.next_table(p3_index) else {
+                    continue;
+                };
+                for p2_index in 0..ENTRY_COUNT {
+                    let Some(p1) = p2.next_table(p2_index) else {
+                        continue;
+                    };
+                    for p1_index in 0..ENTRY_COUNT {
+                        let entry = &p1[p1_index as usize];
+                        if let Some(frame) = entry.pointed_frame() {
+                            let address = (p4_index as u64) << 39
+                                | p3_index << 30
+                                | p2_index << 21
+                                | p1_index << 12;
+                            pages.push((Page::containing_address(address), frame, entry.flags()));
                         }
                     }
                 }
-                None
-            })
-        };
-
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| p1[page.p1_index() as usize].pointed_frame())
-            .or_else(huge_page)
+            }
+        }
+        pages
     }
 
     pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
     where
         A: FrameAllocator,
     {
+        let user = flags.contains(EntryFlags::USER_ACCESSIBLE);
         let p4 = self.p4_mut();
-        let p3 = p4.next_table_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_create(page.p2_index(), allocator);
+        let p3 = p4.next_table_create(page.p4_index(), user, allocator);
+        let p2 = p3.next_table_create(page.p3_index(), user, allocator);
+        let p1 = p2.next_table_create(page.p2_index(), user, allocator);
 
         assert!(p1[page.p1_index() as usize].is_unused());
         p1[page.p1_index() as usize].set(frame, flags | EntryFlags::PRESENT);
     }
+
+    /// Map a page of `size` straight from its p2 or p3 entry, both addresses must be aligned to it
+    pub fn map_to_size<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        size: PageSize,
+        flags: EntryFlags,
+        allocator: &mut A,
+    ) where
+        A: FrameAllocator,
+    {
+        assert!(
+            page.start_address() % size.bytes() == 0
+                && frame.start_address().as_u64() % size.bytes() == 0,
+            "{:?} page at {:#x} isn't aligned",
+            size,
+            page.start_address()
+        );
+        if size == PageSize::Size4KiB {
+            return self.map_to(page, frame, flags, allocator);
+        }
+        let user = flags.contains(EntryFlags::USER_ACCESSIBLE);
+        let p3 = self
+            .p4_mut()
+            .next_table_create(page.p4_index(), user, allocator);
+        let entry = match size {
+            PageSize::Size4KiB => unreachable!(),
+            PageSize::Size2MiB => &mut p3.next_table_create(page.p3_index(), user, allocator)
+                [page.p2_index() as usize],
+            PageSize::Size1GiB => &mut p3[page.p3_index() as usize],
+        };
+        assert!(entry.is_unused());
+        entry.set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+    }
+
+    /// Biggest page mapping `page` to `frame` that fits in `size` bytes and doesn't overlap a mapping
+    pub fn largest_page_size(&self, page: Page, frame: Frame, size: u64) -> PageSize {
+        [PageSize::Size1GiB, PageSize::Size2MiB]
+            .into_iter()
+            .find(|e| {
+                e.supported()
+                    && size >= e.bytes()
+                    && page.start_address() % e.bytes() == 0
+                    && frame.start_address().as_u64() % e.bytes() == 0
+                    && self.is_unmapped(page, *e)
+            })
+            .unwrap_or(PageSize::Size4KiB)
+    }
+
+    /// Whether the huge page of `size` at `page` would overlap nothing
+    fn is_unmapped(&self, page: Page, size: PageSize) -> bool {
+        let Some(p3) = self.p4().next_table(page.p4_index()) else {
+            return true;
+        };
+        let entry = &p3[page.p3_index() as usize];
+        if size == PageSize::Size1GiB || entry.is_unused() {
+            return entry.is_unused();
+        }
+        p3.next_table(page.p3_index())
+            .is_some_and(|p2| p2[page.p2_index() as usize].is_unused())
+    }
+
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
     where
         A: FrameAllocator,
@@ -110,17 +256,126 @@ impl Mapper {
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
+            .expect("huge pages are unmapped through unmap_range");
         let frame = p1[page.p1_index() as usize].pointed_frame().unwrap();
         p1[page.p1_index() as usize].set_unused();
         tlb::flush(VirtAddr::new(page.start_address() as u64));
         frame
     }
 
+    /// Unmap `start..=end` without freeing its frames, huge pages partly inside it get split first
+    pub fn unmap_range<A>(&mut self, start: Page, end: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+
+        let mut page = start;
+        while page <= end {
+            let size = self
+                .page_size(page)
+                .expect("unmapping a page that isn't mapped");
+            if size == PageSize::Size4KiB {
+                self.unmap_addr(page);
+                page = page + 1;
+            } else if page.number % size.pages() == 0
+                && page.number + size.pages() - 1 <= end.number
+            {
+                self.leaf_mut(page).unwrap().0.set_unused();
+                tlb::flush(VirtAddr::new(page.start_address()));
+                page = page + size.pages();
+            } else {
+                self.split(page, size, allocator);
+            }
+        }
+        shootdown(Some(start.start_address()..end.start_address() + PAGE_SIZE));
+    }
+
+    /// Split the huge page of `size` containing `page` into pages of the next size down
+    ///
+    /// The new table is filled in at [`SPLIT_PAGE`] before it replaces the huge page, so the range stays mapped
+    /// throughout. Callers must hold the memory controller, nothing else may use that page.
+    pub fn split<A>(&mut self, page: Page, size: PageSize, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let (entry, found) = self.leaf(page).expect("splitting a page that isn't mapped");
+        assert!(found == size, "splitting a {:?} page as {:?}", found, size);
+        let flags = entry.flags();
+        let start = entry.pointed_frame().unwrap();
+        let (child_size, child_flags) = match size {
+            PageSize::Size1GiB => (PageSize::Size2MiB, flags),
+            // Bit 7 is the PAT bit of a 4KiB entry
+            PageSize::Size2MiB => (PageSize::Size4KiB, flags - EntryFlags::HUGE_PAGE),
+            PageSize::Size4KiB => panic!("4KiB pages can't be split"),
+        };
+
+        let frame = allocator.allocate_frame().expect("no frames available");
+        let split_page = Page::containing_address(SPLIT_PAGE);
+        self.map_to(
+            split_page,
+            frame.clone(),
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            allocator,
+        );
+        let table = unsafe { &mut *(split_page.start_address() as *mut Table<Level1>) };
+        for index in 0..ENTRY_COUNT {
+            let frame = Frame {
+                number: start.number + index * child_size.pages(),
+            };
+            table[index as usize].set(frame, child_flags);
+        }
+        self.unmap_addr(split_page);
+
+        let (entry, _) = self.leaf_mut(page).unwrap();
+        entry.set(
+            frame,
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | (flags & EntryFlags::USER_ACCESSIBLE),
+        );
+        shootdown(None);
+    }
+
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
     where
         A: FrameAllocator,
     {
         allocator.deallocate_frame(self.unmap_addr(page));
     }
+
+    /// Unmap everything below the p4 entries in `range` and free its frames, page tables included
+    pub fn free_p4_entries<A>(&mut self, range: Range<usize>, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+
+        for p4_index in range {
+            let Some(p3) = self.p4_mut().next_table_mut(p4_index as u64) else {
+                continue;
+            };
+            for p3_index in 0..ENTRY_COUNT {
+                let Some(p2) = p3.next_table_mut(p3_index) else {
+                    continue;
+                };
+                for p2_index in 0..ENTRY_COUNT {
+                    let Some(p1) = p2.next_table_mut(p2_index) else {
+                        continue;
+                    };
+                    for p1_index in 0..ENTRY_COUNT as usize {
+                        if let Some(frame) = p1[p1_index].pointed_frame() {
+                            allocator.deallocate_frame(frame);
+                        }
+                    }
+                    allocator.deallocate_frame(p2[p2_index as usize].pointed_frame().unwrap());
+                    p2[p2_index as usize].set_unused();
+                }
+                allocator.deallocate_frame(p3[p3_index as usize].pointed_frame().unwrap());
+                p3[p3_index as usize].set_unused();
+            }
+            let p4 = self.p4_mut();
+            allocator.deallocate_frame(p4[p4_index].pointed_frame().unwrap());
+            p4[p4_index].set_unused();
+        }
+        tlb::flush_all();
+    }
 }
@@ -1,3 +1,4 @@
+use crate::inline_if;
 use crate::memory::FrameAllocator;
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
@@ -47,24 +48,33 @@ where
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
+    /// Table behind `index`, created if needed, `user` makes the path reachable from ring 3
     pub fn next_table_create<A>(
         &mut self,
         index: u64,
+        user: bool,
         allocator: &mut A,
     ) -> &mut Table<L::NextLevel>
     where
         A: FrameAllocator,
     {
+        let user_flag = inline_if!(user, EntryFlags::USER_ACCESSIBLE, EntryFlags::empty());
+        assert!(
+            !self.entries[index as usize]
+                .flags()
+                .contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE),
+            "mapping inside a huge page, split it first"
+        );
         if self.next_table(index).is_none() {
-            assert!(
-                !self.entries[index as usize]
-                    .flags()
-                    .contains(EntryFlags::HUGE_PAGE),
-                "mapping code does not support huge pages"
-            );
             let frame = allocator.allocate_frame().expect("no frames available");
-            self.entries[index as usize].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            self.entries[index as usize].set(
+                frame,
+                EntryFlags::PRESENT | EntryFlags::WRITABLE | user_flag,
+            );
             self.next_table_mut(index).unwrap().zero();
+        } else if !self.entries[index as usize].flags().contains(user_flag) {
+            let entry = &mut self.entries[index as usize];
+            entry.set(entry.pointed_frame().unwrap(), entry.flags() | user_flag);
         }
         self.next_table_mut(index).unwrap()
     }
@@ -0,0 +1,252 @@
+use core::fmt::Display;
+use core::mem::size_of;
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use common::boot::BootInformation;
+use uefi::table::boot::{MemoryDescriptor, MemoryMap, MemoryType};
+
+use crate::inline_if;
+
+use super::PAGE_SIZE;
+
+/// Most ranges that can be reserved, the map is built before there's a heap
+const MAX_RESERVATIONS: usize = 16;
+/// Memory below 1M is left alone, application processors start in real mode from there
+const LOW_MEMORY_END: u64 = 0x100000;
+
+/// What a physical range is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    /// Free or handed out by the buddy allocator
+    Allocator,
+    /// Loaded kernel image, the initrd programs are embedded in it
+    Kernel,
+    /// Kernel elf file the bootloader read, the symbols are looked up in it
+    KernelFile,
+    BootInfo,
+    /// UEFI memory map the boot information points to
+    MemoryMap,
+    Font,
+    /// ACPI tables and firmware storage
+    Acpi,
+    /// UEFI runtime services
+    Firmware,
+    LowMemory,
+    /// Used by the bootloader or the boot services and not reclaimed yet
+    Bootloader,
+    /// Not memory the kernel can use, holes and MMIO
+    Unusable,
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Allocator => write!(f, "Allocator"),
+            Self::Kernel => write!(f, "Kernel"),
+            Self::KernelFile => write!(f, "Kernel file"),
+            Self::BootInfo => write!(f, "Boot information"),
+            Self::MemoryMap => write!(f, "Memory map"),
+            Self::Font => write!(f, "Font"),
+            Self::Acpi => write!(f, "ACPI"),
+            Self::Firmware => write!(f, "Firmware"),
+            Self::LowMemory => write!(f, "Low memory"),
+            Self::Bootloader => write!(f, "Bootloader"),
+            Self::Unusable => write!(f, "Unusable"),
+        }
+    }
+}
+
+/// Physical range of a single memory type and owner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalRange {
+    pub start: u64,
+    pub end: u64,
+    pub ty: MemoryType,
+    pub owner: Owner,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reservation {
+    start: u64,
+    end: u64,
+    owner: Owner,
+}
+
+/// Memory map of the firmware with the ranges the allocator must never get, by start address
+pub struct PhysicalMap {
+    memory_map: &'static MemoryMap<'static>,
+    reservations: [Reservation; MAX_RESERVATIONS],
+    count: usize,
+    /// Whether the bootloader memory went to the allocator
+    reclaimed: bool,
+}
+
+impl PhysicalMap {
+    /// Reserve everything the boot information points to, which the bootloader left in its own memory
+    pub fn new(boot_info: &'static BootInformation) -> PhysicalMap {
+        let mut map = PhysicalMap {
+            memory_map: boot_info.memory_map(),
+            reservations: [Reservation {
+                start: 0,
+                end: 0,
+                owner: Owner::Unusable,
+            }; MAX_RESERVATIONS],
+            count: 0,
+            reclaimed: false,
+        };
+        map.reserve(0, LOW_MEMORY_END, Owner::LowMemory);
+        map.reserve(
+            boot_info.kernel_start(),
+            boot_info.kernel_start() + boot_info.kernel_size() as u64,
+            Owner::Kernel,
+        );
+        let kernel_file = boot_info.elf_section().content();
+        map.reserve(
+            kernel_file.as_ptr() as u64,
+            kernel_file.as_ptr() as u64 + kernel_file.len() as u64,
+            Owner::KernelFile,
+        );
+        let boot_info_start = boot_info as *const BootInformation as u64;
+        map.reserve(
+            boot_info_start,
+            boot_info_start + size_of::<BootInformation>() as u64,
+            Owner::BootInfo,
+        );
+        let entries = || boot_info.memory_map().entries();
+        let first = entries().next().unwrap() as *const MemoryDescriptor as u64;
+        let last = entries().last().unwrap() as *const MemoryDescriptor as u64;
+        map.reserve(
+            first,
+            last + size_of::<MemoryDescriptor>() as u64,
+            Owner::MemoryMap,
+        );
+        if let Some(font) = boot_info.font_addr() {
+            map.reserve(font, font + boot_info.font_size() as u64, Owner::Font);
+        }
+        map
+    }
+
+    /// Keep the pages covering `start..end` away from the allocator
+    fn reserve(&mut self, start: u64, end: u64, owner: Owner) {
+        assert!(
+            self.count < MAX_RESERVATIONS,
+            "Too many memory reservations"
+        );
+        let reservation = Reservation {
+            start: start - start % PAGE_SIZE,
+            end: end.next_multiple_of(PAGE_SIZE),
+            owner,
+        };
+        let index = self
+            .reservations()
+            .partition_point(|e| e.start < reservation.start);
+        self.reservations.copy_within(index..self.count, index + 1);
+        self.reservations[index] = reservation;
+        self.count += 1;
+    }
+
+    /// Drop the reservations of `owner`, returns the range the last one covered
+    pub fn release(&mut self, owner: Owner) -> Option<Range<u64>> {
+        let mut released = None;
+        while let Some(index) = self.reservations().iter().position(|e| e.owner == owner) {
+            released = Some(self.reservations[index].start..self.reservations[index].end);
+            self.reservations.copy_within(index + 1..self.count, index);
+            self.count -= 1;
+        }
+        released
+    }
+
+    /// Whether `address` is in a reservation
+    pub fn is_reserved(&self, address: u64) -> bool {
+        self.reservations()
+            .iter()
+            .any(|e| (e.start..e.end).contains(&address))
+    }
+
+    fn reservations(&self) -> &[Reservation] {
+        &self.reservations[..self.count]
+    }
+
+    /// Owner of memory of type `ty` outside of the reservations
+    fn owner_of(&self, ty: MemoryType) -> Owner {
+        match ty {
+            MemoryType::CONVENTIONAL => Owner::Allocator,
+            MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA => {
+                inline_if!(self.reclaimed, Owner::Allocator, Owner::Bootloader)
+            }
+            MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE => Owner::Acpi,
+            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => {
+                Owner::Firmware
+            }
+            _ => Owner::Unusable,
+        }
+    }
+
+    /// Call `f` with the parts of the memory map of the types `types` outside of the reservations
+    pub fn for_each_free(&self, types: &[MemoryType], mut f: impl FnMut(Range<u64>)) {
+        for entry in self.memory_map.entries().filter(|e| types.contains(&e.ty)) {
+            let end = entry.phys_start + entry.page_count * PAGE_SIZE;
+            let mut start = entry.phys_start;
+            for reservation in self.reservations() {
+                if reservation.end <= start || reservation.start >= end {
+                    continue;
+                }
+                if reservation.start > start {
+                    f(start..reservation.start);
+                }
+                start = start.max(reservation.end);
+            }
+            if start < end {
+                f(start..end);
+            }
+        }
+    }
+
+    /// Every range of the memory map by address, split where its owner changes
+    pub fn ranges(&self) -> Vec<PhysicalRange> {
+        let mut ranges = Vec::new();
+        let mut entries: Vec<&MemoryDescriptor> = self.memory_map.entries().collect();
+        entries.sort_unstable_by_key(|e| e.phys_start);
+        for entry in entries {
+            let end = entry.phys_start + entry.page_count * PAGE_SIZE;
+            let mut push = |start: u64, end: u64, owner: Owner| {
+                ranges.push(PhysicalRange {
+                    start,
+                    end,
+                    ty: entry.ty,
+                    owner,
+                })
+            };
+            let mut start = entry.phys_start;
+            for reservation in self.reservations() {
+                if reservation.end <= start || reservation.start >= end {
+                    continue;
+                }
+                if reservation.start > start {
+                    push(start, reservation.start, self.owner_of(entry.ty));
+                    start = reservation.start;
+                }
+                push(start, reservation.end.min(end), reservation.owner);
+                start = reservation.end.min(end);
+            }
+            if start < end {
+                push(start, end, self.owner_of(entry.ty));
+            }
+        }
+        ranges
+    }
+
+    pub fn reclaimed(&self) -> bool {
+        self.reclaimed
+    }
+
+    pub(super) fn set_reclaimed(&mut self) {
+        self.reclaimed = true;
+    }
+}
+
+unsafe impl Send for PhysicalMap {}
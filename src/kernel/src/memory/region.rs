@@ -0,0 +1,320 @@
+use core::ops::Range;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+use crate::inline_if;
+
+use super::address_space::AddressSpaceError;
+use super::paging::{EntryFlags, Page, PageIter};
+use super::{Frame, FrameAllocator, MemoryController, SharedFrames, PAGE_SIZE};
+
+/// What the pages of an area get filled with when they're first used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames
+    Anonymous,
+    /// The physical range starting at the address, its frames are never freed with the area
+    Physical(PhysAddr),
+    /// A private copy of `data` from `offset` on, zeroed past its end
+    File { data: &'static [u8], offset: u64 },
+}
+
+impl Backing {
+    /// Backing of the part of an area starting `offset` bytes into it
+    fn advance(self, offset: u64) -> Backing {
+        match self {
+            Self::Anonymous => Self::Anonymous,
+            Self::Physical(start) => Self::Physical(start + offset),
+            Self::File {
+                data,
+                offset: start,
+            } => Self::File {
+                data,
+                offset: start + offset,
+            },
+        }
+    }
+}
+
+/// Page aligned range of an address space valid to access with the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub start: u64,
+    pub end: u64,
+    /// Flags the pages get mapped with
+    pub flags: EntryFlags,
+    pub backing: Backing,
+}
+
+impl Area {
+    pub fn contains(&self, address: u64) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    pub fn pages(&self) -> PageIter {
+        Page::range_inclusive(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end - 1),
+        )
+    }
+
+    /// The parts before and from `at`, which must be a page boundary inside the area
+    fn split_at(&self, at: u64) -> (Area, Area) {
+        let low = Area { end: at, ..*self };
+        let high = Area {
+            start: at,
+            backing: self.backing.advance(at - self.start),
+            ..*self
+        };
+        (low, high)
+    }
+}
+
+/// Non overlapping areas inside a window of virtual addresses, by start address
+#[derive(Debug, Clone)]
+pub struct Regions {
+    window: Range<u64>,
+    areas: BTreeMap<u64, Area>,
+}
+
+impl Regions {
+    pub const fn new(window: Range<u64>) -> Regions {
+        Regions {
+            window,
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn window(&self) -> Range<u64> {
+        self.window.clone()
+    }
+
+    /// Area containing `address`
+    pub fn find(&self, address: u64) -> Option<&Area> {
+        self.areas
+            .range(..=address)
+            .next_back()
+            .map(|e| e.1)
+            .filter(|e| e.contains(address))
+    }
+
+    /// Areas in address order
+    pub fn iter(&self) -> impl Iterator<Item = &Area> {
+        self.areas.values()
+    }
+
+    /// Add `area`, it may neither leave the window nor overlap another one
+    pub fn insert(&mut self, area: Area) -> Result<(), AddressSpaceError> {
+        if area.start < self.window.start || area.end > self.window.end {
+            return Err(AddressSpaceError::OutsideWindow(area.start));
+        }
+        if let Some(overlap) = self
+            .areas
+            .range(..area.end)
+            .next_back()
+            .filter(|(_, e)| e.end > area.start)
+        {
+            return Err(AddressSpaceError::AlreadyMapped(
+                overlap.1.start.max(area.start),
+            ));
+        }
+        self.areas.insert(area.start, area);
+        Ok(())
+    }
+
+    /// Lowest `align` aligned address from `from` on where `size` bytes fit between the areas
+    pub fn free_range(&self, from: u64, size: u64, align: u64) -> Option<u64> {
+        let mut start = from
+            .max(self.window.start)
+            .checked_next_multiple_of(align)?;
+        for area in self.areas.values() {
+            if area.end <= start {
+                continue;
+            }
+            if area.start >= start.checked_add(size)? {
+                break;
+            }
+            start = area.end.checked_next_multiple_of(align)?;
+        }
+        start
+            .checked_add(size)
+            .filter(|e| *e <= self.window.end)
+            .map(|_| start)
+    }
+
+    /// Whether every address of `start..end` is inside an area
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        let mut current = start;
+        while current < end {
+            let Some(area) = self.find(current) else {
+                return false;
+            };
+            current = area.end;
+        }
+        true
+    }
+
+    /// Take out everything inside `start..end`, areas crossing its ends are cut there
+    pub fn remove(&mut self, start: u64, end: u64) -> Vec<Area> {
+        self.split(start);
+        self.split(end);
+        let starts: Vec<u64> = self.areas.range(start..end).map(|e| *e.0).collect();
+        starts
+            .iter()
+            .map(|e| self.areas.remove(e).unwrap())
+            .collect()
+    }
+
+    /// Give `start..end` new `flags`, it must be fully covered, returns the changed areas
+    pub fn protect(
+        &mut self,
+        start: u64,
+        end: u64,
+        flags: EntryFlags,
+    ) -> Result<Vec<Area>, AddressSpaceError> {
+        if !self.covers(start, end) {
+            return Err(AddressSpaceError::NotMapped(start));
+        }
+        self.split(start);
+        self.split(end);
+        Ok(self
+            .areas
+            .range_mut(start..end)
+            .map(|(_, e)| {
+                e.flags = flags;
+                *e
+            })
+            .collect())
+    }
+
+    /// Cut the area containing `at` in two so one starts there
+    fn split(&mut self, at: u64) {
+        if let Some(area) = self.find(at).copied().filter(|e| e.start != at) {
+            let (low, high) = area.split_at(at);
+            self.areas.insert(low.start, low);
+            self.areas.insert(high.start, high);
+        }
+    }
+}
+
+/// Page aligned `start..start + size`, which may not be empty
+pub(super) fn page_range(start: u64, size: u64) -> Result<Range<u64>, AddressSpaceError> {
+    if start % PAGE_SIZE != 0 || size == 0 {
+        return Err(AddressSpaceError::InvalidRange(start));
+    }
+    let end = start
+        .checked_add(size)
+        .and_then(|e| e.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(AddressSpaceError::InvalidRange(start))?;
+    Ok(start..end)
+}
+
+/// Frame backing `page` of the active table in `area`, filled in from the backing if the page is unused
+///
+/// A `write` to a frame shared with another address space copies it first
+pub(super) fn populate<const ORDER: usize>(
+    controller: &mut MemoryController<ORDER>,
+    page: Page,
+    area: &Area,
+    write: bool,
+) -> Result<Frame, AddressSpaceError> {
+    let Some(current) = controller.active_table.page_flags(page) else {
+        let offset = page.start_address() - area.start;
+        let frame = match area.backing {
+            Backing::Physical(start) => Frame::containing_address(start.as_u64() + offset),
+            Backing::Anonymous | Backing::File { .. } => {
+                let frame = controller
+                    .allocator
+                    .allocate_frame()
+                    .ok_or(AddressSpaceError::OutOfMemory)?;
+                controller.with_frame(frame.clone(), |bytes| fill(bytes, area.backing, offset));
+                frame
+            }
+        };
+        controller
+            .active_table
+            .map_to(page, frame.clone(), area.flags, &mut controller.allocator);
+        return Ok(frame);
+    };
+    let frame = controller.active_table.translate_page(page).unwrap();
+    if !write || current.contains(EntryFlags::WRITABLE) {
+        return Ok(frame);
+    }
+    if controller.frame_owners(frame.start_address()) == 1 {
+        // The other owners are gone, the page is private already
+        controller.active_table.set_flags(page, area.flags);
+        return Ok(frame);
+    }
+    let copy = controller
+        .allocator
+        .allocate_frame()
+        .ok_or(AddressSpaceError::OutOfMemory)?;
+    let source = page.start_address() as *const u8;
+    controller.with_frame(copy.clone(), |bytes| unsafe {
+        core::ptr::copy_nonoverlapping(source, bytes.as_mut_ptr(), bytes.len())
+    });
+    controller.active_table.unmap_addr(page);
+    SharedFrames {
+        allocator: &mut controller.allocator,
+        shared_frames: &mut controller.shared_frames,
+    }
+    .deallocate_frame(frame);
+    controller
+        .active_table
+        .map_to(page, copy.clone(), area.flags, &mut controller.allocator);
+    Ok(copy)
+}
+
+/// Initial content of the page `offset` bytes into an area with `backing`
+fn fill(bytes: &mut [u8], backing: Backing, offset: u64) {
+    bytes.fill(0);
+    if let Backing::File {
+        data,
+        offset: start,
+    } = backing
+    {
+        let start = (start + offset).min(data.len() as u64) as usize;
+        let count = (data.len() - start).min(bytes.len());
+        bytes[..count].copy_from_slice(&data[start..start + count]);
+    }
+}
+
+/// Unmap the used pages of `areas` from the active table, frames go back once nothing else maps them
+pub(super) fn unmap_areas<const ORDER: usize>(
+    controller: &mut MemoryController<ORDER>,
+    areas: &[Area],
+) {
+    for area in areas {
+        for page in area.pages() {
+            if controller.active_table.translate_page(page).is_none() {
+                continue;
+            }
+            let frame = controller.active_table.unmap_addr(page);
+            if !matches!(area.backing, Backing::Physical(_)) {
+                SharedFrames {
+                    allocator: &mut controller.allocator,
+                    shared_frames: &mut controller.shared_frames,
+                }
+                .deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+/// Apply the flags of `area` to its used pages in the active table, shared frames stay read only
+pub(super) fn protect_pages<const ORDER: usize>(
+    controller: &mut MemoryController<ORDER>,
+    area: &Area,
+) {
+    for page in area.pages() {
+        let Some(frame) = controller.active_table.translate_page(page) else {
+            continue;
+        };
+        let shared = !matches!(area.backing, Backing::Physical(_))
+            && controller.frame_owners(frame.start_address()) > 1;
+        let flags = inline_if!(shared, area.flags - EntryFlags::WRITABLE, area.flags);
+        controller.active_table.set_flags(page, flags);
+    }
+}
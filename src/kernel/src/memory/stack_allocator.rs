@@ -1,53 +1,126 @@
+use alloc::vec::Vec;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+
 use super::{
     paging::{ActivePageTable, EntryFlags, Page, PageIter},
     FrameAllocator, PAGE_SIZE,
 };
 
+/// Guard page and name of every stack in use
+static GUARDS: RwLock<Vec<(u64, &'static str)>> = RwLock::new(Vec::new());
+
 pub struct StackAllocator {
     range: PageIter,
+    /// Released blocks as first page and page count including their guard page, sorted and merged
+    free: Vec<(Page, u64)>,
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            free: Vec::new(),
+        }
     }
 }
 
 impl StackAllocator {
+    /// Map a stack with an unmapped guard page right below it
     pub fn alloc_stack<FA: FrameAllocator>(
         &mut self,
         active_table: &mut ActivePageTable,
         frame_allocator: &mut FA,
         size_in_pages: usize,
+        name: &'static str,
     ) -> Option<Stack> {
         if size_in_pages == 0 {
             return None;
         }
 
-        let mut range = self.range.clone();
+        let guard_page = self
+            .take_free(size_in_pages as u64 + 1)
+            .or_else(|| self.take_unused(size_in_pages))?;
+        let start = guard_page + 1;
+        let end = guard_page + size_in_pages as u64;
+        for page in Page::range_inclusive(start, end) {
+            active_table.map(page, EntryFlags::WRITABLE, frame_allocator);
+        }
+        interrupts::without_interrupts(|| {
+            GUARDS.write().push((guard_page.start_address(), name));
+        });
 
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
+        let top_of_stack = end.start_address() + PAGE_SIZE;
+        Some(Stack::new(top_of_stack, start.start_address()))
+    }
+
+    /// Unmap a stack and give its pages back, no processor may be running on it anymore
+    pub fn dealloc_stack<FA: FrameAllocator>(
+        &mut self,
+        active_table: &mut ActivePageTable,
+        frame_allocator: &mut FA,
+        stack: Stack,
+    ) {
+        let start = Page::containing_address(stack.bottom());
+        let end = Page::containing_address(stack.top() - 1);
+        for page in Page::range_inclusive(start, end) {
+            active_table.unmap(page, frame_allocator);
+        }
+        let guard_page = Page::containing_address(stack.bottom() - PAGE_SIZE);
+        interrupts::without_interrupts(|| {
+            GUARDS.write().retain(|e| e.0 != guard_page.start_address());
+        });
+        self.release(guard_page, stack.size_in_pages() + 1);
+    }
+
+    fn take_free(&mut self, pages: u64) -> Option<Page> {
+        let index = self.free.iter().position(|e| e.1 >= pages)?;
+        let (start, count) = self.free[index];
+        if count == pages {
+            self.free.remove(index);
         } else {
-            range.nth(size_in_pages - 2)
-        };
+            self.free[index] = (start + pages, count - pages);
+        }
+        Some(start)
+    }
 
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                self.range = range;
+    fn take_unused(&mut self, size_in_pages: usize) -> Option<Page> {
+        let mut range = self.range.clone();
 
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, EntryFlags::WRITABLE, frame_allocator);
-                }
+        let guard_page = range.next();
+        let stack_end = range.nth(size_in_pages - 1);
 
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
+        match (guard_page, stack_end) {
+            (Some(guard_page), Some(_)) => {
+                self.range = range;
+                Some(guard_page)
             }
             _ => None,
         }
     }
+
+    fn release(&mut self, start: Page, count: u64) {
+        let index = self.free.partition_point(|e| e.0 < start);
+        self.free.insert(index, (start, count));
+        if index + 1 < self.free.len() && start + count == self.free[index + 1].0 {
+            self.free[index].1 += self.free.remove(index + 1).1;
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == start {
+            self.free[index - 1].1 += self.free.remove(index).1;
+        }
+    }
+}
+
+/// Name of the stack whose guard page contains `address`
+///
+/// Doesn't wait for the lock since the fault may have hit while it was held
+pub fn guard_owner(address: u64) -> Option<&'static str> {
+    let guard_page = Page::containing_address(address).start_address();
+    GUARDS
+        .try_read()?
+        .iter()
+        .find(|e| e.0 == guard_page)
+        .map(|e| e.1)
 }
 
 #[derive(Debug)]
@@ -69,4 +142,8 @@ impl Stack {
     pub fn bottom(&self) -> u64 {
         self.bottom
     }
+
+    pub fn size_in_pages(&self) -> u64 {
+        (self.top - self.bottom) / PAGE_SIZE
+    }
 }
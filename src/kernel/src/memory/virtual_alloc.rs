@@ -0,0 +1,144 @@
+use core::ops::Range;
+
+use x86_64::PhysAddr;
+
+use super::address_space::AddressSpaceError;
+use super::paging::EntryFlags;
+use super::region::{Area, Backing, Regions};
+use super::{memory_controller, MAX_ALIGN, PAGE_SIZE};
+use crate::inline_if;
+
+/// Ranges handed out of a window of kernel addresses, kept as areas nothing gets mapped from
+#[derive(Debug)]
+pub struct VirtualAllocator {
+    ranges: Regions,
+}
+
+impl VirtualAllocator {
+    pub const fn new(window: Range<u64>) -> VirtualAllocator {
+        VirtualAllocator {
+            ranges: Regions::new(window),
+        }
+    }
+
+    /// Lowest free range of `size` bytes rounded up to whole pages, its start aligned to `align`
+    pub fn allocate(&mut self, size: u64, align: u64) -> Result<u64, AddressSpaceError> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(AddressSpaceError::InvalidRange(size));
+        }
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let start = self
+            .ranges
+            .free_range(0, size, align.max(PAGE_SIZE))
+            .ok_or(AddressSpaceError::NoFreeRange(size))?;
+        self.ranges.insert(Area {
+            start,
+            end: start + size,
+            flags: EntryFlags::empty(),
+            backing: Backing::Anonymous,
+        })?;
+        Ok(start)
+    }
+
+    /// Give back the range starting at `start`, returns its size
+    pub fn free(&mut self, start: u64) -> Result<u64, AddressSpaceError> {
+        let end = self
+            .ranges
+            .find(start)
+            .filter(|e| e.start == start)
+            .ok_or(AddressSpaceError::NotMapped(start))?
+            .end;
+        self.ranges.remove(start, end);
+        Ok(end - start)
+    }
+}
+
+/// Physical range mapped into the virtual window, unmapped and given back when dropped
+///
+/// Dropping it locks the memory controller.
+#[derive(Debug)]
+pub struct Mapping {
+    start: u64,
+    size: u64,
+    /// Offset of the physical start into its page
+    offset: u64,
+}
+
+impl Mapping {
+    pub(super) fn new(start: u64, size: u64, offset: u64) -> Mapping {
+        Mapping {
+            start,
+            size,
+            offset,
+        }
+    }
+
+    /// Address the physical start is mapped at
+    pub fn address(&self) -> u64 {
+        self.start + self.offset
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.address() as *mut T
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let mut controller = memory_controller().lock();
+        controller.unmap_addr(self.start, self.size);
+        controller
+            .free_virt(self.start)
+            .expect("Mapping outlived its virtual range");
+    }
+}
+
+/// Map `size` bytes of device memory from `phy_start` somewhere in the virtual window
+pub fn map_physical(size: u64, phy_start: u64) -> Result<Mapping, AddressSpaceError> {
+    memory_controller().lock().map_physical(size, phy_start)
+}
+
+/// Physical memory nothing maps, freed when dropped
+///
+/// Stands in for device memory, an uncached mapping of it can't disagree with a cached one of the same frames.
+#[derive(Debug)]
+pub struct UnmappedFrames {
+    phys: PhysAddr,
+    block: PhysAddr,
+    block_size: usize,
+}
+
+impl UnmappedFrames {
+    /// `size` bytes rounded up to whole pages, starting `align` aligned
+    pub fn new(size: usize, align: u64) -> Result<UnmappedFrames, AddressSpaceError> {
+        let size = size.next_multiple_of(PAGE_SIZE as usize);
+        if size == 0 || !align.is_power_of_two() {
+            return Err(AddressSpaceError::InvalidRange(size as u64));
+        }
+        // Blocks are only aligned to MAX_ALIGN, twice the size leaves room to align it
+        let align = align.max(PAGE_SIZE);
+        let block_size = size.max(align as usize);
+        let block_size = inline_if!(align as usize <= MAX_ALIGN, block_size, block_size * 2);
+        let block = memory_controller()
+            .lock()
+            .physical_alloc(block_size)
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        Ok(UnmappedFrames {
+            phys: PhysAddr::new(block.as_u64().next_multiple_of(align)),
+            block,
+            block_size,
+        })
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+}
+
+impl Drop for UnmappedFrames {
+    fn drop(&mut self) {
+        memory_controller()
+            .lock()
+            .physical_dealloc(self.block, self.block_size);
+    }
+}
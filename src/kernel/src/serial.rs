@@ -1,8 +1,11 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use uart_16550::SerialPort;
 use x86_64::instructions::interrupts;
 
+/// Tries at taking the port for a report before it's forced
+const REPORT_LOCK_SPINS: usize = 1_000_000;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
@@ -11,6 +14,20 @@ lazy_static! {
     };
 }
 
+/// Serial port for panic and fault reports, which are written without touching the heap
+///
+/// Whatever failed may hold the lock forever, it gets taken away from it after a while.
+pub fn report_port() -> MutexGuard<'static, SerialPort> {
+    for _ in 0..REPORT_LOCK_SPINS {
+        if let Some(port) = SERIAL1.try_lock() {
+            return port;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { SERIAL1.force_unlock() };
+    SERIAL1.lock()
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
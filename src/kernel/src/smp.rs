@@ -0,0 +1,123 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+
+use crate::interrupt::{self, local_apic, LAPIC_IDS};
+use crate::memory::{self, memory_controller};
+use crate::task::executor::Executor;
+use crate::time::{busy_wait, wait_until, Duration, Elapsed};
+use crate::{gdt, log, userland};
+
+use self::trampoline::{TrampolineArgs, STARTUP_VECTOR};
+
+pub mod percpu;
+pub mod tlb;
+mod trampoline;
+
+const AP_STACK_PAGES: usize = 8;
+const INIT_DELAY: Duration = Duration::from_millis(10);
+const STARTUP_DELAY: Duration = Duration::from_micros(200);
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of processors running the kernel, the bootstrap processor included
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Destination field of an ipi, the xapic only reads the top byte of the icr
+fn ipi_destination(apic_id: u32) -> u32 {
+    let x2apic = unsafe { __cpuid(1).ecx & (1 << 21) != 0 };
+    if x2apic {
+        apic_id
+    } else {
+        apic_id << 24
+    }
+}
+
+/// Send the init, startup, startup sequence and wait for the processor to report itself online
+fn start_ap(apic_id: u32) -> Result<(), Elapsed> {
+    let online = online_cpus();
+    let destination = ipi_destination(apic_id);
+    unsafe {
+        local_apic().lock().send_init_ipi(destination);
+    }
+    busy_wait(INIT_DELAY);
+    for _ in 0..2 {
+        unsafe {
+            local_apic().lock().send_sipi(STARTUP_VECTOR, destination);
+        }
+        // The second startup ipi is only needed if the first one got lost
+        if wait_until(STARTUP_DELAY, || online_cpus() > online).is_ok() {
+            return Ok(());
+        }
+    }
+    wait_until(STARTUP_TIMEOUT, || online_cpus() > online)
+}
+
+/// Start every usable application processor listed in the madt, one at a time
+pub fn init() {
+    tlb::init();
+    let bsp = percpu::bsp().apic_id();
+    let aps: Vec<u32> = LAPIC_IDS
+        .get()
+        .expect("Local apics not enumerated")
+        .iter()
+        .copied()
+        .filter(|e| *e != bsp)
+        .collect();
+    if aps.is_empty() {
+        return;
+    }
+
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    assert!(
+        cr3 < 1 << 32,
+        "P4 table at {:#x} is out of reach of real mode",
+        cr3
+    );
+    trampoline::install();
+    for apic_id in aps {
+        let stack = match memory_controller()
+            .lock()
+            .alloc_stack(AP_STACK_PAGES, "application processor")
+        {
+            Some(stack) => stack,
+            None => {
+                log!(Error, "No stack left to start processor {}", apic_id);
+                break;
+            }
+        };
+        trampoline::set_args(TrampolineArgs {
+            cr3,
+            stack_top: stack.top(),
+            entry: ap_entry as usize as u64,
+            index: online_cpus() as u64,
+        });
+        if start_ap(apic_id).is_err() {
+            log!(Error, "Processor {} didn't come online", apic_id);
+        }
+    }
+    log!(Info, "{} processors online", online_cpus());
+}
+
+extern "C" fn ap_entry(index: usize) -> ! {
+    let cpu = percpu::init(index);
+    gdt::init_gdt();
+    memory::init_ap();
+    userland::syscall::init();
+    interrupt::init_ap();
+    log!(
+        Info,
+        "Processor {} online with apic id {}",
+        index,
+        cpu.apic_id()
+    );
+    ONLINE.fetch_add(1, Ordering::Release);
+    interrupts::enable();
+    Executor::new().run();
+}
@@ -0,0 +1,127 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::cell::SyncUnsafeCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use spin::{Mutex, Once};
+use x2apic::lapic::LocalApic;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::gdt::Gdt;
+use crate::userland::process::Pid;
+
+static BSP: OnceCell<&'static PerCpu> = OnceCell::uninit();
+
+/// State owned by a single processor, reached through the gs base
+#[repr(C)]
+pub struct PerCpu {
+    // Must stay the first field, `current` reads it from gs:0
+    this: *const PerCpu,
+    /// Stack the system call entry switches to, same as the tss one
+    pub(crate) kernel_stack: AtomicU64,
+    /// User stack saved by the system call entry
+    pub(crate) user_stack: AtomicU64,
+    /// Pid of the process the running thread belongs to, 0 for kernel threads
+    process: AtomicU64,
+    index: usize,
+    apic_id: u32,
+    pub(crate) gdt: Once<Gdt>,
+    pub(crate) tss: Once<SyncUnsafeCell<TaskStateSegment>>,
+    pub(crate) lapic: OnceCell<Mutex<LocalApic>>,
+}
+
+// Only ever mutated by the processor owning it, other processors just read it
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// Logical index, the bootstrap processor is 0 and application processors follow in startup order
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.index == 0
+    }
+
+    /// Stack the processor switches to when an interrupt arrives in ring 3
+    pub(crate) fn set_kernel_stack(&self, top: u64) {
+        let tss = self.tss.get().expect("Tss not loaded");
+        unsafe { (*tss.get()).privilege_stack_table[0] = VirtAddr::new(top) };
+        self.kernel_stack.store(top, Ordering::Relaxed);
+    }
+
+    /// Process the running thread belongs to, none for kernel threads
+    pub fn process(&self) -> Option<Pid> {
+        match self.process.load(Ordering::Relaxed) {
+            0 => None,
+            pid => Some(Pid::from_raw(pid)),
+        }
+    }
+
+    pub(crate) fn set_process(&self, pid: Option<Pid>) {
+        self.process
+            .store(pid.map_or(0, |e| e.as_u64()), Ordering::Relaxed);
+    }
+
+    pub fn lapic(&self) -> &Mutex<LocalApic> {
+        self.lapic.get().expect("Local apic not initialized")
+    }
+}
+
+/// Apic id of the executing processor, read from cpuid so it works before the local apic is set up
+fn current_apic_id() -> u32 {
+    unsafe {
+        // The x2apic topology leaf reports the full 32 bits id
+        if __cpuid(0).eax >= 0xB && __cpuid_count(0xB, 0).ebx != 0 {
+            __cpuid_count(0xB, 0).edx
+        } else {
+            __cpuid(1).ebx >> 24
+        }
+    }
+}
+
+/// Allocate the per cpu block of the executing processor and point its gs base at it
+pub fn init(index: usize) -> &'static PerCpu {
+    let cpu = Box::leak(Box::new(PerCpu {
+        this: core::ptr::null(),
+        kernel_stack: AtomicU64::new(0),
+        user_stack: AtomicU64::new(0),
+        process: AtomicU64::new(0),
+        index,
+        apic_id: current_apic_id(),
+        gdt: Once::new(),
+        tss: Once::new(),
+        lapic: OnceCell::uninit(),
+    }));
+    cpu.this = cpu;
+    // The kernel always runs with this gs base, entries from ring 3 swapgs to get it back
+    GsBase::write(VirtAddr::new(cpu as *const PerCpu as u64));
+    KernelGsBase::write(VirtAddr::new(cpu as *const PerCpu as u64));
+    if index == 0 {
+        BSP.init_once(|| cpu);
+    }
+    cpu
+}
+
+/// Per cpu block of the executing processor
+pub fn current() -> &'static PerCpu {
+    unsafe {
+        let this: *const PerCpu;
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+        &*this
+    }
+}
+
+/// Per cpu block of the bootstrap processor
+pub fn bsp() -> &'static PerCpu {
+    BSP.get().expect("Per cpu data not initialized")
+}
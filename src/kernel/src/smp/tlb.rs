@@ -0,0 +1,82 @@
+use core::hint::spin_loop;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x2apic::lapic::IpiAllShorthand;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::VirtAddr;
+
+use crate::interrupt::handler::{request_interrupt, EoiPolicy, HandlerFlags, IrqReturn};
+use crate::interrupt::local_apic;
+use crate::memory::PAGE_SIZE;
+
+use super::online_cpus;
+
+static VECTOR: OnceCell<u8> = OnceCell::uninit();
+/// Held for a whole shootdown, the range below belongs to its holder
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static START: AtomicU64 = AtomicU64::new(0);
+/// End of the range to invalidate, 0 flushes every non global entry
+static END: AtomicU64 = AtomicU64::new(0);
+/// Processors that still have to acknowledge the current shootdown
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Above this many pages flushing everything is cheaper than invlpg on each
+const FLUSH_ALL_PAGES: u64 = 32;
+
+/// Reserve the shootdown vector, must run before the application processors come up
+pub fn init() {
+    let vector = request_interrupt(
+        "tlb shootdown",
+        EoiPolicy::Auto,
+        HandlerFlags::empty(),
+        |_| {
+            flush_local(START.load(Ordering::Acquire), END.load(Ordering::Acquire));
+            // Processors coming online may see a shootdown sent before they were counted
+            let _ = PENDING.fetch_update(Ordering::AcqRel, Ordering::Acquire, |e| e.checked_sub(1));
+            IrqReturn::Handled
+        },
+    )
+    .expect("No vector left for tlb shootdowns");
+    VECTOR.init_once(|| vector);
+}
+
+fn flush_local(start: u64, end: u64) {
+    if end == 0 || (end - start) / PAGE_SIZE > FLUSH_ALL_PAGES {
+        tlb::flush_all();
+        return;
+    }
+    for address in (start..end).step_by(PAGE_SIZE as usize) {
+        tlb::flush(VirtAddr::new(address));
+    }
+}
+
+/// Invalidate `range` on every online processor and wait for all of them, `None` flushes everything
+///
+/// Processors only acknowledge with interrupts enabled, none may wait for a lock the caller holds with them off.
+pub fn shootdown(range: Option<Range<u64>>) {
+    let (start, end) = range.map_or((0, 0), |e| (e.start, e.end.max(e.start + 1)));
+    flush_local(start, end);
+    let Some(&vector) = VECTOR.get() else {
+        return;
+    };
+    if online_cpus() == 1 {
+        return;
+    }
+
+    let _guard = SHOOTDOWN.lock();
+    START.store(start, Ordering::Release);
+    END.store(end, Ordering::Release);
+    PENDING.store(online_cpus() - 1, Ordering::Release);
+    // The handler takes the lapic lock for its end of interrupt
+    interrupts::without_interrupts(|| unsafe {
+        local_apic()
+            .lock()
+            .send_ipi_all(vector, IpiAllShorthand::AllExcludingSelf);
+    });
+    while PENDING.load(Ordering::Acquire) != 0 {
+        spin_loop();
+    }
+}
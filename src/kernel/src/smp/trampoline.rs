@@ -0,0 +1,141 @@
+use core::arch::global_asm;
+
+use crate::memory::memory_controller;
+use crate::memory::paging::EntryFlags;
+use crate::memory::PAGE_SIZE;
+
+/// Physical address the trampoline runs from, application processors start at its page number
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+pub const STARTUP_VECTOR: u8 = (TRAMPOLINE_ADDR / PAGE_SIZE) as u8;
+
+/// Filled in by the bootstrap processor before each startup ipi
+#[repr(C)]
+pub struct TrampolineArgs {
+    /// Physical address of the p4 table, must be below 4G since it's loaded in real mode
+    pub cr3: u64,
+    pub stack_top: u64,
+    /// `extern "C" fn(usize) -> !` called once in long mode
+    pub entry: u64,
+    /// Passed as the first argument of `entry`
+    pub index: u64,
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_args: u8;
+    static ap_trampoline_end: u8;
+}
+
+// Real mode entry point of the application processors, it switches straight to long mode.
+// It's copied to `TRAMPOLINE_ADDR` so every absolute address is computed from there.
+global_asm!(
+    r#"
+.pushsection .rodata.ap_trampoline, "a"
+.global ap_trampoline_start
+.global ap_trampoline_args
+.global ap_trampoline_end
+
+// Where the labels end up once the trampoline is copied
+.set TRAMPOLINE_GDT, {base} + ap_trampoline_gdt - ap_trampoline_start
+.set TRAMPOLINE_GDT_PTR, {base} + ap_trampoline_gdt_ptr - ap_trampoline_start
+.set TRAMPOLINE_ARGS, {base} + ap_trampoline_args - ap_trampoline_start
+.set TRAMPOLINE_LONG_MODE, {base} + ap_trampoline_long_mode - ap_trampoline_start
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    lgdt [TRAMPOLINE_GDT_PTR]
+
+    // Pae
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, dword ptr [TRAMPOLINE_ARGS]
+    mov cr3, eax
+
+    // Long mode and no execute
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // Paging, write protect and protected mode, long mode gets active with it
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16) | 1
+    mov cr0, eax
+
+    // Far jump with a 32 bits offset to the 64 bits code segment
+    .byte 0x66, 0xEA
+    .4byte TRAMPOLINE_LONG_MODE
+    .2byte 0x08
+
+.code64
+ap_trampoline_long_mode:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, qword ptr [rip + ap_trampoline_args + 8]
+    mov rdi, qword ptr [rip + ap_trampoline_args + 24]
+    mov rax, qword ptr [rip + ap_trampoline_args + 16]
+    // Terminates the frame pointer chain for backtraces
+    xor ebp, ebp
+    call rax
+    ud2
+
+.align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+ap_trampoline_gdt_ptr:
+    .2byte ap_trampoline_gdt_ptr - ap_trampoline_gdt - 1
+    .4byte TRAMPOLINE_GDT
+
+.align 8
+ap_trampoline_args:
+    .fill 4, 8, 0
+ap_trampoline_end:
+.popsection
+"#,
+    base = const TRAMPOLINE_ADDR,
+);
+
+fn trampoline() -> &'static [u8] {
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let end = &ap_trampoline_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Identity map the trampoline page and copy the trampoline into it
+pub fn install() {
+    let code = trampoline();
+    assert!(code.len() as u64 <= PAGE_SIZE, "AP trampoline too large");
+    memory_controller().lock().ident_map_unmapped(
+        PAGE_SIZE,
+        TRAMPOLINE_ADDR,
+        EntryFlags::PRESENT | EntryFlags::WRITABLE,
+    );
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), TRAMPOLINE_ADDR as *mut u8, code.len());
+    }
+}
+
+/// Set the arguments the next application processor starts with
+pub fn set_args(args: TrampolineArgs) {
+    let offset = unsafe {
+        &ap_trampoline_args as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64
+    };
+    unsafe {
+        core::ptr::write_volatile((TRAMPOLINE_ADDR + offset) as *mut TrampolineArgs, args);
+    }
+}
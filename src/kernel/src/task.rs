@@ -8,6 +8,8 @@ use core::{
 use alloc::boxed::Box;
 
 pub mod executor;
+pub mod thread;
+pub mod waker;
 
 pub struct Task {
     id: TaskId,
@@ -5,7 +5,7 @@ use crossbeam_queue::ArrayQueue;
 use hashbrown::HashMap;
 use x86_64::instructions::interrupts;
 
-use super::{AwaitType, Task, TaskId};
+use super::{thread, AwaitType, Task, TaskId};
 
 pub struct Executor {
     tasks: HashMap<TaskId, Task>,
@@ -77,7 +77,7 @@ impl Executor {
     fn sleep_if_idle(&self) {
         interrupts::disable();
         if self.task_queue.is_empty() {
-            interrupts::enable_and_hlt();
+            thread::idle();
         } else {
             interrupts::enable();
         }
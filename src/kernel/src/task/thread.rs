@@ -0,0 +1,261 @@
+use core::arch::asm;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::interrupt::handler::YIELD_VECTOR;
+use crate::interrupt::FullInterruptStackFrame;
+use crate::memory::address_space::AddressSpace;
+use crate::memory::memory_controller;
+use crate::smp::percpu;
+use crate::time::{busy_wait, Duration, Instant};
+use crate::userland::process::Pid;
+use crate::userland::scheduler::{Process, ProcessState, Scheduler, ThreadId, SCHEDULER};
+
+pub const THREAD_STACK_PAGES: usize = 8;
+/// Scheduling weight of new threads, see [`Scheduler::schedule_next`]
+const DEFAULT_RESET: u16 = 1;
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+/// Owned permission to wait for a thread and take its result
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Block until the thread returns
+    pub fn join(self) -> T {
+        loop {
+            if let Some(result) = self.result.lock().take() {
+                return result;
+            }
+            if on_scheduler_cpu() {
+                interrupts::without_interrupts(|| scheduler().lock().join(self.id));
+                yield_now();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+fn scheduler() -> &'static Mutex<Scheduler> {
+    SCHEDULER.get().expect("Scheduler not initialized")
+}
+
+/// Threads only run on the bootstrap processor
+fn on_scheduler_cpu() -> bool {
+    SCHEDULER.get().is_some() && percpu::current().is_bsp()
+}
+
+/// Start the idle thread, picked whenever nothing else can run
+pub fn init() {
+    let (idle, _) = new_thread(
+        "idle",
+        || loop {
+            reap();
+            interrupts::enable_and_hlt();
+        },
+        None,
+    );
+    interrupts::without_interrupts(|| scheduler().lock().add_idle(idle));
+}
+
+/// Run `f` in a new kernel thread preempted by the timer
+pub fn spawn_thread<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn(name, f, None)
+}
+
+/// Run `f` as the thread of process `pid` with `address_space` loaded, it's freed once the thread is reaped
+pub(crate) fn spawn_in<F, T>(
+    name: &str,
+    pid: Pid,
+    address_space: AddressSpace,
+    f: F,
+) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn(name, f, Some((pid, address_space)))
+}
+
+fn spawn<F, T>(name: &str, f: F, process: Option<(Pid, AddressSpace)>) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    reap();
+    let (thread, handle) = new_thread(name, f, process);
+    interrupts::without_interrupts(|| {
+        scheduler().lock().add_process(thread);
+    });
+    handle
+}
+
+/// Thread ready to start in `f`, the scheduler doesn't know about it yet
+fn new_thread<F, T>(
+    name: &str,
+    f: F,
+    process: Option<(Pid, AddressSpace)>,
+) -> (Process, JoinHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let packet = result.clone();
+    let main: ThreadMain = Box::new(move || {
+        let value = f();
+        *packet.lock() = Some(value);
+    });
+    let stack = memory_controller()
+        .lock()
+        .alloc_stack(THREAD_STACK_PAGES, "kernel thread")
+        .expect("No stack left for a new thread");
+
+    // A zero return address ends backtraces and keeps the entry alignment of a call
+    let entry_rsp = stack.top() - 8;
+    let frame =
+        (entry_rsp - size_of::<FullInterruptStackFrame>() as u64) as *mut FullInterruptStackFrame;
+    unsafe {
+        *(entry_rsp as *mut u64) = 0;
+        frame.write(FullInterruptStackFrame {
+            cr3: 0,
+            cr2: 0,
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            rbp: 0,
+            rbx: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rdi: Box::into_raw(Box::new(main)) as u64,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rax: 0,
+            vector: 0,
+            error_code: 0,
+            instruction_pointer: VirtAddr::new(thread_entry as usize as u64),
+            code_segment: CS::get_reg().0 as u64,
+            cpu_flags: (RFlags::INTERRUPT_FLAG | RFlags::from_bits_retain(0x2)).bits(),
+            stack_pointer: VirtAddr::new(entry_rsp),
+            stack_segment: SS::get_reg().0 as u64,
+        });
+    }
+
+    let (pid, address_space) = process.unzip();
+    let process = Process::with_context(
+        DEFAULT_RESET,
+        String::from(name),
+        frame as u64,
+        stack,
+        address_space,
+        pid,
+    );
+    let id = process.id();
+    (process, JoinHandle { id, result })
+}
+
+extern "C" fn thread_entry(main: *mut ThreadMain) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    interrupts::without_interrupts(|| scheduler().lock().exit_current());
+    yield_now();
+    unreachable!("Dead thread got scheduled");
+}
+
+/// Free the stacks and address spaces of exited threads
+pub(crate) fn reap() {
+    if SCHEDULER.get().is_none() {
+        return;
+    }
+    let dead = interrupts::without_interrupts(|| scheduler().lock().take_dead());
+    for mut process in dead {
+        if let Some(stack) = process.take_stack() {
+            memory_controller().lock().dealloc_stack(stack);
+        }
+    }
+}
+
+/// Block until thread `id` is dead, without a handle to it
+pub(crate) fn wait_for_exit(id: ThreadId) {
+    loop {
+        let dead = interrupts::without_interrupts(|| {
+            let mut scheduler = scheduler().lock();
+            let dead = scheduler
+                .find(id)
+                .map_or(true, |e| e.state() == ProcessState::Dead);
+            if !dead {
+                scheduler.join(id);
+            }
+            dead
+        });
+        if dead {
+            return;
+        }
+        yield_now();
+    }
+}
+
+/// Id of the running thread
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| scheduler().lock().current())
+}
+
+/// Give the processor to another ready thread
+pub fn yield_now() {
+    if on_scheduler_cpu() {
+        unsafe {
+            asm!("int {}", const YIELD_VECTOR);
+        }
+    }
+}
+
+/// Block the running thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    if !on_scheduler_cpu() {
+        busy_wait(duration);
+        return;
+    }
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        interrupts::without_interrupts(|| {
+            scheduler()
+                .lock()
+                .set_current_state(ProcessState::Sleeping(deadline))
+        });
+        yield_now();
+    }
+}
+
+/// Wait for the next interrupt with interrupts disabled on entry, other threads run meanwhile
+pub fn idle() {
+    let yield_first = on_scheduler_cpu() && scheduler().lock().has_other_runnable();
+    if yield_first {
+        yield_now();
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
@@ -0,0 +1,47 @@
+use core::task::Waker;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// A single task waker that can be woken from an interrupt handler
+pub struct InterruptWaker {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl InterruptWaker {
+    pub const fn new() -> Self {
+        Self {
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Register the waker of the polling task, replacing the previous one
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut current = self.waker.lock();
+            match current.as_ref() {
+                Some(current) if current.will_wake(waker) => {}
+                _ => *current = Some(waker.clone()),
+            }
+        });
+    }
+
+    /// Wake the registered task, the task has to register again to be woken next time
+    pub fn wake(&self) {
+        let waker = interrupts::without_interrupts(|| self.waker.lock().take());
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Forget the registered task without waking it
+    pub fn clear(&self) {
+        interrupts::without_interrupts(|| self.waker.lock().take());
+    }
+}
+
+impl Default for InterruptWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,227 @@
+use core::arch::x86_64::_rdtsc;
+use core::error::Error;
+use core::fmt::Display;
+use core::ops::{Add, AddAssign, Sub};
+
+use conquer_once::spin::OnceCell;
+use x2apic::lapic::{LocalApic, TimerDivide, TimerMode};
+
+use crate::driver::timer::hpet;
+use crate::interrupt::local_apic;
+use crate::log;
+
+use self::clocksource::{ClockSource, Tsc};
+use self::pit::PitClock;
+
+pub use core::time::Duration;
+
+pub mod clocksource;
+pub mod pit;
+pub mod timer;
+
+/// Frequency of the local apic timer interrupt driving the timer wheel
+pub const TIMER_FREQUENCY: u64 = 1000;
+const CALIBRATION_MICROS: u64 = 10_000;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+static TSC: OnceCell<Tsc> = OnceCell::uninit();
+static PIT: OnceCell<PitClock> = OnceCell::uninit();
+
+struct Clock {
+    source: &'static dyn ClockSource,
+    start: u64,
+    tsc_frequency: u64,
+    lapic_frequency: u64,
+}
+
+/// The deadline of a timeout has passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Deadline has elapsed")
+    }
+}
+
+impl Error for Elapsed {}
+
+/// A point in monotonic time, counted in nanoseconds since the clocksource got selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        let clock = clock();
+        let ticks = clock.source.read().saturating_sub(clock.start);
+        Self((ticks as u128 * NANOS_PER_SEC / clock.source.frequency() as u128) as u64)
+    }
+
+    /// Time since the clock got calibrated
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    /// Returns zero if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|e| self.0.checked_add(e))
+            .map(Self)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|e| self.0.checked_sub(e))
+            .map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("Overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("Overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+fn clock() -> &'static Clock {
+    CLOCK.get().expect("Clock not initialized")
+}
+
+/// The counter [`Instant`] is read from
+pub fn clocksource() -> &'static dyn ClockSource {
+    clock().source
+}
+
+pub fn tsc_frequency() -> u64 {
+    clock().tsc_frequency
+}
+
+/// Frequency the local apic timer counts down at
+pub fn lapic_frequency() -> u64 {
+    clock().lapic_frequency
+}
+
+/// Spin for `duration`, only meant for short delays where a task can't sleep
+pub fn busy_wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Spin until `condition` holds or `timeout` passed
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> Result<(), Elapsed> {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return Err(Elapsed);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Pick the best clocksource: the hpet, then an invariant tsc, then the pit
+fn select_clocksource() -> &'static dyn ClockSource {
+    if let Some(hpet) = hpet::init() {
+        return hpet;
+    }
+    if Tsc::is_invariant() {
+        let frequency = Tsc::cpuid_frequency().unwrap_or_else(measure_tsc);
+        return TSC.get_or_init(|| Tsc::new(frequency));
+    }
+    PIT.get_or_init(PitClock::start)
+}
+
+/// Measure the tsc against a pit countdown, for when cpuid doesn't report its frequency
+fn measure_tsc() -> u64 {
+    let start = unsafe { _rdtsc() };
+    pit::wait(CALIBRATION_MICROS);
+    (unsafe { _rdtsc() } - start) * 1_000_000 / CALIBRATION_MICROS
+}
+
+/// Count the tsc and the local apic timer over a calibration period of `source`
+fn calibrate(source: &dyn ClockSource, lapic: &mut LocalApic) -> (u64, u64) {
+    let period = source.frequency() * CALIBRATION_MICROS / 1_000_000;
+    let (elapsed, tsc_ticks, lapic_ticks) = unsafe {
+        lapic.set_timer_divide(TimerDivide::Div16);
+        lapic.set_timer_mode(TimerMode::OneShot);
+        let start = source.read();
+        lapic.set_timer_initial(u32::MAX);
+        let tsc_start = _rdtsc();
+        let mut elapsed = 0;
+        while elapsed < period {
+            elapsed = source.read() - start;
+        }
+        (
+            elapsed,
+            _rdtsc() - tsc_start,
+            u32::MAX - lapic.timer_current(),
+        )
+    };
+    // Scale by what actually elapsed, reading a slow source can overshoot the period
+    let frequency =
+        |ticks: u64| (ticks as u128 * source.frequency() as u128 / elapsed as u128) as u64;
+    (frequency(tsc_ticks), frequency(lapic_ticks as u64))
+}
+
+/// Select a clocksource, calibrate the tsc and the local apic timer with it, then start the periodic tick
+pub fn init() {
+    let source = select_clocksource();
+    let mut lapic = local_apic().lock();
+    let (tsc_frequency, lapic_frequency) = calibrate(source, &mut lapic);
+
+    CLOCK.init_once(|| Clock {
+        source,
+        start: source.read(),
+        tsc_frequency,
+        lapic_frequency,
+    });
+    unsafe {
+        lapic.set_timer_mode(TimerMode::Periodic);
+        lapic.set_timer_initial((lapic_frequency / TIMER_FREQUENCY) as u32);
+    }
+    log!(
+        Info,
+        "Using {} as clocksource, TSC runs at {} MHz, lapic timer at {} MHz",
+        source.name(),
+        tsc_frequency / 1_000_000,
+        lapic_frequency / 1_000_000
+    );
+}
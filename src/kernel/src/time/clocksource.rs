@@ -0,0 +1,91 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// A free running counter the kernel reads the time from
+pub trait ClockSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Current value of the counter, it never goes backward
+    fn read(&self) -> u64;
+
+    /// Ticks per second
+    fn frequency(&self) -> u64;
+}
+
+/// Widens a counter narrower than 64 bits, it has to be read at least once per wrap around
+pub struct CounterExtender {
+    mask: u64,
+    // Last raw value and the widened counter
+    state: Mutex<(u64, u64)>,
+}
+
+impl CounterExtender {
+    pub const fn new(bits: u32) -> Self {
+        Self {
+            mask: u64::MAX >> (64 - bits),
+            state: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn extend(&self, raw: u64) -> u64 {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            let (last, total) = &mut *state;
+            *total += raw.wrapping_sub(*last) & self.mask;
+            *last = raw & self.mask;
+            *total
+        })
+    }
+}
+
+/// The time stamp counter, only usable as a clocksource when it's invariant
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl Tsc {
+    pub fn new(frequency: u64) -> Self {
+        Self { frequency }
+    }
+
+    /// Whether the tsc ticks at a constant rate regardless of power states
+    pub fn is_invariant() -> bool {
+        unsafe {
+            __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+        }
+    }
+
+    /// Frequency reported by cpuid leaf 0x15 or 0x16, most cpus don't report it
+    pub fn cpuid_frequency() -> Option<u64> {
+        let max_leaf = unsafe { __cpuid(0).eax };
+        if max_leaf >= 0x15 {
+            let leaf = unsafe { __cpuid(0x15) };
+            if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+                return Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64);
+            }
+        }
+        if max_leaf >= 0x16 {
+            let base_mhz = unsafe { __cpuid(0x16) }.eax & 0xFFFF;
+            if base_mhz != 0 {
+                return Some(base_mhz as u64 * 1_000_000);
+            }
+        }
+        None
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        unsafe { _rdtsc() }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
@@ -0,0 +1,94 @@
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use super::clocksource::{ClockSource, CounterExtender};
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0_DATA: u16 = 0x40;
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const CHANNEL_2_GATE: u16 = 0x61;
+
+const GATE_ENABLE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const OUTPUT_HIGH: u8 = 1 << 5;
+
+/// Start a one shot countdown on channel 2, the only channel usable without an interrupt
+///
+/// At most 54ms can be counted, longer durations are clamped
+pub fn start_oneshot(micros: u64) {
+    let count = (PIT_FREQUENCY * micros / 1_000_000).clamp(1, u16::MAX as u64) as u16;
+    unsafe {
+        let mut gate = Port::<u8>::new(CHANNEL_2_GATE);
+        let mut data = Port::<u8>::new(CHANNEL_2_DATA);
+        let value = gate.read() & !(GATE_ENABLE | SPEAKER_ENABLE);
+        gate.write(value);
+        // Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+        Port::<u8>::new(COMMAND).write(0b1011_0000);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        // Counting starts once the gate is raised
+        gate.write(value | GATE_ENABLE);
+    }
+}
+
+/// Whether the countdown started by [`start_oneshot`] reached zero
+pub fn oneshot_expired() -> bool {
+    unsafe { Port::<u8>::new(CHANNEL_2_GATE).read() & OUTPUT_HIGH != 0 }
+}
+
+/// Busy wait using channel 2, only meant for calibrating other clocks
+pub fn wait(micros: u64) {
+    start_oneshot(micros);
+    while !oneshot_expired() {
+        core::hint::spin_loop();
+    }
+}
+
+/// Channel 0 counting freely, its irq is never routed so it doesn't raise interrupts
+///
+/// The counter wraps every 54ms, it's read by the timer interrupt often enough to not miss one
+pub struct PitClock {
+    counter: CounterExtender,
+}
+
+impl PitClock {
+    /// Reprogram channel 0 to count freely
+    pub fn start() -> Self {
+        unsafe {
+            // Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+            Port::<u8>::new(COMMAND).write(0b0011_0100);
+            let mut data = Port::<u8>::new(CHANNEL_0_DATA);
+            // A reload value of 0 counts the full 65536 ticks
+            data.write(0);
+            data.write(0);
+        }
+        Self {
+            counter: CounterExtender::new(16),
+        }
+    }
+}
+
+impl ClockSource for PitClock {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn read(&self) -> u64 {
+        let count = interrupts::without_interrupts(|| unsafe {
+            // Latch channel 0 so both bytes belong to the same count
+            Port::<u8>::new(COMMAND).write(0);
+            let mut data = Port::<u8>::new(CHANNEL_0_DATA);
+            let low = data.read() as u16;
+            let high = data.read() as u16;
+            low | (high << 8)
+        });
+        // The channel counts down
+        self.counter.extend(count.wrapping_neg() as u64)
+    }
+
+    fn frequency(&self) -> u64 {
+        PIT_FREQUENCY
+    }
+}
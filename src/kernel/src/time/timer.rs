@@ -0,0 +1,206 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{Duration, Elapsed, Instant, TIMER_FREQUENCY};
+
+const WHEEL_SLOTS: usize = 256;
+const NANOS_PER_TICK: u64 = 1_000_000_000 / TIMER_FREQUENCY;
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+struct TimerEntry {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+/// Location of a timer in the wheel
+#[derive(Debug, Clone, Copy)]
+struct TimerHandle {
+    id: u64,
+    tick: u64,
+}
+
+/// Hashed timing wheel with one slot per tick, timers further than a rotation wait for their round
+struct TimerWheel {
+    slots: [Vec<TimerEntry>; WHEEL_SLOTS],
+    current: u64,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self {
+            slots: [const { Vec::new() }; WHEEL_SLOTS],
+            current: 0,
+        }
+    }
+
+    fn insert(&mut self, deadline: Instant, waker: Waker) -> TimerHandle {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        // Round up so the timer never fires before the deadline
+        let tick = deadline
+            .since_boot()
+            .as_nanos()
+            .div_ceil(NANOS_PER_TICK as u128) as u64;
+        let tick = tick.max(self.current + 1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.slots[tick as usize % WHEEL_SLOTS].push(TimerEntry {
+            id,
+            deadline: tick,
+            waker,
+        });
+        TimerHandle { id, tick }
+    }
+
+    fn cancel(&mut self, handle: TimerHandle) {
+        self.slots[handle.tick as usize % WHEEL_SLOTS].retain(|e| e.id != handle.id);
+    }
+
+    /// Wake every timer due at or before `now`
+    fn advance(&mut self, now: u64) {
+        if now <= self.current {
+            return;
+        }
+        let ticks = (now - self.current).min(WHEEL_SLOTS as u64);
+        for tick in self.current + 1..=self.current + ticks {
+            self.slots[tick as usize % WHEEL_SLOTS].retain(|e| {
+                if e.deadline <= now {
+                    e.waker.wake_by_ref();
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.current = now;
+    }
+}
+
+/// Called from the local apic timer interrupt
+pub fn handle_tick() {
+    let now = Instant::now().since_boot().as_nanos() as u64 / NANOS_PER_TICK;
+    WHEEL.lock().advance(now);
+}
+
+/// Future that completes once its deadline passed
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<TimerHandle>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn cancel(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            interrupts::without_interrupts(|| WHEEL.lock().cancel(timer));
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Instant::now() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        // Register again, the task may have moved to another waker since the last poll
+        self.cancel();
+        let deadline = self.deadline;
+        self.timer = Some(interrupts::without_interrupts(|| {
+            WHEEL.lock().insert(deadline, cx.waker().clone())
+        }));
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Ticks every `period`, missed ticks are skipped instead of fired in a burst
+pub struct Interval {
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    /// Wait for the next tick and return when it was due
+    pub async fn tick(&mut self) -> Instant {
+        sleep_until(self.next).await;
+        let due = self.next;
+        self.next += self.period;
+        let now = Instant::now();
+        if self.next < now {
+            self.next = now + self.period;
+        }
+        due
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// The first tick completes after one period
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "Interval period must be non zero");
+    Interval {
+        period,
+        next: Instant::now() + period,
+    }
+}
+
+/// Future that gives up on the inner future once the deadline passed
+pub struct Timeout<F: Future> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the inner future is never moved out of the pinned timeout
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
@@ -1,7 +1,13 @@
 use self::scheduler::Scheduler;
 
+pub mod loader;
+pub mod process;
+pub mod programs;
 pub mod scheduler;
+pub mod syscall;
+pub mod usermode;
 
 pub fn init() {
     scheduler::SCHEDULER.init_once(|| Scheduler::new().into());
+    crate::task::thread::init();
 }
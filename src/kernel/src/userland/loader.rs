@@ -0,0 +1,332 @@
+use core::error::Error;
+use core::fmt::Display;
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+use elf_rs::{Elf, ElfClass, ElfFile, ElfMachine, ElfType, ProgramHeaderEntry, ProgramType};
+use x86_64::instructions::random::RdRand;
+
+use crate::memory::address_space::{AddressSpace, AddressSpaceError, USER_END, USER_START};
+use crate::memory::paging::EntryFlags;
+use crate::memory::PAGE_SIZE;
+
+/// Where position independent executables get loaded
+pub const PIE_BASE: u64 = USER_START + 0x4000_0000;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_RELR: u64 = 36;
+
+const R_X86_64_NONE: u64 = 0;
+const R_X86_64_RELATIVE: u64 = 8;
+const RELA_SIZE: usize = 24;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+#[derive(Debug)]
+pub enum LoadError {
+    InvalidElf(elf_rs::Error),
+    Unsupported(&'static str),
+    /// A segment points past the end of the file or has more bytes in the file than in memory
+    InvalidSegment(u64),
+    /// The dynamic section or its relocations point outside of the loaded segments
+    InvalidDynamic,
+    /// Arguments and environment don't fit on the stack
+    ArgumentsTooLarge,
+    AddressSpace(AddressSpaceError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidElf(err) => write!(f, "Invalid elf file: {:?}", err),
+            Self::Unsupported(what) => write!(f, "Unsupported executable: {}", what),
+            Self::InvalidSegment(vaddr) => write!(f, "Invalid segment at {:#x}", vaddr),
+            Self::InvalidDynamic => write!(f, "Invalid dynamic section"),
+            Self::ArgumentsTooLarge => write!(f, "Arguments don't fit on the stack"),
+            Self::AddressSpace(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<AddressSpaceError> for LoadError {
+    fn from(value: AddressSpaceError) -> Self {
+        Self::AddressSpace(value)
+    }
+}
+
+/// Where an executable ended up, what the auxiliary vector tells the program
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedImage {
+    pub entry: u64,
+    /// Offset added to every address of the file, 0 unless it's position independent
+    pub base: u64,
+    /// Address of the program headers in memory, 0 if no segment loads them
+    pub program_headers: u64,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+}
+
+/// Map the loadable segments of a static x86_64 executable or static pie and relocate it
+pub fn load(space: &mut AddressSpace, image: &[u8]) -> Result<LoadedImage, LoadError> {
+    let elf = Elf::from_bytes(image).map_err(LoadError::InvalidElf)?;
+    let header = elf.elf_header();
+    if header.class() != ElfClass::Elf64 || header.machine() != ElfMachine::x86_64 {
+        return Err(LoadError::Unsupported("not an x86_64 executable"));
+    }
+    if elf
+        .program_header_iter()
+        .any(|e| e.ph_type() == ProgramType::INTERP)
+    {
+        return Err(LoadError::Unsupported("dynamically linked"));
+    }
+    let base = match header.elftype() {
+        ElfType::ET_EXEC => 0,
+        ElfType::ET_DYN => PIE_BASE,
+        _ => return Err(LoadError::Unsupported("not an executable")),
+    };
+
+    let segments: Vec<ProgramHeaderEntry> = elf
+        .program_header_iter()
+        .filter(|e| e.ph_type() == ProgramType::LOAD && e.memsz() != 0)
+        .collect();
+    for (start, end, flags) in page_regions(&segments, base)? {
+        space.map(start, end - start, flags)?;
+    }
+    for segment in &segments {
+        let data = segment
+            .content()
+            .filter(|_| segment.filesz() <= segment.memsz())
+            .ok_or(LoadError::InvalidSegment(segment.vaddr()))?;
+        let vaddr = user_address(base, segment.vaddr(), segment.memsz())
+            .ok_or(LoadError::InvalidSegment(segment.vaddr()))?;
+        // The tail past the file content is left as mapped, zeroed, that's the bss
+        space.write(vaddr, data)?;
+    }
+
+    if let Some(dynamic) = elf
+        .program_header_iter()
+        .find(|e| e.ph_type() == ProgramType::DYNAMIC)
+    {
+        let dynamic = dynamic.content().ok_or(LoadError::InvalidDynamic)?;
+        relocate(space, &segments, dynamic, base)?;
+    }
+
+    let program_headers = elf
+        .program_header_iter()
+        .find(|e| e.ph_type() == ProgramType::PHDR)
+        .map(|e| e.vaddr())
+        .or_else(|| {
+            let offset = header.program_header_offset();
+            segments
+                .iter()
+                .find(|e| {
+                    e.offset()
+                        .checked_add(e.filesz())
+                        .is_some_and(|end| (e.offset()..end).contains(&offset))
+                })
+                .and_then(|e| e.vaddr().checked_add(offset - e.offset()))
+        })
+        .and_then(|e| user_address(base, e, 0))
+        .unwrap_or(0);
+    let entry = user_address(base, header.entry_point(), 1)
+        .ok_or(LoadError::Unsupported("entry point outside of user memory"))?;
+    Ok(LoadedImage {
+        entry,
+        base,
+        program_headers,
+        program_header_size: header.program_header_entry_size(),
+        program_header_count: header.program_header_entry_num(),
+    })
+}
+
+/// Page aligned ranges covering the segments, a page shared by two segments gets the access of both
+fn page_regions(
+    segments: &[ProgramHeaderEntry],
+    base: u64,
+) -> Result<Vec<(u64, u64, EntryFlags)>, LoadError> {
+    let mut regions: Vec<(u64, u64, EntryFlags)> = Vec::new();
+    for segment in segments {
+        let vaddr = user_address(base, segment.vaddr(), segment.memsz())
+            .ok_or(LoadError::InvalidSegment(segment.vaddr()))?;
+        let start = vaddr - vaddr % PAGE_SIZE;
+        // User memory ends on a page boundary
+        let end = (vaddr + segment.memsz()).next_multiple_of(PAGE_SIZE);
+        let flags = EntryFlags::from_elf_program_flags(segment.flags());
+        let Some(last) = regions.last_mut() else {
+            regions.push((start, end, flags));
+            continue;
+        };
+        if start >= last.1 {
+            regions.push((start, end, flags));
+            continue;
+        }
+        // Segments come sorted, they may only meet in one page
+        if start != last.1 - PAGE_SIZE {
+            return Err(LoadError::InvalidSegment(segment.vaddr()));
+        }
+        let shared = (start, last.1, merge_flags(last.2, flags));
+        last.1 = start;
+        if last.0 == last.1 {
+            regions.pop();
+        }
+        regions.push(shared);
+        if end > shared.1 {
+            regions.push((shared.1, end, flags));
+        }
+    }
+    Ok(regions)
+}
+
+/// Flags allowing everything either of `first` and `second` allow
+fn merge_flags(first: EntryFlags, second: EntryFlags) -> EntryFlags {
+    let execute = (first & second) & EntryFlags::NO_EXECUTE;
+    ((first | second) - EntryFlags::NO_EXECUTE) | execute
+}
+
+/// Apply the relocations listed in the dynamic section, a static pie only has relative ones
+fn relocate(
+    space: &mut AddressSpace,
+    segments: &[ProgramHeaderEntry],
+    dynamic: &[u8],
+    base: u64,
+) -> Result<(), LoadError> {
+    let (mut rela, mut rela_size, mut rela_entry) = (None, 0, RELA_SIZE as u64);
+    for entry in dynamic.chunks_exact(16) {
+        let (tag, value) = (read_u64(&entry[..8]), read_u64(&entry[8..]));
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => rela_size = value,
+            DT_RELAENT => rela_entry = value,
+            DT_REL | DT_RELR => return Err(LoadError::Unsupported("relocation format")),
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Ok(());
+    };
+    if rela_entry != RELA_SIZE as u64 {
+        return Err(LoadError::InvalidDynamic);
+    }
+    let table = file_bytes(segments, rela, rela_size).ok_or(LoadError::InvalidDynamic)?;
+    for relocation in table.chunks_exact(RELA_SIZE) {
+        let offset = read_u64(&relocation[..8]);
+        let addend = read_u64(&relocation[16..]);
+        match read_u64(&relocation[8..16]) & 0xFFFF_FFFF {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let address = user_address(base, offset, 8).ok_or(LoadError::InvalidDynamic)?;
+                space.write(address, &base.wrapping_add(addend).to_le_bytes())?
+            }
+            _ => return Err(LoadError::Unsupported("relocation type")),
+        }
+    }
+    Ok(())
+}
+
+/// `base + offset`, if the `len` bytes from there are all in user memory
+fn user_address(base: u64, offset: u64, len: u64) -> Option<u64> {
+    let start = base.checked_add(offset)?;
+    (start >= USER_START && start.checked_add(len)? <= USER_END).then_some(start)
+}
+
+/// File content loaded at `vaddr..vaddr + len`, before relocation
+fn file_bytes<'a>(segments: &[ProgramHeaderEntry<'a>], vaddr: u64, len: u64) -> Option<&'a [u8]> {
+    let segment = segments
+        .iter()
+        .find(|e| vaddr >= e.vaddr() && vaddr - e.vaddr() < e.filesz())?;
+    let start = (vaddr - segment.vaddr()) as usize;
+    segment
+        .content()?
+        .get(start..start.checked_add(len as usize)?)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+/// Lay out argc, argv, envp and the auxiliary vector below `top` like the system v abi wants,
+/// returns the stack pointer to start the program with
+pub fn setup_stack(
+    space: &mut AddressSpace,
+    top: u64,
+    size: u64,
+    argv: &[&str],
+    envp: &[&str],
+    image: &LoadedImage,
+) -> Result<u64, LoadError> {
+    let random_address = top - 16;
+    let mut strings = Vec::new();
+    let mut pointers = Vec::new();
+    let string_count = argv.len() + envp.len();
+    let strings_size: usize = argv.iter().chain(envp).map(|e| e.len() + 1).sum();
+    let strings_start = random_address
+        .checked_sub(strings_size as u64)
+        .ok_or(LoadError::ArgumentsTooLarge)?;
+    for string in argv.iter().chain(envp) {
+        pointers.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+
+    let auxv = [
+        (AT_PHDR, image.program_headers),
+        (AT_PHENT, image.program_header_size as u64),
+        (AT_PHNUM, image.program_header_count as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, image.entry),
+        (AT_RANDOM, random_address),
+        (AT_NULL, 0),
+    ];
+    let mut table = Vec::with_capacity(string_count + 3 + 2 * auxv.len());
+    table.push(argv.len() as u64);
+    table.extend_from_slice(&pointers[..argv.len()]);
+    table.push(0);
+    table.extend_from_slice(&pointers[argv.len()..]);
+    table.push(0);
+    for (kind, value) in auxv {
+        table.extend_from_slice(&[kind, value]);
+    }
+
+    let table_size = (table.len() * size_of::<u64>()) as u64;
+    let stack_pointer = strings_start
+        .checked_sub(table_size)
+        .map(|e| e & !0xF)
+        .filter(|e| *e >= top - size)
+        .ok_or(LoadError::ArgumentsTooLarge)?;
+    let table: Vec<u8> = table.iter().flat_map(|e| e.to_le_bytes()).collect();
+    space.write(random_address, &random_bytes())?;
+    space.write(strings_start, &strings)?;
+    space.write(stack_pointer, &table)?;
+    Ok(stack_pointer)
+}
+
+/// Seed for the program's stack protector and such, the time stamp counter without rdrand
+fn random_bytes() -> [u8; 16] {
+    let random = || match RdRand::new() {
+        Some(rdrand) => rdrand.get_u64().unwrap_or_else(time_stamp),
+        None => time_stamp(),
+    };
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&random().to_le_bytes());
+    bytes[8..].copy_from_slice(&random().to_le_bytes());
+    bytes
+}
+
+fn time_stamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
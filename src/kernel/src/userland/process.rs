@@ -0,0 +1,358 @@
+use core::error::Error;
+use core::fmt::Display;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::inline_if;
+use crate::interrupt::FullInterruptStackFrame;
+use crate::memory::address_space::{self, AddressSpace};
+use crate::smp::percpu;
+use crate::task::thread::{self, JoinHandle};
+
+use super::loader::LoadError;
+use super::programs;
+use super::scheduler::{ProcessState, ThreadId, SCHEDULER};
+use super::usermode;
+
+/// Handles a new process starts with, standard input, output and error
+const STANDARD_HANDLES: usize = 3;
+/// Most handles a process can have open at once
+const MAX_HANDLES: usize = 64;
+
+static PROCESS_TABLE: Mutex<BTreeMap<Pid, UserProcess>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_raw(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Display for Pid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessStatus {
+    Running,
+    /// Exited with the status, waiting for its parent to collect it
+    Zombie(i64),
+}
+
+/// What a handle of a process refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    Console,
+    /// Read only file of the initrd
+    File(&'static [u8]),
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    NotFound,
+    Load(LoadError),
+    NoSuchProcess(Pid),
+    NoChildren,
+    /// Processes can only act on their own children
+    NotAChild(Pid),
+    /// The waiting process got killed
+    Interrupted,
+    /// Only user processes can do that
+    NotAProcess,
+    OutOfMemory,
+    TooManyHandles,
+}
+
+impl Display for ProcessError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "No such program"),
+            Self::Load(err) => write!(f, "{}", err),
+            Self::NoSuchProcess(pid) => write!(f, "No process {}", pid),
+            Self::NoChildren => write!(f, "No child process to wait for"),
+            Self::NotAChild(pid) => write!(f, "Process {} isn't a child of the caller", pid),
+            Self::Interrupted => write!(f, "Interrupted"),
+            Self::NotAProcess => write!(f, "Not called from a user process"),
+            Self::OutOfMemory => write!(f, "Out of memory"),
+            Self::TooManyHandles => write!(f, "Too many open handles"),
+        }
+    }
+}
+
+impl Error for ProcessError {}
+
+impl From<LoadError> for ProcessError {
+    fn from(value: LoadError) -> Self {
+        Self::Load(value)
+    }
+}
+
+struct UserProcess {
+    name: String,
+    /// Kernel threads are the parent of processes they spawn, they share it
+    parent: Option<Pid>,
+    /// Removed once it exits instead of becoming a zombie
+    detached: bool,
+    thread: ThreadId,
+    status: ProcessStatus,
+    handles: Vec<Option<Handle>>,
+}
+
+/// Run the program at `path` of the initrd as a child of the caller, `args` follow the path in argv
+pub fn spawn(path: &str, args: &[&str]) -> Result<Pid, ProcessError> {
+    let mut argv = vec![path];
+    argv.extend_from_slice(args);
+    spawn_with_argv(path, &argv)
+}
+
+/// Like [`spawn`] with the whole argv, the first one included
+pub fn spawn_with_argv(path: &str, argv: &[&str]) -> Result<Pid, ProcessError> {
+    let image = programs::find(path).ok_or(ProcessError::NotFound)?;
+    let (pid, _) = start(path, image, argv, &[], current(), false)?;
+    Ok(pid)
+}
+
+/// Load `image` in a new address space and run it in a new thread
+pub(crate) fn start(
+    name: &str,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    parent: Option<Pid>,
+    detached: bool,
+) -> Result<(Pid, JoinHandle<i64>), LoadError> {
+    let (space, entry, stack) = usermode::prepare(image, argv, envp)?;
+    let handles = vec![Some(Handle::Console); STANDARD_HANDLES];
+    Ok(launch(name, space, parent, detached, handles, move || {
+        usermode::run(entry, stack)
+    }))
+}
+
+/// Copy the calling process, the child continues from `frame` with 0 in rax and gets its own pid
+pub fn fork(frame: &FullInterruptStackFrame) -> Result<Pid, ProcessError> {
+    let parent = current().ok_or(ProcessError::NotAProcess)?;
+    let (name, handles) = {
+        let table = PROCESS_TABLE.lock();
+        let process = &table[&parent];
+        (process.name.clone(), process.handles.clone())
+    };
+    let space = address_space::fork_current().map_err(|_| ProcessError::OutOfMemory)?;
+    let mut frame = frame.clone();
+    frame.rax = 0;
+    let (pid, _) = launch(&name, space, Some(parent), false, handles, move || {
+        usermode::resume(&frame)
+    });
+    Ok(pid)
+}
+
+/// Run `user` in a new thread in `space` and add it to the process table
+fn launch(
+    name: &str,
+    space: AddressSpace,
+    parent: Option<Pid>,
+    detached: bool,
+    handles: Vec<Option<Handle>>,
+    user: impl FnOnce() -> i64 + Send + 'static,
+) -> (Pid, JoinHandle<i64>) {
+    let pid = Pid::new();
+    // Locked until the process is in the table, it can't exit before
+    let mut table = PROCESS_TABLE.lock();
+    let handle = thread::spawn_in(name, pid, space, move || {
+        let status = user();
+        exit(pid, status);
+        status
+    });
+    table.insert(
+        pid,
+        UserProcess {
+            name: String::from(name),
+            parent,
+            detached,
+            thread: handle.id(),
+            status: ProcessStatus::Running,
+            handles,
+        },
+    );
+    (pid, handle)
+}
+
+/// Turn `pid` into a zombie, its address space and stack go once its thread is reaped
+fn exit(pid: Pid, status: i64) {
+    let mut table = PROCESS_TABLE.lock();
+    // Nothing is left to wait for the children
+    table.retain(|_, e| e.parent != Some(pid) || e.status == ProcessStatus::Running);
+    for child in table.values_mut().filter(|e| e.parent == Some(pid)) {
+        child.parent = None;
+        child.detached = true;
+    }
+    let process = table
+        .get_mut(&pid)
+        .expect("Exiting process not in the table");
+    process.handles.clear();
+    if process.detached {
+        table.remove(&pid);
+    } else {
+        process.status = ProcessStatus::Zombie(status);
+    }
+    drop(table);
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .get()
+            .expect("Scheduler not initialized")
+            .lock()
+            .wake_waiting()
+    });
+}
+
+/// Block until the child `pid`, or any child without one, exits and collect its status
+///
+/// Every resource of the child is freed once this returns
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, i64), ProcessError> {
+    let parent = current();
+    loop {
+        let mut table = PROCESS_TABLE.lock();
+        let mut children = table
+            .iter()
+            .filter(|(id, e)| e.parent == parent && !e.detached && pid.is_none_or(|p| p == **id))
+            .peekable();
+        if children.peek().is_none() {
+            return Err(pid.map_or(ProcessError::NoChildren, ProcessError::NoSuchProcess));
+        }
+        let zombie = children.find_map(|(id, e)| match e.status {
+            ProcessStatus::Zombie(status) => Some((*id, status)),
+            ProcessStatus::Running => None,
+        });
+        if let Some((id, status)) = zombie {
+            let child = table.remove(&id).unwrap();
+            drop(table);
+            thread::wait_for_exit(child.thread);
+            thread::reap();
+            return Ok((id, status));
+        }
+        let killed = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.get().expect("Scheduler not initialized").lock();
+            let killed = scheduler.current_killed();
+            if !killed {
+                scheduler.set_current_state(ProcessState::Waiting);
+            }
+            killed
+        });
+        drop(table);
+        if killed {
+            return Err(ProcessError::Interrupted);
+        }
+        thread::yield_now();
+    }
+}
+
+/// Thread of `pid` if it's a running child of the caller
+fn killable(pid: Pid) -> Result<ThreadId, ProcessError> {
+    let parent = current();
+    let table = PROCESS_TABLE.lock();
+    let process = table
+        .get(&pid)
+        .filter(|e| e.status == ProcessStatus::Running)
+        .ok_or(ProcessError::NoSuchProcess(pid))?;
+    if process.parent != parent {
+        return Err(ProcessError::NotAChild(pid));
+    }
+    Ok(process.thread)
+}
+
+/// Fails the way [`kill`] would without killing anything
+pub fn check_kill(pid: Pid) -> Result<(), ProcessError> {
+    killable(pid).map(|_| ())
+}
+
+/// Make the child `pid` of the caller exit with [`usermode::KILL_STATUS`] the next time it runs in ring 3
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let thread = killable(pid)?;
+    let found = interrupts::without_interrupts(|| {
+        SCHEDULER
+            .get()
+            .expect("Scheduler not initialized")
+            .lock()
+            .kill(thread)
+    });
+    inline_if!(found, Ok(()), Err(ProcessError::NoSuchProcess(pid)))
+}
+
+/// Process of the running thread, none for kernel threads
+pub fn current() -> Option<Pid> {
+    percpu::current().process()
+}
+
+pub fn status(pid: Pid) -> Option<ProcessStatus> {
+    PROCESS_TABLE.lock().get(&pid).map(|e| e.status)
+}
+
+/// Parent of `pid`, none if it's a kernel thread or `pid` doesn't exist
+pub fn parent(pid: Pid) -> Option<Pid> {
+    PROCESS_TABLE.lock().get(&pid).and_then(|e| e.parent)
+}
+
+pub fn name(pid: Pid) -> Option<String> {
+    PROCESS_TABLE.lock().get(&pid).map(|e| e.name.clone())
+}
+
+/// Handle `fd` of the running process, kernel threads have the standard ones
+pub fn handle(fd: u64) -> Option<Handle> {
+    let Some(pid) = current() else {
+        return inline_if!(fd < STANDARD_HANDLES as u64, Some(Handle::Console), None);
+    };
+    let table = PROCESS_TABLE.lock();
+    let handles = &table.get(&pid)?.handles;
+    handles.get(usize::try_from(fd).ok()?).copied().flatten()
+}
+
+/// Give the running process `handle` under the lowest free number, which gets returned
+pub fn open(handle: Handle) -> Result<u64, ProcessError> {
+    let pid = current().ok_or(ProcessError::NotAProcess)?;
+    let mut table = PROCESS_TABLE.lock();
+    let handles = &mut table.get_mut(&pid).unwrap().handles;
+    let fd = match handles.iter().position(Option::is_none) {
+        Some(fd) => fd,
+        None if handles.len() < MAX_HANDLES => {
+            handles.push(None);
+            handles.len() - 1
+        }
+        None => return Err(ProcessError::TooManyHandles),
+    };
+    handles[fd] = Some(handle);
+    Ok(fd as u64)
+}
+
+/// Close handle `fd` of the running process, returns whether it was open
+pub fn close(fd: u64) -> bool {
+    let Some(pid) = current() else {
+        return false;
+    };
+    let mut table = PROCESS_TABLE.lock();
+    let Some(process) = table.get_mut(&pid) else {
+        return false;
+    };
+    usize::try_from(fd)
+        .ok()
+        .and_then(|e| process.handles.get_mut(e))
+        .and_then(|e| e.take())
+        .is_some()
+}
@@ -0,0 +1,60 @@
+/// Exits with status 42 right away
+pub static EXIT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/exit.elf"));
+
+/// Writes a line with the syscall instruction and exits with the byte count
+pub static HELLO: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/hello.elf"));
+
+/// Passes a kernel address to write and exits with the error it got
+pub static BAD_WRITE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bad_write.elf"));
+
+/// Writes its first argument and exits with `argc << 8` plus the byte count
+pub static ARGS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/args.elf"));
+
+/// Static pie writing a line through a relocated pointer, the byte count is its exit status
+pub static PIE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/pie.elf"));
+
+/// Spawns /bin/exit and exits with its status plus one
+pub static PARENT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/parent.elf"));
+
+/// Spins in ring 3 until it gets killed
+pub static SPIN: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/spin.elf"));
+
+/// Closes standard output and exits with the error writing to it gave
+pub static CLOSE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/close.elf"));
+
+/// Forks and exits with 107, the status of the child plus a stack value only the child changed
+pub static FORK: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/fork.elf"));
+
+/// Writes to its own code and gets killed by the page fault
+pub static SEGV: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/segv.elf"));
+
+/// Maps memory and /bin/exit, exits with 77 once it read both back
+pub static MMAP: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mmap.elf"));
+
+/// Writes to a page it made read only and gets killed by the page fault
+pub static PROTECT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/protect.elf"));
+
+/// Kills itself and exits with the error it got, processes can only kill their children
+pub static KILL_SELF: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/kill_self.elf"));
+
+/// Programs [`find`] knows, the initrd the kernel carries around
+pub static INITRD: [(&str, &[u8]); 13] = [
+    ("/bin/exit", EXIT),
+    ("/bin/hello", HELLO),
+    ("/bin/bad_write", BAD_WRITE),
+    ("/bin/args", ARGS),
+    ("/bin/pie", PIE),
+    ("/bin/parent", PARENT),
+    ("/bin/spin", SPIN),
+    ("/bin/close", CLOSE),
+    ("/bin/fork", FORK),
+    ("/bin/segv", SEGV),
+    ("/bin/mmap", MMAP),
+    ("/bin/protect", PROTECT),
+    ("/bin/kill_self", KILL_SELF),
+];
+
+/// Executable at `path` in the initrd
+pub fn find(path: &str) -> Option<&'static [u8]> {
+    INITRD.iter().find(|e| e.0 == path).map(|e| e.1)
+}
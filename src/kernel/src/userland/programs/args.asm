@@ -0,0 +1,23 @@
+; Writes its first argument and exits with argc in the second byte and the byte count in the first
+%include "elf.inc"
+
+_start:
+    mov rbx, [rsp]                  ; argc
+    mov rsi, [rsp + 16]             ; argv[1]
+    xor edx, edx
+.length:
+    cmp byte [rsi + rdx], 0
+    je .write
+    inc rdx
+    jmp .length
+.write:
+    mov edi, 1                      ; standard output
+    mov eax, 1                      ; write
+    syscall
+    shl rbx, 8
+    lea rdi, [rbx + rax]
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+elf_end
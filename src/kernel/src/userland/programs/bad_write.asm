@@ -0,0 +1,15 @@
+; Hands write a kernel address and exits with what it returned
+%include "elf.inc"
+
+_start:
+    mov edi, 1                      ; standard output
+    mov esi, 0x100000               ; kernel image, not user accessible
+    mov edx, 16
+    mov eax, 1                      ; write
+    syscall
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+elf_end
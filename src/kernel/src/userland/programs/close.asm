@@ -0,0 +1,25 @@
+; Closes standard output, then exits with what writing to it returned
+%include "elf.inc"
+
+_start:
+    mov edi, 1                      ; standard output
+    mov eax, 3                      ; close
+    syscall
+    test rax, rax
+    jnz .exit
+    mov edi, 1
+    lea rsi, [rel message]
+    mov edx, message_size
+    mov eax, 1                      ; write
+    syscall
+.exit:
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+message:
+    db "Still open", 10
+message_size equ $ - message
+
+elf_end
@@ -0,0 +1,10 @@
+; Exits with status 42 through the interrupt gate
+%include "elf.inc"
+
+_start:
+    mov edi, 42
+    mov eax, 60                     ; exit
+    int 0x80
+    ud2
+
+elf_end
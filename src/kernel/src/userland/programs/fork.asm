@@ -0,0 +1,37 @@
+; Forks, the child overwrites a stack value and exits with it, the parent exits with the
+; child's status plus its own untouched copy of the value
+%include "elf.inc"
+
+_start:
+    push 7
+    mov eax, 57                     ; fork
+    syscall
+    test rax, rax
+    js .failed
+    jnz .parent
+    mov qword [rsp], 100
+    mov rdi, [rsp]
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+.parent:
+    mov rbx, rax
+    mov rdi, rax
+    sub rsp, 8
+    mov rsi, rsp                    ; status
+    mov eax, 61                     ; wait4
+    syscall
+    cmp rax, rbx
+    jne .failed
+    pop rdi
+    add rdi, [rsp]
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+.failed:
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+elf_end
@@ -0,0 +1,19 @@
+; Writes a greeting with the syscall instruction and exits with what write returned
+%include "elf.inc"
+
+_start:
+    mov edi, 1                      ; standard output
+    lea rsi, [rel message]
+    mov edx, message_size
+    mov eax, 1                      ; write
+    syscall
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+message:
+    db "Hello from ring 3", 10
+message_size equ $ - message
+
+elf_end
@@ -0,0 +1,16 @@
+; Tries to kill itself, which isn't its own child, and exits with what kill returned
+%include "elf.inc"
+
+_start:
+    mov eax, 39                     ; getpid
+    syscall
+    mov rdi, rax
+    mov esi, 9                      ; SIGKILL
+    mov eax, 62                     ; kill
+    syscall
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+elf_end
@@ -0,0 +1,67 @@
+; Maps an anonymous page and /bin/exit, exits with 77 if the page held a value and the file
+; started with the elf magic
+%include "elf.inc"
+
+_start:
+    xor edi, edi
+    mov esi, 4096
+    mov edx, 3                      ; read and write
+    mov r10d, 0x22                  ; private and anonymous
+    mov r8, -1
+    xor r9d, r9d
+    mov eax, 9                      ; mmap
+    syscall
+    test rax, rax
+    js .failed
+    mov rbx, rax
+    mov qword [rbx], 70
+
+    lea rdi, [rel path]
+    xor esi, esi                    ; read only
+    mov eax, 2                      ; open
+    syscall
+    test rax, rax
+    js .failed
+    xor edi, edi
+    mov esi, 4096
+    mov edx, 1                      ; read
+    mov r10d, 2                     ; private
+    mov r8, rax
+    xor r9d, r9d
+    mov eax, 9                      ; mmap
+    syscall
+    test rax, rax
+    js .failed
+    mov r12, rax
+    cmp dword [r12], 0x464C457F
+    jne .unmap
+    add qword [rbx], 7
+.unmap:
+    mov rdi, r12
+    mov esi, 4096
+    mov eax, 11                     ; munmap
+    syscall
+    test rax, rax
+    js .failed
+
+    mov rdi, rbx
+    mov esi, 4096
+    mov edx, 1                      ; read
+    mov eax, 10                     ; mprotect
+    syscall
+    test rax, rax
+    js .failed
+    mov rdi, [rbx]
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+.failed:
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+path:
+    db "/bin/exit", 0
+
+elf_end
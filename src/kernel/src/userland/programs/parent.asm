@@ -0,0 +1,35 @@
+; Spawns /bin/exit, waits for it and exits with its status plus one
+%include "elf.inc"
+
+_start:
+    lea rdi, [rel path]
+    lea rsi, [rel argv]
+    mov eax, 59                     ; spawn
+    syscall
+    test rax, rax
+    js .failed
+    mov rbx, rax
+    mov rdi, rax
+    sub rsp, 16
+    mov rsi, rsp                    ; status
+    mov eax, 61                     ; wait4
+    syscall
+    cmp rax, rbx
+    jne .failed
+    mov rdi, [rsp]
+    inc rdi
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+.failed:
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+path:
+    db "/bin/exit", 0
+argv:
+    dq path, 0
+
+elf_end
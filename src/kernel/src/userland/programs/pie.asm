@@ -0,0 +1,80 @@
+; Static position independent executable, writes through a pointer only a relative relocation
+; makes valid and exits with the byte count plus a word from the end of its bss
+bits 64
+org 0
+
+elf_header:
+    db 0x7F, "ELF"
+    db 2                            ; 64 bits
+    db 1                            ; little endian
+    db 1                            ; elf version
+    db 0                            ; system v abi
+    times 8 db 0
+    dw 3                            ; shared object
+    dw 0x3E                         ; x86_64
+    dd 1                            ; elf version
+    dq _start                       ; entry point
+    dq program_headers - $$         ; program header offset
+    dq 0                            ; no section headers
+    dd 0                            ; flags
+    dw elf_header_size
+    dw program_header_size
+    dw 2                            ; program header count
+    dw 0                            ; section header size
+    dw 0                            ; section header count
+    dw 0                            ; section name table index
+elf_header_size equ $ - elf_header
+
+program_headers:
+    dd 1                            ; loadable
+    dd 7                            ; readable, writable and executable
+    dq 0                            ; offset
+    dq $$                           ; virtual address
+    dq $$                           ; physical address
+    dq file_size                    ; size in the file
+    dq memory_size                  ; size in memory, the rest is bss
+    dq 0x1000                       ; alignment
+program_header_size equ $ - program_headers
+    dd 2                            ; dynamic
+    dd 6                            ; readable and writable
+    dq dynamic - $$
+    dq dynamic
+    dq dynamic
+    dq dynamic_size
+    dq dynamic_size
+    dq 8
+
+; Right after the headers, at offset 176
+pointer:
+    dq 0
+
+dynamic:
+    dq 7, rela                      ; relocation table
+    dq 8, rela_size                 ; its size
+    dq 9, 24                        ; size of an entry
+    dq 0, 0
+dynamic_size equ $ - dynamic
+
+rela:
+    dq pointer, 8, message          ; relative, pointer = base + message
+rela_size equ $ - rela
+
+_start:
+    mov edi, 1                      ; standard output
+    mov rsi, [rel pointer]
+    mov edx, message_size
+    mov eax, 1                      ; write
+    syscall
+    add rax, [rel bss_end - 8]
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+message:
+    db "Relocated", 10
+message_size equ $ - message
+
+file_size equ $ - $$
+memory_size equ file_size + 0x2000
+bss_end equ $$ + memory_size
@@ -0,0 +1,31 @@
+; Maps a page, makes it read only and writes to it, the page fault kills it
+%include "elf.inc"
+
+_start:
+    xor edi, edi
+    mov esi, 4096
+    mov edx, 3                      ; read and write
+    mov r10d, 0x22                  ; private and anonymous
+    mov r8, -1
+    xor r9d, r9d
+    mov eax, 9                      ; mmap
+    syscall
+    test rax, rax
+    js .failed
+    mov rbx, rax
+    mov qword [rbx], 1
+    mov rdi, rbx
+    mov esi, 4096
+    mov edx, 1                      ; read
+    mov eax, 10                     ; mprotect
+    syscall
+    test rax, rax
+    js .failed
+    mov qword [rbx], 2
+.failed:
+    mov rdi, rax
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+elf_end
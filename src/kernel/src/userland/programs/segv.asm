@@ -0,0 +1,11 @@
+; Writes to its own read only code, the page fault kills it
+%include "elf.inc"
+
+_start:
+    mov byte [rel _start], 0
+    mov edi, 0
+    mov eax, 60                     ; exit
+    syscall
+    ud2
+
+elf_end
@@ -0,0 +1,8 @@
+; Never exits on its own
+%include "elf.inc"
+
+_start:
+    pause
+    jmp _start
+
+elf_end
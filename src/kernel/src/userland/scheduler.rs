@@ -1,39 +1,149 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use alloc::{string::String, vec::Vec};
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use crate::inline_if;
+use crate::interrupt::FullInterruptStackFrame;
+use crate::memory::address_space::AddressSpace;
+use crate::memory::stack_allocator::Stack;
+use crate::smp::percpu;
+use crate::time::{Instant, TIMER_FREQUENCY};
+
+use super::process::Pid;
+use super::usermode::{self, KILL_STATUS};
 
 pub static SCHEDULER: OnceCell<Mutex<Scheduler>> = OnceCell::uninit();
 
+/// Ticks a process runs before the next one gets picked
+const QUANTUM_TICKS: u64 = TIMER_FREQUENCY / 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    Sleeping(Instant),
+    Joining(ThreadId),
+    /// Blocked until a user process exits
+    Waiting,
+    Dead,
+}
+
+impl ProcessState {
+    fn is_runnable(self) -> bool {
+        matches!(self, Self::Ready | Self::Running)
+    }
+}
+
 pub struct Process {
+    id: ThreadId,
     count: u16,
     prio: usize,
     reset: u16,
     name: String,
+    state: ProcessState,
+    /// Address of the saved register frame while it's not running
+    context: u64,
+    /// The boot thread runs on the boot stack and has none
+    stack: Option<Stack>,
+    /// Kernel threads run in the kernel page table
+    address_space: Option<AddressSpace>,
+    /// User process the thread runs, none for kernel threads
+    pid: Option<Pid>,
+    /// Stack interrupts from ring 3 land on, zero until the process entered it
+    kernel_stack: u64,
+    /// Leaves ring 3 as soon as it gets back there
+    killed: bool,
 }
 
 pub struct Scheduler {
     processes: Vec<Process>,
+    current: ThreadId,
+    idle: Option<ThreadId>,
+    slice: u64,
+    kernel_p4: PhysAddr,
 }
 
 impl Process {
     pub fn new(reset: u16, name: String) -> Self {
         Self {
+            id: ThreadId::new(),
             count: reset,
             reset,
             prio: 0,
             name,
+            state: ProcessState::Ready,
+            context: 0,
+            stack: None,
+            address_space: None,
+            pid: None,
+            kernel_stack: 0,
+            killed: false,
+        }
+    }
+
+    /// Process resuming from the frame at `context` on `stack`, in `address_space` if it has one
+    ///
+    /// `pid` is the user process it runs, if any.
+    pub(crate) fn with_context(
+        reset: u16,
+        name: String,
+        context: u64,
+        stack: Stack,
+        address_space: Option<AddressSpace>,
+        pid: Option<Pid>,
+    ) -> Self {
+        Self {
+            context,
+            stack: Some(stack),
+            address_space,
+            pid,
+            ..Self::new(reset, name)
         }
     }
 
     pub fn get_name(&self) -> &String {
         &self.name
     }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub(crate) fn take_stack(&mut self) -> Option<Stack> {
+        self.stack.take()
+    }
 }
 
 impl Scheduler {
+    /// The caller becomes the running boot thread
     pub fn new() -> Self {
+        let mut main = Process::new(1, String::from("main"));
+        main.state = ProcessState::Running;
         Self {
-            processes: Vec::new(),
+            current: main.id,
+            processes: alloc::vec![main],
+            idle: None,
+            slice: QUANTUM_TICKS,
+            kernel_p4: Cr3::read().0.start_address(),
         }
     }
 
@@ -42,36 +152,235 @@ impl Scheduler {
         self
     }
 
+    /// Add the process only picked when no other one can run, it never competes with them
+    pub(crate) fn add_idle(&mut self, process: Process) {
+        self.idle = Some(process.id);
+        self.processes.push(process);
+    }
+
+    pub fn current(&self) -> ThreadId {
+        self.current
+    }
+
+    pub fn find(&mut self, id: ThreadId) -> Option<&mut Process> {
+        self.processes.iter_mut().find(|e| e.id == id)
+    }
+
+    /// Processes picked by [`Self::schedule_next`], the idle one never competes
+    fn is_candidate(idle: Option<ThreadId>, process: &Process) -> bool {
+        Some(process.id) != idle && process.state.is_runnable()
+    }
+
     pub fn schedule_next(&mut self) -> Option<&mut Process> {
-        if self.processes.is_empty() {
+        let idle = self.idle;
+        let candidate = |p: &Process| Self::is_candidate(idle, p);
+        if !self.processes.iter().any(candidate) {
             return None;
         }
 
         let mut is_zero = false;
         while !is_zero {
-            self.processes.iter_mut().for_each(|p| {
-                if p.count != 0 {
-                    p.count -= 1;
-                }
+            self.processes
+                .iter_mut()
+                .filter(|p| candidate(p))
+                .for_each(|p| {
+                    if p.count != 0 {
+                        p.count -= 1;
+                    }
+                    if p.count == 0 {
+                        is_zero = true;
+                    }
+                });
+        }
+
+        self.processes
+            .iter_mut()
+            .filter(|p| candidate(p))
+            .for_each(|p| {
                 if p.count == 0 {
-                    is_zero = true;
+                    p.prio += 1;
                 }
             });
-        }
-
-        self.processes.iter_mut().enumerate().for_each(|(_, p)| {
-            if p.count == 0 {
-                p.prio += 1;
-            }
-        });
-        let (_, process) = self
+        let process = self
             .processes
             .iter_mut()
-            .enumerate()
-            .max_by_key(|(_, process)| process.prio)
+            .filter(|p| candidate(p))
+            .max_by_key(|process| process.prio)
             .unwrap();
         process.count = process.reset;
         process.prio = 0;
         return Some(process);
     }
+
+    /// Whether another process than the running one could use the processor
+    pub fn has_other_runnable(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|e| e.id != self.current && Self::is_candidate(self.idle, e))
+    }
+
+    /// Save `frame` as the context of the running process and return the context to resume
+    pub(crate) fn switch(
+        &mut self,
+        frame: *mut FullInterruptStackFrame,
+    ) -> *mut FullInterruptStackFrame {
+        let current = self.current;
+        let process = self.find(current).expect("Running process not found");
+        process.context = frame as u64;
+        if process.state == ProcessState::Running {
+            process.state = ProcessState::Ready;
+        }
+        let next = match self.schedule_next() {
+            Some(process) => process.id,
+            None => self.idle.unwrap_or(current),
+        };
+        let kernel_p4 = self.kernel_p4;
+        let process = self.find(next).unwrap();
+        process.state = ProcessState::Running;
+        let context = process.context;
+        let p4 = process
+            .address_space
+            .as_ref()
+            .map_or(kernel_p4, |e| e.p4_address());
+        if Cr3::read().0.start_address() != p4 {
+            unsafe {
+                Cr3::write(
+                    PhysFrame::containing_address(p4),
+                    Cr3Flags::PAGE_LEVEL_WRITETHROUGH,
+                );
+            }
+        }
+        let cpu = percpu::current();
+        cpu.set_process(process.pid);
+        if process.kernel_stack != 0 {
+            cpu.set_kernel_stack(process.kernel_stack);
+        }
+        let context = context as *mut FullInterruptStackFrame;
+        // Preempted in ring 3, it can leave right away
+        if process.killed && usermode::is_user_frame(unsafe { &*context }) {
+            usermode::return_to_kernel(unsafe { &mut *context }, process.kernel_stack, KILL_STATUS);
+        }
+        self.current = next;
+        // The idle thread gets rescheduled on every tick in case something woke up
+        self.slice = inline_if!(Some(next) == self.idle, 1, QUANTUM_TICKS);
+        context
+    }
+
+    /// Called on every timer tick, switches once the running process used up its quantum
+    pub(crate) fn tick(
+        &mut self,
+        frame: *mut FullInterruptStackFrame,
+    ) -> *mut FullInterruptStackFrame {
+        let now = Instant::now();
+        for process in self.processes.iter_mut() {
+            if matches!(process.state, ProcessState::Sleeping(deadline) if deadline <= now) {
+                process.state = ProcessState::Ready;
+            }
+        }
+        self.slice = self.slice.saturating_sub(1);
+        if self.slice > 0 {
+            return frame;
+        }
+        self.switch(frame)
+    }
+
+    /// Stack the running process gets back to from ring 3
+    pub(crate) fn kernel_stack(&self) -> u64 {
+        self.processes
+            .iter()
+            .find(|e| e.id == self.current)
+            .unwrap()
+            .kernel_stack
+    }
+
+    pub(crate) fn set_kernel_stack(&mut self, top: u64) {
+        let current = self.current;
+        self.find(current).unwrap().kernel_stack = top;
+    }
+
+    pub(crate) fn set_current_state(&mut self, state: ProcessState) {
+        let current = self.current;
+        self.find(current).unwrap().state = state;
+    }
+
+    /// Mark the running process dead and wake everything joining it
+    pub(crate) fn exit_current(&mut self) {
+        let current = self.current;
+        for process in self.processes.iter_mut() {
+            if process.state == ProcessState::Joining(current) {
+                process.state = ProcessState::Ready;
+            }
+        }
+        self.set_current_state(ProcessState::Dead);
+    }
+
+    /// Make `id` leave ring 3 with [`KILL_STATUS`], returns whether it exists
+    pub(crate) fn kill(&mut self, id: ThreadId) -> bool {
+        let Some(process) = self.find(id) else {
+            return false;
+        };
+        process.killed = true;
+        if matches!(
+            process.state,
+            ProcessState::Waiting | ProcessState::Sleeping(_)
+        ) {
+            process.state = ProcessState::Ready;
+        }
+        true
+    }
+
+    pub(crate) fn current_killed(&self) -> bool {
+        self.processes
+            .iter()
+            .find(|e| e.id == self.current)
+            .unwrap()
+            .killed
+    }
+
+    /// Let every process blocked in [`ProcessState::Waiting`] check again
+    pub(crate) fn wake_waiting(&mut self) {
+        for process in self.processes.iter_mut() {
+            if process.state == ProcessState::Waiting {
+                process.state = ProcessState::Ready;
+            }
+        }
+    }
+
+    /// Block the running process until `id` exits, does nothing if it already did
+    pub(crate) fn join(&mut self, id: ThreadId) {
+        if self.find(id).is_some_and(|e| e.state != ProcessState::Dead) {
+            self.set_current_state(ProcessState::Joining(id));
+        }
+    }
+
+    /// Remove dead processes, their stacks can only be freed once nothing runs on them
+    pub(crate) fn take_dead(&mut self) -> Vec<Process> {
+        let current = self.current;
+        let mut dead = Vec::new();
+        let mut i = 0;
+        while i < self.processes.len() {
+            if self.processes[i].state == ProcessState::Dead && self.processes[i].id != current {
+                dead.push(self.processes.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        dead
+    }
+}
+
+/// Timer interrupt hook, only the bootstrap processor schedules threads
+pub(crate) fn preempt(frame: *mut FullInterruptStackFrame) -> *mut FullInterruptStackFrame {
+    match SCHEDULER.get() {
+        Some(scheduler) if percpu::current().is_bsp() => scheduler.lock().tick(frame),
+        _ => frame,
+    }
+}
+
+/// Yield interrupt hook
+pub(crate) fn reschedule(frame: *mut FullInterruptStackFrame) -> *mut FullInterruptStackFrame {
+    match SCHEDULER.get() {
+        Some(scheduler) if percpu::current().is_bsp() => scheduler.lock().switch(frame),
+        _ => frame,
+    }
 }
@@ -0,0 +1,610 @@
+use core::arch::asm;
+use core::fmt::Display;
+use core::mem::offset_of;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::interrupt::handler::SYSCALL_VECTOR;
+use crate::interrupt::FullInterruptStackFrame;
+use crate::memory::address_space::{self, AddressSpaceError};
+use crate::memory::paging::EntryFlags;
+use crate::memory::region::Backing;
+use crate::memory::PAGE_SIZE;
+use crate::smp::percpu::PerCpu;
+use crate::{inline_if, print};
+
+use super::loader::LoadError;
+use super::process::{self, Handle, Pid, ProcessError};
+use super::programs;
+use super::scheduler::SCHEDULER;
+use super::usermode::{self, KILL_STATUS};
+
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_OPEN: u64 = 2;
+pub const SYS_CLOSE: u64 = 3;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MPROTECT: u64 = 10;
+pub const SYS_MUNMAP: u64 = 11;
+pub const SYS_GETPID: u64 = 39;
+pub const SYS_FORK: u64 = 57;
+/// Takes the place of execve, starts a new child instead of replacing the caller
+pub const SYS_SPAWN: u64 = 59;
+pub const SYS_EXIT: u64 = 60;
+pub const SYS_WAIT4: u64 = 61;
+pub const SYS_KILL: u64 = 62;
+/// Size of [`SYSCALL_TABLE`], numbers past it don't exist
+pub const SYSCALL_COUNT: usize = 64;
+
+/// Bytes [`sys_write`] copies out of the caller at once
+const WRITE_CHUNK: usize = 256;
+/// Longest path [`sys_spawn`] accepts, the terminating zero included
+const PATH_MAX: usize = 256;
+/// Most arguments [`sys_spawn`] passes on
+const ARG_MAX: usize = 64;
+/// Highest signal number [`sys_kill`] accepts
+const SIGNAL_MAX: u64 = 64;
+
+const O_ACCMODE: u64 = 3;
+const O_RDONLY: u64 = 0;
+
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+pub const MAP_SHARED: u64 = 0x1;
+pub const MAP_PRIVATE: u64 = 0x2;
+/// Never replaces what's mapped already, it fails like [`MAP_FIXED_NOREPLACE`]
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+pub const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+
+/// Error numbers, returned negated in rax like on linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EEXIST = 17,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+}
+
+impl Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::EPERM => write!(f, "Operation not permitted"),
+            Self::ENOENT => write!(f, "No such file or directory"),
+            Self::ESRCH => write!(f, "No such process"),
+            Self::EINTR => write!(f, "Interrupted system call"),
+            Self::E2BIG => write!(f, "Argument list too long"),
+            Self::ENOEXEC => write!(f, "Exec format error"),
+            Self::EBADF => write!(f, "Bad file descriptor"),
+            Self::ECHILD => write!(f, "No child processes"),
+            Self::ENOMEM => write!(f, "Cannot allocate memory"),
+            Self::EACCES => write!(f, "Permission denied"),
+            Self::EFAULT => write!(f, "Bad address"),
+            Self::EEXIST => write!(f, "File exists"),
+            Self::EINVAL => write!(f, "Invalid argument"),
+            Self::EMFILE => write!(f, "Too many open files"),
+            Self::ENAMETOOLONG => write!(f, "File name too long"),
+            Self::ENOSYS => write!(f, "Function not implemented"),
+        }
+    }
+}
+
+impl core::error::Error for Errno {}
+
+impl From<ProcessError> for Errno {
+    fn from(value: ProcessError) -> Self {
+        match value {
+            ProcessError::NotFound => Self::ENOENT,
+            ProcessError::Load(LoadError::ArgumentsTooLarge) => Self::E2BIG,
+            ProcessError::Load(LoadError::AddressSpace(AddressSpaceError::OutOfMemory)) => {
+                Self::ENOMEM
+            }
+            ProcessError::Load(_) => Self::ENOEXEC,
+            ProcessError::NoSuchProcess(_) => Self::ESRCH,
+            ProcessError::NoChildren => Self::ECHILD,
+            ProcessError::NotAChild(_) => Self::EPERM,
+            ProcessError::Interrupted => Self::EINTR,
+            ProcessError::NotAProcess => Self::EPERM,
+            ProcessError::OutOfMemory => Self::ENOMEM,
+            ProcessError::TooManyHandles => Self::EMFILE,
+        }
+    }
+}
+
+impl From<AddressSpaceError> for Errno {
+    fn from(value: AddressSpaceError) -> Self {
+        match value {
+            AddressSpaceError::OutOfMemory
+            | AddressSpaceError::NotMapped(_)
+            | AddressSpaceError::NoFreeRange(_) => Self::ENOMEM,
+            AddressSpaceError::AlreadyMapped(_) => Self::EEXIST,
+            AddressSpaceError::OutsideUserSpace(_)
+            | AddressSpaceError::InvalidRange(_)
+            | AddressSpaceError::OutsideWindow(_) => Self::EINVAL,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Arguments of a system call, taken from the registers of the linux convention
+pub struct SyscallArgs<'a> {
+    frame: &'a mut FullInterruptStackFrame,
+}
+
+impl SyscallArgs<'_> {
+    /// Raw value of argument `index`, in rdi, rsi, rdx, r10, r8 then r9
+    pub fn get(&self, index: usize) -> u64 {
+        match index {
+            0 => self.frame.rdi,
+            1 => self.frame.rsi,
+            2 => self.frame.rdx,
+            3 => self.frame.r10,
+            4 => self.frame.r8,
+            5 => self.frame.r9,
+            _ => panic!("System calls take at most 6 arguments"),
+        }
+    }
+
+    pub fn signed(&self, index: usize) -> i64 {
+        self.get(index) as i64
+    }
+
+    pub fn usize(&self, index: usize) -> Result<usize, Errno> {
+        usize::try_from(self.get(index)).map_err(|_| Errno::EINVAL)
+    }
+
+    /// Frame of the calling program, changes to it apply on return
+    pub fn frame(&mut self) -> &mut FullInterruptStackFrame {
+        self.frame
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Syscall {
+    pub name: &'static str,
+    pub handler: fn(&mut SyscallArgs) -> SyscallResult,
+}
+
+/// System calls by number
+pub static SYSCALL_TABLE: [Option<Syscall>; SYSCALL_COUNT] = {
+    let mut table = [None; SYSCALL_COUNT];
+    table[SYS_WRITE as usize] = Some(Syscall {
+        name: "write",
+        handler: sys_write,
+    });
+    table[SYS_OPEN as usize] = Some(Syscall {
+        name: "open",
+        handler: sys_open,
+    });
+    table[SYS_CLOSE as usize] = Some(Syscall {
+        name: "close",
+        handler: sys_close,
+    });
+    table[SYS_MMAP as usize] = Some(Syscall {
+        name: "mmap",
+        handler: sys_mmap,
+    });
+    table[SYS_MPROTECT as usize] = Some(Syscall {
+        name: "mprotect",
+        handler: sys_mprotect,
+    });
+    table[SYS_MUNMAP as usize] = Some(Syscall {
+        name: "munmap",
+        handler: sys_munmap,
+    });
+    table[SYS_GETPID as usize] = Some(Syscall {
+        name: "getpid",
+        handler: sys_getpid,
+    });
+    table[SYS_FORK as usize] = Some(Syscall {
+        name: "fork",
+        handler: sys_fork,
+    });
+    table[SYS_SPAWN as usize] = Some(Syscall {
+        name: "spawn",
+        handler: sys_spawn,
+    });
+    table[SYS_EXIT as usize] = Some(Syscall {
+        name: "exit",
+        handler: sys_exit,
+    });
+    table[SYS_WAIT4 as usize] = Some(Syscall {
+        name: "wait4",
+        handler: sys_wait4,
+    });
+    table[SYS_KILL as usize] = Some(Syscall {
+        name: "kill",
+        handler: sys_kill,
+    });
+    table
+};
+
+/// Point the syscall instruction of the executing processor at [`syscall_entry`]
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("Gdt layout doesn't fit sysret");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // The entry runs on the user stack until it switched, nothing may interrupt it
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Check that `len` bytes at `address` are valid for the caller, writable too if `write`
+///
+/// The pages may still be unused or shared, the copy faults them in like ring 3 would
+fn check_user_range(address: u64, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 || address_space::user_range_accessible(address, len, write) {
+        Ok(())
+    } else {
+        Err(Errno::EFAULT)
+    }
+}
+
+/// Copy from the address space of the calling program
+pub fn copy_from_user(destination: &mut [u8], source: u64) -> Result<(), Errno> {
+    check_user_range(source, destination.len(), false)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            source as *const u8,
+            destination.as_mut_ptr(),
+            destination.len(),
+        );
+    }
+    Ok(())
+}
+
+/// Copy to the address space of the calling program
+pub fn copy_to_user(destination: u64, source: &[u8]) -> Result<(), Errno> {
+    check_user_range(destination, source.len(), true)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len());
+    }
+    Ok(())
+}
+
+/// Zero terminated string at `address`, at most `max` bytes long with the zero
+pub fn copy_string_from_user(address: u64, max: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    let mut current = address;
+    while bytes.len() < max {
+        // Up to the end of the page, the next one may not be mapped
+        let count = ((PAGE_SIZE - current % PAGE_SIZE) as usize).min(max - bytes.len());
+        let start = bytes.len();
+        bytes.resize(start + count, 0);
+        copy_from_user(&mut bytes[start..], current)?;
+        if let Some(end) = bytes[start..].iter().position(|e| *e == 0) {
+            bytes.truncate(start + end);
+            return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+        }
+        current += count as u64;
+    }
+    Err(Errno::ENAMETOOLONG)
+}
+
+/// Run the system call in rax and put its result there, errors as negated [`Errno`]
+pub fn dispatch(frame: &mut FullInterruptStackFrame) {
+    let number = frame.rax;
+    let result = match SYSCALL_TABLE.get(number as usize).copied().flatten() {
+        Some(syscall) => (syscall.handler)(&mut SyscallArgs { frame }),
+        None => Err(Errno::ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+    // Killed while in the kernel, it doesn't get back to ring 3
+    if usermode::is_user_frame(frame) && killed() {
+        usermode::exit_to_kernel(frame, KILL_STATUS);
+    }
+}
+
+fn killed() -> bool {
+    interrupts::without_interrupts(|| SCHEDULER.get().is_some_and(|e| e.lock().current_killed()))
+}
+
+/// System calls raised through the interrupt gate
+pub(crate) extern "C" fn interrupt_handler(frame: &mut FullInterruptStackFrame) {
+    if RFlags::from_bits_truncate(frame.cpu_flags).contains(RFlags::INTERRUPT_FLAG) {
+        interrupts::enable();
+    }
+    dispatch(frame);
+}
+
+extern "C" fn syscall_handler(frame: &mut FullInterruptStackFrame) {
+    // The entry doesn't know the selectors, these are the ones sysret loads
+    let selectors = gdt::selectors();
+    frame.code_segment = selectors.user_code.0 as u64;
+    frame.stack_segment = selectors.user_data.0 as u64;
+    interrupts::enable();
+    dispatch(frame);
+}
+
+/// Entry of the syscall instruction, builds a [`FullInterruptStackFrame`] on the kernel stack
+///
+/// Returns with sysret unless the frame got changed to somewhere sysret can't go
+#[naked]
+extern "C" fn syscall_entry() {
+    unsafe {
+        asm!(
+            "swapgs",
+            "mov gs:[{user_stack}], rsp",
+            "mov rsp, gs:[{kernel_stack}]",
+            // Segments, filled in by the handler
+            "push 0",
+            "push qword ptr gs:[{user_stack}]",
+            // Syscall saved rflags in r11 and rip in rcx
+            "push r11",
+            "push 0",
+            "push rcx",
+            "push 0",
+            "push {vector}",
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push rbx",
+            "push rbp",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "mov rax, cr2",
+            "push rax",
+            "mov rax, cr3",
+            "push rax",
+            "mov rdi, rsp",
+            "cld",
+            "call {handler}",
+            "cli",
+            "add rsp, 16",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbp",
+            "pop rbx",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rax",
+            "add rsp, 16",
+            // Leaving to the kernel, like a program exiting
+            "test byte ptr [rsp + 8], 3",
+            "jz 2f",
+            // Sysret faults in ring 0 on a non canonical rip
+            "mov rcx, [rsp]",
+            "mov r11, rcx",
+            "shr r11, 47",
+            "jnz 3f",
+            "mov r11, [rsp + 16]",
+            "mov rsp, [rsp + 24]",
+            "swapgs",
+            "sysretq",
+            "3:",
+            "swapgs",
+            "2:",
+            "iretq",
+            user_stack = const offset_of!(PerCpu, user_stack),
+            kernel_stack = const offset_of!(PerCpu, kernel_stack),
+            vector = const SYSCALL_VECTOR,
+            handler = sym syscall_handler,
+            options(noreturn)
+        );
+    }
+}
+
+/// write(fd, buffer, len), every handle is the console for now
+///
+/// Each chunk is checked as it's copied, the ones before a bad part of `buffer` are already printed.
+fn sys_write(args: &mut SyscallArgs) -> SyscallResult {
+    let (fd, buffer, len) = (args.get(0), args.get(1), args.usize(2)?);
+    if process::handle(fd) != Some(Handle::Console) {
+        return Err(Errno::EBADF);
+    }
+    let mut chunk = [0; WRITE_CHUNK];
+    for offset in (0..len).step_by(WRITE_CHUNK) {
+        let size = WRITE_CHUNK.min(len - offset);
+        copy_from_user(&mut chunk[..size], buffer + offset as u64)?;
+        print!("{}", String::from_utf8_lossy(&chunk[..size]));
+    }
+    Ok(len as u64)
+}
+
+/// exit(status), returns to the thread that started the program
+fn sys_exit(args: &mut SyscallArgs) -> SyscallResult {
+    let status = args.signed(0);
+    if !usermode::is_user_frame(args.frame()) {
+        return Err(Errno::EPERM);
+    }
+    usermode::exit_to_kernel(args.frame(), status);
+    Ok(status as u64)
+}
+
+/// open(path, flags), files of the initrd can only be read
+fn sys_open(args: &mut SyscallArgs) -> SyscallResult {
+    let path = copy_string_from_user(args.get(0), PATH_MAX)?;
+    if args.get(1) & O_ACCMODE != O_RDONLY {
+        return Err(Errno::EACCES);
+    }
+    let data = programs::find(&path).ok_or(Errno::ENOENT)?;
+    Ok(process::open(Handle::File(data))?)
+}
+
+/// close(fd)
+fn sys_close(args: &mut SyscallArgs) -> SyscallResult {
+    inline_if!(process::close(args.get(0)), Ok(0), Err(Errno::EBADF))
+}
+
+/// getpid(), kernel threads are 0
+fn sys_getpid(_args: &mut SyscallArgs) -> SyscallResult {
+    Ok(process::current().map_or(0, |e| e.as_u64()))
+}
+
+/// fork(), returns the pid of the child in the parent and 0 in the child
+fn sys_fork(args: &mut SyscallArgs) -> SyscallResult {
+    Ok(process::fork(args.frame())?.as_u64())
+}
+
+/// spawn(path, argv), argv is a null terminated array like for execve, returns the pid of the child
+fn sys_spawn(args: &mut SyscallArgs) -> SyscallResult {
+    let path = copy_string_from_user(args.get(0), PATH_MAX)?;
+    let mut argv = Vec::new();
+    let mut pointer = args.get(1);
+    loop {
+        let mut bytes = [0; 8];
+        copy_from_user(&mut bytes, pointer)?;
+        let address = u64::from_le_bytes(bytes);
+        if address == 0 {
+            break;
+        }
+        if argv.len() == ARG_MAX {
+            return Err(Errno::E2BIG);
+        }
+        argv.push(copy_string_from_user(address, PAGE_SIZE as usize)?);
+        pointer += 8;
+    }
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    Ok(process::spawn_with_argv(&path, &argv)?.as_u64())
+}
+
+/// wait4(pid, status), -1 waits for any child and the full exit status gets stored if status isn't null
+fn sys_wait4(args: &mut SyscallArgs) -> SyscallResult {
+    let pid = match args.signed(0) {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_raw(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+    let status_address = args.get(1);
+    let (pid, status) = process::wait(pid)?;
+    if status_address != 0 {
+        copy_to_user(status_address, &status.to_le_bytes())?;
+    }
+    Ok(pid.as_u64())
+}
+
+/// kill(pid, signal), every signal terminates, only children of the caller can be killed
+///
+/// Signal 0 only checks that `pid` could be killed.
+fn sys_kill(args: &mut SyscallArgs) -> SyscallResult {
+    let (pid, signal) = (Pid::from_raw(args.get(0)), args.get(1));
+    match signal {
+        0 => process::check_kill(pid)?,
+        1..=SIGNAL_MAX => process::kill(pid)?,
+        _ => return Err(Errno::EINVAL),
+    }
+    Ok(0)
+}
+
+/// Page flags giving ring 3 the access of `prot`
+fn protection_flags(prot: u64) -> Result<EntryFlags, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut flags = EntryFlags::empty();
+    if prot != PROT_NONE {
+        flags |= EntryFlags::USER_ACCESSIBLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= EntryFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= EntryFlags::NO_EXECUTE;
+    }
+    Ok(flags)
+}
+
+/// mmap(address, len, prot, flags, fd, offset), private mappings only, returns where it got mapped
+///
+/// Without a fixed address, `address` is taken if it's free and ignored otherwise
+fn sys_mmap(args: &mut SyscallArgs) -> SyscallResult {
+    let (address, len, prot, flags) = (args.get(0), args.get(1), args.get(2), args.get(3));
+    let known = MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS | MAP_FIXED_NOREPLACE;
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE || flags & !known != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let page_flags = protection_flags(prot)?;
+    let backing = if flags & MAP_ANONYMOUS != 0 {
+        Backing::Anonymous
+    } else {
+        let Some(Handle::File(data)) = process::handle(args.get(4)) else {
+            return Err(Errno::EBADF);
+        };
+        let offset = args.get(5);
+        if offset % PAGE_SIZE != 0 {
+            return Err(Errno::EINVAL);
+        }
+        Backing::File { data, offset }
+    };
+    if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+        return Ok(address_space::mmap_current(
+            Some(address),
+            len,
+            page_flags,
+            backing,
+        )?);
+    }
+    if address != 0 && address % PAGE_SIZE == 0 {
+        if let Ok(start) = address_space::mmap_current(Some(address), len, page_flags, backing) {
+            return Ok(start);
+        }
+    }
+    Ok(address_space::mmap_current(None, len, page_flags, backing)?)
+}
+
+/// mprotect(address, len, prot)
+fn sys_mprotect(args: &mut SyscallArgs) -> SyscallResult {
+    let flags = protection_flags(args.get(2))?;
+    address_space::mprotect_current(args.get(0), args.get(1), flags)?;
+    Ok(0)
+}
+
+/// munmap(address, len)
+fn sys_munmap(args: &mut SyscallArgs) -> SyscallResult {
+    address_space::munmap_current(args.get(0), args.get(1))?;
+    Ok(0)
+}
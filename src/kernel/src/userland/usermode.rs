@@ -0,0 +1,259 @@
+use core::arch::asm;
+use core::mem::offset_of;
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::interrupt::FullInterruptStackFrame;
+use crate::memory::address_space::{AddressSpace, USER_END};
+use crate::memory::paging::EntryFlags;
+use crate::memory::PAGE_SIZE;
+use crate::smp::percpu;
+use crate::task::thread::JoinHandle;
+
+use super::loader::{self, LoadError};
+use super::process;
+use super::scheduler::SCHEDULER;
+
+/// The page right below the end of user space stays unmapped
+pub const USER_STACK_TOP: u64 = USER_END - PAGE_SIZE;
+pub const USER_STACK_SIZE: u64 = 16 * PAGE_SIZE;
+/// Exit status of a program killed by an exception
+pub const FAULT_STATUS: i64 = -1;
+/// Exit status of a program killed by [`super::process::kill`]
+pub const KILL_STATUS: i64 = -9;
+
+/// Run the static executable `image` in ring 3 in a new thread, joining it gives the exit status
+pub fn spawn_user(name: &str, image: &[u8]) -> Result<JoinHandle<i64>, LoadError> {
+    spawn_user_with_args(name, image, &[name], &[])
+}
+
+/// Like [`spawn_user`], with the arguments and environment the program finds on its stack
+///
+/// Nothing waits for the process, it's gone from the process table once it exits
+pub fn spawn_user_with_args(
+    name: &str,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<JoinHandle<i64>, LoadError> {
+    let (_, handle) = process::start(name, image, argv, envp, None, true)?;
+    Ok(handle)
+}
+
+/// Address space with `image` loaded and its stack set up, with the entry point and stack pointer
+pub(crate) fn prepare(
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(AddressSpace, u64, u64), LoadError> {
+    let mut space = AddressSpace::new()?;
+    let image = loader::load(&mut space, image)?;
+    space.reserve(
+        USER_STACK_TOP - USER_STACK_SIZE,
+        USER_STACK_SIZE,
+        EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+    )?;
+    let stack = loader::setup_stack(
+        &mut space,
+        USER_STACK_TOP,
+        USER_STACK_SIZE,
+        argv,
+        envp,
+        &image,
+    )?;
+    Ok((space, image.entry, stack))
+}
+
+/// Run the program loaded by [`prepare`] in the calling thread, returns its exit status
+pub(crate) fn run(entry: u64, stack: u64) -> i64 {
+    let selectors = gdt::selectors();
+    enter_user(
+        entry,
+        stack,
+        selectors.user_code.0 as u64,
+        selectors.user_data.0 as u64,
+    )
+}
+
+/// Whether the frame was saved while running in ring 3
+pub fn is_user_frame(frame: &FullInterruptStackFrame) -> bool {
+    frame.code_segment & 3 == 3
+}
+
+/// Drop to ring 3 at `entry`, returns the exit status once the program leaves through [`exit_to_kernel`]
+#[naked]
+extern "C" fn enter_user(entry: u64, stack: u64, code_segment: u64, data_segment: u64) -> i64 {
+    unsafe {
+        asm!(
+            "push rbp",
+            "push rbx",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // Keeps the stack aligned for the call, interrupts from ring 3 land right below
+            "sub rsp, 8",
+            "mov r12, rdi",
+            "mov r13, rsi",
+            "mov r14, rdx",
+            "mov r15, rcx",
+            "mov rdi, rsp",
+            "call {set_kernel_stack}",
+            "push r15",
+            "push r13",
+            "push {rflags}",
+            "push r14",
+            "push r12",
+            // Nothing of the kernel leaks into the program
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            "cli",
+            "swapgs",
+            "iretq",
+            set_kernel_stack = sym set_kernel_stack,
+            rflags = const 0x202,
+            options(noreturn)
+        );
+    }
+}
+
+/// Continue in ring 3 with the registers of `frame` like [`enter_user`], a forked child starts there
+pub(crate) fn resume(frame: &FullInterruptStackFrame) -> i64 {
+    resume_user(frame)
+}
+
+#[naked]
+extern "C" fn resume_user(frame: *const FullInterruptStackFrame) -> i64 {
+    unsafe {
+        asm!(
+            "push rbp",
+            "push rbx",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "sub rsp, 8",
+            "mov r12, rdi",
+            "mov rdi, rsp",
+            "call {set_kernel_stack}",
+            "push qword ptr [r12 + {ss}]",
+            "push qword ptr [r12 + {rsp}]",
+            "push qword ptr [r12 + {rflags}]",
+            "push qword ptr [r12 + {cs}]",
+            "push qword ptr [r12 + {rip}]",
+            "mov rax, [r12 + {rax}]",
+            "mov rbx, [r12 + {rbx}]",
+            "mov rcx, [r12 + {rcx}]",
+            "mov rdx, [r12 + {rdx}]",
+            "mov rsi, [r12 + {rsi}]",
+            "mov rdi, [r12 + {rdi}]",
+            "mov rbp, [r12 + {rbp}]",
+            "mov r8, [r12 + {r8}]",
+            "mov r9, [r12 + {r9}]",
+            "mov r10, [r12 + {r10}]",
+            "mov r11, [r12 + {r11}]",
+            "mov r13, [r12 + {r13}]",
+            "mov r14, [r12 + {r14}]",
+            "mov r15, [r12 + {r15}]",
+            "mov r12, [r12 + {r12}]",
+            "cli",
+            "swapgs",
+            "iretq",
+            set_kernel_stack = sym set_kernel_stack,
+            ss = const offset_of!(FullInterruptStackFrame, stack_segment),
+            rsp = const offset_of!(FullInterruptStackFrame, stack_pointer),
+            rflags = const offset_of!(FullInterruptStackFrame, cpu_flags),
+            cs = const offset_of!(FullInterruptStackFrame, code_segment),
+            rip = const offset_of!(FullInterruptStackFrame, instruction_pointer),
+            rax = const offset_of!(FullInterruptStackFrame, rax),
+            rbx = const offset_of!(FullInterruptStackFrame, rbx),
+            rcx = const offset_of!(FullInterruptStackFrame, rcx),
+            rdx = const offset_of!(FullInterruptStackFrame, rdx),
+            rsi = const offset_of!(FullInterruptStackFrame, rsi),
+            rdi = const offset_of!(FullInterruptStackFrame, rdi),
+            rbp = const offset_of!(FullInterruptStackFrame, rbp),
+            r8 = const offset_of!(FullInterruptStackFrame, r8),
+            r9 = const offset_of!(FullInterruptStackFrame, r9),
+            r10 = const offset_of!(FullInterruptStackFrame, r10),
+            r11 = const offset_of!(FullInterruptStackFrame, r11),
+            r12 = const offset_of!(FullInterruptStackFrame, r12),
+            r13 = const offset_of!(FullInterruptStackFrame, r13),
+            r14 = const offset_of!(FullInterruptStackFrame, r14),
+            r15 = const offset_of!(FullInterruptStackFrame, r15),
+            options(noreturn)
+        );
+    }
+}
+
+/// Resumes [`enter_user`] on the stack it recorded, with the exit status in rax
+#[naked]
+extern "C" fn leave_user() -> ! {
+    unsafe {
+        asm!(
+            "add rsp, 8",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbx",
+            "pop rbp",
+            "ret",
+            options(noreturn)
+        );
+    }
+}
+
+extern "C" fn set_kernel_stack(top: u64) {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .get()
+            .expect("Scheduler not initialized")
+            .lock()
+            .set_kernel_stack(top);
+        percpu::current().set_kernel_stack(top);
+    });
+}
+
+/// Make the interrupted program return `status` to the thread that entered ring 3
+pub(crate) fn exit_to_kernel(frame: &mut FullInterruptStackFrame, status: i64) {
+    let kernel_stack = interrupts::without_interrupts(|| {
+        SCHEDULER
+            .get()
+            .expect("Scheduler not initialized")
+            .lock()
+            .kernel_stack()
+    });
+    return_to_kernel(frame, kernel_stack, status);
+}
+
+/// Rewrite a ring 3 frame to resume [`leave_user`] on `kernel_stack`, the one [`enter_user`] recorded
+pub(crate) fn return_to_kernel(
+    frame: &mut FullInterruptStackFrame,
+    kernel_stack: u64,
+    status: i64,
+) {
+    assert!(is_user_frame(frame), "Kernel code can't exit to the kernel");
+    let selectors = gdt::selectors();
+    frame.instruction_pointer = VirtAddr::new(leave_user as usize as u64);
+    frame.code_segment = selectors.kernel_code.0 as u64;
+    frame.stack_pointer = VirtAddr::new(kernel_stack);
+    frame.stack_segment = selectors.kernel_data.0 as u64;
+    frame.cpu_flags = (RFlags::INTERRUPT_FLAG | RFlags::from_bits_retain(0x2)).bits();
+    frame.rax = status as u64;
+}
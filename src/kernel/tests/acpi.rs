@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::acpi::acpi;
+use nothingos::interrupt::{local_apic_id, IOAPICS, LAPIC_IDS};
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn madt_parsed() {
+    let madt = acpi().madt().expect("MADT not found");
+    assert!(madt.local_apic_address() != 0);
+    assert!(!madt.processors().is_empty());
+    assert!(!madt.io_apics().is_empty());
+}
+
+#[test_case]
+fn bsp_in_lapic_list() {
+    let bsp = local_apic_id();
+    assert!(LAPIC_IDS.get().unwrap().contains(&bsp));
+}
+
+#[test_case]
+fn io_apics_cover_isa_irqs() {
+    let ioapics = IOAPICS.get().unwrap();
+    assert_eq!(ioapics.len(), acpi().madt().unwrap().io_apics().len());
+    for irq in 0..16 {
+        let gsi = acpi()
+            .madt()
+            .unwrap()
+            .find_override(irq)
+            .map(|e| e.gsi)
+            .unwrap_or(irq as u32);
+        assert!(ioapics.iter().any(|e| e.lock().handles(gsi)));
+    }
+}
+
+#[test_case]
+fn fadt_parsed() {
+    let fadt = acpi().fadt().expect("FADT not found");
+    assert!(fadt.dsdt_address() != 0);
+}
@@ -13,7 +13,9 @@ use alloc::vec;
 use common::boot::BootInformation;
 use nothingos::{
     driver::storage::{ahci_driver::get_ahci, Drive},
+    serial_println,
     task::{executor::Executor, AwaitType, Task},
+    time::Instant,
 };
 use x86_64::instructions::random;
 
@@ -26,6 +28,7 @@ pub extern "C" fn start(multiboot_information_address: *mut BootInformation) ->
 
 const TEST_SIZE_IN_SECTOR: usize = 256; // 512 per sector
 const SECTOR_TEST_RANGE: u64 = 256;
+const LARGE_READ_SIZE: usize = 0x800000; // 8 MiB
 #[test_case]
 fn simple_read_write() {
     let mut executor = Executor::new();
@@ -56,7 +59,7 @@ fn simple_read_write() {
                 .await
                 .unwrap();
         },
-        AwaitType::Poll,
+        AwaitType::Waker,
     ));
 
     executor.run_exit();
@@ -115,7 +118,85 @@ fn sector_read_write() {
                     .unwrap();
             }
         },
-        AwaitType::Poll,
+        AwaitType::Waker,
+    ));
+
+    executor.run_exit();
+}
+
+#[test_case]
+fn misaligned_read_write() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(
+        async {
+            let mut controller = get_ahci().get_contoller().lock();
+            let mut backup_data = vec![0u8; TEST_SIZE_IN_SECTOR * 512];
+            let mut data = vec![0u8; TEST_SIZE_IN_SECTOR * 512 + 1];
+            get_random(&mut data[1..]);
+
+            let drive = controller.get_drive(0).expect("Cannot get drive");
+            drive
+                .read(0, &mut backup_data, TEST_SIZE_IN_SECTOR)
+                .await
+                .unwrap();
+            // Odd addresses can't go in a prdt entry, these go through bounce buffers
+            drive
+                .write(0, &data[1..], TEST_SIZE_IN_SECTOR)
+                .await
+                .unwrap();
+
+            let mut read_data = vec![0u8; TEST_SIZE_IN_SECTOR * 512 + 1];
+            drive
+                .read(0, &mut read_data[1..], TEST_SIZE_IN_SECTOR)
+                .await
+                .unwrap();
+            assert_eq!(data[1..], read_data[1..]);
+            drive
+                .read(
+                    0,
+                    &mut read_data[..TEST_SIZE_IN_SECTOR * 512],
+                    TEST_SIZE_IN_SECTOR,
+                )
+                .await
+                .unwrap();
+            assert_eq!(data[1..], read_data[..TEST_SIZE_IN_SECTOR * 512]);
+            drive
+                .write(0, &backup_data, TEST_SIZE_IN_SECTOR)
+                .await
+                .unwrap();
+        },
+        AwaitType::Waker,
+    ));
+
+    executor.run_exit();
+}
+
+#[test_case]
+fn large_sequential_read() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(
+        async {
+            let mut controller = get_ahci().get_contoller().lock();
+            let drive = controller.get_drive(0).expect("Cannot get drive");
+            let sectors = (LARGE_READ_SIZE / 512).min(drive.lba_end().await.unwrap() as usize);
+            let mut data = vec![0u8; sectors * 512];
+
+            let start = Instant::now();
+            drive.read(0, &mut data, sectors).await.unwrap();
+            let elapsed = start.elapsed();
+            serial_println!(
+                "Read {} KiB in {:?}, {} KiB/s",
+                sectors / 2,
+                elapsed,
+                (sectors as u128 / 2 * 1000) / elapsed.as_millis().max(1)
+            );
+
+            // Same range through bounce buffers
+            let mut bounced = vec![0u8; sectors * 512 + 1];
+            drive.read(0, &mut bounced[1..], sectors).await.unwrap();
+            assert_eq!(data[..], bounced[1..]);
+        },
+        AwaitType::Waker,
     ));
 
     executor.run_exit();
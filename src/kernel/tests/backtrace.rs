@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use alloc::format;
+use common::boot::BootInformation;
+use nothingos::backtrace::demangle::Demangle;
+use nothingos::backtrace::symbols::resolve;
+use nothingos::backtrace::Backtrace;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn capture_reaches_test_runner() {
+    let found = Backtrace::capture()
+        .filter_map(resolve)
+        .any(|(name, _)| format!("{}", Demangle(name)).contains("test_runner"));
+    assert!(found);
+}
+
+#[test_case]
+fn demangle_path() {
+    assert_eq!(
+        format!("{}", Demangle("_ZN4core3fmt5write17h0123456789abcdefE")),
+        "core::fmt::write"
+    );
+}
+
+#[test_case]
+fn demangle_escapes() {
+    assert_eq!(
+        format!(
+            "{}",
+            Demangle("_ZN60_$LT$alloc..string..String$u20$as$u20$core..fmt..Display$GT$3fmt17h0123456789abcdefE")
+        ),
+        "<alloc::string::String as core::fmt::Display>::fmt"
+    );
+}
+
+#[test_case]
+fn demangle_passthrough() {
+    assert_eq!(format!("{}", Demangle("memcpy")), "memcpy");
+    assert_eq!(format!("{}", Demangle("_ZN99broken")), "_ZN99broken");
+}
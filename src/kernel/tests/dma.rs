@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::address_space::AddressSpaceError;
+use nothingos::memory::dma::{CacheMode, DmaBuffer, DmaConstraints, DmaSegment, ScatterList};
+use nothingos::memory::paging::EntryFlags;
+use nothingos::memory::{memory_controller, PAGE_SIZE};
+use x86_64::{PhysAddr, VirtAddr};
+
+const KIB_64: u64 = 0x10000;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+fn physical(address: u64) -> Option<PhysAddr> {
+    memory_controller()
+        .lock()
+        .get_physical(VirtAddr::new(address))
+}
+
+#[test_case]
+fn buffers_are_mapped_and_zeroed() {
+    let mut buffer =
+        DmaBuffer::new(3 * PAGE_SIZE as usize - 100, DmaConstraints::default()).unwrap();
+    assert_eq!(buffer.size(), 3 * PAGE_SIZE as usize);
+    assert_eq!(buffer.phys().as_u64() % PAGE_SIZE, 0);
+    for offset in [0, PAGE_SIZE + 0x123, 3 * PAGE_SIZE - 1] {
+        assert_eq!(
+            physical(buffer.virt() + offset),
+            Some(buffer.phys() + offset)
+        );
+    }
+    assert!(buffer.as_slice().iter().all(|e| *e == 0));
+    buffer.as_mut_slice().fill(0xAB);
+    assert!(buffer.as_slice().iter().all(|e| *e == 0xAB));
+
+    let virt = buffer.virt();
+    drop(buffer);
+    assert_eq!(physical(virt), None);
+}
+
+#[test_case]
+fn constraints_are_met() {
+    let aligned = DmaBuffer::new(
+        PAGE_SIZE as usize,
+        DmaConstraints {
+            align: KIB_64,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(aligned.phys().as_u64() % KIB_64, 0);
+
+    let bounded = DmaConstraints {
+        boundary: KIB_64,
+        ..Default::default()
+    };
+    for _ in 0..8 {
+        let buffer = DmaBuffer::new(3 * PAGE_SIZE as usize, bounded).unwrap();
+        let first = buffer.phys().as_u64() / KIB_64;
+        let last = (buffer.phys().as_u64() + buffer.size() as u64 - 1) / KIB_64;
+        assert_eq!(first, last);
+    }
+    assert!(matches!(
+        DmaBuffer::new(2 * KIB_64 as usize, bounded),
+        Err(AddressSpaceError::InvalidRange(_))
+    ));
+}
+
+#[test_case]
+fn cache_modes_set_page_attributes() {
+    let flags = |cache| {
+        let buffer = DmaBuffer::new(
+            PAGE_SIZE as usize,
+            DmaConstraints {
+                cache,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        unsafe { buffer.as_ptr::<u64>().write_volatile(0x1234) };
+        assert_eq!(unsafe { buffer.as_ptr::<u64>().read_volatile() }, 0x1234);
+        memory_controller()
+            .lock()
+            .page_flags(VirtAddr::new(buffer.virt()))
+            .unwrap()
+    };
+    let cache_bits = EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH | EntryFlags::PAT;
+    assert_eq!(
+        flags(CacheMode::WriteBack) & cache_bits,
+        EntryFlags::empty()
+    );
+    assert_eq!(
+        flags(CacheMode::Uncached) & cache_bits,
+        EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH
+    );
+    assert_eq!(
+        flags(CacheMode::WriteCombining) & cache_bits,
+        EntryFlags::PAT
+    );
+}
+
+#[test_case]
+fn scatter_lists_merge_and_split() {
+    let segment = |phys, len| DmaSegment {
+        phys: PhysAddr::new(phys),
+        len,
+    };
+    let mut list = ScatterList::new(0x3000, KIB_64);
+    list.push(PhysAddr::new(0x1000), 0x1000);
+    // Follows the last one
+    list.push(PhysAddr::new(0x2000), 0x1000);
+    // Too long for one segment
+    list.push(PhysAddr::new(0x3000), 0x2000);
+    // Crosses the boundary
+    list.push(PhysAddr::new(0xF000), 0x2000);
+    list.push(PhysAddr::new(0x40000), 0x800);
+    assert_eq!(
+        list.segments(),
+        &[
+            segment(0x1000, 0x3000),
+            segment(0x4000, 0x1000),
+            segment(0xF000, 0x1000),
+            segment(0x10000, 0x1000),
+            segment(0x40000, 0x800),
+        ]
+    );
+    assert_eq!(list.len(), 0x6800);
+
+    let buffer = DmaBuffer::new(PAGE_SIZE as usize, DmaConstraints::default()).unwrap();
+    let mut list = ScatterList::new(usize::MAX, 0);
+    list.push_buffer(&buffer, 512);
+    assert_eq!(list.segments(), &[segment(buffer.phys().as_u64(), 512)]);
+}
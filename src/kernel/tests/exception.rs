@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::format;
+use common::boot::BootInformation;
+use nothingos::interrupt::exception::{
+    clear_exception_hook, set_exception_hook, ErrorCode, Exception,
+};
+use nothingos::interrupt::handler::IrqReturn;
+use nothingos::interrupt::FullInterruptStackFrame;
+
+const UNMAPPED_ADDRESS: u64 = 0x7FFF_DEAD_0000;
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn breakpoint_resumes() {
+    x86_64::instructions::interrupts::int3();
+}
+
+fn skip_ud2(frame: &mut FullInterruptStackFrame) -> IrqReturn {
+    HITS.fetch_add(1, Ordering::Relaxed);
+    frame.instruction_pointer += 2u64;
+    IrqReturn::Handled
+}
+
+#[test_case]
+fn invalid_opcode_hook_recovers() {
+    HITS.store(0, Ordering::Relaxed);
+    set_exception_hook(Exception::InvalidOpcode, skip_ud2).unwrap();
+    unsafe { asm!("ud2") };
+    clear_exception_hook(Exception::InvalidOpcode);
+    assert_eq!(HITS.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn hook_slot_is_exclusive() {
+    set_exception_hook(Exception::InvalidOpcode, skip_ud2).unwrap();
+    assert!(set_exception_hook(Exception::InvalidOpcode, skip_ud2).is_err());
+    clear_exception_hook(Exception::InvalidOpcode);
+}
+
+fn fake_load(frame: &mut FullInterruptStackFrame) -> IrqReturn {
+    if frame.cr2 != UNMAPPED_ADDRESS || frame.error_code & 0b10 != 0 {
+        return IrqReturn::NotHandled;
+    }
+    frame.rax = 0xC0FFEE;
+    // mov rax, [rdi]
+    frame.instruction_pointer += 3u64;
+    IrqReturn::Handled
+}
+
+#[test_case]
+fn page_fault_hook_sees_registers() {
+    set_exception_hook(Exception::PageFault, fake_load).unwrap();
+    let value: u64;
+    unsafe {
+        asm!("mov rax, [rdi]", in("rdi") UNMAPPED_ADDRESS, out("rax") value);
+    }
+    clear_exception_hook(Exception::PageFault);
+    assert_eq!(value, 0xC0FFEE);
+}
+
+#[test_case]
+fn error_code_decoding() {
+    assert_eq!(
+        format!("{}", ErrorCode(Exception::GeneralProtectionFault, 0x10)),
+        "Gdt selector 0x2"
+    );
+    assert_eq!(
+        format!("{}", ErrorCode(Exception::GeneralProtectionFault, 0x13)),
+        "Idt selector 0x2 (external)"
+    );
+    assert_eq!(
+        format!("{}", ErrorCode(Exception::InvalidOpcode, 0)),
+        "none"
+    );
+    assert_eq!(
+        format!("{}", ErrorCode(Exception::CpProtection, 1)),
+        "near ret"
+    );
+}
+
+#[test_case]
+fn exception_vectors() {
+    assert_eq!(Exception::from_vector(14), Some(Exception::PageFault));
+    assert_eq!(Exception::from_vector(15), None);
+    assert!(Exception::DoubleFault.has_error_code());
+    assert!(!Exception::InvalidOpcode.has_error_code());
+}
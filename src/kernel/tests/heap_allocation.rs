@@ -7,13 +7,31 @@
 extern crate alloc;
 extern crate nothingos;
 
+#[cfg(feature = "heap-debug")]
+use core::alloc::Layout;
+#[cfg(feature = "heap-debug")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
 use common::boot::BootInformation;
+#[cfg(feature = "heap-debug")]
+use nothingos::memory::allocator::debug;
+#[cfg(not(feature = "heap-debug"))]
+use nothingos::memory::allocator::heap::HeapStats;
+use nothingos::memory::allocator::stats;
+use nothingos::memory::memory_controller;
+
+/// Allocations made from here on must all be freed by the end of the tests
+#[cfg(feature = "heap-debug")]
+static MARK: AtomicU64 = AtomicU64::new(0);
 
 #[no_mangle]
 pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
     nothingos::init(multiboot_information_address);
+    #[cfg(feature = "heap-debug")]
+    MARK.store(debug::mark(), Ordering::Relaxed);
     test_main();
     loop {}
 }
@@ -53,3 +71,83 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+/// Redzones and headers put heap-debug allocations in other size classes
+#[cfg(not(feature = "heap-debug"))]
+#[test_case]
+fn small_objects_come_from_slabs() {
+    let cache = |stats: &HeapStats| stats.caches.iter().find(|e| e.object_size == 32).copied();
+    let before = cache(&stats()).unwrap();
+    let boxes: Vec<Box<[u8; 32]>> = (0..100).map(|_| Box::new([0; 32])).collect();
+    let during = cache(&stats()).unwrap();
+    assert!(during.in_use >= before.in_use + 100);
+    assert!(during.pages > 0);
+    drop(boxes);
+    assert_eq!(cache(&stats()).unwrap().in_use, before.in_use);
+}
+
+#[test_case]
+fn heap_grows() {
+    let big: Vec<u8> = vec![7; 0x800000];
+    assert!(stats().size >= 0x800000);
+    assert!(big.iter().all(|e| *e == 7));
+}
+
+#[test_case]
+fn heap_grows_with_the_memory_controller_locked() {
+    let controller = memory_controller().lock();
+    // More than is free, the heap has to map more while the controller stays locked
+    let size = stats().free + 0x400000;
+    let big: Vec<u8> = vec![3; size];
+    assert!(big.iter().all(|e| *e == 3));
+    drop(big);
+    drop(controller);
+}
+
+#[cfg(not(feature = "heap-debug"))]
+#[test_case]
+fn large_allocations_are_freed() {
+    let vec: Vec<u64> = Vec::with_capacity(1024);
+    let free = stats().free;
+    drop(vec);
+    assert_eq!(stats().free, free + 8192);
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn freed_memory_is_poisoned() {
+    // The block is never handed back to the heap, so it can still be read once freed
+    let mut block = vec![0u64; 64];
+    let layout = Layout::new::<[u8; 64]>();
+    unsafe {
+        let data = debug::alloc(layout, |_| block.as_mut_ptr() as *mut u8);
+        data.write_bytes(0x11, layout.size());
+        debug::dealloc(data, layout, |_, _| {});
+        assert!((0..layout.size()).all(|i| *data.add(i) == 0xDD));
+    }
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn live_allocations_are_tracked() {
+    let mark = debug::mark();
+    let value = Box::new(5u64);
+    let address = &*value as *const u64 as usize;
+    // Other cpus and interrupt handlers may allocate meanwhile, only this one is looked at
+    let find = || {
+        debug::outstanding(mark)
+            .into_iter()
+            .find(|e| e.address == address)
+    };
+    let allocation = find().unwrap();
+    assert_eq!(allocation.size, 8);
+    drop(value);
+    assert!(find().is_none_or(|e| e.id != allocation.id));
+}
+
+/// Runs last, everything the tests allocated has to be freed by now
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn no_leaks() {
+    assert_eq!(debug::dump(MARK.load(Ordering::Relaxed)), 0);
+}
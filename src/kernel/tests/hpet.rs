@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::sync::Arc;
+use common::boot::BootInformation;
+use nothingos::driver::timer::hpet::{hpet, HpetError};
+use nothingos::interrupt::handler::{
+    free_vector, request_interrupt, EoiPolicy, HandlerFlags, IrqReturn,
+};
+use nothingos::time::clocksource::ClockSource;
+use nothingos::time::{busy_wait, clocksource, Duration};
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn hpet_selected_as_clocksource() {
+    let hpet = hpet().expect("Qemu provides an hpet");
+    assert!(hpet.frequency() > 0);
+    assert!(hpet.comparator_count() >= 3);
+    assert_eq!(clocksource().name(), "hpet");
+}
+
+#[test_case]
+fn counter_advances() {
+    let hpet = hpet().unwrap();
+    let start = hpet.read();
+    busy_wait(Duration::from_millis(1));
+    let elapsed = hpet.read() - start;
+    assert!(elapsed >= hpet.frequency() / 1000);
+}
+
+#[test_case]
+fn invalid_comparator_rejected() {
+    let hpet = hpet().unwrap();
+    assert!(matches!(
+        hpet.start_oneshot(31, Duration::from_millis(1)),
+        Err(HpetError::InvalidComparator(31))
+    ));
+}
+
+#[test_case]
+fn oneshot_comparator_fires() {
+    let hpet = hpet().unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+    let handler_count = count.clone();
+    let vector = request_interrupt(
+        "hpet test",
+        EoiPolicy::Auto,
+        HandlerFlags::empty(),
+        move |_| {
+            handler_count.fetch_add(1, Ordering::Relaxed);
+            IrqReturn::Handled
+        },
+    )
+    .unwrap();
+    hpet.route(0, vector).unwrap();
+    hpet.start_oneshot(0, Duration::from_millis(2)).unwrap();
+    busy_wait(Duration::from_millis(10));
+    assert_eq!(count.load(Ordering::Relaxed), 1);
+    hpet.stop(0).unwrap();
+    free_vector(vector).unwrap();
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::paging::PageSize;
+use nothingos::memory::{memory_controller, PAGE_SIZE};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Unused part of the identity mapped p4 entry, past any RAM
+const WINDOW: u64 = 0x7F_0000_0000;
+const MIB_2: u64 = 0x200000;
+const GIB: u64 = 0x40000000;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+/// Physical range of 2 MiB aligned to 2 MiB, with the block to give back
+fn aligned_frames() -> (u64, PhysAddr) {
+    let block = memory_controller()
+        .lock()
+        .physical_alloc(2 * MIB_2 as usize)
+        .unwrap();
+    (block.as_u64().next_multiple_of(MIB_2), block)
+}
+
+fn page_size(address: u64) -> Option<PageSize> {
+    memory_controller().lock().page_size(VirtAddr::new(address))
+}
+
+fn physical(address: u64) -> Option<u64> {
+    memory_controller()
+        .lock()
+        .get_physical(VirtAddr::new(address))
+        .map(|e| e.as_u64())
+}
+
+#[test_case]
+fn aligned_ranges_get_huge_pages() {
+    let (start, block) = aligned_frames();
+    memory_controller().lock().phy_map(MIB_2, start, WINDOW);
+    assert_eq!(page_size(WINDOW), Some(PageSize::Size2MiB));
+    assert_eq!(physical(WINDOW + 0x12345), Some(start + 0x12345));
+    assert_eq!(physical(WINDOW + MIB_2 - 1), Some(start + MIB_2 - 1));
+
+    memory_controller().lock().unmap_addr(WINDOW, MIB_2);
+    assert_eq!(physical(WINDOW), None);
+    memory_controller()
+        .lock()
+        .physical_dealloc(block, 2 * MIB_2 as usize);
+}
+
+#[test_case]
+fn unaligned_ranges_get_small_pages() {
+    let (start, block) = aligned_frames();
+    memory_controller()
+        .lock()
+        .phy_map(MIB_2, start + PAGE_SIZE, WINDOW);
+    assert_eq!(page_size(WINDOW), Some(PageSize::Size4KiB));
+    assert_eq!(physical(WINDOW + 0x1234), Some(start + PAGE_SIZE + 0x1234));
+
+    memory_controller().lock().unmap_addr(WINDOW, MIB_2);
+    assert_eq!(physical(WINDOW), None);
+    memory_controller()
+        .lock()
+        .physical_dealloc(block, 2 * MIB_2 as usize);
+}
+
+#[test_case]
+fn partial_unmap_splits_huge_pages() {
+    let (start, block) = aligned_frames();
+    memory_controller().lock().phy_map(MIB_2, start, WINDOW);
+    memory_controller()
+        .lock()
+        .unmap_addr(WINDOW + PAGE_SIZE, PAGE_SIZE);
+    assert_eq!(page_size(WINDOW), Some(PageSize::Size4KiB));
+    assert_eq!(physical(WINDOW + PAGE_SIZE), None);
+    assert_eq!(physical(WINDOW), Some(start));
+    assert_eq!(
+        physical(WINDOW + 2 * PAGE_SIZE),
+        Some(start + 2 * PAGE_SIZE)
+    );
+
+    memory_controller().lock().unmap_addr(WINDOW, PAGE_SIZE);
+    memory_controller()
+        .lock()
+        .unmap_addr(WINDOW + 2 * PAGE_SIZE, MIB_2 - 2 * PAGE_SIZE);
+    assert_eq!(physical(WINDOW + MIB_2 - PAGE_SIZE), None);
+    memory_controller()
+        .lock()
+        .physical_dealloc(block, 2 * MIB_2 as usize);
+}
+
+#[test_case]
+fn gigabyte_ranges_get_the_biggest_supported_page() {
+    // Nothing is read through it, it only aliases the start of physical memory
+    let window = WINDOW + GIB;
+    memory_controller().lock().phy_map(GIB, 0, window);
+    let expected = match PageSize::Size1GiB.supported() {
+        true => PageSize::Size1GiB,
+        false => PageSize::Size2MiB,
+    };
+    assert_eq!(page_size(window), Some(expected));
+    assert_eq!(physical(window + 0x1234567), Some(0x1234567));
+
+    memory_controller().lock().unmap_addr(window, GIB);
+    assert_eq!(physical(window), None);
+}
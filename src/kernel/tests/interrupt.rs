@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::sync::Arc;
+use common::boot::BootInformation;
+use nothingos::interrupt::handler::{
+    allocate_vector, free_vector, register_handler, request_interrupt, unhandled_count,
+    unregister_handler, EoiPolicy, HandlerFlags, InterruptError, IrqReturn, DYNAMIC_VECTOR_END,
+    DYNAMIC_VECTOR_START,
+};
+use nothingos::interrupt::local_apic;
+use x86_64::instructions::interrupts;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+fn raise(vector: u8) {
+    // The interrupt is taken once interrupts are enabled again, after the lapic lock is released
+    interrupts::without_interrupts(|| unsafe {
+        local_apic().lock().send_ipi_self(vector);
+    });
+    for _ in 0..100000 {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn allocated_vectors_are_unique() {
+    let first = allocate_vector().unwrap();
+    let second = allocate_vector().unwrap();
+    assert_ne!(first, second);
+    for vector in [first, second] {
+        assert!((DYNAMIC_VECTOR_START..=DYNAMIC_VECTOR_END).contains(&vector));
+        free_vector(vector).unwrap();
+    }
+}
+
+#[test_case]
+fn reserved_vectors_rejected() {
+    for vector in [0x0E, 0x20, 0x80, 0xFF] {
+        assert!(matches!(
+            register_handler(
+                vector,
+                "test",
+                EoiPolicy::Auto,
+                HandlerFlags::empty(),
+                |_| IrqReturn::Handled
+            ),
+            Err(InterruptError::ReservedVector(_))
+        ));
+    }
+}
+
+#[test_case]
+fn handler_called() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let handler_count = count.clone();
+    let vector = request_interrupt("test", EoiPolicy::Auto, HandlerFlags::empty(), move |_| {
+        handler_count.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::Handled
+    })
+    .unwrap();
+    raise(vector);
+    assert_eq!(count.load(Ordering::Relaxed), 1);
+    free_vector(vector).unwrap();
+}
+
+#[test_case]
+fn shared_handlers_chained() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let first = count.clone();
+    let second = count.clone();
+    let vector = request_interrupt("first", EoiPolicy::Auto, HandlerFlags::SHARED, move |_| {
+        first.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::NotHandled
+    })
+    .unwrap();
+    register_handler(
+        vector,
+        "second",
+        EoiPolicy::Auto,
+        HandlerFlags::SHARED,
+        move |_| {
+            second.fetch_add(1, Ordering::Relaxed);
+            IrqReturn::Handled
+        },
+    )
+    .unwrap();
+    assert!(matches!(
+        register_handler(
+            vector,
+            "exclusive",
+            EoiPolicy::Auto,
+            HandlerFlags::empty(),
+            |_| IrqReturn::Handled
+        ),
+        Err(InterruptError::VectorBusy(_))
+    ));
+    raise(vector);
+    assert_eq!(count.load(Ordering::Relaxed), 2);
+    assert_eq!(unhandled_count(vector), 0);
+    free_vector(vector).unwrap();
+}
+
+#[test_case]
+fn unregistered_vector_counted() {
+    let vector = allocate_vector().unwrap();
+    let before = unhandled_count(vector);
+    raise(vector);
+    assert_eq!(unhandled_count(vector), before + 1);
+    free_vector(vector).unwrap();
+}
+
+#[test_case]
+fn handler_can_unregister_itself() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let handler_count = count.clone();
+    let vector = allocate_vector().unwrap();
+    register_handler(
+        vector,
+        "once",
+        EoiPolicy::Auto,
+        HandlerFlags::empty(),
+        move |vector| {
+            handler_count.fetch_add(1, Ordering::Relaxed);
+            unregister_handler(vector, "once").unwrap();
+            IrqReturn::Handled
+        },
+    )
+    .unwrap();
+    raise(vector);
+    raise(vector);
+    assert_eq!(count.load(Ordering::Relaxed), 1);
+    free_vector(vector).unwrap();
+}
@@ -0,0 +1,237 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use common::boot::BootInformation;
+use nothingos::memory::address_space::{AddressSpace, USER_START};
+use nothingos::memory::memory_controller;
+use nothingos::memory::paging::EntryFlags;
+use nothingos::memory::PAGE_SIZE;
+use nothingos::userland::loader::{
+    load, setup_stack, LoadError, AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, PIE_BASE,
+};
+use nothingos::userland::programs::{ARGS, EXIT, PIE};
+use nothingos::userland::usermode::{
+    spawn_user, spawn_user_with_args, USER_STACK_SIZE, USER_STACK_TOP,
+};
+use x86_64::VirtAddr;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+/// Elf file of the given type made of headers only, segments are (type, flags, vaddr, memsz)
+fn elf(kind: u16, segments: &[(u32, u32, u64, u64)]) -> Vec<u8> {
+    let mut file = vec![0x7F, b'E', b'L', b'F', 2, 1, 1, 0];
+    file.resize(16, 0);
+    file.extend_from_slice(&kind.to_le_bytes());
+    file.extend_from_slice(&0x3Eu16.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&segments[0].2.to_le_bytes());
+    file.extend_from_slice(&64u64.to_le_bytes());
+    file.extend_from_slice(&[0; 12]);
+    for value in [64u16, 56, segments.len() as u16, 0, 0, 0] {
+        file.extend_from_slice(&value.to_le_bytes());
+    }
+    for (kind, flags, vaddr, memsz) in segments {
+        file.extend_from_slice(&kind.to_le_bytes());
+        file.extend_from_slice(&flags.to_le_bytes());
+        for value in [0, *vaddr, *vaddr, 0, *memsz, PAGE_SIZE] {
+            file.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    file
+}
+
+fn read_u64(space: &AddressSpace, address: u64) -> u64 {
+    let mut bytes = [0; 8];
+    space.read(address, &mut bytes).unwrap();
+    u64::from_le_bytes(bytes)
+}
+
+fn read_string(space: &AddressSpace, address: u64, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len + 1];
+    space.read(address, &mut bytes).unwrap();
+    bytes
+}
+
+#[test_case]
+fn shared_page_gets_both_flags() {
+    let mut space = AddressSpace::new().unwrap();
+    let image = elf(
+        2,
+        &[
+            (1, 5, USER_START, 0x1800),
+            (1, 6, USER_START + 0x1800, 0x1000),
+        ],
+    );
+    load(&mut space, &image).unwrap();
+    let flags = |address| {
+        memory_controller()
+            .lock()
+            .with_address_space(&space, |e| e.page_flags(VirtAddr::new(address)))
+            .unwrap()
+    };
+    let text = flags(USER_START);
+    assert!(!text.contains(EntryFlags::WRITABLE) && !text.contains(EntryFlags::NO_EXECUTE));
+    let shared = flags(USER_START + PAGE_SIZE);
+    assert!(shared.contains(EntryFlags::WRITABLE) && !shared.contains(EntryFlags::NO_EXECUTE));
+    let data = flags(USER_START + 2 * PAGE_SIZE);
+    assert!(data.contains(EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn overlapping_segments_are_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let image = elf(
+        2,
+        &[
+            (1, 5, USER_START, 0x3000),
+            (1, 6, USER_START + 0x1000, 0x1000),
+        ],
+    );
+    assert!(matches!(
+        load(&mut space, &image),
+        Err(LoadError::InvalidSegment(_))
+    ));
+}
+
+#[test_case]
+fn segments_outside_user_memory_are_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    for image in [
+        elf(2, &[(1, 5, 0xFFFF_8000_0000_0000, 0x1000)]),
+        elf(2, &[(1, 5, USER_START, u64::MAX)]),
+        // Wraps around once the pie base is added
+        elf(3, &[(1, 5, u64::MAX - 0xFFF, 0x1000)]),
+    ] {
+        assert!(matches!(
+            load(&mut space, &image),
+            Err(LoadError::InvalidSegment(_))
+        ));
+    }
+}
+
+#[test_case]
+fn interpreter_is_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let image = elf(3, &[(1, 5, 0, 0x1000), (3, 4, 0, 0)]);
+    assert!(matches!(
+        load(&mut space, &image),
+        Err(LoadError::Unsupported(_))
+    ));
+}
+
+#[test_case]
+fn pie_is_relocated() {
+    let mut space = AddressSpace::new().unwrap();
+    let image = load(&mut space, PIE).unwrap();
+    assert_eq!(image.base, PIE_BASE);
+    let entry = u64::from_le_bytes(PIE[24..32].try_into().unwrap());
+    assert_eq!(image.entry, PIE_BASE + entry);
+    assert_eq!(image.program_headers, PIE_BASE + 64);
+    let pointer = read_u64(&space, PIE_BASE + 176);
+    assert!((PIE_BASE..PIE_BASE + PIE.len() as u64).contains(&pointer));
+    // Last word of the bss
+    assert_eq!(
+        read_u64(&space, PIE_BASE + PIE.len() as u64 + 0x2000 - 8),
+        0
+    );
+}
+
+#[test_case]
+fn stack_layout() {
+    let mut space = AddressSpace::new().unwrap();
+    let image = load(&mut space, EXIT).unwrap();
+    space
+        .map(
+            USER_STACK_TOP - USER_STACK_SIZE,
+            USER_STACK_SIZE,
+            EntryFlags::WRITABLE,
+        )
+        .unwrap();
+    let stack = setup_stack(
+        &mut space,
+        USER_STACK_TOP,
+        USER_STACK_SIZE,
+        &["prog", "-v"],
+        &["A=1"],
+        &image,
+    )
+    .unwrap();
+    assert_eq!(stack % 16, 0);
+    assert_eq!(read_u64(&space, stack), 2);
+    assert_eq!(
+        read_string(&space, read_u64(&space, stack + 8), 4),
+        b"prog\0"
+    );
+    assert_eq!(
+        read_string(&space, read_u64(&space, stack + 16), 2),
+        b"-v\0"
+    );
+    assert_eq!(read_u64(&space, stack + 24), 0);
+    assert_eq!(
+        read_string(&space, read_u64(&space, stack + 32), 3),
+        b"A=1\0"
+    );
+    assert_eq!(read_u64(&space, stack + 40), 0);
+
+    let mut auxv = Vec::new();
+    let mut address = stack + 48;
+    loop {
+        let (kind, value) = (read_u64(&space, address), read_u64(&space, address + 8));
+        auxv.push((kind, value));
+        if kind == AT_NULL {
+            break;
+        }
+        address += 16;
+    }
+    assert!(auxv.contains(&(AT_PAGESZ, PAGE_SIZE)));
+    assert!(auxv.contains(&(AT_ENTRY, image.entry)));
+    assert!(auxv.contains(&(AT_PHDR, USER_START + 64)));
+}
+
+#[test_case]
+fn arguments_must_fit() {
+    let mut space = AddressSpace::new().unwrap();
+    let image = load(&mut space, EXIT).unwrap();
+    space
+        .map(USER_STACK_TOP - PAGE_SIZE, PAGE_SIZE, EntryFlags::WRITABLE)
+        .unwrap();
+    let argument = "x".repeat(PAGE_SIZE as usize);
+    assert!(matches!(
+        setup_stack(
+            &mut space,
+            USER_STACK_TOP,
+            PAGE_SIZE,
+            &[&argument],
+            &[],
+            &image
+        ),
+        Err(LoadError::ArgumentsTooLarge)
+    ));
+}
+
+#[test_case]
+fn program_reads_arguments() {
+    let handle = spawn_user_with_args("args", ARGS, &["args", "four"], &["A=1"]).unwrap();
+    assert_eq!(handle.join(), (2 << 8) + 4);
+}
+
+#[test_case]
+fn pie_runs() {
+    assert_eq!(
+        spawn_user("pie", PIE).unwrap().join(),
+        "Relocated\n".len() as i64
+    );
+}
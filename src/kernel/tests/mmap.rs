@@ -0,0 +1,211 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::address_space::{AddressSpace, AddressSpaceError, MMAP_BASE, USER_START};
+use nothingos::memory::paging::EntryFlags;
+use nothingos::memory::region::Backing;
+use nothingos::memory::{memory_controller, PAGE_SIZE};
+use nothingos::userland::process::{spawn, wait};
+use nothingos::userland::programs::EXIT;
+use nothingos::userland::usermode::FAULT_STATUS;
+use x86_64::VirtAddr;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+fn allocated() -> usize {
+    memory_controller().lock().allocated()
+}
+
+#[test_case]
+fn kernel_region_is_mapped_and_freed() {
+    let before = allocated();
+    let start = memory_controller()
+        .lock()
+        .map_region(3 * PAGE_SIZE, EntryFlags::WRITABLE, Backing::Anonymous)
+        .unwrap();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(start as *mut u8, 3 * PAGE_SIZE as usize) };
+    assert!(bytes.iter().all(|e| *e == 0));
+    bytes.fill(0xAA);
+    assert!(allocated() > before);
+    let mut controller = memory_controller().lock();
+    assert!(matches!(
+        controller.map_region_at(
+            start + PAGE_SIZE,
+            PAGE_SIZE,
+            EntryFlags::empty(),
+            Backing::Anonymous
+        ),
+        Err(AddressSpaceError::AlreadyMapped(_))
+    ));
+    controller.unmap_region(start, 3 * PAGE_SIZE).unwrap();
+    assert!(controller.regions().iter().all(|e| !e.contains(start)));
+    assert_eq!(controller.get_physical(VirtAddr::new(start)), None);
+    drop(controller);
+    assert_eq!(allocated(), before);
+}
+
+#[test_case]
+fn kernel_region_protection_changes() {
+    let mut controller = memory_controller().lock();
+    let start = controller
+        .map_region(2 * PAGE_SIZE, EntryFlags::WRITABLE, Backing::Anonymous)
+        .unwrap();
+    controller
+        .protect_region(start + PAGE_SIZE, PAGE_SIZE, EntryFlags::NO_EXECUTE)
+        .unwrap();
+    let flags = |address: u64| controller.page_flags(VirtAddr::new(address)).unwrap();
+    assert!(flags(start).contains(EntryFlags::WRITABLE));
+    assert!(!flags(start + PAGE_SIZE).contains(EntryFlags::WRITABLE));
+    assert_eq!(
+        controller
+            .regions()
+            .iter()
+            .filter(|e| e.start >= start && e.end <= start + 2 * PAGE_SIZE)
+            .count(),
+        2
+    );
+    assert!(matches!(
+        controller.protect_region(start, 3 * PAGE_SIZE, EntryFlags::empty()),
+        Err(AddressSpaceError::NotMapped(_))
+    ));
+    controller.unmap_region(start, 2 * PAGE_SIZE).unwrap();
+}
+
+#[test_case]
+fn physical_region_keeps_its_frames() {
+    let mut controller = memory_controller().lock();
+    let physical = controller.physical_alloc(PAGE_SIZE as usize).unwrap();
+    let first = controller
+        .map_region(PAGE_SIZE, EntryFlags::WRITABLE, Backing::Physical(physical))
+        .unwrap();
+    let second = controller
+        .map_region(PAGE_SIZE, EntryFlags::WRITABLE, Backing::Physical(physical))
+        .unwrap();
+    assert_ne!(first, second);
+    unsafe {
+        *(first as *mut u64) = 0x1234;
+        assert_eq!(*(second as *const u64), 0x1234);
+    }
+    let before = controller.allocated();
+    controller.unmap_region(first, PAGE_SIZE).unwrap();
+    controller.unmap_region(second, PAGE_SIZE).unwrap();
+    assert_eq!(controller.allocated(), before);
+    controller.physical_dealloc(physical, PAGE_SIZE as usize);
+}
+
+#[test_case]
+fn user_mmap_finds_room() {
+    let mut space = AddressSpace::new().unwrap();
+    let flags = EntryFlags::USER_ACCESSIBLE | EntryFlags::WRITABLE;
+    let first = space.mmap(None, 1, flags, Backing::Anonymous).unwrap();
+    assert_eq!(first, MMAP_BASE);
+    let second = space
+        .mmap(None, PAGE_SIZE, flags, Backing::Anonymous)
+        .unwrap();
+    assert_eq!(second, MMAP_BASE + PAGE_SIZE);
+    assert!(matches!(
+        space.mmap(Some(second), PAGE_SIZE, flags, Backing::Anonymous),
+        Err(AddressSpaceError::AlreadyMapped(_))
+    ));
+    assert!(matches!(
+        space.mmap(Some(USER_START + 1), PAGE_SIZE, flags, Backing::Anonymous),
+        Err(AddressSpaceError::InvalidRange(_))
+    ));
+    space.munmap(first, PAGE_SIZE).unwrap();
+    assert_eq!(
+        space
+            .mmap(None, PAGE_SIZE, flags, Backing::Anonymous)
+            .unwrap(),
+        first
+    );
+}
+
+#[test_case]
+fn user_munmap_splits_areas() {
+    let before = allocated();
+    let mut space = AddressSpace::new().unwrap();
+    let start = space
+        .mmap(
+            None,
+            4 * PAGE_SIZE,
+            EntryFlags::WRITABLE,
+            Backing::Anonymous,
+        )
+        .unwrap();
+    space.write(start, &[1; 4 * PAGE_SIZE as usize]).unwrap();
+    space.munmap(start + PAGE_SIZE, 2 * PAGE_SIZE).unwrap();
+    let areas = space.areas();
+    assert_eq!(areas.len(), 2);
+    assert_eq!((areas[0].start, areas[0].end), (start, start + PAGE_SIZE));
+    assert_eq!(
+        (areas[1].start, areas[1].end),
+        (start + 3 * PAGE_SIZE, start + 4 * PAGE_SIZE)
+    );
+    assert_eq!(space.translate(start + PAGE_SIZE), None);
+    assert!(space.translate(start + 3 * PAGE_SIZE).is_some());
+    space
+        .mprotect(start, PAGE_SIZE, EntryFlags::empty())
+        .unwrap();
+    assert!(matches!(
+        space.mprotect(start, 2 * PAGE_SIZE, EntryFlags::empty()),
+        Err(AddressSpaceError::NotMapped(_))
+    ));
+    drop(space);
+    assert_eq!(allocated(), before);
+}
+
+#[test_case]
+fn file_mapping_copies_from_offset() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = space
+        .mmap(
+            None,
+            2 * PAGE_SIZE,
+            EntryFlags::WRITABLE,
+            Backing::File {
+                data: EXIT,
+                offset: 0,
+            },
+        )
+        .unwrap();
+    let mut bytes = [0; 4];
+    space.read(start, &mut bytes).unwrap();
+    assert_eq!(bytes, EXIT[..4]);
+    // Past the end of the file it's zeroes
+    space.read(start + PAGE_SIZE, &mut bytes).unwrap();
+    assert_eq!(bytes, [0; 4]);
+    // The tail keeps reading from where it starts in the file
+    space.munmap(start, PAGE_SIZE).unwrap();
+    assert_eq!(
+        space.areas()[0].backing,
+        Backing::File {
+            data: EXIT,
+            offset: PAGE_SIZE
+        }
+    );
+}
+
+#[test_case]
+fn program_maps_memory() {
+    let pid = spawn("/bin/mmap", &[]).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, 77));
+}
+
+#[test_case]
+fn protection_is_enforced() {
+    let pid = spawn("/bin/protect", &[]).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, FAULT_STATUS));
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use bitflags::bitflags;
+use common::boot::BootInformation;
+use nothingos::driver::mmio::{access, MmioRegion, ReadOnly, ReadWrite, RegisterBlock, WriteOnly};
+use nothingos::memory::virtual_alloc::UnmappedFrames;
+use nothingos::memory::PAGE_SIZE;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Control: u32 {
+        const ENABLE = 1 << 0;
+        const IRQ = 1 << 4;
+    }
+}
+
+#[repr(C)]
+struct Device {
+    id: ReadOnly<u32>,
+    control: ReadWrite<Control>,
+    doorbell: WriteOnly<u64>,
+}
+
+unsafe impl RegisterBlock for Device {}
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+/// A page of ram standing in for device memory
+fn with_region(f: impl FnOnce(&MmioRegion)) {
+    let frames = UnmappedFrames::new(PAGE_SIZE as usize, PAGE_SIZE).unwrap();
+    let region = MmioRegion::map(frames.phys().as_u64(), PAGE_SIZE as usize).unwrap();
+    f(&region);
+}
+
+#[test_case]
+fn registers_read_and_write_the_device() {
+    with_region(|region| {
+        // Raw views of the same registers, what the device would see
+        let raw = region.blocks::<ReadWrite<u32>>(0, 4);
+        raw[0].write(0x1234);
+        raw[1].write(0);
+        let registers = region.block::<Device>(0);
+        assert_eq!(registers.id.read(), 0x1234);
+
+        registers.control.write(Control::ENABLE);
+        registers.control.insert(Control::IRQ);
+        assert!(registers.control.contains(Control::ENABLE | Control::IRQ));
+        registers.control.remove(Control::ENABLE);
+        assert_eq!(raw[1].read(), Control::IRQ.bits());
+
+        registers.doorbell.write(u64::MAX);
+        assert_eq!(raw[2].read(), u32::MAX);
+        assert_eq!(raw[3].read(), u32::MAX);
+    });
+}
+
+#[test_case]
+fn registers_are_bounds_checked() {
+    with_region(|region| {
+        let last = PAGE_SIZE as usize - 4;
+        region.register::<u32, access::RW>(last).write(1);
+        assert!(region.try_blocks::<Device>(last, 1).is_none());
+        // Misaligned
+        assert!(region.try_blocks::<ReadOnly<u32>>(2, 1).is_none());
+        let count = PAGE_SIZE as usize / 4;
+        assert_eq!(
+            region
+                .try_blocks::<ReadOnly<u32>>(0, count)
+                .map(|e| e.len()),
+            Some(count)
+        );
+        assert!(region.try_blocks::<ReadOnly<u32>>(0, count + 1).is_none());
+    });
+}
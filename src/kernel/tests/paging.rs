@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::address_space::{AddressSpace, AddressSpaceError, USER_START};
+use nothingos::memory::paging::EntryFlags;
+use nothingos::memory::{memory_controller, PAGE_SIZE};
+use nothingos::userland::process::{spawn, wait};
+use nothingos::userland::usermode::FAULT_STATUS;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+fn allocated() -> usize {
+    memory_controller().lock().allocated()
+}
+
+fn owners(space: &AddressSpace, address: u64) -> usize {
+    let physical = space.translate(address).unwrap();
+    memory_controller().lock().frame_owners(physical)
+}
+
+#[test_case]
+fn reserved_pages_are_lazy() {
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .reserve(USER_START, 16 * PAGE_SIZE, EntryFlags::WRITABLE)
+        .unwrap();
+    assert_eq!(space.translate(USER_START), None);
+    let before = allocated();
+    space.write(USER_START + 5 * PAGE_SIZE, &[1]).unwrap();
+    assert!(space.translate(USER_START + 5 * PAGE_SIZE).is_some());
+    assert_eq!(space.translate(USER_START + 4 * PAGE_SIZE), None);
+    assert_eq!(space.translate(USER_START + 6 * PAGE_SIZE), None);
+    assert!(allocated() > before);
+}
+
+#[test_case]
+fn outside_areas_is_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .reserve(USER_START, PAGE_SIZE, EntryFlags::WRITABLE)
+        .unwrap();
+    assert!(matches!(
+        space.write(USER_START + PAGE_SIZE, &[1]),
+        Err(AddressSpaceError::NotMapped(address)) if address == USER_START + PAGE_SIZE
+    ));
+    assert!(matches!(
+        space.reserve(USER_START, PAGE_SIZE, EntryFlags::empty()),
+        Err(AddressSpaceError::AlreadyMapped(USER_START))
+    ));
+}
+
+#[test_case]
+fn fork_shares_frames_until_written() {
+    let before = allocated();
+    let mut parent = AddressSpace::new().unwrap();
+    parent
+        .map(USER_START, 2 * PAGE_SIZE, EntryFlags::WRITABLE)
+        .unwrap();
+    parent.write(USER_START, &[1, 2, 3]).unwrap();
+    let mut child = parent.fork().unwrap();
+    assert_eq!(child.areas(), parent.areas());
+    assert_eq!(child.translate(USER_START), parent.translate(USER_START));
+    assert_eq!(owners(&parent, USER_START), 2);
+
+    child.write(USER_START, &[4]).unwrap();
+    assert_ne!(child.translate(USER_START), parent.translate(USER_START));
+    assert_eq!(owners(&parent, USER_START), 1);
+    assert_eq!(owners(&parent, USER_START + PAGE_SIZE), 2);
+    let (mut parent_bytes, mut child_bytes) = ([0; 3], [0; 3]);
+    parent.read(USER_START, &mut parent_bytes).unwrap();
+    child.read(USER_START, &mut child_bytes).unwrap();
+    assert_eq!(parent_bytes, [1, 2, 3]);
+    assert_eq!(child_bytes, [4, 2, 3]);
+
+    drop(parent);
+    assert_eq!(owners(&child, USER_START + PAGE_SIZE), 1);
+    drop(child);
+    assert_eq!(allocated(), before);
+}
+
+#[test_case]
+fn forked_program_gets_private_stack() {
+    let pid = spawn("/bin/fork", &[]).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, 107));
+}
+
+#[test_case]
+fn write_to_code_is_killed() {
+    let pid = spawn("/bin/segv", &[]).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, FAULT_STATUS));
+}
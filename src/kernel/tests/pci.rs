@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::driver::pci::capability::CapabilityId;
+use nothingos::driver::pci::{DeviceType, PciHeader};
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+fn find_sata_controller() -> Option<PciHeader> {
+    (0..32)
+        .flat_map(|device| (0..8).map(move |function| PciHeader::new(0, device, function)))
+        .find(|e| e.get_vendor().is_valid() && e.get_device() == DeviceType::SataController)
+}
+
+#[test_case]
+fn capability_list_terminates() {
+    let header = find_sata_controller().expect("Cannot find sata controller");
+    assert!(header.capabilities().count() > 0);
+    for capability in header.capabilities() {
+        assert!(capability.offset() >= 0x40);
+    }
+}
+
+#[test_case]
+fn ahci_uses_msi() {
+    let header = find_sata_controller().expect("Cannot find sata controller");
+    if header.find_capability(CapabilityId::Msi).is_none() {
+        return;
+    }
+    let msi = header.msi().unwrap();
+    assert!(msi.is_enabled());
+    assert!(msi.vector_count() >= 1);
+}
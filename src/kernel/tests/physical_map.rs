@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::physical_map::Owner;
+use nothingos::memory::{memory_controller, physical_map, PAGE_SIZE};
+use uefi::table::boot::MemoryType;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn ranges_are_ordered() {
+    let ranges = physical_map().lock().ranges();
+    assert!(!ranges.is_empty());
+    assert!(ranges
+        .iter()
+        .all(|e| e.start < e.end && e.start % PAGE_SIZE == 0));
+    assert!(ranges.windows(2).all(|e| e[0].end <= e[1].start));
+}
+
+#[test_case]
+fn kernel_is_reserved() {
+    let kernel = start as usize as u64;
+    let ranges = physical_map().lock().ranges();
+    let range = ranges
+        .iter()
+        .find(|e| (e.start..e.end).contains(&kernel))
+        .expect("Kernel isn't in the memory map");
+    assert_eq!(range.owner, Owner::Kernel);
+    assert!(ranges
+        .iter()
+        .any(|e| e.owner == Owner::LowMemory && e.start == 0));
+}
+
+#[test_case]
+fn boot_memory_is_reclaimed() {
+    let map = physical_map().lock();
+    assert!(map.reclaimed());
+    let ranges = map.ranges();
+    assert!(ranges.iter().all(|e| e.owner != Owner::Bootloader));
+    assert!(ranges.iter().all(|e| e.owner != Owner::Font));
+    assert!(ranges
+        .iter()
+        .any(|e| e.ty == MemoryType::LOADER_DATA && e.owner == Owner::KernelFile));
+}
+
+#[test_case]
+fn allocated_frames_belong_to_the_allocator() {
+    let frame = memory_controller()
+        .lock()
+        .physical_alloc(PAGE_SIZE as usize)
+        .unwrap();
+    let ranges = physical_map().lock().ranges();
+    let range = ranges
+        .iter()
+        .find(|e| (e.start..e.end).contains(&frame.as_u64()))
+        .unwrap();
+    assert_eq!(range.owner, Owner::Allocator);
+    memory_controller()
+        .lock()
+        .physical_dealloc(frame, PAGE_SIZE as usize);
+}
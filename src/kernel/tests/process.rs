@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::memory_controller;
+use nothingos::task::thread::sleep;
+use nothingos::time::Duration;
+use nothingos::userland::process::{
+    self, check_kill, current, kill, spawn, wait, Handle, Pid, ProcessError, ProcessStatus,
+};
+use nothingos::userland::syscall::Errno;
+use nothingos::userland::usermode::KILL_STATUS;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn kernel_threads_are_no_process() {
+    assert_eq!(current(), None);
+    assert_eq!(process::handle(1), Some(Handle::Console));
+    assert_eq!(process::handle(3), None);
+}
+
+#[test_case]
+fn spawn_and_wait() {
+    let pid = spawn("/bin/exit", &[]).unwrap();
+    assert_eq!(process::parent(pid), None);
+    assert_eq!(process::name(pid).as_deref(), Some("/bin/exit"));
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, 42));
+    assert_eq!(process::status(pid), None);
+}
+
+#[test_case]
+fn zombie_keeps_status() {
+    let pid = spawn("/bin/exit", &[]).unwrap();
+    while process::status(pid) == Some(ProcessStatus::Running) {
+        sleep(Duration::from_millis(1));
+    }
+    assert_eq!(process::status(pid), Some(ProcessStatus::Zombie(42)));
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, 42));
+}
+
+#[test_case]
+fn wait_for_any_child() {
+    let first = spawn("/bin/exit", &[]).unwrap();
+    let second = spawn("/bin/hello", &[]).unwrap();
+    let (a, _) = wait(None).unwrap();
+    let (b, _) = wait(None).unwrap();
+    assert!((a == first && b == second) || (a == second && b == first));
+    assert!(matches!(wait(None), Err(ProcessError::NoChildren)));
+}
+
+#[test_case]
+fn missing_program() {
+    assert!(matches!(
+        spawn("/bin/missing", &[]),
+        Err(ProcessError::NotFound)
+    ));
+}
+
+#[test_case]
+fn program_spawns_and_waits() {
+    let pid = spawn("/bin/parent", &[]).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, 43));
+}
+
+#[test_case]
+fn closed_handle_is_gone() {
+    let pid = spawn("/bin/close", &[]).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap().1, -(Errno::EBADF as i64));
+}
+
+#[test_case]
+fn kill_releases_everything() {
+    // Warm up so the heap and page tables don't grow in the measured part
+    let pid = spawn("/bin/spin", &[]).unwrap();
+    kill(pid).unwrap();
+    wait(Some(pid)).unwrap();
+
+    let before = memory_controller().lock().allocated();
+    let pid = spawn("/bin/spin", &[]).unwrap();
+    sleep(Duration::from_millis(20));
+    assert_eq!(process::status(pid), Some(ProcessStatus::Running));
+    kill(pid).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, KILL_STATUS));
+    assert_eq!(memory_controller().lock().allocated(), before);
+}
+
+#[test_case]
+fn check_kill_leaves_the_process_running() {
+    let pid = spawn("/bin/spin", &[]).unwrap();
+    check_kill(pid).unwrap();
+    sleep(Duration::from_millis(20));
+    assert_eq!(process::status(pid), Some(ProcessStatus::Running));
+    kill(pid).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap(), (pid, KILL_STATUS));
+}
+
+#[test_case]
+fn kill_checks_pid() {
+    assert!(matches!(
+        kill(Pid::from_raw(u64::MAX)),
+        Err(ProcessError::NoSuchProcess(_))
+    ));
+    assert!(matches!(
+        wait(Some(Pid::from_raw(u64::MAX))),
+        Err(ProcessError::NoSuchProcess(_))
+    ));
+}
+
+#[test_case]
+fn only_children_can_be_killed() {
+    let pid = spawn("/bin/kill_self", &[]).unwrap();
+    assert_eq!(wait(Some(pid)).unwrap().1, -(Errno::EPERM as i64));
+}
@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::interrupt::{local_apic, local_apic_id, LAPIC_IDS};
+use nothingos::memory::PAGE_SIZE;
+use nothingos::smp::online_cpus;
+use nothingos::smp::percpu::{bsp, current};
+use nothingos::smp::tlb::shootdown;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn every_processor_online() {
+    assert_eq!(online_cpus(), LAPIC_IDS.get().unwrap().len());
+}
+
+#[test_case]
+fn bsp_per_cpu_block() {
+    let cpu = current();
+    assert!(cpu.is_bsp());
+    assert_eq!(cpu.index(), 0);
+    assert!(core::ptr::eq(cpu, bsp()));
+    assert_eq!(cpu.apic_id(), local_apic_id());
+    assert!(LAPIC_IDS.get().unwrap().contains(&cpu.apic_id()));
+}
+
+#[test_case]
+fn per_cpu_lapic_is_bsp() {
+    assert!(unsafe { local_apic().lock().is_bsp() });
+}
+
+#[test_case]
+fn shootdowns_are_acknowledged() {
+    // Returning at all means every other processor ran the flush
+    shootdown(Some(0x1000..0x1000 + PAGE_SIZE));
+    shootdown(None);
+}
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::stack_allocator::guard_owner;
+use nothingos::memory::{memory_controller, PAGE_SIZE};
+use x86_64::VirtAddr;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn guard_page_is_unmapped() {
+    let stack = memory_controller().lock().alloc_stack(2, "test").unwrap();
+    let mut controller = memory_controller().lock();
+    assert_eq!(stack.size_in_pages(), 2);
+    assert!(controller
+        .get_physical(VirtAddr::new(stack.bottom()))
+        .is_some());
+    assert!(controller
+        .get_physical(VirtAddr::new(stack.bottom() - 1))
+        .is_none());
+    controller.dealloc_stack(stack);
+}
+
+#[test_case]
+fn guard_page_names_its_stack() {
+    let stack = memory_controller()
+        .lock()
+        .alloc_stack(1, "guarded")
+        .unwrap();
+    assert_eq!(guard_owner(stack.bottom() - 8), Some("guarded"));
+    assert_eq!(guard_owner(stack.bottom() - PAGE_SIZE), Some("guarded"));
+    assert_eq!(guard_owner(stack.bottom()), None);
+    let bottom = stack.bottom();
+    memory_controller().lock().dealloc_stack(stack);
+    assert_eq!(guard_owner(bottom - 8), None);
+}
+
+#[test_case]
+fn freed_stack_is_reused() {
+    let stack = memory_controller().lock().alloc_stack(4, "test").unwrap();
+    let bottom = stack.bottom();
+    memory_controller().lock().dealloc_stack(stack);
+    let stack = memory_controller().lock().alloc_stack(4, "test").unwrap();
+    assert_eq!(stack.bottom(), bottom);
+    memory_controller().lock().dealloc_stack(stack);
+}
+
+#[test_case]
+fn released_stacks_merge() {
+    let first = memory_controller().lock().alloc_stack(1, "test").unwrap();
+    let second = memory_controller().lock().alloc_stack(1, "test").unwrap();
+    let bottom = first.bottom();
+    memory_controller().lock().dealloc_stack(second);
+    memory_controller().lock().dealloc_stack(first);
+    // Two blocks of a guard page and one stack page make room for a 3 pages stack
+    let stack = memory_controller().lock().alloc_stack(3, "test").unwrap();
+    assert_eq!(stack.bottom(), bottom);
+    memory_controller().lock().dealloc_stack(stack);
+}
+
+#[test_case]
+fn region_is_not_exhausted() {
+    for _ in 0..1000 {
+        let stack = memory_controller().lock().alloc_stack(8, "test").unwrap();
+        unsafe { *((stack.top() - 8) as *mut u64) = 0 };
+        memory_controller().lock().dealloc_stack(stack);
+    }
+}
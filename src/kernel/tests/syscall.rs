@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use core::arch::asm;
+
+use common::boot::BootInformation;
+use nothingos::gdt::selectors;
+use nothingos::memory::address_space::USER_START;
+use nothingos::userland::programs::{BAD_WRITE, EXIT, HELLO};
+use nothingos::userland::syscall::{
+    copy_from_user, copy_to_user, Errno, SYSCALL_TABLE, SYS_EXIT, SYS_KILL, SYS_WRITE,
+};
+use nothingos::userland::usermode::spawn_user;
+use x86_64::registers::model_specific::Star;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+/// Raise system call `number` through the interrupt gate
+fn int_syscall(number: u64, arg: u64) -> i64 {
+    int_syscall2(number, arg, 0)
+}
+
+fn int_syscall2(number: u64, first: u64, second: u64) -> i64 {
+    let result: u64;
+    unsafe {
+        asm!("int 0x80", inlateout("rax") number => result, in("rdi") first, in("rsi") second);
+    }
+    result as i64
+}
+
+#[test_case]
+fn star_matches_gdt() {
+    let selectors = selectors();
+    let (user_code, user_data, kernel_code, kernel_data) = Star::read();
+    assert_eq!(user_code, selectors.user_code);
+    assert_eq!(user_data, selectors.user_data);
+    assert_eq!(kernel_code, selectors.kernel_code);
+    assert_eq!(kernel_data, selectors.kernel_data);
+}
+
+#[test_case]
+fn table_is_numbered() {
+    assert_eq!(SYSCALL_TABLE[SYS_WRITE as usize].unwrap().name, "write");
+    assert_eq!(SYSCALL_TABLE[SYS_EXIT as usize].unwrap().name, "exit");
+    assert!(SYSCALL_TABLE[0].is_none());
+}
+
+#[test_case]
+fn unknown_number_is_enosys() {
+    assert_eq!(int_syscall(63, 0), -(Errno::ENOSYS as i64));
+    assert_eq!(int_syscall(u64::MAX, 0), -(Errno::ENOSYS as i64));
+}
+
+#[test_case]
+fn kernel_cannot_exit() {
+    assert_eq!(int_syscall(SYS_EXIT, 0), -(Errno::EPERM as i64));
+}
+
+#[test_case]
+fn kill_checks_signal() {
+    assert_eq!(
+        int_syscall2(SYS_KILL, u64::MAX, 65),
+        -(Errno::EINVAL as i64)
+    );
+    assert_eq!(int_syscall2(SYS_KILL, u64::MAX, 0), -(Errno::ESRCH as i64));
+}
+
+#[test_case]
+fn kernel_buffer_is_efault() {
+    let mut buffer = [0u8; 8];
+    let kernel = &buffer as *const _ as u64;
+    assert_eq!(copy_from_user(&mut buffer, kernel), Err(Errno::EFAULT));
+    assert_eq!(copy_to_user(kernel, &[1, 2, 3]), Err(Errno::EFAULT));
+    assert_eq!(copy_from_user(&mut buffer, USER_START), Err(Errno::EFAULT));
+    assert_eq!(
+        copy_from_user(&mut buffer, u64::MAX - 4),
+        Err(Errno::EFAULT)
+    );
+    assert_eq!(copy_from_user(&mut [], kernel), Ok(()));
+}
+
+#[test_case]
+fn write_through_syscall_instruction() {
+    let handle = spawn_user("hello", HELLO).unwrap();
+    assert_eq!(handle.join(), "Hello from ring 3\n".len() as i64);
+}
+
+#[test_case]
+fn bad_pointer_is_efault() {
+    let handle = spawn_user("bad write", BAD_WRITE).unwrap();
+    assert_eq!(handle.join(), -(Errno::EFAULT as i64));
+}
+
+#[test_case]
+fn interrupt_gate_still_works() {
+    assert_eq!(spawn_user("exit", EXIT).unwrap().join(), 42);
+}
@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use common::boot::BootInformation;
+use nothingos::task::thread::{current, sleep, spawn_thread, yield_now};
+use nothingos::time::{busy_wait, Duration, Instant};
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn join_returns_result() {
+    let handle = spawn_thread("answer", || 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn thread_has_own_id() {
+    let main = current();
+    let handle = spawn_thread("id", current);
+    let id = handle.id();
+    assert_eq!(handle.join(), id);
+    assert_ne!(id, main);
+}
+
+#[test_case]
+fn busy_threads_are_preempted() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let spins = Arc::new(AtomicU64::new(0));
+    let handle = {
+        let (stop, spins) = (stop.clone(), spins.clone());
+        spawn_thread("spinner", move || {
+            while !stop.load(Ordering::Relaxed) {
+                spins.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    };
+    // Neither side yields, the spinner only makes progress if the timer preempts this thread
+    busy_wait(Duration::from_millis(50));
+    assert!(spins.load(Ordering::Relaxed) > 0);
+    stop.store(true, Ordering::Relaxed);
+    handle.join();
+}
+
+#[test_case]
+fn sleep_waits() {
+    let start = Instant::now();
+    sleep(Duration::from_millis(20));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn sleeping_thread_lets_others_run() {
+    let handle = spawn_thread("sleeper", || {
+        let start = Instant::now();
+        sleep(Duration::from_millis(20));
+        start.elapsed()
+    });
+    assert!(handle.join() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn yield_without_other_threads() {
+    yield_now();
+}
+
+#[test_case]
+fn threads_interleave() {
+    let handles: Vec<_> = (0..4u64)
+        .map(|i| {
+            spawn_thread("worker", move || {
+                (0..5).fold(i, |acc, _| {
+                    yield_now();
+                    acc + 1
+                })
+            })
+        })
+        .collect();
+    let results: Vec<u64> = handles.into_iter().map(|e| e.join()).collect();
+    assert_eq!(results, [5, 6, 7, 8]);
+}
+
+#[test_case]
+fn stacks_are_reclaimed() {
+    for i in 0..300 {
+        assert_eq!(spawn_thread("short", move || i).join(), i);
+    }
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::task::{executor::Executor, AwaitType, Task};
+use nothingos::time::timer::{interval, sleep, timeout};
+use nothingos::time::{busy_wait, lapic_frequency, tsc_frequency, Duration, Elapsed, Instant};
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn clock_calibrated() {
+    assert!(tsc_frequency() > 0);
+    assert!(lapic_frequency() > 0);
+}
+
+#[test_case]
+fn instant_monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn busy_wait_duration() {
+    let start = Instant::now();
+    busy_wait(Duration::from_millis(5));
+    assert!(start.elapsed() >= Duration::from_millis(5));
+}
+
+#[test_case]
+fn sleep_wakes_task() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(
+        async {
+            let start = Instant::now();
+            sleep(Duration::from_millis(20)).await;
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        },
+        AwaitType::Waker,
+    ));
+    executor.run_exit();
+}
+
+#[test_case]
+fn timeout_elapses() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(
+        async {
+            let result = timeout(Duration::from_millis(10), core::future::pending::<()>()).await;
+            assert_eq!(result, Err(Elapsed));
+            let result = timeout(Duration::from_millis(10), async { 1 }).await;
+            assert_eq!(result, Ok(1));
+        },
+        AwaitType::Waker,
+    ));
+    executor.run_exit();
+}
+
+#[test_case]
+fn interval_ticks() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(
+        async {
+            let start = Instant::now();
+            let mut interval = interval(Duration::from_millis(5));
+            let mut last = start;
+            for _ in 0..3 {
+                let due = interval.tick().await;
+                assert!(due > last);
+                last = due;
+            }
+            assert!(start.elapsed() >= Duration::from_millis(15));
+        },
+        AwaitType::Waker,
+    ));
+    executor.run_exit();
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use alloc::vec::Vec;
+use common::boot::BootInformation;
+use nothingos::gdt::selectors;
+use nothingos::memory::address_space::{AddressSpace, AddressSpaceError, USER_END, USER_START};
+use nothingos::memory::paging::EntryFlags;
+use nothingos::memory::{memory_controller, PAGE_SIZE};
+use nothingos::userland::loader::{load, LoadError};
+use nothingos::userland::programs::EXIT;
+use nothingos::userland::usermode::{spawn_user, FAULT_STATUS};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+static SHARED: u64 = 0;
+
+#[test_case]
+fn selectors_have_ring() {
+    let selectors = selectors();
+    assert_eq!(selectors.kernel_code.rpl(), PrivilegeLevel::Ring0);
+    assert_eq!(selectors.kernel_data.rpl(), PrivilegeLevel::Ring0);
+    assert_eq!(selectors.user_code.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(selectors.user_data.rpl(), PrivilegeLevel::Ring3);
+    // Sysret loads user data and code from consecutive entries after kernel data
+    assert_eq!(
+        selectors.user_data.index(),
+        selectors.kernel_data.index() + 1
+    );
+    assert_eq!(selectors.user_code.index(), selectors.user_data.index() + 1);
+}
+
+#[test_case]
+fn program_exit_status() {
+    let handle = spawn_user("exit", EXIT).unwrap();
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn faulting_program_is_killed() {
+    // Start right at the trailing ud2, the whole file is loaded at the start of user space
+    let mut image: Vec<u8> = EXIT.to_vec();
+    let entry = USER_START + EXIT.len() as u64 - 2;
+    image[24..32].copy_from_slice(&entry.to_le_bytes());
+    assert_eq!(spawn_user("ud2", &image).unwrap().join(), FAULT_STATUS);
+}
+
+#[test_case]
+fn programs_run_one_after_another() {
+    for _ in 0..20 {
+        assert_eq!(spawn_user("exit", EXIT).unwrap().join(), 42);
+    }
+}
+
+#[test_case]
+fn kernel_half_is_shared() {
+    let space = AddressSpace::new().unwrap();
+    let address = &SHARED as *const u64 as u64;
+    assert_eq!(
+        space.translate(address),
+        memory_controller()
+            .lock()
+            .get_physical(VirtAddr::new(address))
+    );
+    assert_eq!(space.translate(USER_START), None);
+}
+
+#[test_case]
+fn user_pages_are_private() {
+    let mut first = AddressSpace::new().unwrap();
+    let second = AddressSpace::new().unwrap();
+    first
+        .map(USER_START, PAGE_SIZE, EntryFlags::WRITABLE)
+        .unwrap();
+    assert!(first.translate(USER_START).is_some());
+    assert_eq!(second.translate(USER_START), None);
+    assert!(memory_controller()
+        .lock()
+        .get_physical(VirtAddr::new(USER_START))
+        .is_none());
+}
+
+#[test_case]
+fn map_checks_range() {
+    let mut space = AddressSpace::new().unwrap();
+    assert!(matches!(
+        space.map(USER_START - PAGE_SIZE, PAGE_SIZE, EntryFlags::WRITABLE),
+        Err(AddressSpaceError::OutsideUserSpace(_))
+    ));
+    assert!(matches!(
+        space.map(USER_END - PAGE_SIZE, 2 * PAGE_SIZE, EntryFlags::WRITABLE),
+        Err(AddressSpaceError::OutsideUserSpace(_))
+    ));
+    space
+        .map(USER_START, PAGE_SIZE, EntryFlags::WRITABLE)
+        .unwrap();
+    assert!(matches!(
+        space.map(USER_START, 2 * PAGE_SIZE, EntryFlags::WRITABLE),
+        Err(AddressSpaceError::AlreadyMapped(USER_START))
+    ));
+    assert!(matches!(
+        space.write(USER_START + PAGE_SIZE - 1, &[1, 2]),
+        Err(AddressSpaceError::NotMapped(_))
+    ));
+}
+
+#[test_case]
+fn dropping_frees_frames() {
+    let before = memory_controller().lock().allocated();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        space
+            .map(USER_START, 16 * PAGE_SIZE, EntryFlags::WRITABLE)
+            .unwrap();
+        space.write(USER_START + 10, &[0xAA; 5000]).unwrap();
+    }
+    assert_eq!(memory_controller().lock().allocated(), before);
+}
+
+#[test_case]
+fn loader_rejects_garbage() {
+    let mut space = AddressSpace::new().unwrap();
+    assert!(matches!(
+        load(
+            &mut space,
+            b"definitely not an elf file, just some bytes padding it out"
+        ),
+        Err(LoadError::InvalidElf(_))
+    ));
+    assert_eq!(load(&mut space, EXIT).unwrap().entry, USER_START + 64 + 56);
+}
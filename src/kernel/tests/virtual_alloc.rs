@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(nothingos::test_runner)]
+
+extern crate alloc;
+extern crate nothingos;
+
+use common::boot::BootInformation;
+use nothingos::memory::address_space::AddressSpaceError;
+use nothingos::memory::paging::PageSize;
+use nothingos::memory::virtual_alloc::{map_physical, UnmappedFrames};
+use nothingos::memory::{memory_controller, PAGE_SIZE};
+use x86_64::VirtAddr;
+
+const MIB_2: u64 = 0x200000;
+
+#[no_mangle]
+pub extern "C" fn start(multiboot_information_address: *mut BootInformation) -> ! {
+    nothingos::init(multiboot_information_address);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn ranges_are_aligned_and_disjoint() {
+    let mut controller = memory_controller().lock();
+    let first = controller.alloc_virt(100, PAGE_SIZE).unwrap();
+    let second = controller.alloc_virt(PAGE_SIZE, 0x10000).unwrap();
+    assert_eq!(first % PAGE_SIZE, 0);
+    assert_eq!(second % 0x10000, 0);
+    assert!(second >= first + PAGE_SIZE);
+
+    assert_eq!(controller.free_virt(first).unwrap(), PAGE_SIZE);
+    assert_eq!(controller.alloc_virt(PAGE_SIZE, PAGE_SIZE).unwrap(), first);
+    controller.free_virt(first).unwrap();
+    controller.free_virt(second).unwrap();
+    assert!(matches!(
+        controller.free_virt(second),
+        Err(AddressSpaceError::NotMapped(address)) if address == second
+    ));
+    assert!(matches!(
+        controller.alloc_virt(0, PAGE_SIZE),
+        Err(AddressSpaceError::InvalidRange(_))
+    ));
+}
+
+#[test_case]
+fn mappings_unmap_on_drop() {
+    let frames = UnmappedFrames::new(PAGE_SIZE as usize, PAGE_SIZE).unwrap();
+    let mapping = map_physical(16, frames.phys().as_u64() + 0x10).unwrap();
+    let address = mapping.address();
+    assert_eq!(address % PAGE_SIZE, 0x10);
+    assert_eq!(
+        memory_controller()
+            .lock()
+            .get_physical(VirtAddr::new(address)),
+        Some(frames.phys() + 0x10)
+    );
+    drop(mapping);
+    let mut controller = memory_controller().lock();
+    assert_eq!(controller.get_physical(VirtAddr::new(address)), None);
+    // The range went back to the window
+    let start = controller.alloc_virt(PAGE_SIZE, PAGE_SIZE).unwrap();
+    assert_eq!(start, address - 0x10);
+    controller.free_virt(start).unwrap();
+}
+
+#[test_case]
+fn aligned_mappings_use_huge_pages() {
+    let frames = UnmappedFrames::new(MIB_2 as usize, MIB_2).unwrap();
+    let mapping = map_physical(MIB_2, frames.phys().as_u64()).unwrap();
+    assert_eq!(
+        memory_controller()
+            .lock()
+            .page_size(VirtAddr::new(mapping.address())),
+        Some(PageSize::Size2MiB)
+    );
+}